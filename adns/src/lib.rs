@@ -14,6 +14,8 @@ pub struct Resolver {
     buf: Vec<u8>,
     qnum: u16,
     timeout: Duration,
+    /// Whether A records are queried before AAAA records
+    prefer_v4: bool,
 }
 
 struct Query {
@@ -21,6 +23,7 @@ struct Query {
     query_deadline: Instant,
     deadline: Instant,
     v4: bool,
+    prefer_v4: bool,
     server: usize,
 }
 
@@ -52,6 +55,7 @@ impl Resolver {
             timeout: Duration::from_secs(3),
             buf,
             qnum: 0,
+            prefer_v4: true,
         }
     }
 
@@ -59,6 +63,36 @@ impl Resolver {
         self.cache.clear();
     }
 
+    /// Drops all outstanding queries without responding to them.
+    pub fn cancel(&mut self) {
+        self.queries.clear();
+        self.responses.clear();
+    }
+
+    /// Sets whether AAAA records are queried before A records.
+    pub fn prefer_v6(&mut self, v6: bool) {
+        self.prefer_v4 = !v6;
+    }
+
+    /// Returns the cached address of a domain, if any.
+    pub fn cached(&self, domain: &str) -> Option<IpAddr> {
+        self.cache
+            .get(domain)
+            .filter(|entry| Instant::now() < entry.deadline)
+            .map(|entry| entry.ip)
+    }
+
+    /// Caches the address of a domain which was resolved by other means.
+    pub fn insert(&mut self, domain: &str, ip: IpAddr, ttl: u32) {
+        self.cache.insert(
+            domain.to_owned(),
+            CacheEntry {
+                ip,
+                deadline: Instant::now() + Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+
     pub fn from_resolv() -> io::Result<Resolver> {
         let buf = vec![0u8; 512];
         let mut conf = Vec::with_capacity(4096);
@@ -92,6 +126,7 @@ impl Resolver {
             timeout: Duration::from_secs(cfg.timeout as u64),
             buf,
             qnum: 0,
+            prefer_v4: true,
         })
     }
 
@@ -117,9 +152,7 @@ impl Resolver {
         if self.responses.get(domain).is_none() {
             let qn = self.qnum;
             self.qnum = self.qnum.wrapping_add(1);
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(domain, dns_parser::QueryType::A, dns_parser::QueryClass::IN);
-            let packet = query.build().unwrap_or_else(|d| d);
+            let packet = query_packet(qn, domain, self.prefer_v4);
             sock.send_to(&packet, self.servers[0])?;
 
             self.responses.insert(domain.to_string(), vec![]);
@@ -127,7 +160,8 @@ impl Resolver {
             self.queries.insert(
                 qn,
                 Query {
-                    v4: true,
+                    v4: self.prefer_v4,
+                    prefer_v4: self.prefer_v4,
                     server: 0,
                     domain: domain.to_string(),
                     deadline: now + self.timeout,
@@ -260,29 +294,39 @@ impl Resolver {
 impl Query {
     pub fn next(&mut self, qn: u16) -> Vec<u8> {
         self.query_deadline = Instant::now() + Duration::from_millis(QUERY_TIMEOUT_MS);
-        if self.v4 {
-            self.v4 = false;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                dns_parser::QueryType::AAAA,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
+        // Try the other address family, then move on to the next server
+        if self.v4 == self.prefer_v4 {
+            self.v4 = !self.prefer_v4;
         } else {
             self.server += 1;
-            self.v4 = true;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                dns_parser::QueryType::A,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
+            self.v4 = self.prefer_v4;
         }
+        query_packet(qn, &self.domain, self.v4)
     }
 }
 
+/// Builds a recursive query packet for the A or AAAA record of a domain.
+pub fn query_packet(qn: u16, domain: &str, v4: bool) -> Vec<u8> {
+    let qtype = if v4 {
+        dns_parser::QueryType::A
+    } else {
+        dns_parser::QueryType::AAAA
+    };
+    let mut query = dns_parser::Builder::new_query(qn, true);
+    query.add_question(domain, qtype, dns_parser::QueryClass::IN);
+    query.build().unwrap_or_else(|d| d)
+}
+
+/// Extracts the first address and its TTL from a response packet.
+pub fn parse_answer(data: &[u8]) -> Option<(IpAddr, u32)> {
+    let packet = dns_parser::Packet::parse(data).ok()?;
+    packet.answers.iter().find_map(|answer| match answer.data {
+        dns_parser::RRData::A(addr) => Some((addr.into(), answer.ttl)),
+        dns_parser::RRData::AAAA(addr) => Some((addr.into(), answer.ttl)),
+        _ => None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -334,4 +378,55 @@ mod tests {
         #[cfg(not(target_os = "macos"))]
         assert!(processed);
     }
+
+    #[test]
+    fn test_parse_answer() {
+        let mut packet = query_packet(7, "example.com", true);
+        assert_eq!(parse_answer(&packet), None);
+
+        // Mark as a response with a single A record answer
+        packet[2] |= 0x80;
+        packet[7] = 1;
+        packet.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        packet.extend_from_slice(&[93, 184, 216, 34]);
+        assert_eq!(
+            parse_answer(&packet),
+            Some(("93.184.216.34".parse().unwrap(), 3600))
+        );
+    }
+
+    #[test]
+    fn test_family_order() {
+        let mut q = Query {
+            domain: "example.com".to_owned(),
+            query_deadline: Instant::now(),
+            deadline: Instant::now(),
+            v4: false,
+            prefer_v4: false,
+            server: 0,
+        };
+        q.next(0);
+        assert_eq!((q.v4, q.server), (true, 0));
+        q.next(0);
+        assert_eq!((q.v4, q.server), (false, 1));
+
+        q.prefer_v4 = true;
+        q.v4 = true;
+        q.next(0);
+        assert_eq!((q.v4, q.server), (false, 1));
+        q.next(0);
+        assert_eq!((q.v4, q.server), (true, 2));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut resolver = Resolver::new(&["127.0.0.1:53".parse().unwrap()]);
+        let mut sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+
+        assert_eq!(resolver.query(&mut sock, 0, "example.com").unwrap(), None);
+        resolver.cancel();
+        assert!(resolver.queries.is_empty());
+        assert!(resolver.responses.is_empty());
+    }
 }
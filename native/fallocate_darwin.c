@@ -2,6 +2,7 @@
 
 #include <fcntl.h>
 #include <stdint.h>
+#include <sys/stat.h>
 #include <unistd.h>
 
 int native_fallocate(int fd, uint64_t len) {
@@ -21,3 +22,23 @@ int native_fallocate(int fd, uint64_t len) {
     }
     return ftruncate(fd, len);
 }
+
+int native_preallocate(int fd, uint64_t offset, uint64_t len) {
+    struct stat st;
+    if (fstat(fd, &st) == -1) {
+        return -1;
+    }
+    fstore_t fstore;
+    fstore.fst_flags = F_ALLOCATEALL;
+    fstore.fst_posmode = F_PEOFPOSMODE;
+    fstore.fst_offset = 0;
+    fstore.fst_length = len;
+    if (fcntl(fd, F_PREALLOCATE, &fstore) == -1) {
+        return -1;
+    }
+    // Never shrink the file, earlier ranges may already hold data
+    if ((uint64_t)st.st_size < offset + len) {
+        return ftruncate(fd, offset + len);
+    }
+    return 0;
+}
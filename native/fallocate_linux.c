@@ -1,9 +1,19 @@
 #define _GNU_SOURCE
 #define _FILE_OFFSET_BITS 64
 
+#include <errno.h>
 #include <fcntl.h>
 #include <stdint.h>
 
 int native_fallocate(int fd, uint64_t len) {
     return fallocate(fd, 0, 0, (off_t)len);
 }
+
+int native_preallocate(int fd, uint64_t offset, uint64_t len) {
+    int res = posix_fallocate(fd, (off_t)offset, (off_t)len);
+    if (res != 0) {
+        errno = res;
+        return -1;
+    }
+    return 0;
+}
@@ -1,5 +1,6 @@
 #define _FILE_OFFSET_BITS 64
 
+#include <errno.h>
 #include <fcntl.h>
 #include <unistd.h>
 #include <stdint.h>
@@ -9,3 +10,12 @@ int native_fallocate(int fd, uint64_t len) {
     // like ZFS
     return ftruncate(fd, len);
 }
+
+int native_preallocate(int fd, uint64_t offset, uint64_t len) {
+    int res = posix_fallocate(fd, (off_t)offset, (off_t)len);
+    if (res != 0) {
+        errno = res;
+        return -1;
+    }
+    return 0;
+}
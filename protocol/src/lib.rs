@@ -5,9 +5,12 @@ use std::ops::Deref;
 use byteorder::{BigEndian, WriteBytesExt};
 
 pub const DHT_EXT: (usize, u8) = (7, 1);
+pub const FAST_EXT: (usize, u8) = (7, 0x04);
 pub const EXT_PROTO: (usize, u8) = (5, 0x10);
 pub const UT_META_ID: u8 = 9;
 pub const UT_PEX_ID: u8 = 11;
+pub const UT_HOLEPUNCH_ID: u8 = 4;
+pub const LT_DONTHAVE_ID: u8 = 7;
 
 pub trait Bitfield: Clone + From<Vec<u8>> {
     fn bytes(&self) -> usize;
@@ -50,6 +53,15 @@ pub enum Message<BF: Bitfield, Buf: Clone + Deref<Target = [u8]>> {
         length: u32,
     },
     Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
     Extension {
         id: u8,
         payload: Vec<u8>,
@@ -91,6 +103,19 @@ impl<BF: Bitfield, Buf: Buffer> fmt::Debug for Message<BF, Buf> {
                 index, begin, length
             ),
             Message::Port(port) => write!(f, "Message::Port({:?})", port),
+            Message::SuggestPiece(p) => write!(f, "Message::SuggestPiece({})", p),
+            Message::HaveAll => write!(f, "Message::HaveAll"),
+            Message::HaveNone => write!(f, "Message::HaveNone"),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "Message::RejectRequest {{ idx: {}, begin: {}, len: {} }}",
+                index, begin, length
+            ),
+            Message::AllowedFast(p) => write!(f, "Message::AllowedFast({})", p),
             Message::Extension { id, .. } => write!(f, "Message::Extension {{ id: {} }}", id),
         }
     }
@@ -137,6 +162,19 @@ impl<BF: Bitfield, Buf: Buffer> Clone for Message<BF, Buf> {
                 length,
             },
             Message::Port(port) => Message::Port(port),
+            Message::SuggestPiece(p) => Message::SuggestPiece(p),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => Message::RejectRequest {
+                index,
+                begin,
+                length,
+            },
+            Message::AllowedFast(p) => Message::AllowedFast(p),
             Message::Extension { id, ref payload } => Message::Extension {
                 id,
                 payload: payload.clone(),
@@ -160,8 +198,12 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
             | (&Message::Choke, &Message::Choke)
             | (&Message::Unchoke, &Message::Unchoke)
             | (&Message::Interested, &Message::Interested)
-            | (&Message::Uninterested, &Message::Uninterested) => true,
-            (&Message::Have(p), &Message::Have(p_)) => p == p_,
+            | (&Message::Uninterested, &Message::Uninterested)
+            | (&Message::HaveAll, &Message::HaveAll)
+            | (&Message::HaveNone, &Message::HaveNone) => true,
+            (&Message::Have(p), &Message::Have(p_))
+            | (&Message::SuggestPiece(p), &Message::SuggestPiece(p_))
+            | (&Message::AllowedFast(p), &Message::AllowedFast(p_)) => p == p_,
             (&Message::Port(p), &Message::Port(p_)) => p == p_,
             (
                 &Message::Request {
@@ -200,6 +242,18 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
                     begin: b,
                     length: l,
                 },
+            )
+            | (
+                &Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                },
+                &Message::RejectRequest {
+                    index: i,
+                    begin: b,
+                    length: l,
+                },
             ) => index == i && begin == b && length == l,
             (
                 &Message::Extension { id, ref payload },
@@ -218,6 +272,7 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         let mut rsv = [0u8; 8];
         rsv[DHT_EXT.0] |= DHT_EXT.1;
         rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
         Message::Handshake {
             rsv,
             hash: *hash,
@@ -260,11 +315,16 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         match *self {
             Message::Handshake { .. } => 68,
             Message::KeepAlive => 4,
-            Message::Choke | Message::Unchoke | Message::Interested | Message::Uninterested => 5,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::Uninterested
+            | Message::HaveAll
+            | Message::HaveNone => 5,
             Message::Port(_) => 7,
-            Message::Have(_) => 9,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 9,
             Message::Bitfield(ref pf) => 5 + pf.bytes(),
-            Message::Request { .. } | Message::Cancel { .. } => 17,
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 17,
             Message::Piece { ref data, .. } => 13 + data.len(),
             Message::Extension { ref payload, .. } => 6 + payload.len(),
         }
@@ -354,6 +414,35 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 buf.write_u32::<BigEndian>(begin)?;
                 buf.write_u32::<BigEndian>(length)?;
             }
+            Message::SuggestPiece(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x0D)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
+            Message::HaveAll => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0E)?;
+            }
+            Message::HaveNone => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0F)?;
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.write_u32::<BigEndian>(13)?;
+                buf.write_u8(0x10)?;
+                buf.write_u32::<BigEndian>(index)?;
+                buf.write_u32::<BigEndian>(begin)?;
+                buf.write_u32::<BigEndian>(length)?;
+            }
+            Message::AllowedFast(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x11)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
             Message::Extension { id, ref payload } => {
                 buf.write_u32::<BigEndian>(2 + payload.len() as u32)?;
                 buf.write_u8(20)?;
@@ -3,7 +3,7 @@ use std::borrow::Cow;
 use chrono::{DateTime, Utc};
 
 use super::criterion::Criterion;
-use super::resource::{CResourceUpdate, ResourceKind, SResourceUpdate};
+use super::resource::{CResourceUpdate, ErrorCode, ResourceKind, SResourceUpdate};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Version {
@@ -61,6 +61,9 @@ pub enum CMessage {
         start: bool,
         #[serde(default = "default_false")]
         import: bool,
+        link: Option<LinkMode>,
+        #[serde(default = "default_false")]
+        memory: bool,
     },
     UploadMagnet {
         serial: u64,
@@ -68,6 +71,8 @@ pub enum CMessage {
         path: Option<String>,
         #[serde(default = "default_true")]
         start: bool,
+        #[serde(default = "default_false")]
+        memory: bool,
     },
     UploadFiles {
         serial: u64,
@@ -100,9 +105,31 @@ pub enum CMessage {
         serial: u64,
         ids: Vec<String>,
     },
+    RepairResources {
+        serial: u64,
+        ids: Vec<String>,
+    },
     PurgeDns {
         serial: u64,
     },
+    GetPeerDiff {
+        serial: u64,
+        id: String,
+        #[serde(default)]
+        generation: Option<u64>,
+    },
+    GetIntegrityReport {
+        serial: u64,
+        id: String,
+    },
+    SetPieceDeadline {
+        serial: u64,
+        id: String,
+        piece: u32,
+        /// Milliseconds from now, or None to clear the deadline
+        #[serde(default)]
+        deadline: Option<u64>,
+    },
 }
 
 /// Server -> client message
@@ -137,6 +164,20 @@ pub enum SMessage<'a> {
         serial: u64,
         id: String,
     },
+    PeerDiff {
+        serial: u64,
+        generation: u64,
+        full: bool,
+        added: Vec<SResourceUpdate<'a>>,
+        changed: Vec<SResourceUpdate<'a>>,
+        removed: Vec<String>,
+    },
+    IntegrityReport(IntegrityReport),
+    PieceDeadlineExpired {
+        serial: u64,
+        id: String,
+        piece: u32,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -151,9 +192,39 @@ pub enum SMessage<'a> {
 #[serde(deny_unknown_fields)]
 pub struct Error {
     pub serial: Option<u64>,
+    pub code: ErrorCode,
     pub reason: String,
 }
 
+/// What is known about the state of a torrent's data on disk
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntegrityReport {
+    pub serial: u64,
+    pub id: String,
+    /// Pieces we have which passed their last hash check
+    pub pieces_ok: u64,
+    /// Pieces which failed their last hash check
+    pub pieces_failed: u64,
+    /// Pieces we have which were never hashed, e.g. after an import
+    pub pieces_unverified: u64,
+    /// IDs of wanted files which are missing or of the wrong size
+    pub files_mismatched: Vec<String>,
+    /// When the torrent was last fully validated
+    pub last_scrub: Option<DateTime<Utc>>,
+}
+
+/// How files an uploaded torrent shares with existing torrents are put in
+/// its download directory. Either falls back to copying the file.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Copy-on-write clone, supported by e.g. btrfs and XFS
+    Reflink,
+    /// Hard link, so that both torrents share the file
+    Hardlink,
+}
+
 impl Version {
     pub fn current() -> Version {
         Version {
@@ -207,4 +278,361 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_error_code() {
+        let m = SMessage::UnknownResource(Error {
+            serial: Some(1),
+            code: ErrorCode::UnknownResource,
+            reason: "unknown resource id a".to_owned(),
+        });
+        let v = serde_json::to_value(&m).unwrap();
+        assert_eq!(v["type"], "UNKNOWN_RESOURCE");
+        assert_eq!(v["code"], "unknown_resource");
+        assert_eq!(
+            serde_json::to_value(ErrorCode::TrackerInvalidResponse).unwrap(),
+            ErrorCode::TrackerInvalidResponse.as_str()
+        );
+    }
+
+    #[test]
+    fn test_upload_link() {
+        let m: CMessage = serde_json::from_str(
+            r#"{ "type": "UPLOAD_TORRENT", "serial": 1, "size": 2, "path": null, "link": "reflink" }"#,
+        )
+        .unwrap();
+        match m {
+            CMessage::UploadTorrent { link, .. } => assert_eq!(link, Some(LinkMode::Reflink)),
+            _ => unreachable!(),
+        }
+        let m: CMessage =
+            serde_json::from_str(r#"{ "type": "UPLOAD_TORRENT", "serial": 1, "size": 2 }"#)
+                .unwrap();
+        match m {
+            CMessage::UploadTorrent { link, .. } => assert_eq!(link, None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_upload_memory() {
+        let m: CMessage = serde_json::from_str(
+            r#"{ "type": "UPLOAD_MAGNET", "serial": 1, "uri": "magnet:", "memory": true }"#,
+        )
+        .unwrap();
+        match m {
+            CMessage::UploadMagnet { memory, start, .. } => assert!(memory && start),
+            _ => unreachable!(),
+        }
+        let m: CMessage =
+            serde_json::from_str(r#"{ "type": "UPLOAD_TORRENT", "serial": 1, "size": 2 }"#)
+                .unwrap();
+        match m {
+            CMessage::UploadTorrent { memory, .. } => assert!(!memory),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_integrity_report() {
+        let m: CMessage =
+            serde_json::from_str(r#"{ "type": "GET_INTEGRITY_REPORT", "serial": 1, "id": "a" }"#)
+                .unwrap();
+        match m {
+            CMessage::GetIntegrityReport { serial: 1, id } => assert_eq!(id, "a"),
+            _ => unreachable!(),
+        }
+
+        let report = IntegrityReport {
+            serial: 1,
+            id: "a".to_owned(),
+            pieces_ok: 10,
+            pieces_failed: 1,
+            pieces_unverified: 0,
+            files_mismatched: vec!["b".to_owned()],
+            last_scrub: None,
+        };
+        let data = serde_json::to_string(&SMessage::IntegrityReport(report.clone())).unwrap();
+        assert!(data.starts_with(r#"{"type":"INTEGRITY_REPORT","serial":1,"#));
+        match serde_json::from_str(&data).unwrap() {
+            SMessage::IntegrityReport(r) => assert_eq!(r, report),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_dht_update() {
+        let update = resource::SResourceUpdate::ServerDht {
+            id: "server".to_owned(),
+            kind: resource::ResourceKind::Server,
+            dht_nodes: 120,
+            dht_buckets: 18,
+            dht_fresh_buckets: 15,
+            dht_query_rate: 2.5,
+            dht_torrents: 4,
+            dht_peers: 9,
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut server = resource::Server::default();
+        server.update(parsed);
+        assert_eq!((server.dht_nodes, server.dht_fresh_buckets), (120, 15));
+        assert_eq!(server.dht_query_rate, 2.5);
+    }
+
+    #[test]
+    fn test_upload_slots_update() {
+        let update = resource::SResourceUpdate::TorrentUploadSlots {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            upload_slots: Some(2),
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "upload_slots": null}"#).unwrap();
+        assert_eq!(update.upload_slots, Some(None));
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "upload_slots": 3}"#).unwrap();
+        assert_eq!(update.upload_slots, Some(Some(3)));
+        assert!(serde_json::from_str::<resource::CResourceUpdate>(
+            r#"{"id": "torrent", "upload_slots": 0}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_super_seeding_update() {
+        let update = resource::SResourceUpdate::TorrentSuperSeeding {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            super_seeding: true,
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut torrent = resource::Torrent::default();
+        torrent.update(parsed);
+        assert!(torrent.super_seeding);
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "super_seeding": false}"#).unwrap();
+        assert_eq!(update.super_seeding, Some(false));
+    }
+
+    #[test]
+    fn test_peer_client_update() {
+        let update = resource::SResourceUpdate::PeerClient {
+            id: "peer".to_owned(),
+            kind: resource::ResourceKind::Peer,
+            client: "Transmission 3.00".to_owned(),
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut peer = resource::Peer::default();
+        peer.update(parsed);
+        assert_eq!(peer.client, Some("Transmission 3.00".to_owned()));
+    }
+
+    #[test]
+    fn test_peer_sources_update() {
+        let update = resource::SResourceUpdate::TorrentPeerSources {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            peers_tracker: 4,
+            peers_dht: 3,
+            peers_pex: 2,
+            peers_holepunch: 0,
+            peers_incoming: 1,
+            peers_manual: 0,
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut torrent = resource::Torrent::default();
+        torrent.update(parsed);
+        assert_eq!((torrent.peers_tracker, torrent.peers_dht), (4, 3));
+
+        let data = serde_json::to_string(&resource::PeerSource::Pex).unwrap();
+        assert_eq!(data, r#""pex""#);
+        assert_eq!(resource::PeerSource::Pex.as_str(), "pex");
+    }
+
+    #[test]
+    fn test_file_position_update() {
+        let update = resource::SResourceUpdate::FilePosition {
+            id: "file".to_owned(),
+            kind: resource::ResourceKind::File,
+            position: Some(1 << 20),
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut file = resource::File::default();
+        file.update(parsed);
+        assert_eq!(file.position, Some(1 << 20));
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "file", "position": 4096}"#).unwrap();
+        assert_eq!(update.position, Some(Some(4096)));
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "file", "position": null}"#).unwrap();
+        assert_eq!(update.position, Some(None));
+        assert!(serde_json::from_str::<resource::CResourceUpdate>(
+            r#"{"id": "file", "position": -1}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_availability_map_update() {
+        let update = resource::SResourceUpdate::TorrentAvailabilityMap {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            piece_availability: vec![(3, true, 10), (0, false, 2)],
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        assert!(data.contains(r#""piece_availability":[[3,true,10],[0,false,2]]"#));
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut torrent = resource::Torrent::default();
+        torrent.update(parsed);
+        assert_eq!(
+            torrent.piece_availability,
+            vec![(3, true, 10), (0, false, 2)]
+        );
+    }
+
+    #[test]
+    fn test_first_last_update() {
+        let update = resource::SResourceUpdate::TorrentFirstLast {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            first_last: true,
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut torrent = resource::Torrent::default();
+        torrent.update(parsed);
+        assert!(torrent.first_last);
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "first_last": true}"#).unwrap();
+        assert_eq!(update.first_last, Some(true));
+    }
+
+    #[test]
+    fn test_piece_deadline() {
+        let m: CMessage = serde_json::from_str(
+            r#"{ "type": "SET_PIECE_DEADLINE", "serial": 1, "id": "a", "piece": 3, "deadline": 500 }"#,
+        )
+        .unwrap();
+        match m {
+            CMessage::SetPieceDeadline {
+                serial: 1,
+                piece: 3,
+                deadline: Some(500),
+                ..
+            } => {}
+            _ => unreachable!(),
+        }
+        let m: CMessage = serde_json::from_str(
+            r#"{ "type": "SET_PIECE_DEADLINE", "serial": 2, "id": "a", "piece": 3 }"#,
+        )
+        .unwrap();
+        match m {
+            CMessage::SetPieceDeadline { deadline: None, .. } => {}
+            _ => unreachable!(),
+        }
+
+        let m = SMessage::PieceDeadlineExpired {
+            serial: 1,
+            id: "a".to_owned(),
+            piece: 3,
+        };
+        let v = serde_json::to_value(&m).unwrap();
+        assert_eq!(v["type"], "PIECE_DEADLINE_EXPIRED");
+        assert_eq!(v["piece"], 3);
+    }
+
+    #[test]
+    fn test_completed_path_update() {
+        let update = resource::SResourceUpdate::TorrentCompletedPath {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            completed_path: Some("/done".to_owned()),
+        };
+        let data = serde_json::to_string(&update).unwrap();
+        let parsed: resource::SResourceUpdate<'_> = serde_json::from_str(&data).unwrap();
+        assert_eq!(parsed, update);
+
+        let mut torrent = resource::Torrent::default();
+        torrent.update(parsed);
+        assert_eq!(torrent.completed_path, Some("/done".to_owned()));
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "completed_path": null}"#).unwrap();
+        assert_eq!(update.completed_path, Some(None));
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent"}"#).unwrap();
+        assert_eq!(update.completed_path, None);
+    }
+
+    #[test]
+    fn test_move_updates() {
+        let mut torrent = resource::Torrent::default();
+        torrent.update(resource::SResourceUpdate::TorrentStatus {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            error: None,
+            error_code: None,
+            status: resource::Status::Moving,
+        });
+        assert_eq!(serde_json::to_value(&torrent).unwrap()["status"], "moving");
+        torrent.update(resource::SResourceUpdate::TorrentPath {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            path: "/new".to_owned(),
+        });
+        assert_eq!(torrent.path, "/new");
+    }
+
+    #[test]
+    fn test_rename_updates() {
+        let mut torrent = resource::Torrent::default();
+        torrent.update(resource::SResourceUpdate::TorrentName {
+            id: "torrent".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            name: "Renamed".to_owned(),
+        });
+        assert_eq!(torrent.name, Some("Renamed".to_owned()));
+
+        let update = resource::SResourceUpdate::FilePath {
+            id: "file".to_owned(),
+            kind: resource::ResourceKind::File,
+            path: "Renamed/a.mkv".to_owned(),
+        };
+        let v = serde_json::to_value(&update).unwrap();
+        assert_eq!(v["type"], "file");
+        assert_eq!(v["path"], "Renamed/a.mkv");
+        let mut file = resource::File::default();
+        file.update(update);
+        assert_eq!(file.path, "Renamed/a.mkv");
+
+        let update: resource::CResourceUpdate =
+            serde_json::from_str(r#"{"id": "torrent", "name": "Renamed"}"#).unwrap();
+        assert_eq!(update.name, Some("Renamed".to_owned()));
+    }
 }
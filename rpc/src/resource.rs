@@ -87,12 +87,24 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerDht {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht_nodes: u64,
+        dht_buckets: u64,
+        dht_fresh_buckets: u64,
+        dht_query_rate: f32,
+        dht_torrents: u64,
+        dht_peers: u64,
+    },
 
     TorrentStatus {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         error: Option<String>,
+        error_code: Option<ErrorCode>,
         status: Status,
     },
     TorrentTransfer {
@@ -112,12 +124,48 @@ pub enum SResourceUpdate<'a> {
         peers: u16,
         availability: f32,
     },
+    TorrentSwarm {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht_seeders: u32,
+        dht_leechers: u32,
+    },
+    TorrentPeerSources {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        peers_tracker: u16,
+        peers_dht: u16,
+        peers_pex: u16,
+        peers_holepunch: u16,
+        peers_incoming: u16,
+        peers_manual: u16,
+    },
     TorrentPicker {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         strategy: Strategy,
     },
+    TorrentSuperSeeding {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        super_seeding: bool,
+    },
+    TorrentUploadSlots {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        upload_slots: Option<u16>,
+    },
+    TorrentFirstLast {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        first_last: bool,
+    },
     TorrentPriority {
         id: String,
         #[serde(rename = "type")]
@@ -130,12 +178,30 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         path: String,
     },
+    TorrentCompletedPath {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        completed_path: Option<String>,
+    },
+    TorrentName {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        name: String,
+    },
     TorrentPieces {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         piece_field: String,
     },
+    TorrentAvailabilityMap {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        piece_availability: Vec<(u16, bool, u32)>,
+    },
 
     TrackerStatus {
         id: String,
@@ -143,6 +209,10 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        error_code: Option<ErrorCode>,
+        seeders: u32,
+        leechers: u32,
+        downloaded: u32,
     },
 
     FilePriority {
@@ -157,6 +227,18 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         progress: f32,
     },
+    FilePosition {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        position: Option<u64>,
+    },
+    FilePath {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        path: String,
+    },
 
     PieceAvailable {
         id: String,
@@ -177,6 +259,18 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         availability: f32,
     },
+    PeerCapabilities {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        capabilities: Vec<String>,
+    },
+    PeerClient {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        client: String,
+    },
 }
 
 /// Collection of mutable fields that clients
@@ -185,15 +279,27 @@ pub enum SResourceUpdate<'a> {
 #[serde(deny_unknown_fields)]
 pub struct CResourceUpdate {
     pub id: String,
+    pub name: Option<String>,
     pub path: Option<String>,
+    #[serde(deserialize_with = "deserialize_completed_path")]
+    #[serde(default)]
+    pub completed_path: Option<Option<String>>,
     pub priority: Option<u8>,
     pub strategy: Option<Strategy>,
+    pub super_seeding: Option<bool>,
+    #[serde(deserialize_with = "deserialize_slots")]
+    #[serde(default)]
+    pub upload_slots: Option<Option<u16>>,
+    pub first_last: Option<bool>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_up: Option<Option<i64>>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_position")]
+    #[serde(default)]
+    pub position: Option<Option<u64>>,
     pub user_data: Option<json::Value>,
 }
 
@@ -211,6 +317,12 @@ pub struct Server {
     pub ses_transferred_up: u64,
     pub ses_transferred_down: u64,
     pub free_space: u64,
+    pub dht_nodes: u64,
+    pub dht_buckets: u64,
+    pub dht_fresh_buckets: u64,
+    pub dht_query_rate: f32,
+    pub dht_torrents: u64,
+    pub dht_peers: u64,
     pub started: DateTime<Utc>,
     pub user_data: json::Value,
 }
@@ -226,6 +338,22 @@ impl Server {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
             }
+            SResourceUpdate::ServerDht {
+                dht_nodes,
+                dht_buckets,
+                dht_fresh_buckets,
+                dht_query_rate,
+                dht_torrents,
+                dht_peers,
+                ..
+            } => {
+                self.dht_nodes = dht_nodes;
+                self.dht_buckets = dht_buckets;
+                self.dht_fresh_buckets = dht_fresh_buckets;
+                self.dht_query_rate = dht_query_rate;
+                self.dht_torrents = dht_torrents;
+                self.dht_peers = dht_peers;
+            }
             SResourceUpdate::ServerTransfer {
                 rate_up,
                 rate_down,
@@ -268,14 +396,20 @@ pub struct Torrent {
     pub comment: Option<String>,
     pub private: bool,
     pub path: String,
+    pub completed_path: Option<String>,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
     pub status: Status,
     pub error: Option<String>,
+    pub error_code: Option<ErrorCode>,
     pub priority: u8,
     pub progress: f32,
     pub availability: f32,
     pub strategy: Strategy,
+    pub super_seeding: bool,
+    pub upload_slots: Option<u16>,
+    pub first_last: bool,
+    pub memory: bool,
     pub rate_up: u64,
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
@@ -283,12 +417,24 @@ pub struct Torrent {
     pub transferred_up: u64,
     pub transferred_down: u64,
     pub peers: u16,
+    /// Connected peers by where they were found
+    pub peers_tracker: u16,
+    pub peers_dht: u16,
+    pub peers_pex: u16,
+    pub peers_holepunch: u16,
+    pub peers_incoming: u16,
+    pub peers_manual: u16,
+    pub dht_seeders: u32,
+    pub dht_leechers: u32,
     pub trackers: u8,
     pub tracker_urls: Vec<String>,
     pub size: Option<u64>,
     pub pieces: Option<u64>,
     pub piece_size: Option<u32>,
     pub piece_field: String,
+    /// Runs of consecutive pieces which as many connected peers have, and
+    /// which we either have or not, as (peers, have, pieces)
+    pub piece_availability: Vec<(u16, bool, u32)>,
     pub files: Option<u32>,
     pub user_data: json::Value,
 }
@@ -305,8 +451,14 @@ impl Torrent {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
             }
-            SResourceUpdate::TorrentStatus { error, status, .. } => {
+            SResourceUpdate::TorrentStatus {
+                error,
+                error_code,
+                status,
+                ..
+            } => {
                 self.error = error;
+                self.error_code = error_code;
                 self.status = status;
             }
             SResourceUpdate::TorrentTransfer {
@@ -331,15 +483,62 @@ impl Torrent {
                 self.peers = peers;
                 self.availability = availability;
             }
+            SResourceUpdate::TorrentSwarm {
+                dht_seeders,
+                dht_leechers,
+                ..
+            } => {
+                self.dht_seeders = dht_seeders;
+                self.dht_leechers = dht_leechers;
+            }
+            SResourceUpdate::TorrentPeerSources {
+                peers_tracker,
+                peers_dht,
+                peers_pex,
+                peers_holepunch,
+                peers_incoming,
+                peers_manual,
+                ..
+            } => {
+                self.peers_tracker = peers_tracker;
+                self.peers_dht = peers_dht;
+                self.peers_pex = peers_pex;
+                self.peers_holepunch = peers_holepunch;
+                self.peers_incoming = peers_incoming;
+                self.peers_manual = peers_manual;
+            }
             SResourceUpdate::TorrentPicker { strategy, .. } => {
                 self.strategy = strategy;
             }
+            SResourceUpdate::TorrentSuperSeeding { super_seeding, .. } => {
+                self.super_seeding = super_seeding;
+            }
+            SResourceUpdate::TorrentUploadSlots { upload_slots, .. } => {
+                self.upload_slots = upload_slots;
+            }
+            SResourceUpdate::TorrentFirstLast { first_last, .. } => {
+                self.first_last = first_last;
+            }
             SResourceUpdate::TorrentPriority { priority, .. } => {
                 self.priority = priority;
             }
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::TorrentPath { path, .. } => {
+                self.path = path;
+            }
+            SResourceUpdate::TorrentCompletedPath { completed_path, .. } => {
+                self.completed_path = completed_path;
+            }
+            SResourceUpdate::TorrentName { name, .. } => {
+                self.name = Some(name);
+            }
+            SResourceUpdate::TorrentAvailabilityMap {
+                piece_availability, ..
+            } => {
+                self.piece_availability = piece_availability;
+            }
             SResourceUpdate::Resource(Cow::Borrowed(Resource::Torrent(t))) => *self = t.clone(),
             SResourceUpdate::Resource(Cow::Owned(Resource::Torrent(mut t))) => {
                 mem::swap(self, &mut t)
@@ -363,6 +562,8 @@ pub enum Status {
     Idle,
     Seeding,
     Hashing,
+    Allocating,
+    Moving,
     Error,
 }
 
@@ -374,6 +575,41 @@ pub enum Strategy {
     Sequential,
 }
 
+/// Machine readable counterpart of an error message. Unlike the message,
+/// these are stable, so clients may branch on or localize them.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Errors in response to a client's request
+    UnknownResource,
+    WrongResourceKind,
+    MalformedJson,
+    MalformedMessage,
+    InvalidPeerAddress,
+    InvalidTrackerUrl,
+    InvalidMagnet,
+    InvalidTorrent,
+    TorrentExists,
+    PeerConnectionFailed,
+    MetadataMissing,
+    TransferFailed,
+    TransferTimeout,
+
+    // Tracker errors
+    TrackerRejected,
+    TrackerInvalidResponse,
+    TrackerInvalidRequest,
+    TrackerUnreachable,
+    TrackerTimeout,
+    TrackerDns,
+
+    // Disk errors
+    DiskNotFound,
+    DiskPermissionDenied,
+    DiskFull,
+    DiskIo,
+}
+
 impl Strategy {
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -383,6 +619,33 @@ impl Strategy {
     }
 }
 
+/// Where a peer was found
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum PeerSource {
+    Tracker = 0,
+    Dht,
+    Pex,
+    Holepunch,
+    #[default]
+    Incoming,
+    Manual,
+}
+
+impl PeerSource {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "dht",
+            PeerSource::Pex => "pex",
+            PeerSource::Holepunch => "holepunch",
+            PeerSource::Incoming => "incoming",
+            PeerSource::Manual => "manual",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Piece {
@@ -418,6 +681,8 @@ pub struct File {
     pub availability: f32,
     pub priority: u8,
     pub size: u64,
+    /// Playback position in bytes, while the file is being streamed
+    pub position: Option<u64>,
     pub user_data: json::Value,
 }
 
@@ -430,6 +695,12 @@ impl File {
             SResourceUpdate::FileProgress { progress, .. } => {
                 self.progress = progress;
             }
+            SResourceUpdate::FilePosition { position, .. } => {
+                self.position = position;
+            }
+            SResourceUpdate::FilePath { path, .. } => {
+                self.path = path;
+            }
             _ => {}
         }
     }
@@ -441,10 +712,15 @@ pub struct Peer {
     pub id: String,
     pub torrent_id: String,
     pub client_id: String,
+    /// Client name and version, if the peer identified itself
+    pub client: Option<String>,
     pub ip: String,
     pub rate_up: u64,
     pub rate_down: u64,
     pub availability: f32,
+    pub source: PeerSource,
+    /// Extensions supported by both the peer and us
+    pub capabilities: Vec<String>,
     pub user_data: json::Value,
 }
 
@@ -460,6 +736,12 @@ impl Peer {
             SResourceUpdate::PeerAvailability { availability, .. } => {
                 self.availability = availability;
             }
+            SResourceUpdate::PeerCapabilities { capabilities, .. } => {
+                self.capabilities = capabilities;
+            }
+            SResourceUpdate::PeerClient { client, .. } => {
+                self.client = Some(client);
+            }
             _ => {}
         }
     }
@@ -474,6 +756,10 @@ pub struct Tracker {
     pub url: Url,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    pub error_code: Option<ErrorCode>,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub downloaded: u32,
     pub user_data: json::Value,
 }
 
@@ -481,10 +767,20 @@ impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
         match update {
             SResourceUpdate::TrackerStatus {
-                last_report, error, ..
+                last_report,
+                error,
+                error_code,
+                seeders,
+                leechers,
+                downloaded,
+                ..
             } => {
                 self.last_report = last_report;
                 self.error = error;
+                self.error_code = error_code;
+                self.seeders = seeders;
+                self.leechers = leechers;
+                self.downloaded = downloaded;
             }
             _ => {}
         }
@@ -501,17 +797,30 @@ impl<'a> SResourceUpdate<'a> {
             | &SResourceUpdate::ServerTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerDht { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
+            | &SResourceUpdate::TorrentSwarm { ref id, .. }
+            | &SResourceUpdate::TorrentPeerSources { ref id, .. }
             | &SResourceUpdate::TorrentPicker { ref id, .. }
+            | &SResourceUpdate::TorrentSuperSeeding { ref id, .. }
+            | &SResourceUpdate::TorrentUploadSlots { ref id, .. }
+            | &SResourceUpdate::TorrentFirstLast { ref id, .. }
             | &SResourceUpdate::TorrentPriority { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
+            | &SResourceUpdate::TorrentCompletedPath { ref id, .. }
+            | &SResourceUpdate::TorrentName { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
+            | &SResourceUpdate::TorrentAvailabilityMap { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
+            | &SResourceUpdate::FilePosition { ref id, .. }
+            | &SResourceUpdate::FilePath { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
+            | &SResourceUpdate::PeerCapabilities { ref id, .. }
+            | &SResourceUpdate::PeerClient { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
             | &SResourceUpdate::PieceDownloaded { ref id, .. } => id,
         }
@@ -700,6 +1009,9 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  path: {}", t.path)?;
                 write!(f, "\n")?;
+                if let Some(ref p) = t.completed_path {
+                    writeln!(f, "  completed path: {}", p)?;
+                }
                 write!(f, "  created at: {}", t.created)?;
                 write!(f, "\n")?;
                 write!(f, "  modified at: {}", t.modified)?;
@@ -718,6 +1030,16 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  strategy: {:?}", t.strategy)?;
                 write!(f, "\n")?;
+                write!(f, "  super seeding: {}", t.super_seeding)?;
+                write!(f, "\n")?;
+                match t.upload_slots {
+                    Some(s) => write!(f, "  upload slots: {}", s)?,
+                    None => write!(f, "  upload slots: auto")?,
+                }
+                write!(f, "\n")?;
+                write!(f, "  first/last pieces first: {}", t.first_last)?;
+                write!(f, "\n")?;
+                writeln!(f, "  in memory: {}", t.memory)?;
                 write!(f, "  upload: {} B/s", t.rate_up)?;
                 write!(f, "\n")?;
                 write!(f, "  download: {} B/s", t.rate_down)?;
@@ -810,6 +1132,56 @@ where
     }
 }
 
+fn deserialize_slots<'de, D>(de: D) -> Result<Option<Option<u16>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Number(ref i) => match i.as_u64() {
+            Some(s) if s > 0 && s <= u64::from(u16::MAX) => Ok(Some(Some(s as u16))),
+            _ => Err(serde::de::Error::custom(
+                "Upload slots must be a positive integer",
+            )),
+        },
+        _ => Err(serde::de::Error::custom(
+            "Upload slots must be number or null",
+        )),
+    }
+}
+
+fn deserialize_completed_path<'de, D>(de: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::String(s) => Ok(Some(Some(s))),
+        _ => Err(serde::de::Error::custom(
+            "Completed path must be string or null",
+        )),
+    }
+}
+
+fn deserialize_position<'de, D>(de: D) -> Result<Option<Option<u64>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Number(ref i) => match i.as_u64() {
+            Some(p) => Ok(Some(Some(p))),
+            None => Err(serde::de::Error::custom(
+                "Position must be a non-negative integer",
+            )),
+        },
+        _ => Err(serde::de::Error::custom("Position must be number or null")),
+    }
+}
+
 // TODO: Proc macros to remove this shit
 
 impl Queryable for Resource {
@@ -861,6 +1233,12 @@ impl Queryable for Server {
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "dht_nodes" => Some(Field::N(self.dht_nodes as i64)),
+            "dht_buckets" => Some(Field::N(self.dht_buckets as i64)),
+            "dht_fresh_buckets" => Some(Field::N(self.dht_fresh_buckets as i64)),
+            "dht_query_rate" => Some(Field::F(self.dht_query_rate)),
+            "dht_torrents" => Some(Field::N(self.dht_torrents as i64)),
+            "dht_peers" => Some(Field::N(self.dht_peers as i64)),
 
             "started" => Some(Field::D(self.started)),
 
@@ -895,6 +1273,12 @@ impl Queryable for Torrent {
                     .unwrap_or(FNULL),
             ),
             "path" => Some(Field::S(&self.path)),
+            "completed_path" => Some(
+                self.completed_path
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
             "status" => Some(Field::S(self.status.as_str())),
             "error" => Some(
                 self.error
@@ -902,6 +1286,11 @@ impl Queryable for Torrent {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "error_code" => Some(
+                self.error_code
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             "priority" => Some(Field::N(self.priority as i64)),
             "rate_up" => Some(Field::N(self.rate_up as i64)),
@@ -911,6 +1300,14 @@ impl Queryable for Torrent {
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "peers" => Some(Field::N(self.peers as i64)),
+            "peers_tracker" => Some(Field::N(self.peers_tracker as i64)),
+            "peers_dht" => Some(Field::N(self.peers_dht as i64)),
+            "peers_pex" => Some(Field::N(self.peers_pex as i64)),
+            "peers_holepunch" => Some(Field::N(self.peers_holepunch as i64)),
+            "peers_incoming" => Some(Field::N(self.peers_incoming as i64)),
+            "peers_manual" => Some(Field::N(self.peers_manual as i64)),
+            "dht_seeders" => Some(Field::N(self.dht_seeders as i64)),
+            "dht_leechers" => Some(Field::N(self.dht_leechers as i64)),
             "trackers" => Some(Field::N(self.trackers as i64)),
             "tracker_urls" => Some(Field::V(
                 self.tracker_urls.iter().map(|url| Field::S(url)).collect(),
@@ -927,6 +1324,14 @@ impl Queryable for Torrent {
             "availability" => Some(Field::F(self.availability)),
 
             "strategy" => Some(Field::S(self.strategy.as_str())),
+            "super_seeding" => Some(Field::B(self.super_seeding)),
+            "upload_slots" => Some(
+                self.upload_slots
+                    .map(|v| Field::N(v as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "first_last" => Some(Field::B(self.first_last)),
+            "memory" => Some(Field::B(self.memory)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -966,6 +1371,8 @@ impl Queryable for File {
 
             "progress" => Some(Field::F(self.progress)),
 
+            "position" => Some(self.position.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -986,6 +1393,16 @@ impl Queryable for Peer {
             "availability" => Some(Field::F(self.availability)),
 
             "client_id" => Some(Field::S(&self.client_id)),
+            "client" => Some(
+                self.client
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "source" => Some(Field::S(self.source.as_str())),
+            "capabilities" => Some(Field::V(
+                self.capabilities.iter().map(|c| Field::S(c)).collect(),
+            )),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -1006,6 +1423,15 @@ impl Queryable for Tracker {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "error_code" => Some(
+                self.error_code
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+
+            "seeders" => Some(Field::N(self.seeders as i64)),
+            "leechers" => Some(Field::N(self.leechers as i64)),
+            "downloaded" => Some(Field::N(self.downloaded as i64)),
 
             "last_report" => Some(Field::D(self.last_report)),
 
@@ -1025,12 +1451,44 @@ impl Status {
             Status::Idle => "idle",
             Status::Seeding => "seeding",
             Status::Hashing => "hashing",
+            Status::Allocating => "allocating",
+            Status::Moving => "moving",
             Status::Magnet => "magnet",
             Status::Error => "error",
         }
     }
 }
 
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCode::UnknownResource => "unknown_resource",
+            ErrorCode::WrongResourceKind => "wrong_resource_kind",
+            ErrorCode::MalformedJson => "malformed_json",
+            ErrorCode::MalformedMessage => "malformed_message",
+            ErrorCode::InvalidPeerAddress => "invalid_peer_address",
+            ErrorCode::InvalidTrackerUrl => "invalid_tracker_url",
+            ErrorCode::InvalidMagnet => "invalid_magnet",
+            ErrorCode::InvalidTorrent => "invalid_torrent",
+            ErrorCode::TorrentExists => "torrent_exists",
+            ErrorCode::PeerConnectionFailed => "peer_connection_failed",
+            ErrorCode::MetadataMissing => "metadata_missing",
+            ErrorCode::TransferFailed => "transfer_failed",
+            ErrorCode::TransferTimeout => "transfer_timeout",
+            ErrorCode::TrackerRejected => "tracker_rejected",
+            ErrorCode::TrackerInvalidResponse => "tracker_invalid_response",
+            ErrorCode::TrackerInvalidRequest => "tracker_invalid_request",
+            ErrorCode::TrackerUnreachable => "tracker_unreachable",
+            ErrorCode::TrackerTimeout => "tracker_timeout",
+            ErrorCode::TrackerDns => "tracker_dns",
+            ErrorCode::DiskNotFound => "disk_not_found",
+            ErrorCode::DiskPermissionDenied => "disk_permission_denied",
+            ErrorCode::DiskFull => "disk_full",
+            ErrorCode::DiskIo => "disk_io",
+        }
+    }
+}
+
 /// Merges json objects according to RFC 7396
 pub fn merge_json(original: &mut json::Value, update: &mut json::Value) {
     match (original, update) {
@@ -1070,6 +1528,12 @@ impl Default for Server {
             ses_transferred_up: 0,
             ses_transferred_down: 0,
             free_space: 0,
+            dht_nodes: 0,
+            dht_buckets: 0,
+            dht_fresh_buckets: 0,
+            dht_query_rate: 0.,
+            dht_torrents: 0,
+            dht_peers: 0,
             download_token: "".to_owned(),
             started: Utc::now(),
             user_data: json::Value::Null,
@@ -1086,14 +1550,20 @@ impl Default for Torrent {
             creator: None,
             private: false,
             path: "".to_owned(),
+            completed_path: None,
             created: Utc::now(),
             modified: Utc::now(),
             status: Default::default(),
             error: None,
+            error_code: None,
             priority: 0,
             progress: 0.,
             availability: 0.,
             strategy: Strategy::Rarest,
+            super_seeding: false,
+            upload_slots: None,
+            first_last: false,
+            memory: false,
             rate_up: 0,
             rate_down: 0,
             throttle_up: None,
@@ -1101,12 +1571,21 @@ impl Default for Torrent {
             transferred_up: 0,
             transferred_down: 0,
             peers: 0,
+            peers_tracker: 0,
+            peers_dht: 0,
+            peers_pex: 0,
+            peers_holepunch: 0,
+            peers_incoming: 0,
+            peers_manual: 0,
+            dht_seeders: 0,
+            dht_leechers: 0,
             trackers: 0,
             tracker_urls: vec![],
             size: None,
             pieces: None,
             piece_size: None,
             piece_field: "".to_owned(),
+            piece_availability: vec![],
             files: None,
             user_data: json::Value::Null,
         }
@@ -1121,6 +1600,10 @@ impl Default for Tracker {
             url: Url::parse("http://my.tracker/announce").unwrap(),
             last_report: Utc::now(),
             error: None,
+            error_code: None,
+            seeders: 0,
+            leechers: 0,
+            downloaded: 0,
             user_data: json::Value::Null,
         }
     }
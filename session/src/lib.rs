@@ -3,7 +3,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Session;
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_4d7b0e as current;
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Bitfield {
@@ -12,8 +12,28 @@ pub mod torrent {
     }
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_4d7b0e::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_9a4f3e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_7c1d42::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_e5c83a::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_b3e7a0::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_2f81c4::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_d40c8a::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_7b2e91::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_a5d10c::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_3c9e2d::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_249b1b::Session>(data) {
@@ -33,7 +53,7 @@ pub mod torrent {
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_4d7b0e {
         use super::Bitfield;
 
         use chrono::{DateTime, Utc};
@@ -54,6 +74,17 @@ pub mod torrent {
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
             pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+            pub upload_slots: Option<u16>,
+            pub first_last: bool,
+            pub completed_path: Option<String>,
+            pub renamed: Vec<(usize, PathBuf)>,
+            pub memory: bool,
         }
 
         #[derive(Clone, Serialize, Deserialize)]
@@ -76,6 +107,7 @@ pub mod torrent {
         pub struct File {
             pub path: PathBuf,
             pub length: u64,
+            pub pad: bool,
         }
 
         #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -96,6 +128,617 @@ pub mod torrent {
         }
     }
 
+    pub mod ver_9a4f3e {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_4d7b0e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+            pub upload_slots: Option<u16>,
+            pub first_last: bool,
+            pub completed_path: Option<String>,
+            pub renamed: Vec<(usize, PathBuf)>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: self.http_seeds,
+                    upload_slots: self.upload_slots,
+                    first_last: self.first_last,
+                    completed_path: self.completed_path,
+                    renamed: self.renamed,
+                    memory: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7c1d42 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_9a4f3e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+            pub upload_slots: Option<u16>,
+            pub first_last: bool,
+            pub completed_path: Option<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: self.http_seeds,
+                    upload_slots: self.upload_slots,
+                    first_last: self.first_last,
+                    completed_path: self.completed_path,
+                    renamed: vec![],
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e5c83a {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_7c1d42 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+            pub upload_slots: Option<u16>,
+            pub first_last: bool,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: self.http_seeds,
+                    upload_slots: self.upload_slots,
+                    first_last: self.first_last,
+                    completed_path: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_b3e7a0 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_e5c83a as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+            pub upload_slots: Option<u16>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: self.http_seeds,
+                    upload_slots: self.upload_slots,
+                    first_last: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_2f81c4 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_b3e7a0 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+            pub http_seeds: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: self.http_seeds,
+                    upload_slots: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_d40c8a {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_2f81c4 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+            pub failed: Bitfield,
+            pub unverified: Bitfield,
+            pub last_scrub: Option<DateTime<Utc>>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: self.failed,
+                    unverified: self.unverified,
+                    last_scrub: self.last_scrub,
+                    http_seeds: vec![],
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7b2e91 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_d40c8a as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+            pub corrupt: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                let empty = Bitfield {
+                    len: self.pieces.len,
+                    data: vec![0; self.pieces.data.len()].into_boxed_slice(),
+                };
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: self.corrupt,
+                    failed: empty.clone(),
+                    unverified: empty,
+                    last_scrub: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_a5d10c {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_7b2e91 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                    corrupt: 0,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_3c9e2d {
+        pub use self::next::{Status, StatusState};
+        pub use super::ver_a5d10c as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub web_seeds: Vec<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        files: self
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                pad: false,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: self.web_seeds,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_fa1b6f {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_3c9e2d as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    web_seeds: vec![],
+                }
+                .migrate()
+            }
+        }
+    }
+
     pub mod ver_6e27af {
         pub use self::next::{File, Status, StatusState};
         pub use super::ver_fa1b6f as next;
@@ -388,3 +1031,21 @@ pub mod torrent {
         }
     }
 }
+
+/// Sizes and modification times of a torrent's files when synapse last
+/// shut down, which let pieces be trusted without hashing them again if
+/// nothing changed since.
+pub mod resume {
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct Resume {
+        /// One per file, None for padding files and files which didn't exist
+        pub files: Vec<Option<Stamp>>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct Stamp {
+        pub len: u64,
+        pub mtime_secs: i64,
+        pub mtime_nanos: u32,
+    }
+}
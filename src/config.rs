@@ -1,7 +1,10 @@
+use std::collections::BTreeMap;
 use std::io::Read;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::{fs, process};
 
+use url::Url;
+
 use crate::args;
 
 error_chain! {
@@ -27,18 +30,29 @@ error_chain! {
 pub struct Config {
     pub port: u16,
     pub max_dl: u32,
+    pub wanted_progress: bool,
     pub trk: TrkConfig,
     pub dht: DhtConfig,
+    pub dns: DnsConfig,
     pub rpc: RpcConfig,
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub picker: PickerConfig,
 }
 
 #[derive(Debug, Clone)]
 pub struct DhtConfig {
     pub port: u16,
     pub bootstrap_node: Option<SocketAddr>,
+    pub enforce_node_id: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfig {
+    pub servers: Vec<SocketAddr>,
+    pub doh: Option<Url>,
+    pub prefer_ipv6: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,6 +61,8 @@ pub struct ConfigFile {
     pub port: u16,
     #[serde(default = "default_max_dl")]
     pub max_dl: u32,
+    #[serde(default = "default_wanted_progress")]
+    pub wanted_progress: bool,
     #[serde(default)]
     pub rpc: RpcConfig,
     #[serde(default)]
@@ -54,11 +70,15 @@ pub struct ConfigFile {
     #[serde(default)]
     pub dht: DhtConfigFile,
     #[serde(default)]
+    pub dns: DnsConfigFile,
+    #[serde(default)]
     pub disk: DiskConfig,
     #[serde(default)]
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub picker: PickerConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,6 +101,21 @@ pub struct RpcConfig {
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    #[serde(default = "default_trk_headers")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_trk_lenient")]
+    pub lenient: bool,
+    #[serde(default = "default_trk_http_timeout")]
+    pub http_timeout_ms: u64,
+    #[serde(default = "default_trk_udp_timeout")]
+    pub udp_timeout_ms: u64,
+    /// Limit on resolving and connecting, otherwise the protocol's own
+    /// timeout applies
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default = "default_trk_tick")]
+    pub tick_ms: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,6 +124,18 @@ pub struct DhtConfigFile {
     pub port: u16,
     #[serde(default = "default_bootstrap_node")]
     pub bootstrap_node: Option<String>,
+    #[serde(default = "default_enforce_node_id")]
+    pub enforce_node_id: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsConfigFile {
+    #[serde(default = "default_dns_servers")]
+    pub servers: Vec<String>,
+    #[serde(default = "default_doh")]
+    pub doh: Option<String>,
+    #[serde(default = "default_prefer_ipv6")]
+    pub prefer_ipv6: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -99,6 +146,82 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    #[serde(default = "default_allocation")]
+    pub allocation: Allocation,
+    /// Directory completed torrents are moved to, if any
+    #[serde(default)]
+    pub completed: Option<String>,
+    /// Keep incomplete files with a .part suffix
+    #[serde(default = "default_part_suffix")]
+    pub part_suffix: bool,
+    /// Serve reads from memory mapped files
+    #[serde(default = "default_mmap")]
+    pub mmap: bool,
+    /// Most file data kept mapped at once, in MiB
+    #[serde(default = "default_mmap_cache")]
+    pub mmap_cache: usize,
+    /// Batch reads and writes through io_uring
+    #[serde(default = "default_io_uring")]
+    pub io_uring: bool,
+    /// Memory used to cache pieces read for seeding, in MiB
+    #[serde(default = "default_read_cache")]
+    pub read_cache: usize,
+    /// Most written data buffered before it's written out, in MiB
+    #[serde(default = "default_write_cache")]
+    pub write_cache: usize,
+    /// Longest buffered data waits before it's written out, in seconds
+    #[serde(default = "default_write_flush_interval")]
+    pub write_flush_interval: u64,
+    /// Threads performing disk I/O
+    #[serde(default = "default_disk_workers")]
+    pub workers: usize,
+    #[serde(default = "default_sync")]
+    pub sync: SyncPolicy,
+    /// Time between syncs with the periodic policy, in seconds
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval: u64,
+    /// Sync directories after renaming files in them
+    #[serde(default = "default_sync_dirs")]
+    pub sync_dirs: bool,
+    /// Limit on the reads of rechecks, in MiB/s per disk worker, 0 for none
+    #[serde(default = "default_recheck_rate")]
+    pub recheck_rate: u64,
+    /// Check there's room for the rest of a torrent before downloading it
+    #[serde(default = "default_check_space")]
+    pub check_space: bool,
+    /// MiB of torrent data kept in memory, for torrents added to memory
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: u64,
+    /// Seconds between checks of seeding torrents' files for changes, 0
+    /// for none
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval: u64,
+}
+
+/// When downloaded data is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// Once each piece is verified
+    Piece,
+    /// Every `sync_interval` seconds
+    Periodic,
+    /// Once each file is complete
+    Complete,
+    /// Never, leaving it up to the OS
+    Never,
+}
+
+/// How space is reserved for the files being downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocation {
+    /// Files are sparse, taking up space only as pieces are written
+    Sparse,
+    /// Files are fully allocated when first written to
+    Dense,
+    /// Every wanted file is fully allocated before downloading starts
+    Full,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,12 +232,115 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    /// Most peers a single torrent may be connected to
+    #[serde(default = "default_max_torrent_peers")]
+    pub max_torrent_peers: usize,
+    /// Most outgoing peer connections which may be in progress at once
+    #[serde(default = "default_max_half_open")]
+    pub max_half_open: usize,
+    /// Most outgoing peer connections started per second, 0 for no limit
+    #[serde(default = "default_connect_rate")]
+    pub connect_rate: u32,
+    /// Accept uTP connections on the peer port
+    #[serde(default = "default_utp")]
+    pub utp: bool,
+    /// Make outgoing peer connections over uTP rather than TCP
+    #[serde(default = "default_prefer_utp")]
+    pub prefer_utp: bool,
+    /// Dial IPv4 peers over both TCP and uTP, keeping whichever connects
+    /// first
+    #[serde(default = "default_race_transports")]
+    pub race_transports: bool,
+    /// Listen for and connect to peers over IPv6
+    #[serde(default = "default_ipv6")]
+    pub ipv6: bool,
+    /// Dial peers known by both an IPv4 and IPv6 address on the latter
+    #[serde(default = "default_prefer_ipv6_peers")]
+    pub prefer_ipv6: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default)]
+    pub extensions: ExtConfig,
+    #[serde(default)]
+    pub tuning: TuningConfig,
+    #[serde(default = "default_choking")]
+    pub choking: Choking,
+    /// Leave a few pieces out of the bitfield sent while seeding
+    #[serde(default = "default_lazy_bitfield")]
+    pub lazy_bitfield: bool,
+    /// Hash failures a peer may contribute to before being banned, 0
+    /// disables banning
+    #[serde(default = "default_ban_strikes")]
+    pub ban_strikes: u32,
+    #[serde(default = "default_ban_secs")]
+    pub ban_secs: u64,
+}
+
+/// How unchoke slots are handed out among interested peers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Choking {
+    TitForTat,
+    RoundRobin,
+    Fixed,
+}
+
+/// Request parameters which are tuned automatically unless pinned
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TuningConfig {
+    pub queue_depth: Option<u16>,
+    pub unchoke_slots: Option<usize>,
+    pub prefetch: Option<usize>,
+    /// Upload rate each unchoke slot should get while an upload limit
+    /// is set, in the throttle's units
+    pub slot_rate: Option<u64>,
+}
+
+/// Extensions advertised to (and accepted from) peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtConfig {
+    #[serde(default = "default_enabled")]
+    pub dht: bool,
+    #[serde(default = "default_enabled")]
+    pub extension_protocol: bool,
+    #[serde(default = "default_enabled")]
+    pub fast: bool,
+    #[serde(default = "default_enabled")]
+    pub ut_metadata: bool,
+    #[serde(default = "default_enabled")]
+    pub ut_pex: bool,
+    #[serde(default = "default_enabled")]
+    pub ut_holepunch: bool,
+    #[serde(default = "default_enabled")]
+    pub lt_donthave: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PickerConfig {
+    #[serde(default = "default_aging_secs")]
+    pub aging_secs: u64,
+    #[serde(default = "default_aging_max")]
+    pub aging_max: u8,
+    #[serde(default = "default_dup_requests")]
+    pub dup_requests: usize,
+    /// Wanted blocks left to download below which endgame starts
+    #[serde(default = "default_endgame_blocks")]
+    pub endgame_blocks: usize,
+    /// Bytes ahead of a streamed file's playback position to download first
+    #[serde(default = "default_stream_window")]
+    pub stream_window: u64,
+    /// Whether new torrents download the first and last pieces of each
+    /// wanted file first
+    #[serde(default = "default_first_last")]
+    pub first_last: bool,
+    /// Distance in pieces from ones being downloaded within which equally
+    /// rare pieces are preferred, 0 disabling it
+    #[serde(default = "default_locality")]
+    pub locality: u32,
 }
 
 impl ConfigFile {
@@ -141,6 +367,67 @@ impl ConfigFile {
                         error!("Config max_dl must not be 0");
                         process::exit(1);
                     }
+                    if cfg.picker.aging_max > 5 {
+                        error!("Config picker aging_max must be between 0 and 5");
+                        process::exit(1);
+                    }
+                    if cfg.picker.dup_requests == 0 || cfg.picker.dup_requests > 3 {
+                        error!("Config picker dup_requests must be between 1 and 3");
+                        process::exit(1);
+                    }
+                    let tuning = &cfg.peer.tuning;
+                    if tuning
+                        .queue_depth
+                        .map(|q| q == 0 || q > 600)
+                        .unwrap_or(false)
+                        || tuning.unchoke_slots.map(|s| s == 0).unwrap_or(false)
+                        || tuning.prefetch.map(|p| p == 0).unwrap_or(false)
+                        || tuning.slot_rate.map(|r| r == 0).unwrap_or(false)
+                    {
+                        error!("Config peer tuning values must be positive, with queue_depth at most 600");
+                        process::exit(1);
+                    }
+                    let trk = &cfg.tracker;
+                    if !valid_header(&trk.user_agent)
+                        || !trk
+                            .headers
+                            .iter()
+                            .all(|(k, v)| valid_header(k) && valid_header(v))
+                    {
+                        error!("Config tracker headers must be non empty single lines");
+                        process::exit(1);
+                    }
+                    let timeouts = [trk.http_timeout_ms, trk.udp_timeout_ms];
+                    if !timeouts
+                        .iter()
+                        .chain(trk.connect_timeout_ms.iter())
+                        .all(|t| (1_000..=120_000).contains(t))
+                    {
+                        error!("Config tracker timeouts must be between 1000 and 120000 ms");
+                        process::exit(1);
+                    }
+                    if !(10..=1_000).contains(&trk.tick_ms) {
+                        error!("Config tracker tick_ms must be between 10 and 1000");
+                        process::exit(1);
+                    }
+                    if let Some(s) = cfg.dns.servers.iter().find(|s| dns_server(s).is_none()) {
+                        error!("Config dns server {} is not a valid IP address", s);
+                        process::exit(1);
+                    }
+                    if let Some(ref doh) = cfg.dns.doh {
+                        if doh_url(doh).is_none() {
+                            error!("Config dns doh must be an https URL");
+                            process::exit(1);
+                        }
+                    }
+                    if cfg.net.max_torrent_peers == 0 || cfg.net.max_half_open == 0 {
+                        error!("Config net max_torrent_peers and max_half_open must not be 0");
+                        process::exit(1);
+                    }
+                    if cfg.net.prefer_utp && !cfg.net.utp {
+                        error!("Config net prefer_utp requires utp to be enabled");
+                        process::exit(1);
+                    }
                     if !cfg!(debug_assertions) && !cfg.disk.validate {
                         error!("validation skipping can only be used in development, overriding!");
                         cfg.disk.validate = true;
@@ -184,34 +471,95 @@ impl Config {
         let dht = DhtConfig {
             port: file.dht.port,
             bootstrap_node: addr,
+            enforce_node_id: file.dht.enforce_node_id,
+        };
+        let dns = DnsConfig {
+            servers: file
+                .dns
+                .servers
+                .iter()
+                .filter_map(|s| dns_server(s))
+                .collect(),
+            doh: file.dns.doh.and_then(|u| doh_url(&u)),
+            prefer_ipv6: file.dns.prefer_ipv6,
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        file.disk.completed = file
+            .disk
+            .completed
+            .map(|c| shellexpand::tilde(&c).into_owned());
         Config {
             port: file.port,
             max_dl: file.max_dl,
+            wanted_progress: file.wanted_progress,
             trk: file.tracker,
             rpc: file.rpc,
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            picker: file.picker,
             dht,
+            dns,
         }
     }
 }
 
+/// Header names and values are written verbatim into requests, so must fit on one line
+fn valid_header(s: &str) -> bool {
+    !s.is_empty() && !s.contains(&['\r', '\n'][..])
+}
+
+/// Parses a nameserver, given as an IP address with an optional port
+fn dns_server(s: &str) -> Option<SocketAddr> {
+    s.parse()
+        .ok()
+        .or_else(|| s.parse().ok().map(|ip: IpAddr| SocketAddr::new(ip, 53)))
+}
+
+fn doh_url(s: &str) -> Option<Url> {
+    Url::parse(s)
+        .ok()
+        .filter(|u| u.scheme() == "https" && u.host_str().is_some())
+}
+
 fn default_port() -> u16 {
     16_384
 }
 fn default_max_dl() -> u32 {
     10
 }
+fn default_wanted_progress() -> bool {
+    false
+}
+
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_user_agent() -> String {
+    concat!("synapse/", env!("CARGO_PKG_VERSION")).to_owned()
+}
+fn default_trk_headers() -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+fn default_trk_lenient() -> bool {
+    true
+}
+fn default_trk_http_timeout() -> u64 {
+    5_000
+}
+fn default_trk_udp_timeout() -> u64 {
+    15_000
+}
+fn default_trk_tick() -> u64 {
+    150
+}
 fn default_dht_port() -> u16 {
     16_309
 }
+fn default_enforce_node_id() -> bool {
+    true
+}
 fn default_rpc_port() -> u16 {
     8_412
 }
@@ -230,6 +578,15 @@ fn default_ssl() -> String {
 fn default_bootstrap_node() -> Option<String> {
     None
 }
+fn default_dns_servers() -> Vec<String> {
+    Vec::new()
+}
+fn default_doh() -> Option<String> {
+    None
+}
+fn default_prefer_ipv6() -> bool {
+    false
+}
 fn default_session_dir() -> String {
     shellexpand::full("$XDG_DATA_HOME/synapse")
         .unwrap_or_else(|_| shellexpand::tilde("~/.local/share/synapse"))
@@ -241,6 +598,54 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_allocation() -> Allocation {
+    Allocation::Sparse
+}
+fn default_part_suffix() -> bool {
+    false
+}
+fn default_mmap() -> bool {
+    false
+}
+fn default_mmap_cache() -> usize {
+    1024
+}
+fn default_io_uring() -> bool {
+    false
+}
+fn default_read_cache() -> usize {
+    64
+}
+fn default_write_cache() -> usize {
+    32
+}
+fn default_write_flush_interval() -> u64 {
+    5
+}
+fn default_disk_workers() -> usize {
+    1
+}
+fn default_sync() -> SyncPolicy {
+    SyncPolicy::Complete
+}
+fn default_sync_interval() -> u64 {
+    30
+}
+fn default_sync_dirs() -> bool {
+    false
+}
+fn default_recheck_rate() -> u64 {
+    0
+}
+fn default_check_space() -> bool {
+    true
+}
+fn default_memory_limit() -> u64 {
+    64
+}
+fn default_watch_interval() -> u64 {
+    60
+}
 fn default_max_files() -> usize {
     500
 }
@@ -250,21 +655,84 @@ fn default_max_sockets() -> usize {
 fn default_max_announces() -> usize {
     50
 }
+fn default_max_torrent_peers() -> usize {
+    50
+}
+fn default_max_half_open() -> usize {
+    30
+}
+fn default_connect_rate() -> u32 {
+    20
+}
+fn default_utp() -> bool {
+    true
+}
+fn default_prefer_utp() -> bool {
+    false
+}
+fn default_race_transports() -> bool {
+    true
+}
+fn default_ipv6() -> bool {
+    true
+}
+fn default_prefer_ipv6_peers() -> bool {
+    true
+}
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_choking() -> Choking {
+    Choking::TitForTat
+}
+fn default_lazy_bitfield() -> bool {
+    false
+}
+fn default_ban_strikes() -> u32 {
+    3
+}
+fn default_ban_secs() -> u64 {
+    3600
+}
+fn default_enabled() -> bool {
+    true
+}
+fn default_aging_secs() -> u64 {
+    300
+}
+fn default_aging_max() -> u8 {
+    5
+}
+fn default_dup_requests() -> usize {
+    3
+}
+fn default_endgame_blocks() -> usize {
+    128
+}
+fn default_stream_window() -> u64 {
+    16 * 1024 * 1024
+}
+fn default_first_last() -> bool {
+    false
+}
+fn default_locality() -> u32 {
+    4
+}
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             port: default_port(),
             max_dl: default_max_dl(),
+            wanted_progress: default_wanted_progress(),
             trk: Default::default(),
             rpc: Default::default(),
             disk: Default::default(),
             net: Default::default(),
             dht: Default::default(),
+            dns: Default::default(),
             peer: Default::default(),
+            picker: Default::default(),
         }
     }
 }
@@ -286,6 +754,13 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            user_agent: default_user_agent(),
+            headers: default_trk_headers(),
+            lenient: default_trk_lenient(),
+            http_timeout_ms: default_trk_http_timeout(),
+            udp_timeout_ms: default_trk_udp_timeout(),
+            connect_timeout_ms: None,
+            tick_ms: default_trk_tick(),
         }
     }
 }
@@ -295,6 +770,7 @@ impl Default for DhtConfigFile {
         DhtConfigFile {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node(),
+            enforce_node_id: default_enforce_node_id(),
         }
     }
 }
@@ -304,6 +780,17 @@ impl Default for DhtConfig {
         DhtConfig {
             port: default_dht_port(),
             bootstrap_node: None,
+            enforce_node_id: default_enforce_node_id(),
+        }
+    }
+}
+
+impl Default for DnsConfigFile {
+    fn default() -> DnsConfigFile {
+        DnsConfigFile {
+            servers: default_dns_servers(),
+            doh: default_doh(),
+            prefer_ipv6: default_prefer_ipv6(),
         }
     }
 }
@@ -314,6 +801,23 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            allocation: default_allocation(),
+            completed: None,
+            part_suffix: default_part_suffix(),
+            mmap: default_mmap(),
+            mmap_cache: default_mmap_cache(),
+            io_uring: default_io_uring(),
+            read_cache: default_read_cache(),
+            write_cache: default_write_cache(),
+            write_flush_interval: default_write_flush_interval(),
+            workers: default_disk_workers(),
+            sync: default_sync(),
+            sync_interval: default_sync_interval(),
+            sync_dirs: default_sync_dirs(),
+            recheck_rate: default_recheck_rate(),
+            check_space: default_check_space(),
+            memory_limit: default_memory_limit(),
+            watch_interval: default_watch_interval(),
         }
     }
 }
@@ -324,6 +828,14 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            max_torrent_peers: default_max_torrent_peers(),
+            max_half_open: default_max_half_open(),
+            connect_rate: default_connect_rate(),
+            utp: default_utp(),
+            prefer_utp: default_prefer_utp(),
+            race_transports: default_race_transports(),
+            ipv6: default_ipv6(),
+            prefer_ipv6: default_prefer_ipv6_peers(),
         }
     }
 }
@@ -332,6 +844,40 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            extensions: Default::default(),
+            tuning: Default::default(),
+            choking: default_choking(),
+            lazy_bitfield: default_lazy_bitfield(),
+            ban_strikes: default_ban_strikes(),
+            ban_secs: default_ban_secs(),
+        }
+    }
+}
+
+impl Default for ExtConfig {
+    fn default() -> ExtConfig {
+        ExtConfig {
+            dht: default_enabled(),
+            extension_protocol: default_enabled(),
+            fast: default_enabled(),
+            ut_metadata: default_enabled(),
+            ut_pex: default_enabled(),
+            ut_holepunch: default_enabled(),
+            lt_donthave: default_enabled(),
+        }
+    }
+}
+
+impl Default for PickerConfig {
+    fn default() -> PickerConfig {
+        PickerConfig {
+            aging_secs: default_aging_secs(),
+            aging_max: default_aging_max(),
+            dup_requests: default_dup_requests(),
+            endgame_blocks: default_endgame_blocks(),
+            stream_window: default_stream_window(),
+            first_last: default_first_last(),
+            locality: default_locality(),
         }
     }
 }
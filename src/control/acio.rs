@@ -1,18 +1,26 @@
 use std::cell::RefCell;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener};
 use std::rc::Rc;
 use std::{io, time};
 
 use amy::{self, ChannelError};
+use net2::TcpBuilder;
 
 use crate::control::cio::{self, Error, ErrorKind, Result, ResultExt};
+use crate::socket::Socket;
 use crate::torrent::peer::reader::RRes;
 use crate::util::UHashMap;
+use crate::utp::Utp;
 use crate::CONFIG;
-use crate::{disk, rpc, torrent, tracker};
+use crate::{dht, disk, rpc, torrent, tracker};
 
 const POLL_INT_MS: usize = 1000;
 const PRUNE_GOAL: usize = 50;
+/// Interval at which uTP retransmission timers are checked
+const UTP_TICK_MS: usize = 100;
+/// uTP sockets aren't registered with the poller, so their IDs are taken
+/// from a range the registrar won't reach
+const UTP_PID_START: usize = usize::MAX / 2;
 
 /// Amy based CIO implementation. Currently the default one used.
 pub struct ACIO {
@@ -28,6 +36,9 @@ pub struct ACChans {
 
     pub trk_tx: amy::Sender<tracker::Request>,
     pub trk_rx: amy::Receiver<tracker::Response>,
+
+    pub dht_tx: amy::Sender<dht::Request>,
+    pub dht_rx: amy::Receiver<dht::Response>,
 }
 
 struct ACIOData {
@@ -39,6 +50,17 @@ struct ACIOData {
     crashed: bool,
     listener: TcpListener,
     lid: usize,
+    /// The IPv6 listener and its ID, if IPv6 is enabled and available
+    listener6: Option<(TcpListener, usize)>,
+    utp: Option<UtpData>,
+}
+
+struct UtpData {
+    utp: Utp,
+    /// ID of the UDP socket
+    id: usize,
+    timer: usize,
+    next_pid: usize,
 }
 
 impl ACIO {
@@ -48,6 +70,31 @@ impl ACIO {
         let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
         listener.set_nonblocking(true)?;
         let lid = reg.register(&listener, amy::Event::Both)?;
+        let listener6 = if CONFIG.net.ipv6 {
+            match listen_v6(port) {
+                Ok(l) => {
+                    let id = reg.register(&l, amy::Event::Both)?;
+                    Some((l, id))
+                }
+                Err(e) => {
+                    info!("Not accepting IPv6 connections, failed to listen: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let utp = if CONFIG.net.utp {
+            let utp = Utp::bind(port)?;
+            Some(UtpData {
+                id: reg.register(&utp, amy::Event::Read)?,
+                timer: reg.set_interval(UTP_TICK_MS)?,
+                next_pid: UTP_PID_START,
+                utp,
+            })
+        } else {
+            None
+        };
 
         let data = ACIOData {
             poll,
@@ -55,6 +102,8 @@ impl ACIO {
             chans,
             listener,
             lid,
+            listener6,
+            utp,
             peers: UHashMap::default(),
             events: Vec::new(),
             crashed: false,
@@ -82,6 +131,10 @@ impl ACIO {
             while let Ok(t) = d.chans.trk_rx.try_recv() {
                 events.push(cio::Event::Tracker(Ok(t)));
             }
+        } else if d.chans.dht_rx.get_id() == id {
+            while let Ok(t) = d.chans.dht_rx.try_recv() {
+                events.push(cio::Event::Dht(t));
+            }
         } else if d.peers.contains_key(&id) {
             if let Err(e) = self.process_peer_ev(not, events, &mut d.peers) {
                 d.remove_peer(id);
@@ -91,23 +144,16 @@ impl ACIO {
                 });
             }
         } else if d.lid == id {
-            loop {
-                match d.listener.accept() {
-                    Ok((conn, ip)) => {
-                        debug!("Accepted new connection from {:?}!", ip);
-                        if conn.set_nonblocking(true).is_err() {
-                            continue;
-                        }
-                        events.push(cio::Event::Incoming(conn));
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        break;
-                    }
-                    Err(e) => {
-                        error!("Unexpected error occured during accept: {}!", e);
-                    }
-                }
+            accept(&d.listener, events);
+        } else if let Some((ref l, _)) = d.listener6.as_ref().filter(|l| l.1 == id) {
+            accept(l, events);
+        } else if let Some(u) = d.utp.as_ref().filter(|u| u.id == id) {
+            for stream in u.utp.readable() {
+                debug!("Accepted new uTP connection from {:?}!", stream.addr());
+                events.push(cio::Event::Incoming(Socket::from_utp(stream)));
             }
+        } else if let Some(u) = d.utp.as_ref().filter(|u| u.timer == id) {
+            u.utp.tick();
         } else {
             // Timer event
             events.push(cio::Event::Timer(id));
@@ -128,6 +174,11 @@ impl ACIO {
                 Err(ChannelError::SendError(_)) => d.crashed = true,
                 Err(e) => error!("Unknown error sending to channel: {:?}", e),
             }
+            match d.chans.dht_tx.send(dht::Request::Ping) {
+                Ok(_) => {}
+                Err(ChannelError::SendError(_)) => d.crashed = true,
+                Err(e) => error!("Unknown error sending to channel: {:?}", e),
+            }
         }
     }
 
@@ -167,6 +218,28 @@ impl ACIO {
         }
         Ok(())
     }
+
+    /// Handles uTP sockets which became ready, as they aren't polled on.
+    fn process_utp(&self, events: &mut Vec<cio::Event>) {
+        let mut d = self.data.borrow_mut();
+        let ready = match d.utp {
+            Some(ref u) => u.utp.take_ready(),
+            None => return,
+        };
+        for peer in ready {
+            let not = amy::Notification {
+                id: peer,
+                event: amy::Event::Both,
+            };
+            if let Err(e) = self.process_peer_ev(not, events, &mut d.peers) {
+                d.remove_peer(peer);
+                events.push(cio::Event::Peer {
+                    peer,
+                    event: Err(e),
+                });
+            }
+        }
+    }
 }
 
 impl cio::CIO for ACIO {
@@ -194,6 +267,7 @@ impl cio::CIO for ACIO {
                 error!("Failed to poll for events: {}", e);
             }
         }
+        self.process_utp(events);
         Ok(())
     }
 
@@ -225,12 +299,15 @@ impl cio::CIO for ACIO {
                 self.remove_peer(id);
             }
         }
-        let id = self
-            .data
-            .borrow_mut()
-            .reg
-            .register(peer.sock(), amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
+        let id = match peer.sock().tcp() {
+            Some(conn) => self
+                .data
+                .borrow_mut()
+                .reg
+                .register(conn, amy::Event::Both)
+                .chain_err(|| ErrorKind::IO)?,
+            None => self.data.borrow_mut().bind_utp(&mut peer)?,
+        };
         if let Some(t) = peer.sock_mut().throttle.as_mut() {
             t.id = id
         }
@@ -312,6 +389,15 @@ impl cio::CIO for ACIO {
         }
     }
 
+    fn msg_dht(&mut self, msg: dht::Request) {
+        let mut d = self.data.borrow_mut();
+
+        if d.chans.dht_tx.send(msg).is_err() && !d.crashed {
+            d.crashed = true;
+            error!("DHT thread crashed, shutting down!");
+        }
+    }
+
     fn msg_disk(&mut self, msg: disk::Request) {
         let mut d = self.data.borrow_mut();
 
@@ -337,10 +423,22 @@ impl cio::CIO for ACIO {
 }
 
 impl ACIOData {
+    fn bind_utp(&mut self, peer: &mut torrent::PeerConn) -> Result<cio::PID> {
+        let u = match self.utp {
+            Some(ref mut u) => u,
+            None => bail!(ErrorKind::IO),
+        };
+        u.next_pid += 1;
+        peer.sock_mut().bind_utp(&u.utp, u.next_pid);
+        Ok(u.next_pid)
+    }
+
     fn remove_peer(&mut self, pid: cio::PID) {
         if let Some(p) = self.peers.remove(&pid) {
-            if let Err(e) = self.reg.deregister(p.sock()) {
-                error!("Failed to deregister sock: {:?}", e);
+            if let Some(conn) = p.sock().tcp() {
+                if let Err(e) = self.reg.deregister(conn) {
+                    error!("Failed to deregister sock: {:?}", e);
+                }
             }
             self.events.push(cio::Event::Peer {
                 peer: pid,
@@ -349,3 +447,35 @@ impl ACIOData {
         }
     }
 }
+
+/// Binds the IPv6 listener. It only accepts IPv6 connections so that it
+/// can share the port with the IPv4 listener.
+fn listen_v6(port: u16) -> io::Result<TcpListener> {
+    let builder = TcpBuilder::new_v6()?;
+    builder.only_v6(true)?;
+    builder.reuse_address(true)?;
+    builder.bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))?;
+    let listener = builder.listen(128)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accepts every pending connection on a listener.
+fn accept(listener: &TcpListener, events: &mut Vec<cio::Event>) {
+    loop {
+        match listener.accept() {
+            Ok((conn, ip)) => {
+                debug!("Accepted new connection from {:?}!", ip);
+                if let Ok(sock) = Socket::from_stream(conn) {
+                    events.push(cio::Event::Incoming(sock));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                break;
+            }
+            Err(e) => {
+                error!("Unexpected error occured during accept: {}!", e);
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::{disk, rpc, torrent, tracker};
-use std::net::TcpStream;
+use crate::socket::Socket;
+use crate::{dht, disk, rpc, torrent, tracker};
 
 error_chain! {
     errors {
@@ -31,8 +31,9 @@ pub enum Event {
     },
     RPC(Result<rpc::Message>),
     Tracker(Result<tracker::Response>),
+    Dht(dht::Response),
     Disk(Result<disk::Response>),
-    Incoming(TcpStream),
+    Incoming(Socket),
 }
 
 /// Control IO trait used as an abstraction boundary between
@@ -69,6 +70,9 @@ pub trait CIO {
     /// Sends a message to the tracker
     fn msg_trk(&mut self, msg: tracker::Request);
 
+    /// Sends a message to the DHT node
+    fn msg_dht(&mut self, msg: dht::Request);
+
     /// Sends a message to the disk worker
     fn msg_disk(&mut self, msg: disk::Request);
 
@@ -82,7 +86,7 @@ pub trait CIO {
 #[cfg(test)]
 pub mod test {
     use super::{Event, Result, CIO, PID, TID};
-    use crate::{disk, rpc, torrent, tracker};
+    use crate::{dht, disk, rpc, torrent, tracker};
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
 
@@ -97,6 +101,7 @@ pub mod test {
         pub flushed_peers: Vec<PID>,
         pub rpc_msgs: Vec<rpc::CtlMessage>,
         pub trk_msgs: Vec<tracker::Request>,
+        pub dht_msgs: Vec<dht::Request>,
         pub disk_msgs: Vec<disk::Request>,
         pub timers: usize,
         pub peer_cnt: usize,
@@ -110,6 +115,7 @@ pub mod test {
                 flushed_peers: Vec::new(),
                 rpc_msgs: Vec::new(),
                 trk_msgs: Vec::new(),
+                dht_msgs: Vec::new(),
                 disk_msgs: Vec::new(),
                 timers: 0,
                 peer_cnt: 0,
@@ -176,6 +182,11 @@ pub mod test {
             d.trk_msgs.push(msg);
         }
 
+        fn msg_dht(&mut self, msg: dht::Request) {
+            let mut d = self.data.lock().unwrap();
+            d.dht_msgs.push(msg);
+        }
+
         fn msg_disk(&mut self, msg: disk::Request) {
             let mut d = self.data.lock().unwrap();
             d.disk_msgs.push(msg);
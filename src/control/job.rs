@@ -1,5 +1,3 @@
-use std::collections::HashSet;
-use std::net::SocketAddr;
 use std::time;
 
 use crate::control::cio;
@@ -30,6 +28,16 @@ impl<T: cio::CIO> Job<T> for UnchokeUpdate {
     }
 }
 
+pub struct WebSeedUpdate;
+
+impl<T: cio::CIO> Job<T> for WebSeedUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_web_seeds();
+        }
+    }
+}
+
 pub struct SessionUpdate;
 
 impl<T: cio::CIO> Job<T> for SessionUpdate {
@@ -86,36 +94,32 @@ impl<T: cio::CIO> Job<T> for TorrentTxUpdate {
     }
 }
 
-pub struct PEXUpdate {
-    peers: UHashMap<HashSet<SocketAddr>>,
+pub struct AvailabilityUpdate;
+
+impl<T: cio::CIO> Job<T> for AvailabilityUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_rpc_availability();
+        }
+    }
 }
 
-impl PEXUpdate {
-    pub fn new() -> PEXUpdate {
-        PEXUpdate {
-            peers: UHashMap::default(),
+pub struct ModifiedUpdate;
+
+impl<T: cio::CIO> Job<T> for ModifiedUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.check_modified();
         }
     }
 }
 
+pub struct PEXUpdate;
+
 impl<T: cio::CIO> Job<T> for PEXUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
-        for (id, torrent) in torrents.iter_mut().filter(|&(_, ref t)| !t.info().private) {
-            if !self.peers.contains_key(id) {
-                self.peers.insert(*id, HashSet::new());
-            }
-
-            let (added, removed) = {
-                let peers: HashSet<_> = torrent.peers().values().map(|p| p.addr()).collect();
-                let prev = self.peers.get_mut(id).unwrap();
-                let mut add: Vec<_> = peers.difference(prev).cloned().collect();
-                let mut rem: Vec<_> = prev.difference(&peers).cloned().collect();
-                add.truncate(50);
-                rem.truncate(50 - add.len());
-                (add, rem)
-            };
-            torrent.update_pex(&added, &removed);
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_pex();
         }
-        self.peers.retain(|id, _| torrents.contains_key(id));
     }
 }
@@ -1,22 +1,28 @@
+use std::collections::VecDeque;
 use std::io::Read;
-use std::net::TcpStream;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::atomic;
-use std::{fs, io, mem, process, time};
+use std::{cmp, fs, io, mem, process, time};
 
 use chrono::Utc;
 
+use crate::rpc::resource::PeerSource;
+use crate::socket::Socket;
 use crate::throttle::Throttler;
+use crate::torrent::peer::priority;
 use crate::torrent::{self, peer, Torrent};
 use crate::util::{
-    self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap, UHashMap,
-    UHashSet,
+    self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashMap, FHashSet, MHashMap,
+    UHashMap, UHashSet,
 };
-use crate::{disk, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use crate::{dht, disk, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
 
 pub mod acio;
 pub mod cio;
 mod job;
+mod rollup;
+pub mod score;
 
 /// Tracker update job interval
 const TRK_JOB_SECS: u64 = 60;
@@ -28,10 +34,29 @@ const SES_JOB_SECS: u64 = 60;
 const TX_JOB_MS: u64 = 500;
 /// Interval to check space on disk
 const SPACE_JOB_SECS: u64 = 10;
-/// Interval to send PEX updates
-const PEX_JOB_SECS: u64 = 60 * 5;
+/// Interval to send PEX updates, BEP 11 allows at most one a minute
+const PEX_JOB_SECS: u64 = 60;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to hand out pieces to web seeds
+const WEBSEED_JOB_SECS: u64 = 2;
+/// Interval to update RPC of piece availability
+const AVAIL_JOB_SECS: u64 = 5;
+/// Interval to expire stalled connection attempts
+const CONNECT_JOB_SECS: u64 = 5;
+/// Time an outgoing connection has to complete its handshake
+const HALF_OPEN_TIMEOUT_SECS: u64 = 20;
+/// Most peers queued to be connected to, older ones are dropped first
+const MAX_CANDIDATES: usize = 2_000;
+/// Time given to the preferred transport before the other one is tried
+/// too, when racing them
+const RACE_STAGGER_MS: u64 = 300;
+/// Transfer statistics rollup interval
+const ROLLUP_JOB_SECS: u64 = 60;
+/// File in the session directory which transfer rollups are kept in
+const ROLLUP_FILE: &str = "stat_rollups.json";
+/// File in the session directory which peer scores are kept in
+const SCORE_FILE: &str = "peer_scores";
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
@@ -47,8 +72,21 @@ pub struct Control<T: cio::CIO> {
     queue: Queue,
     peers: UHashMap<usize>,
     incoming: UHashSet,
+    /// Outgoing connections awaiting a handshake, when they were made and
+    /// the addresses they were made to
+    half_open: UHashMap<(time::Instant, SocketAddr)>,
+    /// Torrents and the addresses of peers waiting to be connected to,
+    /// along with where they were found
+    candidates: VecDeque<(usize, SocketAddr, PeerSource)>,
+    /// Connection attempts which may be made right now, and when that was
+    /// last topped up
+    dials: (f64, time::Instant),
+    /// Peers being dialed over both TCP and uTP
+    races: Vec<Race>,
     hash_idx: MHashMap<[u8; 20], usize>,
     data: ServerData,
+    rollups: rollup::Rollups,
+    scores: score::Scores,
     db: amy::Sender<disk::Request>,
 }
 
@@ -67,6 +105,20 @@ struct ServerData {
     throttle_dl: Option<i64>,
 }
 
+/// A peer dialed over its preferred transport and, after a short stagger,
+/// the other one too. Whichever connection completes its handshake first
+/// is kept, much like Happy Eyeballs does for address families.
+struct Race {
+    tid: usize,
+    addr: SocketAddr,
+    source: PeerSource,
+    started: time::Instant,
+    /// Whether the attempt over the other transport has been made
+    staggered: bool,
+    /// Attempts still in progress
+    attempts: Vec<usize>,
+}
+
 struct Queue {
     active_dl: FHashSet<usize>,
     inactive_dl: [FHashSet<usize>; 6],
@@ -105,18 +157,34 @@ impl<T: cio::CIO> Control<T> {
             time::Duration::from_secs(UNCHK_JOB_SECS),
         );
         jobs.add_job(job::SessionUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_job(
+            job::WebSeedUpdate,
+            time::Duration::from_secs(WEBSEED_JOB_SECS),
+        );
         jobs.add_job(
             job::TorrentTxUpdate::new(),
             time::Duration::from_millis(TX_JOB_MS),
         );
+        jobs.add_job(job::PEXUpdate, time::Duration::from_secs(PEX_JOB_SECS));
         jobs.add_job(
-            job::PEXUpdate::new(),
-            time::Duration::from_secs(PEX_JOB_SECS),
+            job::AvailabilityUpdate,
+            time::Duration::from_secs(AVAIL_JOB_SECS),
         );
+        if CONFIG.disk.watch_interval != 0 {
+            jobs.add_job(
+                job::ModifiedUpdate,
+                time::Duration::from_secs(CONFIG.disk.watch_interval),
+            );
+        }
 
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
         jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
+        jobs.add_cjob(ConnectUpdate, time::Duration::from_secs(CONNECT_JOB_SECS));
         jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_cjob(
+            RollupUpdate::new(),
+            time::Duration::from_secs(ROLLUP_JOB_SECS),
+        );
         let job_timer = cio
             .set_timer(JOB_INT_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
@@ -129,9 +197,15 @@ impl<T: cio::CIO> Control<T> {
             torrents,
             peers,
             incoming,
+            half_open: UHashMap::default(),
+            candidates: VecDeque::new(),
+            dials: (0., time::Instant::now()),
+            races: Vec::new(),
             hash_idx,
             stat: stat::EMA::new(),
             data: Default::default(),
+            rollups: rollup::Rollups::new(),
+            scores: score::Scores::new(),
             db,
             queue: Queue::new(),
         })
@@ -158,6 +232,11 @@ impl<T: cio::CIO> Control<T> {
                 break;
             }
         }
+        for torrent in self.torrents.values_mut() {
+            torrent.report_connected();
+            torrent.save_resume();
+        }
+        self.update_scores();
         self.serialize();
     }
 
@@ -174,6 +253,27 @@ impl<T: cio::CIO> Control<T> {
                 error!("Failed to serialize server data");
             }
         }
+        let mut path = PathBuf::from(sd);
+        path.push(ROLLUP_FILE);
+        match serde_json::to_vec(&self.rollups) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize transfer rollups");
+            }
+        }
+        self.scores.prune(Utc::now().timestamp());
+        let mut path = PathBuf::from(sd);
+        path.push(SCORE_FILE);
+        match bincode::serialize(&self.scores) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize peer scores");
+            }
+        }
         debug!("Serializing torrents!");
         for torrent in self.torrents.values_mut() {
             torrent.serialize();
@@ -194,6 +294,18 @@ impl<T: cio::CIO> Control<T> {
             self.data = ServerData::new();
         }
 
+        let mut pb = PathBuf::from(sd);
+        pb.push(ROLLUP_FILE);
+        if let Ok(Ok(rollups)) = fs::File::open(pb).map(serde_json::from_reader) {
+            self.rollups = rollups;
+        }
+
+        let mut pb = PathBuf::from(sd);
+        pb.push(SCORE_FILE);
+        if let Ok(Ok(scores)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
+            self.scores = scores;
+        }
+
         debug!("Deserializing torrents!");
         for entry in fs::read_dir(sd)? {
             if self.deserialize_torrent(entry).is_err() {
@@ -214,6 +326,16 @@ impl<T: cio::CIO> Control<T> {
         if dir.file_name().len() != 40 {
             return Ok(());
         }
+        // Session files are named by info hash, so a second file for the
+        // same torrent (e.g. differing in case) would load it twice.
+        let hash = dir.file_name().to_str().and_then(id_to_hash);
+        if matches!(hash, Some(h) if self.hash_idx.contains_key(&h)) {
+            error!(
+                "Session file {:?} duplicates an already loaded torrent, ignoring it",
+                dir.file_name()
+            );
+            return Ok(());
+        }
         trace!("Attempting to deserialize file {:?}", dir);
         let mut f = fs::File::open(dir.path())?;
         let mut data = Vec::new();
@@ -246,6 +368,9 @@ impl<T: cio::CIO> Control<T> {
                 error!("tracker error: {}", e);
                 trace!("tracker error bt: {:?}", e.backtrace());
             }
+            cio::Event::Dht(e) => {
+                self.handle_dht_ev(e);
+            }
             cio::Event::Disk(Ok(e)) => {
                 self.handle_disk_ev(e);
             }
@@ -275,6 +400,8 @@ impl<T: cio::CIO> Control<T> {
                 } else if t == self.throttler.fid() {
                     self.flush_blocked_peers();
                 } else if t == self.job_timer {
+                    self.dial_candidates();
+                    self.update_scores();
                     self.update_jobs();
                     self.update_rpc_tx();
                 } else {
@@ -289,13 +416,13 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
-        let (id, peers) = match tr {
+        let (id, peers, source) = match tr {
             tracker::Response::Tracker { tid, url, resp } => {
                 debug!("Handling tracker response for {:?}", url);
                 if let Some(torrent) = self.torrents.get_mut(&tid) {
                     torrent.set_tracker_response(url.as_ref(), &resp);
                     if let Ok(r) = resp {
-                        (tid, r.peers)
+                        (tid, r.peers, PeerSource::Tracker)
                     } else {
                         return;
                     }
@@ -303,17 +430,199 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::PEX { tid, peers } => (tid, peers, PeerSource::Pex),
+            tracker::Response::Holepunch { tid, peer } => {
+                // Both sides connect at once, so this can't wait in the queue
+                self.add_peer(tid, peer::PeerConn::new_utp(&peer, PeerSource::Holepunch));
+                return;
+            }
+            tracker::Response::WebSeed {
+                tid,
+                piece,
+                offset,
+                resp,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.set_web_seed_response(piece, offset, resp);
+                }
+                return;
+            }
+        };
+        self.add_peers(id, &peers, source);
+    }
+
+    fn handle_dht_ev(&mut self, resp: dht::Response) {
+        match resp {
+            dht::Response::Peers { tid, peers } => self.add_peers(tid, &peers, PeerSource::Dht),
+            dht::Response::Stats(stats) => self.update_rpc_dht(&stats),
+            dht::Response::Scrape {
+                tid,
+                seeders,
+                leechers,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.set_dht_swarm(seeders, leechers);
+                }
+            }
+        }
+    }
+
+    fn add_peers(&mut self, id: usize, peers: &[SocketAddr], source: PeerSource) {
+        // Dual-stack peers may be listed under both of their addresses, so
+        // the preferred family is dialed first. Torrents drop the other
+        // connection once they find out it's the same peer. Within each
+        // family, peers which served us well before are dialed first, then
+        // the rest in order of canonical priority.
+        let ours = self
+            .torrents
+            .get(&id)
+            .and_then(|t| t.external_ip())
+            .or_else(|| self.torrents.values().find_map(|t| t.external_ip()))
+            .map(|ip| SocketAddr::new(ip, CONFIG.port));
+        let mut peers: Vec<_> = peers
+            .iter()
+            .filter(|addr| addr.is_ipv4() || CONFIG.net.ipv6)
+            .collect();
+        peers.sort_by_key(|&&addr| {
+            let priority = ours.and_then(|ours| priority::canonical(ours, addr));
+            (
+                addr.is_ipv6() != CONFIG.net.prefer_ipv6,
+                cmp::Reverse(self.scores.score(addr.ip())),
+                cmp::Reverse(priority),
+            )
+        });
+        for &ip in peers {
+            trace!("Queueing peer({:?})!", ip);
+            self.candidates.push_back((id, ip, source));
+        }
+        while self.candidates.len() > MAX_CANDIDATES {
+            self.candidates.pop_front();
+        }
+        self.dial_candidates();
+    }
+
+    /// Connects to queued peers while there's room for more connection
+    /// attempts, at no more than `net.connect_rate` a second so that large
+    /// peer lists don't flood the NAT table of the router.
+    fn dial_candidates(&mut self) {
+        let rate = f64::from(CONFIG.net.connect_rate);
+        if rate > 0. {
+            let (allowance, at) = self.dials;
+            let elapsed = at.elapsed();
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.;
+            self.dials = ((allowance + elapsed * rate).min(rate), time::Instant::now());
+        }
+        while self.half_open.len() < CONFIG.net.max_half_open {
+            if rate > 0. && self.dials.0 < 1. {
+                break;
+            }
+            let (id, ip, source) = match self.candidates.pop_front() {
+                Some(c) => c,
+                None => break,
+            };
+            self.dials.0 -= 1.;
+            if let Ok(peer) = peer::PeerConn::new_outgoing(&ip, source) {
+                if let Some(pid) = self.add_peer(id, peer) {
+                    trace!("Added peer({:?})!", ip);
+                    self.half_open.insert(pid, (time::Instant::now(), ip));
+                    // The uTP socket only speaks IPv4
+                    if CONFIG.net.utp && CONFIG.net.race_transports && ip.is_ipv4() {
+                        self.races.push(Race {
+                            tid: id,
+                            addr: ip,
+                            source,
+                            started: time::Instant::now(),
+                            staggered: false,
+                            attempts: vec![pid],
+                        });
+                    }
+                }
+            }
+        }
+        let stagger = time::Duration::from_millis(RACE_STAGGER_MS);
+        for i in 0..self.races.len() {
+            if !self.races[i].staggered && self.races[i].started.elapsed() >= stagger {
+                self.stagger_race(i);
+            }
+        }
+    }
+
+    /// Dials a racing peer over the transport it wasn't dialed over first.
+    fn stagger_race(&mut self, i: usize) {
+        let race = &mut self.races[i];
+        race.staggered = true;
+        let conn = if CONFIG.net.prefer_utp {
+            match Socket::new(&race.addr) {
+                Ok(sock) => peer::PeerConn::new(sock, race.source),
+                Err(_) => return,
             }
+        } else {
+            peer::PeerConn::new_utp(&race.addr, race.source)
+        };
+        let pid = match self
+            .torrents
+            .get_mut(&race.tid)
+            .and_then(|t| t.add_racing_peer(conn))
+        {
+            Some(pid) => pid,
+            None => return,
+        };
+        trace!("Racing peer({:?})!", race.addr);
+        self.peers.insert(pid, race.tid);
+        self.half_open
+            .insert(pid, (time::Instant::now(), race.addr));
+        race.attempts.push(pid);
+    }
+
+    /// Settles the race a connection attempt may be part of, returning
+    /// whether another attempt to the same peer is still in progress. The
+    /// first attempt to connect wins and the others are dropped, while a
+    /// failure makes the other transport be tried straight away.
+    fn settle_race(&mut self, pid: usize, connected: bool) -> bool {
+        let i = match self.races.iter().position(|r| r.attempts.contains(&pid)) {
+            Some(i) => i,
+            None => return false,
         };
-        for ip in &peers {
-            trace!("Adding peer({:?})!", ip);
-            if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
-                trace!("Added peer({:?})!", ip);
-                self.add_peer(id, peer);
+        if connected {
+            for other in self.races.swap_remove(i).attempts {
+                if other != pid && self.half_open.remove(&other).is_some() {
+                    self.cio.remove_peer(other);
+                }
             }
+            return false;
         }
+        self.races[i].attempts.retain(|&a| a != pid);
+        if !self.races[i].staggered {
+            self.stagger_race(i);
+        }
+        if self.races[i].attempts.is_empty() {
+            self.races.swap_remove(i);
+            return false;
+        }
+        true
+    }
+
+    /// Gives up on connection attempts which are taking too long, making
+    /// room for queued peers.
+    fn expire_half_open(&mut self) {
+        let timeout = time::Duration::from_secs(HALF_OPEN_TIMEOUT_SECS);
+        let expired: Vec<_> = self
+            .half_open
+            .iter()
+            .filter(|&(_, (started, _))| started.elapsed() > timeout)
+            .map(|(&pid, _)| pid)
+            .collect();
+        let now = Utc::now().timestamp();
+        for pid in expired {
+            debug!("Connection attempt to peer {} timed out", pid);
+            if let Some((_, addr)) = self.half_open.remove(&pid) {
+                if !self.settle_race(pid, false) {
+                    self.scores.record(now, addr.ip(), score::Report::Failed);
+                }
+            }
+            self.cio.remove_peer(pid);
+        }
+        self.dial_candidates();
     }
 
     fn update_jobs(&mut self) {
@@ -335,18 +644,13 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
-    fn handle_incoming_conn(&mut self, conn: TcpStream) {
-        match peer::PeerConn::new_incoming(conn) {
-            Ok(pconn) => match self.cio.add_peer(pconn) {
-                Ok(pid) => {
-                    self.incoming.insert(pid);
-                }
-                Err(e) => {
-                    error!("Failed to add peer connection: {:?}", e);
-                }
-            },
+    fn handle_incoming_conn(&mut self, sock: Socket) {
+        match self.cio.add_peer(peer::PeerConn::new_incoming(sock)) {
+            Ok(pid) => {
+                self.incoming.insert(pid);
+            }
             Err(e) => {
-                error!("Failed to create peer connection: {:?}", e);
+                error!("Failed to add peer connection: {:?}", e);
             }
         }
     }
@@ -379,6 +683,15 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_peer_ev(&mut self, pid: cio::PID, ev: cio::Result<torrent::Message>) {
+        // An outgoing connection's first event is either its handshake or
+        // its failure, after which it no longer counts as half open
+        if let Some((_, addr)) = self.half_open.remove(&pid) {
+            if !self.settle_race(pid, ev.is_ok()) && ev.is_err() {
+                let now = Utc::now().timestamp();
+                self.scores.record(now, addr.ip(), score::Report::Failed);
+            }
+            self.dial_candidates();
+        }
         let p = &mut self.peers;
 
         if let Some(&tid) = p.get(&pid) {
@@ -396,6 +709,16 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
+    /// Collects what torrents learned about peer addresses.
+    fn update_scores(&mut self) {
+        let now = Utc::now().timestamp();
+        for torrent in self.torrents.values_mut() {
+            for (ip, report) in torrent.take_score_reports() {
+                self.scores.record(now, ip, report);
+            }
+        }
+    }
+
     fn flush_blocked_peers(&mut self) {
         trace!("Flushing blocked peers!");
         self.cio.flush_peers(self.throttler.flush_dl());
@@ -408,21 +731,54 @@ impl<T: cio::CIO> Control<T> {
         path: Option<String>,
         start: bool,
         import: bool,
+        link: Option<rpc::LinkMode>,
+        memory: bool,
         client: usize,
         serial: u64,
     ) {
         debug!("Adding {:?}, start: {}!", info, start);
         let id = hash_to_id(&info.hash);
-        if self.hash_idx.contains_key(&info.hash) {
+        if let Some(tid) = self.hash_idx.get(&info.hash) {
             debug!("Tried to add torrent that already exists!");
+            // Rather than running two copies of the torrent, any new
+            // trackers are merged into the existing one.
+            let merged = self
+                .torrents
+                .get_mut(tid)
+                .map(|t| t.merge_trackers(&info))
+                .unwrap_or(0);
+            let reason = if merged > 0 {
+                format!(
+                    "Torrent {} already exists, merged {} new trackers into it",
+                    id, merged
+                )
+            } else {
+                format!("Torrent {} already exists", id)
+            };
             self.cio.msg_rpc(rpc::CtlMessage::Error {
                 client,
                 serial,
-                reason: format!("Torrent {} already exists", id),
+                code: rpc::resource::ErrorCode::TorrentExists,
+                reason,
             });
             return;
         }
+        if !info.private && CONFIG.peer.extensions.dht {
+            for addr in &info.nodes {
+                self.cio.msg_dht(dht::Request::AddNode(*addr));
+            }
+        }
         let tid = self.tid_cnt;
+        if let (Some(mode), false) = (link, memory) {
+            // Queued first, so the torrent's own jobs see the reused files
+            let files = self.link_sources(&info);
+            if !files.is_empty() {
+                let dir = path.as_ref().map(|p| shellexpand::tilde(p).into_owned());
+                let hardlink = mode == rpc::LinkMode::Hardlink;
+                self.cio
+                    .msg_disk(disk::Request::link(tid, files, dir, hardlink));
+            }
+        }
         let throttle = self.throttler.get_throttle(tid);
         let t = Torrent::new(
             tid,
@@ -432,6 +788,7 @@ impl<T: cio::CIO> Control<T> {
             self.cio.new_handle(),
             start,
             import,
+            memory,
         );
         self.hash_idx.insert(t.info().hash, tid);
         self.tid_cnt += 1;
@@ -441,6 +798,25 @@ impl<T: cio::CIO> Control<T> {
             .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
     }
 
+    /// Finds complete files of existing torrents which a new torrent also
+    /// contains, by their path inside the torrent's folder and length.
+    fn link_sources(&self, info: &torrent::Info) -> Vec<(PathBuf, PathBuf)> {
+        let mut existing = FHashMap::default();
+        for t in self.torrents.values() {
+            for (f, loc) in t.complete_files() {
+                existing.entry((f.inner_path(), f.length)).or_insert(loc);
+            }
+        }
+        info.files
+            .iter()
+            .filter(|f| !f.pad)
+            .filter_map(|f| {
+                let src = existing.get(&(f.inner_path(), f.length))?;
+                Some((src.clone(), f.path.clone()))
+            })
+            .collect()
+    }
+
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
@@ -462,13 +838,17 @@ impl<T: cio::CIO> Control<T> {
                 path,
                 start,
                 import,
+                link,
+                memory,
                 client,
                 serial,
-            } => self.add_torrent(info, path, start, import, client, serial),
+            } => self.add_torrent(info, path, start, import, link, memory, client, serial),
             rpc::Message::UpdateFile {
                 id,
                 torrent_id,
                 priority,
+                position,
+                path,
             } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -476,7 +856,7 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i));
                 if let Some(t) = res {
-                    t.rpc_update_file(id, priority);
+                    t.rpc_update_file(id, priority, position, path);
                 }
             }
             rpc::Message::AddPeer {
@@ -488,7 +868,7 @@ impl<T: cio::CIO> Control<T> {
                 let res = id_to_hash(&id)
                     .and_then(|d| self.hash_idx.get(d.as_ref()))
                     .cloned();
-                let pres = peer::PeerConn::new_outgoing(&peer);
+                let pres = peer::PeerConn::new_outgoing(&peer, PeerSource::Manual);
                 if let Some(tid) = res {
                     if let Ok(pc) = pres {
                         if let Some(id) = self.add_peer_rpc(tid, pc) {
@@ -496,6 +876,7 @@ impl<T: cio::CIO> Control<T> {
                                 .msg_rpc(rpc::CtlMessage::Pending { id, client, serial });
                         } else {
                             self.cio.msg_rpc(rpc::CtlMessage::Error {
+                                code: rpc::resource::ErrorCode::PeerConnectionFailed,
                                 client,
                                 serial,
                                 reason: format!("Could not add peer {}", peer),
@@ -503,6 +884,7 @@ impl<T: cio::CIO> Control<T> {
                         }
                     } else {
                         self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::PeerConnectionFailed,
                             client,
                             serial,
                             reason: format!("Could not create peer {}", peer),
@@ -510,6 +892,7 @@ impl<T: cio::CIO> Control<T> {
                     }
                 } else {
                     self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        code: rpc::resource::ErrorCode::UnknownResource,
                         client,
                         serial,
                         reason: format!("torrent {} does not exist", id),
@@ -533,6 +916,7 @@ impl<T: cio::CIO> Control<T> {
                     .map(|id| cio.msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
                             reason,
                             client,
                             serial,
@@ -576,6 +960,7 @@ impl<T: cio::CIO> Control<T> {
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
@@ -614,6 +999,60 @@ impl<T: cio::CIO> Control<T> {
                     }
                 }
             }
+            rpc::Message::Repair(targets) => {
+                let hash_idx = &mut self.hash_idx;
+                let torrents = &mut self.torrents;
+                for (id, files) in targets {
+                    if let Some(t) = id_to_hash(&id)
+                        .and_then(|d| hash_idx.get(d.as_ref()))
+                        .and_then(|i| torrents.get_mut(i))
+                    {
+                        t.repair(&files);
+                    }
+                }
+            }
+            rpc::Message::IntegrityReport { id, client, serial } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let cio = &mut self.cio;
+                let reason = "Torrent does not exist!".to_string();
+                id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.integrity_report(client, serial))
+                    .unwrap_or_else(|| {
+                        cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
+                            client,
+                            serial,
+                            reason,
+                        })
+                    });
+            }
+            rpc::Message::SetPieceDeadline {
+                id,
+                piece,
+                deadline,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let cio = &mut self.cio;
+                let reason = "Torrent does not exist!".to_string();
+                id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.set_piece_deadline(piece, deadline, client, serial))
+                    .unwrap_or_else(|| {
+                        cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
+                            client,
+                            serial,
+                            reason,
+                        })
+                    });
+            }
             rpc::Message::RemovePeer {
                 id,
                 torrent_id,
@@ -631,6 +1070,7 @@ impl<T: cio::CIO> Control<T> {
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
@@ -654,6 +1094,7 @@ impl<T: cio::CIO> Control<T> {
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: rpc::resource::ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
@@ -688,17 +1129,16 @@ impl<T: cio::CIO> Control<T> {
         None
     }
 
-    fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
+    fn add_peer(&mut self, id: usize, peer: peer::PeerConn) -> Option<usize> {
         trace!("Adding peer to torrent {:?}!", id);
-        if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
-                return;
-            }
-            if let Some(pid) = torrent.add_peer(peer) {
-                self.peers.insert(pid, id);
-            }
+        let torrent = self.torrents.get_mut(&id)?;
+        if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
+            self.queue.add(id, torrent.priority());
+            return None;
         }
+        let pid = torrent.add_peer(peer)?;
+        self.peers.insert(pid, id);
+        Some(pid)
     }
 
     fn add_inc_peer(
@@ -732,6 +1172,21 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_rpc_dht(&mut self, stats: &dht::Stats) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerDht {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                dht_nodes: stats.nodes as u64,
+                dht_buckets: stats.buckets as u64,
+                dht_fresh_buckets: stats.fresh_buckets as u64,
+                dht_query_rate: stats.query_rate,
+                dht_torrents: stats.torrents as u64,
+                dht_peers: stats.peers as u64,
+            },
+        ]));
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
@@ -777,6 +1232,7 @@ impl<T: cio::CIO> Drop for Control<T> {
         self.torrents.drain().last();
         self.cio.msg_rpc(rpc::CtlMessage::Shutdown);
         self.cio.msg_trk(tracker::Request::Shutdown);
+        self.cio.msg_dht(dht::Request::Shutdown);
         self.cio.msg_disk(disk::Request::shutdown());
     }
 }
@@ -913,6 +1369,51 @@ impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
     }
 }
 
+pub struct ConnectUpdate;
+
+impl<T: cio::CIO> CJob<T> for ConnectUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.expire_half_open();
+    }
+}
+
+pub struct RollupUpdate {
+    /// Transfer totals of each torrent when last recorded
+    last: UHashMap<(u64, u64)>,
+}
+
+impl RollupUpdate {
+    fn new() -> RollupUpdate {
+        RollupUpdate {
+            last: UHashMap::default(),
+        }
+    }
+}
+
+impl<T: cio::CIO> CJob<T> for RollupUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let now = Utc::now().timestamp();
+        let torrents = &control.torrents;
+        let rollups = &mut control.rollups;
+        self.last.retain(|tid, _| torrents.contains_key(tid));
+        for (tid, torrent) in torrents {
+            let total = (torrent.uploaded(), torrent.downloaded());
+            // The first sample of a torrent only establishes a baseline
+            if let Some(prev) = self.last.insert(*tid, total) {
+                let host = torrent.trackers().front().and_then(|t| t.url.host_str());
+                rollups.record(
+                    now,
+                    &hash_to_id(&torrent.info().hash),
+                    host,
+                    total.0.saturating_sub(prev.0),
+                    total.1.saturating_sub(prev.1),
+                );
+            }
+        }
+        rollups.prune(now);
+    }
+}
+
 pub struct SerializeUpdate;
 
 impl<T: cio::CIO> CJob<T> for SerializeUpdate {
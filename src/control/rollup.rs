@@ -0,0 +1,148 @@
+//! Hourly and daily rollups of transferred bytes per torrent and per
+//! tracker host. They're persisted as JSON in the session directory so that
+//! reports like "uploaded this month per tracker" can be built from them
+//! without synapse running.
+
+use std::collections::{HashMap, VecDeque};
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+/// Hourly buckets kept, one week's worth
+const HOURLY_RETENTION: usize = 7 * 24;
+/// Daily buckets kept, a little over a year so yearly reports are possible
+const DAILY_RETENTION: usize = 400;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Rollups {
+    /// Keyed by torrent ID
+    torrents: HashMap<String, Series>,
+    /// Keyed by tracker host
+    trackers: HashMap<String, Series>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Series {
+    hourly: VecDeque<Bucket>,
+    daily: VecDeque<Bucket>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Bucket {
+    /// Unix timestamp of the start of the bucket
+    start: i64,
+    ul: u64,
+    dl: u64,
+}
+
+impl Rollups {
+    pub fn new() -> Rollups {
+        Default::default()
+    }
+
+    /// Records bytes transferred by a torrent at the given unix time,
+    /// attributing them to its tracker's host as well.
+    pub fn record(&mut self, now: i64, torrent: &str, tracker: Option<&str>, ul: u64, dl: u64) {
+        if ul == 0 && dl == 0 {
+            return;
+        }
+        self.torrents
+            .entry(torrent.to_owned())
+            .or_default()
+            .record(now, ul, dl);
+        if let Some(host) = tracker {
+            self.trackers
+                .entry(host.to_owned())
+                .or_default()
+                .record(now, ul, dl);
+        }
+    }
+
+    /// Drops buckets past their retention, and series left empty by that.
+    pub fn prune(&mut self, now: i64) {
+        for series in self.torrents.values_mut().chain(self.trackers.values_mut()) {
+            series.prune(now);
+        }
+        self.torrents.retain(|_, s| !s.daily.is_empty());
+        self.trackers.retain(|_, s| !s.daily.is_empty());
+    }
+}
+
+impl Series {
+    fn record(&mut self, now: i64, ul: u64, dl: u64) {
+        Series::add(&mut self.hourly, now - now.rem_euclid(HOUR), ul, dl);
+        Series::add(&mut self.daily, now - now.rem_euclid(DAY), ul, dl);
+    }
+
+    fn add(buckets: &mut VecDeque<Bucket>, start: i64, ul: u64, dl: u64) {
+        match buckets.back_mut() {
+            Some(b) if b.start == start => {
+                b.ul += ul;
+                b.dl += dl;
+            }
+            _ => buckets.push_back(Bucket { start, ul, dl }),
+        }
+    }
+
+    fn prune(&mut self, now: i64) {
+        let hourly = now - HOURLY_RETENTION as i64 * HOUR;
+        while self
+            .hourly
+            .front()
+            .map(|b| b.start < hourly)
+            .unwrap_or(false)
+        {
+            self.hourly.pop_front();
+        }
+        let daily = now - DAILY_RETENTION as i64 * DAY;
+        while self.daily.front().map(|b| b.start < daily).unwrap_or(false) {
+            self.daily.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut r = Rollups::new();
+        let t = 10 * DAY;
+        r.record(t, "a", Some("tracker.com"), 10, 5);
+        r.record(t + HOUR / 2, "a", Some("tracker.com"), 10, 5);
+        r.record(t + HOUR, "a", None, 1, 0);
+        r.record(t + HOUR, "b", Some("tracker.com"), 0, 0);
+
+        let a = &r.torrents["a"];
+        assert_eq!(a.hourly.len(), 2);
+        assert_eq!(
+            a.hourly[0],
+            Bucket {
+                start: t,
+                ul: 20,
+                dl: 10
+            }
+        );
+        assert_eq!(a.daily.len(), 1);
+        assert_eq!(a.daily[0].ul, 21);
+        assert_eq!(r.trackers["tracker.com"].daily[0].ul, 20);
+        // Idle torrents don't create series
+        assert!(!r.torrents.contains_key("b"));
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut r = Rollups::new();
+        r.record(0, "a", Some("tracker.com"), 1, 1);
+        r.record(DAY, "b", None, 1, 1);
+        r.prune(DAY + HOURLY_RETENTION as i64 * HOUR);
+        assert!(r.torrents["a"].hourly.is_empty());
+        assert_eq!(r.torrents["a"].daily.len(), 1);
+        assert_eq!(r.torrents["b"].hourly.len(), 1);
+
+        r.prune(DAY + DAILY_RETENTION as i64 * DAY);
+        assert!(!r.torrents.contains_key("a"));
+        assert!(r.trackers.is_empty());
+        assert_eq!(r.torrents["b"].daily.len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+//! Scores of peer addresses, persisted in the session directory so that
+//! peers which were fast before are connected to first after a restart.
+//! Each address remembers the download rate it gave us and how often it
+//! let us down, either by failing to complete a handshake or by sending
+//! corrupt pieces.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Addresses remembered, the ones seen least recently are forgotten first
+const MAX_ENTRIES: usize = 10_000;
+/// Addresses which haven't been seen for this long are forgotten
+const RETENTION_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Something learned about a peer address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Report {
+    /// A connection ended, after downloading at most at this rate
+    Disconnected { rate: u64 },
+    /// A connection attempt failed before the handshake completed
+    Failed,
+    /// The address sent blocks of a piece which failed its hash check
+    Corrupt,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Scores {
+    entries: HashMap<IpAddr, Entry>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    /// Download rate over past connections, in bytes per second
+    rate: u64,
+    /// Connection attempts which failed since the last successful one
+    failures: u32,
+    /// Corrupt pieces the address contributed to
+    corrupt: u32,
+    /// Unix timestamp of the last report
+    seen: i64,
+}
+
+impl Scores {
+    pub fn new() -> Scores {
+        Default::default()
+    }
+
+    pub fn record(&mut self, now: i64, ip: IpAddr, report: Report) {
+        let e = self.entries.entry(ip).or_default();
+        match report {
+            Report::Disconnected { rate } => {
+                // Older connections count for less and less
+                e.rate = if e.rate == 0 {
+                    rate
+                } else {
+                    (e.rate + rate) / 2
+                };
+                e.failures = 0;
+            }
+            Report::Failed => e.failures = e.failures.saturating_add(1),
+            Report::Corrupt => e.corrupt = e.corrupt.saturating_add(1),
+        }
+        e.seen = now;
+    }
+
+    /// Scores an address, higher being better. Throughput earns credit,
+    /// which each failed connection halves and each corrupt piece
+    /// quarters. Unknown addresses rank above ones which let us down.
+    pub fn score(&self, ip: IpAddr) -> u64 {
+        self.entries
+            .get(&ip)
+            .map(|e| {
+                let penalty = e.failures.saturating_add(e.corrupt.saturating_mul(2));
+                (e.rate + 1) >> penalty.min(63)
+            })
+            .unwrap_or(1)
+    }
+
+    /// Forgets addresses not seen within the retention period, then the
+    /// least recently seen ones until at most `MAX_ENTRIES` are left.
+    pub fn prune(&mut self, now: i64) {
+        self.entries.retain(|_, e| now - e.seen < RETENTION_SECS);
+        if self.entries.len() <= MAX_ENTRIES {
+            return;
+        }
+        let mut by_age: Vec<_> = self.entries.iter().map(|(&ip, e)| (e.seen, ip)).collect();
+        by_age.sort_unstable();
+        let excess = by_age.len() - MAX_ENTRIES;
+        for (_, ip) in by_age.into_iter().take(excess) {
+            self.entries.remove(&ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let mut s = Scores::new();
+        let (fast, slow, bad) = (
+            "1.2.3.4".parse().unwrap(),
+            "5.6.7.8".parse().unwrap(),
+            "9.9.9.9".parse().unwrap(),
+        );
+        let unknown = "8.8.8.8".parse().unwrap();
+        s.record(0, fast, Report::Disconnected { rate: 1_000_000 });
+        s.record(0, slow, Report::Disconnected { rate: 1_000 });
+        s.record(0, bad, Report::Failed);
+        assert!(s.score(fast) > s.score(slow));
+        assert!(s.score(slow) > s.score(unknown));
+        assert!(s.score(unknown) > s.score(bad));
+
+        // Failures cost throughput credit until a connection succeeds
+        s.record(0, fast, Report::Failed);
+        assert_eq!(s.score(fast), 500_000);
+        s.record(0, fast, Report::Disconnected { rate: 3_000_000 });
+        assert_eq!(s.score(fast), 2_000_001);
+        s.record(0, fast, Report::Corrupt);
+        assert_eq!(s.score(fast), 500_000);
+        s.record(0, bad, Report::Disconnected { rate: 0 });
+        assert_eq!(s.score(bad), s.score(unknown));
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut s = Scores::new();
+        s.record(0, "1.2.3.4".parse().unwrap(), Report::Failed);
+        for i in 0..MAX_ENTRIES as u32 + 5 {
+            let ip = IpAddr::from((0x0a00_0000 + i).to_be_bytes());
+            s.record(1 + i64::from(i % 3), ip, Report::Failed);
+        }
+        s.prune(RETENTION_SECS);
+        assert_eq!(s.entries.len(), MAX_ENTRIES);
+        assert!(!s.entries.contains_key(&"1.2.3.4".parse().unwrap()));
+        assert!(s.entries.values().filter(|e| e.seen == 1).count() < MAX_ENTRIES / 3);
+
+        s.prune(RETENTION_SECS + 3);
+        assert!(s.entries.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Bloom filters used to scrape swarm sizes from the DHT (BEP 33).
+
+use std::net::IpAddr;
+
+use crate::util::sha1_hash;
+
+const BITS: usize = 2048;
+pub const BYTES: usize = BITS / 8;
+
+#[derive(Clone, Debug)]
+pub struct Bloom {
+    data: [u8; BYTES],
+}
+
+impl Bloom {
+    pub fn new() -> Bloom {
+        Bloom { data: [0; BYTES] }
+    }
+
+    /// Parses a filter from its wire form, if it's the right size.
+    pub fn from_bytes(data: &[u8]) -> Option<Bloom> {
+        if data.len() != BYTES {
+            return None;
+        }
+        let mut b = Bloom::new();
+        b.data.copy_from_slice(data);
+        Some(b)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => sha1_hash(&ip.octets()),
+            IpAddr::V6(ip) => sha1_hash(&ip.octets()),
+        };
+        for i in &[0, 2] {
+            let bit = (usize::from(hash[*i]) | usize::from(hash[i + 1]) << 8) % BITS;
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &Bloom) {
+        for (a, b) in self.data.iter_mut().zip(other.data.iter()) {
+            *a |= b;
+        }
+    }
+
+    /// Estimates the number of distinct IPs inserted into the filter.
+    pub fn estimate(&self) -> u32 {
+        let set: u32 = self.data.iter().map(|b| b.count_ones()).sum();
+        // A full filter's estimate is infinite, so cap it one short
+        let zero = (BITS as u32 - set).max(1) as f64;
+        let m = BITS as f64;
+        ((zero / m).ln() / (2. * (1. - 1. / m).ln())).round() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_estimate() {
+        let mut b = Bloom::new();
+        assert_eq!(b.estimate(), 0);
+        for i in 0..100u8 {
+            b.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 1, i)));
+        }
+        let est = b.estimate();
+        assert!(est > 90 && est < 110, "estimate {} too far off", est);
+
+        // Merging in the same IPs shouldn't change the estimate
+        let mut c = Bloom::from_bytes(&b.to_bytes()).unwrap();
+        c.union(&b);
+        assert_eq!(c.estimate(), est);
+    }
+
+    #[test]
+    fn test_bep33_vector() {
+        // Example from BEP 33: 192.0.2.0 - 192.0.2.255 and 2001:DB8:: - 2001:DB8::3E7,
+        // which estimates 1224.93
+        let mut b = Bloom::new();
+        for i in 0..=255u8 {
+            b.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        for i in 0..=0x3e7u16 {
+            let ip: std::net::Ipv6Addr = format!("2001:db8::{:x}", i).parse().unwrap();
+            b.insert(IpAddr::V6(ip));
+        }
+        assert_eq!(b.estimate(), 1225);
+    }
+}
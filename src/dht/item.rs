@@ -0,0 +1,258 @@
+//! Storage of arbitrary data for other nodes (BEP 44).
+//!
+//! Immutable items are keyed by the hash of their value, mutable ones by
+//! the hash of the ed25519 key (and salt) which signs them.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use ring::signature;
+
+use super::proto::ErrorKind;
+use crate::bencode::BEncode;
+use crate::util::sha1_hash;
+
+/// Largest bencoded value which may be stored
+const MAX_VALUE_LEN: usize = 1000;
+const MAX_SALT_LEN: usize = 64;
+/// Limit on stored items, so a spammer can't fill our memory
+const MAX_ITEMS: usize = 2000;
+/// Items are dropped unless they're put again within this time
+const ITEM_TIMEOUT_MINS: i64 = 2 * 60;
+
+#[derive(Clone, Debug, Default)]
+pub struct Store {
+    items: HashMap<[u8; 20], Item>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub v: BEncode,
+    pub mutable: Option<Mutable>,
+    stored: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mutable {
+    /// Public key, 32 bytes
+    pub k: Vec<u8>,
+    /// Signature over the salt, seq and value, 64 bytes
+    pub sig: Vec<u8>,
+    pub seq: i64,
+    pub salt: Vec<u8>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Default::default()
+    }
+
+    pub fn get(&self, target: &[u8; 20]) -> Option<&Item> {
+        self.items.get(target)
+    }
+
+    pub fn put_immutable(&mut self, v: BEncode) -> Result<[u8; 20], ErrorKind> {
+        let data = v.encode_to_buf();
+        if data.len() > MAX_VALUE_LEN {
+            return Err(too_big());
+        }
+        let target = sha1_hash(&data);
+        self.insert(target, v, None);
+        Ok(target)
+    }
+
+    /// Stores a mutable item after checking its signature. If `cas` is
+    /// given, the item only replaces one with that sequence number.
+    pub fn put_mutable(
+        &mut self,
+        v: BEncode,
+        m: Mutable,
+        cas: Option<i64>,
+    ) -> Result<[u8; 20], ErrorKind> {
+        let data = v.encode_to_buf();
+        if data.len() > MAX_VALUE_LEN {
+            return Err(too_big());
+        }
+        if m.salt.len() > MAX_SALT_LEN {
+            return Err(ErrorKind::Storage(207, "salt too big".to_owned()));
+        }
+        if !m.verify(&data) {
+            return Err(ErrorKind::Storage(206, "invalid signature".to_owned()));
+        }
+        let target = m.target();
+        if let Some(cur) = self.items.get(&target).and_then(|i| i.mutable.as_ref()) {
+            if cas.map(|c| c != cur.seq).unwrap_or(false) {
+                return Err(ErrorKind::Storage(301, "CAS mismatch".to_owned()));
+            }
+            // An equal seq is only a refresh if the value is unchanged
+            if m.seq < cur.seq || (m.seq == cur.seq && m.sig != cur.sig) {
+                return Err(ErrorKind::Storage(
+                    302,
+                    "sequence number less than current".to_owned(),
+                ));
+            }
+        }
+        self.insert(target, v, Some(m));
+        Ok(target)
+    }
+
+    /// Drops items which haven't been put recently.
+    pub fn tick(&mut self) {
+        self.items.retain(|_, i| {
+            Utc::now().signed_duration_since(i.stored).num_minutes() < ITEM_TIMEOUT_MINS
+        });
+    }
+
+    fn insert(&mut self, target: [u8; 20], v: BEncode, mutable: Option<Mutable>) {
+        if !self.items.contains_key(&target) && self.items.len() >= MAX_ITEMS {
+            // Make room by evicting the least recently stored item
+            let oldest = self
+                .items
+                .iter()
+                .min_by_key(|(_, i)| i.stored)
+                .map(|(t, _)| *t)
+                .unwrap();
+            self.items.remove(&oldest);
+        }
+        self.items.insert(
+            target,
+            Item {
+                v,
+                mutable,
+                stored: Utc::now(),
+            },
+        );
+    }
+}
+
+impl Mutable {
+    pub fn target(&self) -> [u8; 20] {
+        let mut data = self.k.clone();
+        data.extend_from_slice(&self.salt);
+        sha1_hash(&data)
+    }
+
+    /// Checks the signature against the bencoded value.
+    pub fn verify(&self, v: &[u8]) -> bool {
+        if self.k.len() != 32 || self.sig.len() != 64 {
+            return false;
+        }
+        let mut msg = Vec::with_capacity(v.len() + self.salt.len() + 32);
+        if !self.salt.is_empty() {
+            msg.extend_from_slice(format!("4:salt{}:", self.salt.len()).as_bytes());
+            msg.extend_from_slice(&self.salt);
+        }
+        msg.extend_from_slice(format!("3:seqi{}e1:v", self.seq).as_bytes());
+        msg.extend_from_slice(v);
+        signature::UnparsedPublicKey::new(&signature::ED25519, &self.k)
+            .verify(&msg, &self.sig)
+            .is_ok()
+    }
+}
+
+fn too_big() -> ErrorKind {
+    ErrorKind::Storage(205, "message (v field) too big".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn value() -> BEncode {
+        BEncode::from_str("Hello World!")
+    }
+
+    // Test vectors from BEP 44
+    fn mutable(salt: &str, sig: &str) -> Mutable {
+        Mutable {
+            k: unhex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548"),
+            sig: unhex(sig),
+            seq: 1,
+            salt: salt.as_bytes().to_vec(),
+        }
+    }
+
+    fn unsalted() -> Mutable {
+        mutable(
+            "",
+            "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+             1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+        )
+    }
+
+    #[test]
+    fn test_immutable() {
+        let mut s = Store::new();
+        let target = s.put_immutable(value()).unwrap();
+        assert_eq!(
+            target.to_vec(),
+            unhex("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+        );
+        assert_eq!(s.get(&target).unwrap().v, value());
+
+        let big = BEncode::String(vec![0; MAX_VALUE_LEN]);
+        assert!(s.put_immutable(big).is_err());
+    }
+
+    #[test]
+    fn test_mutable() {
+        let mut s = Store::new();
+        let target = s.put_mutable(value(), unsalted(), None).unwrap();
+        assert_eq!(
+            target.to_vec(),
+            unhex("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+        );
+
+        let salted = mutable(
+            "foobar",
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+             df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+        );
+        let target = s.put_mutable(value(), salted, None).unwrap();
+        assert_eq!(
+            target.to_vec(),
+            unhex("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+        );
+        assert_eq!(s.items.len(), 2);
+
+        // Tampering with anything signed invalidates the item
+        let mut m = unsalted();
+        m.seq = 2;
+        assert_matches!(
+            s.put_mutable(value(), m, None),
+            Err(ErrorKind::Storage(206, _))
+        );
+        assert_matches!(
+            s.put_mutable(BEncode::from_str("Hello World?"), unsalted(), None),
+            Err(ErrorKind::Storage(206, _))
+        );
+
+        // Re-putting the same item refreshes it, unless the CAS is off
+        assert!(s.put_mutable(value(), unsalted(), Some(1)).is_ok());
+        assert_matches!(
+            s.put_mutable(value(), unsalted(), Some(0)),
+            Err(ErrorKind::Storage(301, _))
+        );
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut s = Store::new();
+        for i in 0..=MAX_ITEMS {
+            s.put_immutable(BEncode::Int(i as i64)).unwrap();
+        }
+        assert_eq!(s.items.len(), MAX_ITEMS);
+        s.items.values_mut().for_each(|i| {
+            i.stored = i.stored - chrono::Duration::minutes(ITEM_TIMEOUT_MINS);
+        });
+        s.tick();
+        assert_eq!(s.items.len(), 0);
+    }
+}
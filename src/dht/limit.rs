@@ -0,0 +1,82 @@
+//! Per-IP rate limiting of incoming queries, so that a single host can't
+//! use us to amplify traffic or monopolize our responses.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Sustained queries per second allowed from a single IP
+const RATE: f32 = 5.;
+/// Queries a host may burst before being limited
+const BURST: f32 = 20.;
+/// Number of hosts tracked before idle ones are forgotten
+const MAX_HOSTS: usize = 10_000;
+
+pub struct Limiter {
+    hosts: HashMap<IpAddr, Allowance>,
+    /// Queries dropped since the last call to `take_dropped`
+    dropped: u64,
+}
+
+struct Allowance {
+    tokens: f32,
+    updated: Instant,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            hosts: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Whether a query from the IP should be answered.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.hosts.len() >= MAX_HOSTS && !self.hosts.contains_key(&ip) {
+            self.prune(now);
+        }
+        let a = self.hosts.entry(ip).or_insert(Allowance {
+            tokens: BURST,
+            updated: now,
+        });
+        let elapsed = now.duration_since(a.updated).as_secs_f32();
+        a.tokens = (a.tokens + elapsed * RATE).min(BURST);
+        a.updated = now;
+        if a.tokens >= 1. {
+            a.tokens -= 1.;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::replace(&mut self.dropped, 0)
+    }
+
+    /// Forgets hosts whose allowance has refilled, since they're
+    /// indistinguishable from hosts never seen.
+    fn prune(&mut self, now: Instant) {
+        self.hosts
+            .retain(|_, a| a.tokens + now.duration_since(a.updated).as_secs_f32() * RATE < BURST);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let mut l = Limiter::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let allowed = (0..100).filter(|_| l.allow(ip)).count();
+        assert_eq!(allowed, BURST as usize);
+        assert_eq!(l.take_dropped(), 100 - BURST as u64);
+        // Other hosts are unaffected
+        assert!(l.allow("10.0.0.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,345 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::{result, thread, time};
+
+use num_bigint::BigUint;
+
+use crate::disk;
+use crate::handle;
+use crate::CONFIG;
+
+mod bloom;
+mod item;
+mod limit;
+mod proto;
+mod rt;
+mod secure;
+mod token;
+
+type ID = BigUint;
+
+const BUCKET_MAX: usize = 8;
+const MAX_BUCKETS: usize = 512;
+const VERSION: &str = "SY";
+const SESSION_FILE: &str = "dht_data";
+const MIN_BOOTSTRAP_BKTS: usize = 32;
+const TX_TIMEOUT_SECS: i64 = 20;
+const POLL_INT_MS: usize = 1000;
+const TICK_MS: usize = 150;
+const STATS_SECS: u64 = 10;
+
+/// Mainline DHT node (BEP 5), run on its own thread.
+pub struct Dht {
+    poll: amy::Poller,
+    ch: handle::Handle<Request, Response>,
+    timer: usize,
+    manager: Manager,
+}
+
+#[derive(Debug)]
+pub enum Request {
+    /// Searches for peers of a torrent
+    GetPeers {
+        tid: usize,
+        hash: [u8; 20],
+    },
+    AddNode(SocketAddr),
+    Announce {
+        hash: [u8; 20],
+        seed: bool,
+    },
+    Ping,
+    Shutdown,
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Peers {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
+    /// Swarm size estimated from the bloom filters of a search (BEP 33)
+    Scrape {
+        tid: usize,
+        seeders: u32,
+        leechers: u32,
+    },
+    Stats(Stats),
+}
+
+/// Health of the routing table and peer storage
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub nodes: usize,
+    pub buckets: usize,
+    /// Buckets with a node heard from in the last 15 minutes
+    pub fresh_buckets: usize,
+    /// Incoming queries per second
+    pub query_rate: f32,
+    /// Torrents and peers stored for other nodes
+    pub torrents: usize,
+    pub peers: usize,
+}
+
+struct Manager {
+    id: usize,
+    table: rt::RoutingTable,
+    limiter: limit::Limiter,
+    dht_flush: time::Instant,
+    /// Incoming queries since stats were last sent
+    queries: u64,
+    stats_sent: time::Instant,
+    sock: UdpSocket,
+    buf: Vec<u8>,
+    db: amy::Sender<disk::Request>,
+}
+
+impl Dht {
+    pub fn start(
+        creg: &mut amy::Registrar,
+        db: amy::Sender<disk::Request>,
+    ) -> io::Result<(handle::Handle<Response, Request>, thread::JoinHandle<()>)> {
+        let poll = amy::Poller::new()?;
+        let mut reg = poll.get_registrar();
+        let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
+        let timer = reg.set_interval(TICK_MS)?;
+        let manager = Manager::new(&reg, db)?;
+        let th = dh.run("dht", move |h| {
+            Dht {
+                poll,
+                ch: h,
+                timer,
+                manager,
+            }
+            .run()
+        })?;
+        Ok((ch, th))
+    }
+
+    pub fn run(&mut self) {
+        self.manager.init();
+
+        debug!("Initialized!");
+        'outer: loop {
+            match self.poll.wait(POLL_INT_MS) {
+                Ok(events) => {
+                    for event in events {
+                        if self.handle_event(event).is_err() {
+                            break 'outer;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to poll for events: {}", e);
+                }
+            }
+        }
+        self.manager.save();
+    }
+
+    fn handle_event(&mut self, event: amy::Notification) -> result::Result<(), ()> {
+        if event.id == self.ch.rx.get_id() {
+            return self.handle_request();
+        } else if event.id == self.timer {
+            for resp in self.manager.tick() {
+                self.ch.send(resp).ok();
+            }
+        } else if event.id == self.manager.id {
+            for resp in self.manager.readable() {
+                self.ch.send(resp).ok();
+            }
+        } else {
+            error!("Unknown event occured for dht: {:?}", event);
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self) -> result::Result<(), ()> {
+        while let Ok(r) = self.ch.recv() {
+            match r {
+                Request::GetPeers { tid, hash } => {
+                    trace!("Handling dht peer find req!");
+                    self.manager.get_peers(tid, hash);
+                }
+                Request::AddNode(addr) => {
+                    trace!("Handling dht node addition req!");
+                    self.manager.add_addr(addr);
+                }
+                Request::Announce { hash, seed } => {
+                    trace!("Handling dht announce req!");
+                    self.manager.announce(hash, seed);
+                }
+                Request::Ping => {}
+                Request::Shutdown => {
+                    return Err(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Manager {
+    pub fn new(reg: &amy::Registrar, db: amy::Sender<disk::Request>) -> io::Result<Manager> {
+        let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        // Turn off DHT if no bootstrap is specified.
+        if CONFIG.dht.bootstrap_node.is_none() {
+            reg.deregister(&sock)?;
+        }
+
+        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        let mut data = Vec::new();
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+            f.read_to_end(&mut data)?;
+        }
+        let mut table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
+            t
+        } else {
+            info!("DHT table could not be read from disk, creating new table!");
+            rt::RoutingTable::new()
+        };
+        if !table.is_bootstrapped() {
+            info!("Attempting DHT bootstrap!");
+            if let Some(addr) = CONFIG.dht.bootstrap_node {
+                let (msg, _) = table.add_addr(addr);
+                sock.send_to(&msg.encode(), addr).ok();
+            }
+        }
+
+        Ok(Manager {
+            table,
+            limiter: limit::Limiter::new(),
+            sock,
+            id,
+            db,
+            // Large enough for BEP 44 puts of the biggest values
+            buf: vec![0u8; 1500],
+            dht_flush: time::Instant::now(),
+            queries: 0,
+            stats_sent: time::Instant::now(),
+        })
+    }
+
+    pub fn init(&mut self) {
+        debug!("Initializing DHT nodes!");
+        for (q, a) in self.table.init() {
+            self.send_msg(&q.encode(), a);
+        }
+    }
+
+    pub fn readable(&mut self) -> Vec<Response> {
+        let mut resps = Vec::new();
+        loop {
+            match self.sock.recv_from(&mut self.buf[..]) {
+                Ok((v, addr)) => {
+                    trace!("Processing msg from {}", addr);
+                    if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
+                        self.queries += 1;
+                        if !self.limiter.allow(addr.ip()) {
+                            continue;
+                        }
+                        let mut resp = self.table.handle_req(req, addr);
+                        resp.ip = Some(addr);
+                        self.send_msg(&resp.encode(), addr);
+                    } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
+                        match self.table.handle_resp(resp, addr) {
+                            Ok(r) => resps.push(r),
+                            Err(q) => {
+                                for (req, a) in q {
+                                    self.send_msg(&req.encode(), a);
+                                }
+                            }
+                        }
+                    } else {
+                        trace!("Received invalid message from {:?}!", addr);
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    } else {
+                        error!(
+                            "Encountered unexpected error reading from UDP socket: {:?}!",
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        resps
+    }
+
+    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
+        for (req, a) in self.table.get_peers(tid, hash) {
+            self.send_msg(&req.encode(), a);
+        }
+    }
+
+    pub fn add_addr(&mut self, addr: SocketAddr) {
+        let (req, a) = self.table.add_addr(addr);
+        self.send_msg(&req.encode(), a);
+    }
+
+    pub fn announce(&mut self, hash: [u8; 20], seed: bool) {
+        for (req, a) in self.table.announce(hash, seed) {
+            self.send_msg(&req.encode(), a);
+        }
+    }
+
+    pub fn tick(&mut self) -> Vec<Response> {
+        if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
+            let data = self.table.serialize();
+            let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+            self.db.send(disk::Request::WriteFile { data, path }).ok();
+            self.dht_flush = time::Instant::now();
+        }
+        for (req, a) in self.table.tick() {
+            self.send_msg(&req.encode(), a);
+        }
+        let mut resps = self.table.take_scrapes();
+        let elapsed = self.stats_sent.elapsed();
+        if elapsed > time::Duration::from_secs(STATS_SECS) {
+            let mut stats = self.table.stats();
+            stats.query_rate = self.queries as f32 / elapsed.as_secs_f32();
+            resps.push(Response::Stats(stats));
+            let dropped = self.limiter.take_dropped();
+            if dropped > 0 {
+                debug!("Dropped {} rate limited DHT queries", dropped);
+            }
+            self.queries = 0;
+            self.stats_sent = time::Instant::now();
+        }
+        resps
+    }
+
+    /// Writes the routing table out directly, since the disk
+    /// thread may already be gone by the time we shut down.
+    pub fn save(&self) {
+        let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        let temp = path.with_extension("temp");
+        let res = fs::write(&temp, self.table.serialize()).and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = res {
+            error!("Failed to save DHT table: {}", e);
+            fs::remove_file(&temp).ok();
+        }
+    }
+
+    fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
+        // Cap tries to avoid burning CPU
+        for _ in 0..25 {
+            if let Err(e) = self.sock.send_to(msg, addr) {
+                if e.raw_os_error().map(|c| c != 11).unwrap_or(true) {
+                    error!("Failed to send message on UDP socket: {:?}", e);
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
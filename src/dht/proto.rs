@@ -1,3 +1,4 @@
+use super::item::Item;
 use super::{ID, VERSION};
 use crate::bencode::{self, BEncode};
 use crate::util::{addr_to_bytes, bytes_to_addr};
@@ -29,6 +30,12 @@ error_chain! {
                 display("method unknown: {}", r)
         }
 
+        /// Rejected item put, with a BEP 44 error code
+        Storage(code: i64, r: String) {
+            description("storage error")
+                display("storage error {}: {}", code, r)
+        }
+
         InvalidResponse(r: &'static str) {
             description("invalid response")
                 display("invalid response: {}", r)
@@ -58,6 +65,8 @@ pub enum RequestKind {
     GetPeers {
         id: ID,
         hash: [u8; 20],
+        /// Whether swarm size bloom filters are wanted (BEP 33)
+        scrape: bool,
     },
     AnnouncePeer {
         id: ID,
@@ -65,12 +74,39 @@ pub enum RequestKind {
         token: Vec<u8>,
         port: u16,
         implied_port: bool,
+        /// Whether the announcer is a seed (BEP 33)
+        seed: bool,
+    },
+    /// Requests a sample of the infohashes a node stores (BEP 51)
+    SampleInfohashes {
+        id: ID,
+        target: ID,
+    },
+    /// Requests a stored item (BEP 44)
+    Get {
+        id: ID,
+        target: [u8; 20],
+        /// Only return mutable items newer than this
+        seq: Option<i64>,
+    },
+    /// Stores an item, which is mutable if signed (BEP 44)
+    Put {
+        id: ID,
+        token: Vec<u8>,
+        v: BEncode,
+        k: Option<Vec<u8>>,
+        sig: Option<Vec<u8>>,
+        seq: Option<i64>,
+        cas: Option<i64>,
+        salt: Option<Vec<u8>>,
     },
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub transaction: Vec<u8>,
+    /// Address of the requester as seen by the responder (BEP 42)
+    pub ip: Option<SocketAddr>,
     pub kind: ResponseKind,
 }
 
@@ -86,10 +122,36 @@ pub enum ResponseKind {
         token: Vec<u8>,
         values: Vec<SocketAddr>,
         nodes: Vec<Node>,
+        scrape: Option<Scrape>,
+    },
+    Samples {
+        id: ID,
+        interval: u32,
+        num: u32,
+        samples: Vec<[u8; 20]>,
+        nodes: Vec<Node>,
+    },
+    /// Item lookup result (BEP 44). The value is absent if the node
+    /// doesn't store the item or only has an older version of it.
+    Item {
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        v: Option<BEncode>,
+        k: Option<Vec<u8>>,
+        sig: Option<Vec<u8>>,
+        seq: Option<i64>,
     },
     Error(ErrorKind),
 }
 
+/// Bloom filters of the seeds and peers of a torrent (BEP 33)
+#[derive(Debug)]
+pub struct Scrape {
+    pub seeds: Vec<u8>,
+    pub peers: Vec<u8>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Node {
     pub id: ID,
@@ -117,11 +179,29 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
-            kind: RequestKind::GetPeers { id, hash },
+            kind: RequestKind::GetPeers {
+                id,
+                hash,
+                scrape: true,
+            },
         }
     }
 
-    pub fn announce(transaction: Vec<u8>, id: ID, hash: [u8; 20], token: Vec<u8>) -> Self {
+    pub fn sample_infohashes(transaction: Vec<u8>, id: ID, target: ID) -> Self {
+        Request {
+            transaction,
+            version: Some(VERSION.to_owned()),
+            kind: RequestKind::SampleInfohashes { id, target },
+        }
+    }
+
+    pub fn announce(
+        transaction: Vec<u8>,
+        id: ID,
+        hash: [u8; 20],
+        token: Vec<u8>,
+        seed: bool,
+    ) -> Self {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
@@ -131,6 +211,7 @@ impl Request {
                 token,
                 port: CONFIG.dht.port,
                 implied_port: false,
+                seed,
             },
         }
     }
@@ -163,13 +244,28 @@ impl Request {
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
-            RequestKind::GetPeers { id, hash } => {
+            RequestKind::GetPeers { id, hash, scrape } => {
                 b.insert(String::from("q"), BEncode::from_str("get_peers"));
 
                 let mut args = BTreeMap::new();
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
                 let ib = Vec::from(&hash[..]);
                 args.insert(String::from("info_hash"), BEncode::String(ib));
+                if scrape {
+                    args.insert(String::from("scrape"), BEncode::Int(1));
+                }
+
+                b.insert(String::from("a"), BEncode::Dict(args));
+            }
+            RequestKind::SampleInfohashes { id, target } => {
+                b.insert(String::from("q"), BEncode::from_str("sample_infohashes"));
+
+                let mut args = BTreeMap::new();
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(
+                    String::from("target"),
+                    BEncode::String(target.to_bytes_be()),
+                );
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
@@ -179,6 +275,7 @@ impl Request {
                 token,
                 port,
                 implied_port,
+                seed,
             } => {
                 b.insert(String::from("q"), BEncode::from_str("announce_peer"));
                 let mut args = BTreeMap::new();
@@ -192,6 +289,55 @@ impl Request {
                 );
                 args.insert(String::from("port"), BEncode::Int(i64::from(port)));
                 args.insert(String::from("token"), BEncode::String(token));
+                if seed {
+                    args.insert(String::from("seed"), BEncode::Int(1));
+                }
+
+                b.insert(String::from("a"), BEncode::Dict(args));
+            }
+            RequestKind::Get { id, target, seq } => {
+                b.insert(String::from("q"), BEncode::from_str("get"));
+
+                let mut args = BTreeMap::new();
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("target"), BEncode::String(target.to_vec()));
+                if let Some(seq) = seq {
+                    args.insert(String::from("seq"), BEncode::Int(seq));
+                }
+
+                b.insert(String::from("a"), BEncode::Dict(args));
+            }
+            RequestKind::Put {
+                id,
+                token,
+                v,
+                k,
+                sig,
+                seq,
+                cas,
+                salt,
+            } => {
+                b.insert(String::from("q"), BEncode::from_str("put"));
+
+                let mut args = BTreeMap::new();
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("token"), BEncode::String(token));
+                args.insert(String::from("v"), v);
+                if let Some(k) = k {
+                    args.insert(String::from("k"), BEncode::String(k));
+                }
+                if let Some(sig) = sig {
+                    args.insert(String::from("sig"), BEncode::String(sig));
+                }
+                if let Some(seq) = seq {
+                    args.insert(String::from("seq"), BEncode::Int(seq));
+                }
+                if let Some(cas) = cas {
+                    args.insert(String::from("cas"), BEncode::Int(cas));
+                }
+                if let Some(salt) = salt {
+                    args.insert(String::from("salt"), BEncode::String(salt));
+                }
 
                 b.insert(String::from("a"), BEncode::Dict(args));
             }
@@ -240,6 +386,18 @@ impl Request {
             })?;
         let kind = match &q[..] {
             "ping" => RequestKind::Ping(id),
+            "sample_infohashes" => {
+                let target = a
+                    .remove("target")
+                    .and_then(|b| b.into_bytes())
+                    .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(sample_infohashes must have target field)",
+                        ))
+                    })?;
+                RequestKind::SampleInfohashes { id, target }
+            }
             "find_node" => {
                 let target = a
                     .remove("target")
@@ -268,7 +426,12 @@ impl Request {
                             "Invalid BEncoded data(get_peers must have hash field)",
                         ))
                     })?;
-                RequestKind::GetPeers { id, hash }
+                let scrape = a
+                    .remove("scrape")
+                    .and_then(|b| b.into_int())
+                    .map(|b| b > 0)
+                    .unwrap_or(false);
+                RequestKind::GetPeers { id, hash, scrape }
             }
             "announce_peer" => {
                 let mut hash = [0u8; 20];
@@ -314,12 +477,57 @@ impl Request {
                             "Invalid BEncoded data(announce_peer must have port field)",
                         ))
                     })?;
+                let seed = a
+                    .remove("seed")
+                    .and_then(|b| b.into_int())
+                    .map(|b| b > 0)
+                    .unwrap_or(false);
                 RequestKind::AnnouncePeer {
                     id,
                     hash,
                     implied_port,
                     port,
                     token,
+                    seed,
+                }
+            }
+            "get" => {
+                let mut target = [0u8; 20];
+                a.remove("target")
+                    .and_then(|b| b.into_bytes())
+                    .filter(|b| b.len() == 20)
+                    .map(|b| target.copy_from_slice(&b))
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(get must have target field)",
+                        ))
+                    })?;
+                let seq = a.remove("seq").and_then(|b| b.into_int());
+                RequestKind::Get { id, target, seq }
+            }
+            "put" => {
+                let token = a
+                    .remove("token")
+                    .and_then(|b| b.into_bytes())
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(put must have token field)",
+                        ))
+                    })?;
+                let v = a.remove("v").ok_or_else(|| {
+                    Error::from(ErrorKind::InvalidRequest(
+                        "Invalid BEncoded data(put must have v field)",
+                    ))
+                })?;
+                RequestKind::Put {
+                    id,
+                    token,
+                    v,
+                    k: a.remove("k").and_then(|b| b.into_bytes()),
+                    sig: a.remove("sig").and_then(|b| b.into_bytes()),
+                    seq: a.remove("seq").and_then(|b| b.into_int()),
+                    cas: a.remove("cas").and_then(|b| b.into_int()),
+                    salt: a.remove("salt").and_then(|b| b.into_bytes()),
                 }
             }
             _ => {
@@ -341,6 +549,7 @@ impl Response {
     pub fn id(transaction: Vec<u8>, id: ID) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::ID(id),
         }
     }
@@ -348,30 +557,107 @@ impl Response {
     pub fn find_node(transaction: Vec<u8>, id: ID, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::FindNode { id, nodes },
         }
     }
 
-    pub fn peers(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<SocketAddr>) -> Self {
+    pub fn peers(
+        transaction: Vec<u8>,
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<SocketAddr>,
+        scrape: Option<Scrape>,
+    ) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
                 values: nodes,
                 nodes: Vec::new(),
+                scrape,
             },
         }
     }
 
-    pub fn nodes(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<Node>) -> Self {
+    pub fn nodes(
+        transaction: Vec<u8>,
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        scrape: Option<Scrape>,
+    ) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
                 nodes,
                 values: Vec::new(),
+                scrape,
+            },
+        }
+    }
+
+    pub fn samples(
+        transaction: Vec<u8>,
+        id: ID,
+        interval: u32,
+        num: u32,
+        samples: Vec<[u8; 20]>,
+        nodes: Vec<Node>,
+    ) -> Self {
+        Response {
+            transaction,
+            ip: None,
+            kind: ResponseKind::Samples {
+                id,
+                interval,
+                num,
+                samples,
+                nodes,
+            },
+        }
+    }
+
+    /// Responds to a get query with a stored item, omitting its value
+    /// if the requester already has a version at least as new.
+    pub fn item(
+        transaction: Vec<u8>,
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        item: Option<&Item>,
+        seq: Option<i64>,
+    ) -> Self {
+        let (mut v, mut k, mut sig, mut iseq) = (None, None, None, None);
+        match item.map(|i| (i, &i.mutable)) {
+            Some((i, None)) => v = Some(i.v.clone()),
+            Some((_, Some(m))) if seq.map(|s| s >= m.seq).unwrap_or(false) => {
+                iseq = Some(m.seq);
+            }
+            Some((i, Some(m))) => {
+                v = Some(i.v.clone());
+                k = Some(m.k.clone());
+                sig = Some(m.sig.clone());
+                iseq = Some(m.seq);
+            }
+            None => {}
+        }
+        Response {
+            transaction,
+            ip: None,
+            kind: ResponseKind::Item {
+                id,
+                token,
+                nodes,
+                v,
+                k,
+                sig,
+                seq: iseq,
             },
         }
     }
@@ -379,6 +665,7 @@ impl Response {
     pub fn error(transaction: Vec<u8>, error: ErrorKind) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::Error(error),
         }
     }
@@ -387,6 +674,9 @@ impl Response {
         let mut b = BTreeMap::new();
         let is_err = self.is_err();
         b.insert(String::from("t"), BEncode::String(self.transaction));
+        if let Some(ip) = self.ip {
+            b.insert(String::from("ip"), BEncode::String(addr_to_bytes(&ip)));
+        }
         let mut args = BTreeMap::new();
         match self.kind {
             ResponseKind::ID(id) => {
@@ -405,12 +695,17 @@ impl Response {
                 token,
                 nodes,
                 values,
+                scrape,
             } => {
                 args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
                 args.insert(String::from("token"), BEncode::String(token));
+                if let Some(scrape) = scrape {
+                    args.insert(String::from("BFsd"), BEncode::String(scrape.seeds));
+                    args.insert(String::from("BFpe"), BEncode::String(scrape.peers));
+                }
                 let mut values_b = Vec::new();
                 for addr in values {
-                    values_b.push(BEncode::String(addr_to_bytes(&addr).to_vec()));
+                    values_b.push(BEncode::String(addr_to_bytes(&addr)));
                 }
                 args.insert(String::from("values"), BEncode::List(values_b));
 
@@ -420,6 +715,56 @@ impl Response {
                 }
                 args.insert(String::from("nodes"), BEncode::String(nodes_b));
             }
+            ResponseKind::Samples {
+                id,
+                interval,
+                num,
+                samples,
+                nodes,
+            } => {
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("interval"), BEncode::Int(i64::from(interval)));
+                args.insert(String::from("num"), BEncode::Int(i64::from(num)));
+                let mut data = Vec::with_capacity(samples.len() * 20);
+                for hash in samples {
+                    data.extend_from_slice(&hash);
+                }
+                args.insert(String::from("samples"), BEncode::String(data));
+                let mut nodes_b = Vec::new();
+                for node in nodes {
+                    nodes_b.extend(node.to_bytes())
+                }
+                args.insert(String::from("nodes"), BEncode::String(nodes_b));
+            }
+            ResponseKind::Item {
+                id,
+                token,
+                nodes,
+                v,
+                k,
+                sig,
+                seq,
+            } => {
+                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(String::from("token"), BEncode::String(token));
+                let mut nodes_b = Vec::new();
+                for node in nodes {
+                    nodes_b.extend(node.to_bytes())
+                }
+                args.insert(String::from("nodes"), BEncode::String(nodes_b));
+                if let Some(v) = v {
+                    args.insert(String::from("v"), v);
+                }
+                if let Some(k) = k {
+                    args.insert(String::from("k"), BEncode::String(k));
+                }
+                if let Some(sig) = sig {
+                    args.insert(String::from("sig"), BEncode::String(sig));
+                }
+                if let Some(seq) = seq {
+                    args.insert(String::from("seq"), BEncode::Int(seq));
+                }
+            }
             ResponseKind::Error(e) => {
                 let mut err = Vec::new();
                 match e {
@@ -439,6 +784,10 @@ impl Response {
                         err.push(BEncode::from_int(204));
                         err.push(BEncode::from_str(&msg));
                     }
+                    ErrorKind::Storage(code, msg) => {
+                        err.push(BEncode::from_int(code));
+                        err.push(BEncode::from_str(&msg));
+                    }
                     _ => unreachable!(),
                 }
                 b.insert(String::from("e"), BEncode::List(err));
@@ -466,6 +815,11 @@ impl Response {
                 "Invalid BEncoded data(dict must have t field)",
             ))
         })?;
+        let ip = d
+            .remove("ip")
+            .and_then(|b| b.into_bytes())
+            .filter(|b| b.len() == 6)
+            .map(|b| bytes_to_addr(&b));
         let y = d.remove("y").and_then(|b| b.into_string()).ok_or_else(|| {
             Error::from(ErrorKind::InvalidResponse(
                 "Invalid BEncoded data(dict must have y field)",
@@ -499,6 +853,7 @@ impl Response {
                     202 => ErrorKind::Server(msg),
                     203 => ErrorKind::Protocol(msg),
                     204 => ErrorKind::MethodUnknown(msg),
+                    205..=207 | 301 | 302 => ErrorKind::Storage(code, msg),
                     _ => {
                         return Err(ErrorKind::InvalidResponse(
                             "Invalid BEncoded data(invalid error code)",
@@ -508,6 +863,7 @@ impl Response {
                 };
                 Ok(Response {
                     transaction,
+                    ip,
                     kind: ResponseKind::Error(err),
                 })
             }
@@ -528,7 +884,52 @@ impl Response {
                         ))
                     })?;
 
-                let kind = if let Some(token) = r.remove("token").and_then(|b| b.into_bytes()) {
+                let kind = if let Some(data) = r.remove("samples").and_then(|b| b.into_bytes()) {
+                    let int = |r: &mut BTreeMap<String, BEncode>, key| {
+                        r.remove(key)
+                            .and_then(|b| b.into_int())
+                            .filter(|i| *i >= 0 && *i <= i64::from(u32::MAX))
+                            .map(|i| i as u32)
+                            .unwrap_or(0)
+                    };
+                    let samples = data
+                        .chunks(20)
+                        .filter(|c| c.len() == 20)
+                        .map(|c| {
+                            let mut hash = [0u8; 20];
+                            hash.copy_from_slice(c);
+                            hash
+                        })
+                        .collect();
+                    ResponseKind::Samples {
+                        id,
+                        interval: int(&mut r, "interval"),
+                        num: int(&mut r, "num"),
+                        samples,
+                        nodes: r
+                            .remove("nodes")
+                            .and_then(|b| b.into_bytes())
+                            .map(|ns| Node::decode_compact(&ns))
+                            .unwrap_or_default(),
+                    }
+                } else if r.contains_key("v") || r.contains_key("seq") {
+                    ResponseKind::Item {
+                        id,
+                        token: r
+                            .remove("token")
+                            .and_then(|b| b.into_bytes())
+                            .unwrap_or_default(),
+                        nodes: r
+                            .remove("nodes")
+                            .and_then(|b| b.into_bytes())
+                            .map(|ns| Node::decode_compact(&ns))
+                            .unwrap_or_default(),
+                        v: r.remove("v"),
+                        k: r.remove("k").and_then(|b| b.into_bytes()),
+                        sig: r.remove("sig").and_then(|b| b.into_bytes()),
+                        seq: r.remove("seq").and_then(|b| b.into_int()),
+                    }
+                } else if let Some(token) = r.remove("token").and_then(|b| b.into_bytes()) {
                     let mut values = Vec::new();
                     if let Some(addrs) = r.remove("values").and_then(|b| b.into_list()) {
                         for addr in addrs {
@@ -547,11 +948,18 @@ impl Response {
                             }
                         }
                     }
+                    let seeds = r.remove("BFsd").and_then(|b| b.into_bytes());
+                    let peers = r.remove("BFpe").and_then(|b| b.into_bytes());
+                    let scrape = match (seeds, peers) {
+                        (Some(seeds), Some(peers)) => Some(Scrape { seeds, peers }),
+                        _ => None,
+                    };
                     ResponseKind::GetPeers {
                         id,
                         token,
                         nodes,
                         values,
+                        scrape,
                     }
                 } else if let Some(ns) = r.remove("nodes").and_then(|b| b.into_bytes()) {
                     let mut nodes = Vec::new();
@@ -564,7 +972,11 @@ impl Response {
                 } else {
                     ResponseKind::ID(id)
                 };
-                Ok(Response { transaction, kind })
+                Ok(Response {
+                    transaction,
+                    ip,
+                    kind,
+                })
             }
             _ => {
                 Err(ErrorKind::InvalidResponse("Invalid BEncoded data(y field must be e/r)").into())
@@ -589,9 +1001,17 @@ impl Node {
         }
     }
 
+    /// Parses a list of nodes in compact node info format.
+    pub fn decode_compact(data: &[u8]) -> Vec<Node> {
+        data.chunks(26)
+            .filter(|n| n.len() == 26)
+            .map(Node::new)
+            .collect()
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = self.id.to_bytes_be();
-        data.extend_from_slice(&addr_to_bytes(&self.addr)[..]);
+        data.extend_from_slice(&addr_to_bytes(&self.addr));
         data
     }
 }
@@ -625,7 +1045,7 @@ mod tests {
     #[test]
     fn test_diff() {
         let mut t = DhtProtoTest;
-        platina::TestFile::new("src/tracker/dht/test/proto_test.plat")
+        platina::TestFile::new("src/dht/test/proto_test.plat")
             .run_tests(&mut t)
             .unwrap();
     }
@@ -634,7 +1054,7 @@ mod tests {
     #[ignore]
     fn test_update() {
         let mut t = DhtProtoTest;
-        platina::TestFile::new("src/tracker/dht/test/proto_test.plat")
+        platina::TestFile::new("src/dht/test/proto_test.plat")
             .run_tests_and_update(&mut t)
             .unwrap();
     }
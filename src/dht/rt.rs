@@ -0,0 +1,1343 @@
+use super::bloom::Bloom;
+use super::item::{self, Mutable};
+use super::token::Tokens;
+use super::{
+    proto, secure, Response, Stats, BUCKET_MAX, ID, MAX_BUCKETS, MIN_BOOTSTRAP_BKTS,
+    TX_TIMEOUT_SECS,
+};
+use crate::CONFIG;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use num_bigint::BigUint;
+use rand::{self, Rng};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::{cmp, mem};
+
+const MAX_SEARCH_DEPTH: u8 = 5;
+/// Queries a single peer search may send, across all depths
+const MAX_SEARCH_QUERIES: usize = 128;
+/// Number of nodes which must report our IP before we trust it
+const IP_VOTES: usize = 10;
+/// Maximum number of infohashes returned in a sample (BEP 51)
+const MAX_SAMPLES: usize = 20;
+/// Seconds other nodes should wait before sampling us again
+const SAMPLE_INTERVAL_SECS: u32 = 6 * 60 * 60;
+/// Minutes between our own sample_infohashes queries
+const SAMPLE_QUERY_MINS: i64 = 10;
+/// Limits on peers stored for other nodes, so a spammer can't fill our memory
+const MAX_TORRENTS: usize = 5000;
+const MAX_TORRENT_PEERS: usize = 200;
+/// Torrents a single IP may have announced to at once
+const MAX_IP_ANNOUNCES: usize = 50;
+/// Announced peers are dropped unless they re-announce within this time
+const PEER_TIMEOUT_MINS: i64 = 45;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutingTable {
+    id: ID,
+    buckets: Vec<Bucket>,
+    last_resp_recvd: DateTime<Utc>,
+    last_req_recvd: DateTime<Utc>,
+    last_tick: DateTime<Utc>,
+    transactions: HashMap<u32, Transaction>,
+    torrents: HashMap<[u8; 20], Torrent>,
+    bootstrapping: bool,
+    /// External IP as reported by each responding node
+    #[serde(skip)]
+    ip_votes: HashMap<IpAddr, IpAddr>,
+    /// Swarm size filters gathered by each torrent's current search
+    #[serde(skip)]
+    scrapes: HashMap<usize, Scrape>,
+    #[serde(skip)]
+    last_sample: Option<DateTime<Utc>>,
+    /// Nodes each torrent's current peer search has queried
+    #[serde(skip)]
+    searches: HashMap<usize, HashSet<SocketAddr>>,
+    #[serde(skip)]
+    tokens: Tokens,
+    /// Number of torrents each IP has announced to
+    #[serde(skip)]
+    announcers: HashMap<IpAddr, usize>,
+    /// Items stored for other nodes (BEP 44)
+    #[serde(skip)]
+    items: item::Store,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Transaction {
+    created: DateTime<Utc>,
+    kind: TransactionKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum TransactionKind {
+    Initialization,
+    Query(ID),
+    TSearch {
+        id: ID,
+        torrent: usize,
+        hash: [u8; 20],
+        depth: u8,
+    },
+    Sample(ID),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Torrent {
+    peers: Vec<Peer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Peer {
+    addr: SocketAddr,
+    seed: bool,
+    announced: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+struct Scrape {
+    seeds: Bloom,
+    peers: Bloom,
+    dirty: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bucket {
+    start: ID,
+    end: ID,
+    last_updated: DateTime<Utc>,
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Node {
+    id: ID,
+    state: NodeState,
+    addr: SocketAddr,
+    last_updated: DateTime<Utc>,
+    rem_token: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NodeState {
+    Good,
+    Questionable(usize),
+    Bad,
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        let mut id = [0u8; 20];
+        let mut rng = rand::thread_rng();
+        for i in &mut id {
+            *i = rng.gen::<u8>();
+        }
+
+        RoutingTable {
+            buckets: vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+            last_resp_recvd: Utc::now(),
+            last_req_recvd: Utc::now(),
+            last_tick: Utc::now(),
+            id: BigUint::from_bytes_be(&id),
+            transactions: HashMap::new(),
+            torrents: HashMap::new(),
+            bootstrapping: true,
+            ip_votes: HashMap::new(),
+            scrapes: HashMap::new(),
+            last_sample: None,
+            searches: HashMap::new(),
+            tokens: Tokens::new(),
+            announcers: HashMap::new(),
+            items: item::Store::new(),
+        }
+    }
+
+    pub fn init(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        self.refresh_tokens()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Option<RoutingTable> {
+        bincode::deserialize(data).ok().map(|rt: RoutingTable| {
+            info!(
+                "Loaded {} dht buckets, {} nodes",
+                rt.buckets.len(),
+                rt.buckets.iter().map(|buk| buk.nodes.len()).sum::<usize>()
+            );
+            rt
+        })
+    }
+
+    pub fn add_addr(&mut self, addr: SocketAddr) -> (proto::Request, SocketAddr) {
+        let tx = self.new_init_tx();
+        (proto::Request::ping(tx, self.id.clone()), addr)
+    }
+
+    pub fn get_peers(
+        &mut self,
+        torrent: usize,
+        hash: [u8; 20],
+    ) -> Vec<(proto::Request, SocketAddr)> {
+        let nodes = self.closest_nodes(&BigUint::from_bytes_be(&hash[..]));
+        self.scrapes.remove(&torrent);
+        self.searches.insert(torrent, HashSet::new());
+        let mut reqs = Vec::new();
+        for node in nodes {
+            reqs.extend(self.search_node(node, torrent, hash, 0));
+        }
+        reqs
+    }
+
+    pub fn announce(&mut self, hash: [u8; 20], seed: bool) -> Vec<(proto::Request, SocketAddr)> {
+        let mut nodes: Vec<(proto::Node, Vec<u8>)> = Vec::new();
+        for bucket in &self.buckets {
+            for node in &bucket.nodes {
+                if let Some(ref tok) = node.rem_token {
+                    nodes.push((node.into(), tok.clone()))
+                }
+            }
+        }
+
+        let mut reqs = Vec::new();
+        for (node, tok) in nodes {
+            let tx = self.new_query_tx(node.id);
+            let req = proto::Request::announce(tx, self.id.clone(), hash, tok, seed);
+            reqs.push((req, node.addr));
+        }
+        reqs
+    }
+
+    pub fn handle_req(&mut self, req: proto::Request, mut addr: SocketAddr) -> proto::Response {
+        self.last_req_recvd = Utc::now();
+        match req.kind {
+            // TODO: Consider adding the node if we don't have it?
+            proto::RequestKind::Ping(id) => {
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                proto::Response::id(req.transaction, self.id.clone())
+            }
+            proto::RequestKind::FindNode { id, target } => {
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                let mut nodes = Vec::new();
+                if self.contains_id(&target) {
+                    nodes.push(self.get_node(&target).into())
+                } else {
+                    let b = self.bucket_idx(&target);
+                    for node in &self.buckets[b].nodes {
+                        nodes.push(node.into());
+                    }
+                }
+                proto::Response::find_node(req.transaction, self.id.clone(), nodes)
+            }
+            proto::RequestKind::AnnouncePeer {
+                id,
+                implied_port,
+                hash,
+                port,
+                token,
+                seed,
+            } => {
+                if !self.tokens.valid(&token, addr.ip()) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Bad token!".to_owned()),
+                    );
+                }
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                if !implied_port {
+                    addr.set_port(port);
+                }
+                if !self.store_peer(hash, addr, seed) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Generic("Too many announces".to_owned()),
+                    );
+                }
+                proto::Response::id(req.transaction, self.id.clone())
+            }
+            proto::RequestKind::GetPeers { id, hash, scrape } => {
+                if !self.contains_id(&id) {
+                    let n = Node::new(id.clone(), addr);
+                    if self.add_node(n).is_err() {
+                        // This will be processed immediately after.
+                    }
+                }
+                let token = self.tokens.token(addr.ip());
+                if let Some(t) = self.torrents.get(&hash) {
+                    let scrape = if scrape { Some(t.scrape()) } else { None };
+                    proto::Response::peers(
+                        req.transaction,
+                        self.id.clone(),
+                        token,
+                        t.peers.iter().map(|p| p.addr).collect(),
+                        scrape,
+                    )
+                } else {
+                    let nodes = self.closest_nodes(&BigUint::from_bytes_be(&hash[..]));
+                    proto::Response::nodes(req.transaction, self.id.clone(), token, nodes, None)
+                }
+            }
+            proto::RequestKind::SampleInfohashes { id, target } => {
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                let mut samples: Vec<_> = self.torrents.keys().cloned().collect();
+                let num = samples.len() as u32;
+                rand::thread_rng().shuffle(&mut samples[..]);
+                samples.truncate(MAX_SAMPLES);
+                proto::Response::samples(
+                    req.transaction,
+                    self.id.clone(),
+                    SAMPLE_INTERVAL_SECS,
+                    num,
+                    samples,
+                    self.closest_nodes(&target),
+                )
+            }
+            proto::RequestKind::Get { id, target, seq } => {
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                let token = self.tokens.token(addr.ip());
+                let nodes = self.closest_nodes(&BigUint::from_bytes_be(&target[..]));
+                proto::Response::item(
+                    req.transaction,
+                    self.id.clone(),
+                    token,
+                    nodes,
+                    self.items.get(&target),
+                    seq,
+                )
+            }
+            proto::RequestKind::Put {
+                id,
+                token,
+                v,
+                k,
+                sig,
+                seq,
+                cas,
+                salt,
+            } => {
+                if !self.tokens.valid(&token, addr.ip()) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Bad token!".to_owned()),
+                    );
+                }
+                if self.contains_id(&id) {
+                    self.get_node_mut(&id).update();
+                }
+                let res = match (k, sig, seq) {
+                    (None, None, None) => self.items.put_immutable(v),
+                    (Some(k), Some(sig), Some(seq)) => {
+                        let salt = salt.unwrap_or_default();
+                        let m = Mutable { k, sig, seq, salt };
+                        self.items.put_mutable(v, m, cas)
+                    }
+                    _ => Err(proto::ErrorKind::Protocol(
+                        "Mutable items need k, sig and seq".to_owned(),
+                    )),
+                };
+                match res {
+                    Ok(_) => proto::Response::id(req.transaction, self.id.clone()),
+                    Err(e) => proto::Response::error(req.transaction, e),
+                }
+            }
+        }
+    }
+
+    /// Returns the swarm size estimates gathered by searches since the
+    /// last call, as (seeders, leechers) per torrent.
+    pub fn take_scrapes(&mut self) -> Vec<Response> {
+        self.scrapes
+            .iter_mut()
+            .filter(|(_, s)| s.dirty)
+            .map(|(tid, s)| {
+                s.dirty = false;
+                Response::Scrape {
+                    tid: *tid,
+                    seeders: s.seeds.estimate(),
+                    leechers: s.peers.estimate(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn handle_resp(
+        &mut self,
+        resp: proto::Response,
+        addr: SocketAddr,
+    ) -> Result<Response, Vec<(proto::Request, SocketAddr)>> {
+        self.last_resp_recvd = Utc::now();
+        if let Some(ip) = resp.ip {
+            self.vote_ip(addr.ip(), ip.ip());
+        }
+        let mut reqs = Vec::new();
+        if resp.transaction.len() < 4 {
+            return Err(reqs);
+        }
+        let tid = BigEndian::read_u32(&resp.transaction[..]);
+        let tx = if let Some(tx) = self.transactions.remove(&tid) {
+            tx
+        } else {
+            return Err(reqs);
+        };
+
+        match (tx.kind, resp.kind) {
+            (TransactionKind::Initialization, proto::ResponseKind::ID(id)) => {
+                let mut n = Node::new(id.clone(), addr);
+                n.update();
+                // Nodes we were explicitly asked to contact, such as the
+                // bootstrap node, are trusted regardless of their ID.
+                if self.insert_node(n).is_ok() && self.bootstrapping {
+                    let tx = self.new_query_tx(id);
+                    reqs.push((
+                        proto::Request::find_node(tx, self.id.clone(), self.id.clone()),
+                        addr,
+                    ));
+                }
+            }
+
+            (TransactionKind::Query(ref id1), proto::ResponseKind::ID(ref id2)) if id1 == id2 => {
+                if !self.contains_id(id1) {
+                    return Err(reqs);
+                }
+                self.get_node_mut(id1).update();
+                if self.bootstrapping {
+                    let tx = self.new_query_tx(id1.clone());
+                    reqs.push((
+                        proto::Request::find_node(tx, self.id.clone(), self.id.clone()),
+                        addr,
+                    ));
+                }
+            }
+
+            (
+                TransactionKind::Query(ref id1),
+                proto::ResponseKind::FindNode {
+                    id: ref id2,
+                    ref mut nodes,
+                },
+            ) if id1 == id2 => {
+                if !self.contains_id(id1) {
+                    return Err(reqs);
+                }
+                self.get_node_mut(id1).update();
+                for node in nodes.drain(..) {
+                    if !self.contains_id(&node.id) {
+                        let id = node.id.clone();
+                        let addr = node.addr;
+                        if self.add_node(node.into()).is_ok() {
+                            let tx = self.new_query_tx(id);
+                            reqs.push((proto::Request::ping(tx, self.id.clone()), addr));
+                        }
+                    }
+                }
+            }
+
+            // Token refresh query
+            (
+                TransactionKind::Query(ref id1),
+                proto::ResponseKind::GetPeers {
+                    id: ref id2,
+                    ref mut token,
+                    ..
+                },
+            ) if id1 == id2 => {
+                if !self.contains_id(id1) {
+                    return Err(reqs);
+                }
+                let node = self.get_node_mut(id1);
+                node.update();
+                if let Some(ref mut rt) = node.rem_token {
+                    mem::swap(rt, token);
+                } else {
+                    node.rem_token = Some(token.clone());
+                }
+            }
+
+            (
+                TransactionKind::TSearch {
+                    id: ref id1,
+                    torrent,
+                    hash,
+                    depth,
+                },
+                proto::ResponseKind::GetPeers {
+                    id: ref id2,
+                    ref mut values,
+                    ref mut nodes,
+                    ref mut token,
+                    ref scrape,
+                },
+            ) if id1 == id2 => {
+                if let Some(scrape) = scrape {
+                    self.add_scrape(torrent, scrape);
+                }
+                if self.contains_id(id1) {
+                    let node = self.get_node_mut(id1);
+                    node.update();
+                    if let Some(ref mut rt) = node.rem_token {
+                        mem::swap(rt, token);
+                    } else {
+                        node.rem_token = Some(token.clone());
+                    }
+                }
+
+                if !values.is_empty() {
+                    return Ok(Response::Peers {
+                        tid: torrent,
+                        peers: mem::replace(values, vec![]),
+                    });
+                }
+
+                if depth < MAX_SEARCH_DEPTH {
+                    for node in nodes.drain(..) {
+                        if !self.contains_id(&node.id) {
+                            reqs.extend(self.search_node(node, torrent, hash, depth + 1));
+                        }
+                    }
+                }
+            }
+
+            (
+                TransactionKind::Sample(ref id1),
+                proto::ResponseKind::Samples {
+                    id: ref id2,
+                    ref samples,
+                    ref mut nodes,
+                    ..
+                },
+            ) if id1 == id2 => {
+                if !self.contains_id(id1) {
+                    return Err(reqs);
+                }
+                self.get_node_mut(id1).update();
+                debug!("Sampled {} infohashes from {}", samples.len(), addr);
+                for node in nodes.drain(..) {
+                    if !self.contains_id(&node.id) {
+                        let id = node.id.clone();
+                        let addr = node.addr;
+                        if self.add_node(node.into()).is_ok() {
+                            let tx = self.new_query_tx(id);
+                            reqs.push((proto::Request::ping(tx, self.id.clone()), addr));
+                        }
+                    }
+                }
+            }
+
+            (TransactionKind::Query(id), proto::ResponseKind::Error(_)) => {
+                if !self.contains_id(&id) {
+                    return Err(reqs);
+                }
+                self.get_node_mut(&id).update();
+            }
+
+            // Mismatched IDs
+            (TransactionKind::Query(id), proto::ResponseKind::ID(_))
+            | (TransactionKind::Query(id), proto::ResponseKind::FindNode { .. })
+            | (TransactionKind::Query(id), proto::ResponseKind::GetPeers { .. })
+            | (TransactionKind::Query(id), proto::ResponseKind::Samples { .. })
+            | (TransactionKind::Query(id), proto::ResponseKind::Item { .. })
+            | (TransactionKind::TSearch { id, .. }, proto::ResponseKind::GetPeers { .. })
+            | (TransactionKind::TSearch { id, .. }, _)
+            | (TransactionKind::Sample(id), _) => {
+                self.remove_node(&id);
+            }
+
+            // Bad response to initialization ping
+            (TransactionKind::Initialization, _) => {
+                // No need to do anything here, we haven't added the node to the routing
+                // table yet.
+            }
+        }
+        Err(reqs)
+    }
+
+    pub fn tick(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        let mut reqs = Vec::new();
+        let dur = Utc::now().signed_duration_since(self.last_tick);
+        if dur.num_seconds() < 10 {
+            return reqs;
+        }
+        self.last_tick = Utc::now();
+
+        let mut nodes_to_ping: Vec<proto::Node> = Vec::new();
+        if self.is_bootstrapped() && self.bootstrapping {
+            self.bootstrapping = false;
+        }
+
+        self.transactions.retain(|_, tx| {
+            Utc::now().signed_duration_since(tx.created).num_seconds() < TX_TIMEOUT_SECS
+        });
+        // Searches are over once none of their queries are outstanding
+        let searching: HashSet<_> = self
+            .transactions
+            .values()
+            .filter_map(|tx| match tx.kind {
+                TransactionKind::TSearch { torrent, .. } => Some(torrent),
+                _ => None,
+            })
+            .collect();
+        self.searches.retain(|t, _| searching.contains(t));
+
+        self.tokens.tick();
+        self.items.tick();
+
+        for bucket in &mut self.buckets {
+            for node in &mut bucket.nodes {
+                let dur = Utc::now().signed_duration_since(node.last_updated);
+                if dur.num_minutes() > 15 {
+                    if node.good() {
+                        node.state = NodeState::Questionable(1);
+                        nodes_to_ping.push((&*node).into());
+                    } else if let NodeState::Questionable(1) = node.state {
+                        node.state = NodeState::Questionable(2);
+                        nodes_to_ping.push((&*node).into());
+                    } else {
+                        node.state = NodeState::Bad;
+                    }
+                }
+            }
+        }
+        for node in nodes_to_ping {
+            let tx = self.new_query_tx(node.id);
+            reqs.push((proto::Request::ping(tx, self.id.clone()), node.addr));
+        }
+        reqs.extend(self.sample());
+        let announcers = &mut self.announcers;
+        self.torrents.retain(|_, t| {
+            t.peers.retain(|p| {
+                let age = Utc::now().signed_duration_since(p.announced);
+                if age.num_minutes() < PEER_TIMEOUT_MINS {
+                    return true;
+                }
+                RoutingTable::unannounce(announcers, p.addr.ip());
+                false
+            });
+            !t.peers.is_empty()
+        });
+        reqs
+    }
+
+    /// Stores an announced peer, returning false if the storage
+    /// limits prevent it. Peers are tracked by IP, so re-announcing
+    /// from another port replaces the previous entry.
+    fn store_peer(&mut self, hash: [u8; 20], addr: SocketAddr, seed: bool) -> bool {
+        let peer = Peer {
+            addr,
+            seed,
+            announced: Utc::now(),
+        };
+        if let Some(t) = self.torrents.get_mut(&hash) {
+            if let Some(p) = t.peers.iter_mut().find(|p| p.addr.ip() == addr.ip()) {
+                *p = peer;
+                return true;
+            }
+        } else if self.torrents.len() >= MAX_TORRENTS {
+            return false;
+        }
+        let count = self.announcers.entry(addr.ip()).or_insert(0);
+        if *count >= MAX_IP_ANNOUNCES {
+            return false;
+        }
+        *count += 1;
+        let t = self
+            .torrents
+            .entry(hash)
+            .or_insert(Torrent { peers: Vec::new() });
+        if t.peers.len() >= MAX_TORRENT_PEERS {
+            // Make room by evicting the least recently announced peer
+            let (idx, _) = t
+                .peers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| p.announced)
+                .unwrap();
+            let old = t.peers.swap_remove(idx);
+            RoutingTable::unannounce(&mut self.announcers, old.addr.ip());
+        }
+        t.peers.push(peer);
+        true
+    }
+
+    fn unannounce(announcers: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+        if let Some(count) = announcers.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                announcers.remove(&ip);
+            }
+        }
+    }
+
+    /// Serializes the table for reuse on restart. Only good nodes are kept,
+    /// and in flight searches are dropped since they won't outlive us.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut rt = self.clone();
+        rt.transactions.clear();
+        rt.torrents.clear();
+        for bucket in &mut rt.buckets {
+            bucket.nodes.retain(Node::good);
+        }
+        bincode::serialize(&rt).unwrap()
+    }
+
+    pub fn stats(&self) -> Stats {
+        // Nodes are only good if they've been heard from in the last 15 minutes
+        let fresh = |b: &&Bucket| b.nodes.iter().any(Node::good);
+        Stats {
+            nodes: self.buckets.iter().map(|b| b.nodes.len()).sum(),
+            buckets: self.buckets.len(),
+            fresh_buckets: self.buckets.iter().filter(fresh).count(),
+            torrents: self.torrents.len(),
+            peers: self.torrents.values().map(|t| t.peers.len()).sum(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_bootstrapped(&self) -> bool {
+        self.buckets.len() >= MIN_BOOTSTRAP_BKTS
+    }
+
+    /// Periodically samples the infohashes of a random good node, which
+    /// also discovers nodes in a random part of the keyspace.
+    fn sample(&mut self) -> Option<(proto::Request, SocketAddr)> {
+        if !self.is_bootstrapped() {
+            return None;
+        }
+        if let Some(last) = self.last_sample {
+            if Utc::now().signed_duration_since(last).num_minutes() < SAMPLE_QUERY_MINS {
+                return None;
+            }
+        }
+        self.last_sample = Some(Utc::now());
+        let mut target = [0u8; 20];
+        rand::thread_rng().fill(&mut target);
+        let target = BigUint::from_bytes_be(&target);
+        let node: proto::Node = {
+            let b = self.bucket_idx(&target);
+            self.buckets[b]
+                .nodes
+                .iter()
+                .chain(self.buckets.iter().flat_map(|b| b.nodes.iter()))
+                .find(|n| n.good())?
+                .into()
+        };
+        let tx = self.new_sample_tx(node.id);
+        let req = proto::Request::sample_infohashes(tx, self.id.clone(), target);
+        Some((req, node.addr))
+    }
+
+    fn add_scrape(&mut self, torrent: usize, scrape: &proto::Scrape) {
+        let (seeds, peers) = match (
+            Bloom::from_bytes(&scrape.seeds),
+            Bloom::from_bytes(&scrape.peers),
+        ) {
+            (Some(s), Some(p)) => (s, p),
+            _ => return,
+        };
+        let s = self.scrapes.entry(torrent).or_insert_with(|| Scrape {
+            seeds: Bloom::new(),
+            peers: Bloom::new(),
+            dirty: false,
+        });
+        s.seeds.union(&seeds);
+        s.peers.union(&peers);
+        s.dirty = true;
+    }
+
+    /// The nodes in the table closest to the target by XOR distance.
+    fn closest_nodes(&self, target: &ID) -> Vec<proto::Node> {
+        let mut nodes: Vec<_> = self.buckets.iter().flat_map(|b| b.nodes.iter()).collect();
+        nodes.sort_by_cached_key(|n| &n.id ^ target);
+        nodes.truncate(BUCKET_MAX);
+        nodes.into_iter().map(|n| n.into()).collect()
+    }
+
+    /// Continues a torrent's peer search by querying a node, unless the
+    /// search already has or has run out of queries.
+    fn search_node(
+        &mut self,
+        node: proto::Node,
+        torrent: usize,
+        hash: [u8; 20],
+        depth: u8,
+    ) -> Option<(proto::Request, SocketAddr)> {
+        let queried = self.searches.entry(torrent).or_default();
+        if queried.len() >= MAX_SEARCH_QUERIES || !queried.insert(node.addr) {
+            return None;
+        }
+        let tx = self.new_tsearch_tx(node.id, torrent, hash, depth);
+        Some((
+            proto::Request::get_peers(tx, self.id.clone(), hash),
+            node.addr,
+        ))
+    }
+
+    /// Send a bogus get_peers query to obtain each node's token.
+    fn refresh_tokens(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        let mut nodes: Vec<proto::Node> = Vec::new();
+        for bucket in &self.buckets {
+            for node in &bucket.nodes {
+                nodes.push(node.into());
+            }
+        }
+
+        let mut reqs = Vec::new();
+        for node in nodes {
+            let tx = self.new_query_tx(node.id);
+            let req = proto::Request::get_peers(tx, self.id.clone(), [0xBEu8; 20]);
+            reqs.push((req, node.addr));
+        }
+        reqs
+    }
+
+    fn get_node_mut(&mut self, id: &ID) -> &mut Node {
+        let idx = self.bucket_idx(id);
+        let bidx = self.buckets[idx].idx_of(id).unwrap();
+        &mut self.buckets[idx].nodes[bidx]
+    }
+
+    fn get_node(&self, id: &ID) -> &Node {
+        let idx = self.bucket_idx(id);
+        let bidx = self.buckets[idx].idx_of(id).unwrap();
+        &self.buckets[idx].nodes[bidx]
+    }
+
+    fn contains_id(&self, id: &ID) -> bool {
+        let idx = self.bucket_idx(id);
+        self.buckets[idx].contains(id)
+    }
+
+    fn new_init_tx(&mut self) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::Initialization,
+            },
+        );
+        tb
+    }
+
+    fn new_query_tx(&mut self, id: ID) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::Query(id),
+            },
+        );
+        tb
+    }
+
+    fn new_sample_tx(&mut self, id: ID) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::Sample(id),
+            },
+        );
+        tb
+    }
+
+    fn new_tsearch_tx(&mut self, id: ID, torrent: usize, hash: [u8; 20], depth: u8) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::TSearch {
+                    id,
+                    torrent,
+                    hash,
+                    depth,
+                },
+            },
+        );
+        tb
+    }
+
+    /// Records the external IP a node saw us at, switching to an ID
+    /// which is valid for it once enough nodes agree.
+    fn vote_ip(&mut self, voter: IpAddr, ip: IpAddr) {
+        self.ip_votes.insert(voter, ip);
+        if self.ip_votes.len() < IP_VOTES {
+            return;
+        }
+        let mut tally: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.ip_votes.values() {
+            *tally.entry(*ip).or_insert(0) += 1;
+        }
+        self.ip_votes.clear();
+        let ip = match tally.into_iter().max_by_key(|&(_, c)| c) {
+            Some((ip, c)) if c > IP_VOTES / 2 => ip,
+            _ => return,
+        };
+        if !secure::valid_id(&self.id, ip) {
+            info!("Changing DHT node ID to match external IP {}", ip);
+            self.reset_id(secure::node_id(ip));
+        }
+    }
+
+    /// Changes our node ID, rebuilding the buckets around it.
+    fn reset_id(&mut self, id: ID) {
+        self.id = id;
+        let buckets = mem::replace(
+            &mut self.buckets,
+            vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+        );
+        for node in buckets.into_iter().flat_map(|b| b.nodes) {
+            self.insert_node(node).ok();
+        }
+    }
+
+    fn add_node(&mut self, node: Node) -> Result<(), ()> {
+        if CONFIG.dht.enforce_node_id && !secure::valid_id(&node.id, node.addr.ip()) {
+            return Err(());
+        }
+        self.insert_node(node)
+    }
+
+    fn insert_node(&mut self, node: Node) -> Result<(), ()> {
+        let idx = self.bucket_idx(&node.id);
+        if self.buckets[idx].full() {
+            if self.buckets[idx].could_hold(&self.id) && self.buckets.len() < MAX_BUCKETS {
+                self.split_bucket(idx);
+                self.insert_node(node)
+            } else {
+                Err(())
+            }
+        } else {
+            self.buckets[idx].add_node(node);
+            Ok(())
+        }
+    }
+
+    fn remove_node(&mut self, id: &ID) {
+        let idx = self.bucket_idx(id);
+        if let Some(i) = self.buckets[idx].idx_of(id) {
+            self.buckets[idx].nodes.remove(i);
+        }
+    }
+
+    fn split_bucket(&mut self, idx: usize) {
+        let midpoint = self.buckets[idx].midpoint();
+        let mut nb;
+        {
+            let pb = &mut self.buckets[idx];
+            nb = Bucket::new(midpoint.clone(), pb.end.clone());
+            pb.end = midpoint;
+            let nodes = mem::replace(&mut pb.nodes, Vec::with_capacity(BUCKET_MAX));
+            for node in nodes {
+                if pb.could_hold(&node.id) {
+                    pb.nodes.push(node);
+                } else {
+                    nb.nodes.push(node);
+                }
+            }
+        }
+        self.buckets.insert(idx + 1, nb);
+    }
+
+    fn bucket_idx(&self, id: &ID) -> usize {
+        RoutingTable::bucket_idx_(id, &self.buckets)
+    }
+
+    fn bucket_idx_(id: &ID, buckets: &[Bucket]) -> usize {
+        buckets
+            .binary_search_by(|bucket| {
+                if bucket.could_hold(id) {
+                    cmp::Ordering::Equal
+                } else {
+                    bucket.start.cmp(id)
+                }
+            })
+            .unwrap()
+    }
+}
+
+impl Torrent {
+    fn scrape(&self) -> proto::Scrape {
+        let mut seeds = Bloom::new();
+        let mut peers = Bloom::new();
+        for p in &self.peers {
+            if p.seed {
+                seeds.insert(p.addr.ip());
+            } else {
+                peers.insert(p.addr.ip());
+            }
+        }
+        proto::Scrape {
+            seeds: seeds.to_bytes(),
+            peers: peers.to_bytes(),
+        }
+    }
+}
+
+impl Bucket {
+    fn new(start: ID, end: ID) -> Bucket {
+        Bucket {
+            start,
+            end,
+            last_updated: Utc::now(),
+            nodes: Vec::with_capacity(BUCKET_MAX),
+        }
+    }
+
+    fn add_node(&mut self, mut node: Node) {
+        if self.nodes.len() < BUCKET_MAX {
+            self.nodes.push(node);
+        } else {
+            for n in &mut self.nodes {
+                if !n.good() {
+                    mem::swap(n, &mut node);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn could_hold(&self, id: &ID) -> bool {
+        &self.start <= id && id < &self.end
+    }
+
+    fn full(&self) -> bool {
+        self.nodes.len() >= BUCKET_MAX && self.nodes.iter().all(|n| n.good())
+    }
+
+    fn midpoint(&self) -> ID {
+        self.start.clone() + (&self.end - &self.start) / BigUint::from(2u8)
+    }
+
+    fn contains(&self, id: &ID) -> bool {
+        self.idx_of(id).is_some()
+    }
+
+    fn idx_of(&self, id: &ID) -> Option<usize> {
+        self.nodes.iter().position(|node| &node.id == id)
+    }
+}
+
+impl Node {
+    fn new(id: ID, addr: SocketAddr) -> Node {
+        Node {
+            id,
+            state: NodeState::Bad,
+            addr,
+            last_updated: Utc::now(),
+            rem_token: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn new_test(id: ID) -> Node {
+        Node::new(id, "127.0.0.1:0".parse().unwrap())
+    }
+
+    fn good(&self) -> bool {
+        if let NodeState::Good = self.state {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn update(&mut self) {
+        self.state = NodeState::Good;
+        self.last_updated = Utc::now();
+    }
+}
+
+impl From<proto::Node> for Node {
+    fn from(node: proto::Node) -> Self {
+        Node::new(node.id, node.addr)
+    }
+}
+
+impl<'a> Into<proto::Node> for &'a Node {
+    fn into(self) -> proto::Node {
+        proto::Node {
+            id: self.id.clone(),
+            addr: self.addr,
+        }
+    }
+}
+
+/// creates an ID of value 2^(pow)
+fn id_from_pow(pow: usize) -> ID {
+    let mut id = [0u8; 21];
+    let idx = 20 - pow / 8;
+    let offset = pow % 8;
+    let block = id[idx];
+    id[idx] = block | (1 << offset);
+    BigUint::from_bytes_be(&id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        id_from_pow, proto, secure, Bucket, Node, Peer, Response, RoutingTable, Torrent,
+        MAX_IP_ANNOUNCES, MAX_TORRENT_PEERS,
+    };
+    use crate::bencode::BEncode;
+    use crate::util::sha1_hash;
+    use chrono::Utc;
+    use num_bigint::BigUint;
+    use std::net::{IpAddr, SocketAddr};
+
+    fn peer(i: u8, seed: bool) -> Peer {
+        Peer {
+            addr: SocketAddr::from(([10, 0, 0, i], 6881)),
+            seed,
+            announced: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_id_from_pow() {
+        assert!(id_from_pow(159) > id_from_pow(158));
+        assert_eq!(id_from_pow(1), BigUint::from(2u8));
+        assert_eq!(id_from_pow(8), BigUint::from(256u16));
+    }
+
+    #[test]
+    fn test_bucket_midpoint() {
+        let b = Bucket::new(BigUint::from(0u8), BigUint::from(20u8));
+        assert_eq!(b.midpoint(), BigUint::from(10u8));
+        let b = Bucket::new(BigUint::from(0u8), id_from_pow(160));
+        assert_eq!(b.midpoint(), id_from_pow(159));
+    }
+
+    #[test]
+    fn test_bucket_split_far() {
+        let mut rt = RoutingTable::new();
+        rt.buckets[0].nodes = vec![Node::new_test(id_from_pow(100)); 8];
+        rt.split_bucket(0);
+        assert_eq!(rt.buckets[0].nodes.len(), 8);
+        assert_eq!(rt.buckets[1].nodes.len(), 0);
+    }
+
+    #[test]
+    fn test_bucket_split_close() {
+        let mut rt = RoutingTable::new();
+        rt.buckets[0].nodes = vec![Node::new_test(id_from_pow(159)); 8];
+        rt.split_bucket(0);
+        assert_eq!(rt.buckets[0].nodes.len(), 0);
+        assert_eq!(rt.buckets[1].nodes.len(), 8);
+    }
+
+    #[test]
+    fn test_ip_votes() {
+        let mut rt = RoutingTable::new();
+        rt.buckets[0].nodes = vec![Node::new_test(id_from_pow(100))];
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        for i in 0..10 {
+            assert!(!secure::valid_id(&rt.id, ip));
+            rt.vote_ip(IpAddr::from([1, 2, 3, i]), ip);
+        }
+        assert!(secure::valid_id(&rt.id, ip));
+        assert_eq!(rt.buckets.iter().map(|b| b.nodes.len()).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_serialize_good_nodes() {
+        let mut rt = RoutingTable::new();
+        let mut good = Node::new_test(id_from_pow(100));
+        good.update();
+        rt.buckets[0].nodes = vec![good, Node::new_test(id_from_pow(101))];
+        rt.new_init_tx();
+
+        let rt = RoutingTable::deserialize(&rt.serialize()).unwrap();
+        assert_eq!(rt.buckets[0].nodes.len(), 1);
+        assert_eq!(rt.buckets[0].nodes[0].id, id_from_pow(100));
+        assert!(rt.transactions.is_empty());
+    }
+
+    #[test]
+    fn test_scrape() {
+        let mut rt = RoutingTable::new();
+        let t = Torrent {
+            peers: (0..10).map(|i| peer(i, i < 3)).collect(),
+        };
+        let scrape = t.scrape();
+        rt.add_scrape(1, &scrape);
+        // Filters from different nodes with the same peers are merged
+        rt.add_scrape(1, &scrape);
+        match &rt.take_scrapes()[..] {
+            [Response::Scrape {
+                tid: 1,
+                seeders: 3,
+                leechers: 7,
+            }] => {}
+            r => panic!("unexpected scrapes {:?}", r),
+        }
+        assert!(rt.take_scrapes().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut rt = RoutingTable::new();
+        let mut good = Node::new_test(id_from_pow(100));
+        good.update();
+        rt.buckets[0].nodes = vec![good, Node::new_test(id_from_pow(101))];
+        rt.torrents.insert(
+            [0; 20],
+            Torrent {
+                peers: vec![peer(1, false)],
+            },
+        );
+        let stats = rt.stats();
+        assert_eq!((stats.nodes, stats.buckets, stats.fresh_buckets), (2, 1, 1));
+        assert_eq!((stats.torrents, stats.peers), (1, 1));
+        rt.buckets[0].nodes.remove(0);
+        assert_eq!(rt.stats().fresh_buckets, 0);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut rt = RoutingTable::new();
+        rt.buckets[0].nodes = (0..20)
+            .map(|i| {
+                Node::new(
+                    id_from_pow(140 + i),
+                    SocketAddr::from(([10, 0, 0, i as u8], 1)),
+                )
+            })
+            .collect();
+        // The search starts from the nodes closest to the hash, which
+        // besides the exact match are those with the lowest bits set
+        let mut hash = [0u8; 20];
+        hash[0] = 0x80;
+        let reqs = rt.get_peers(1, hash);
+        assert_eq!(reqs.len(), 8);
+        assert!(reqs
+            .iter()
+            .any(|(_, a)| a.ip() == IpAddr::from([10, 0, 0, 19])));
+        assert!(!reqs
+            .iter()
+            .any(|(_, a)| a.ip() == IpAddr::from([10, 0, 0, 18])));
+
+        // Nodes are only queried once per search
+        let node = proto::Node {
+            id: id_from_pow(10),
+            addr: "10.0.1.1:1".parse().unwrap(),
+        };
+        assert!(rt.search_node(node.clone(), 1, hash, 1).is_some());
+        assert!(rt.search_node(node.clone(), 1, hash, 1).is_none());
+        assert!(rt.search_node(node, 2, hash, 1).is_some());
+    }
+
+    #[test]
+    fn test_announce_token() {
+        let mut rt = RoutingTable::new();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let announce = |token: Vec<u8>| {
+            proto::Request::announce(b"aa".to_vec(), id_from_pow(100), [1; 20], token, false)
+        };
+
+        // Tokens are tied to the IP they were handed out to
+        let token = rt.tokens.token(addr.ip());
+        let other = SocketAddr::from(([10, 0, 0, 2], 6881));
+        let resp = rt.handle_req(announce(token.clone()), other);
+        assert_matches!(resp.kind, proto::ResponseKind::Error(_));
+        assert!(rt.torrents.is_empty());
+
+        // Nodes outside the routing table may still announce
+        let resp = rt.handle_req(announce(token), addr);
+        assert_matches!(resp.kind, proto::ResponseKind::ID(_));
+        assert_eq!(rt.torrents[&[1; 20]].peers.len(), 1);
+    }
+
+    #[test]
+    fn test_items() {
+        let mut rt = RoutingTable::new();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v = BEncode::from_str("Hello World!");
+        let put = |token: Vec<u8>, seq: Option<i64>| proto::Request {
+            transaction: b"aa".to_vec(),
+            version: None,
+            kind: proto::RequestKind::Put {
+                id: id_from_pow(100),
+                token,
+                v: v.clone(),
+                k: seq.map(|_| vec![0; 32]),
+                sig: seq.map(|_| vec![0; 64]),
+                seq,
+                cas: None,
+                salt: None,
+            },
+        };
+        let token = rt.tokens.token(addr.ip());
+        let resp = rt.handle_req(put(vec![], None), addr);
+        assert_matches!(resp.kind, proto::ResponseKind::Error(_));
+        let resp = rt.handle_req(put(token.clone(), Some(1)), addr);
+        assert_matches!(
+            resp.kind,
+            proto::ResponseKind::Error(proto::ErrorKind::Storage(206, _))
+        );
+        let resp = rt.handle_req(put(token, None), addr);
+        assert_matches!(resp.kind, proto::ResponseKind::ID(_));
+
+        let get = proto::Request {
+            transaction: b"aa".to_vec(),
+            version: None,
+            kind: proto::RequestKind::Get {
+                id: id_from_pow(100),
+                target: sha1_hash(&v.encode_to_buf()),
+                seq: None,
+            },
+        };
+        match rt.handle_req(get, addr).kind {
+            proto::ResponseKind::Item { v: value, seq, .. } => {
+                assert_eq!(value, Some(v));
+                assert_eq!(seq, None);
+            }
+            k => panic!("Unexpected response {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_store_limits() {
+        let mut rt = RoutingTable::new();
+        let ip = |i: usize| SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881));
+
+        // Re-announcing from the same IP replaces the entry
+        assert!(rt.store_peer([0; 20], ip(1), false));
+        assert!(rt.store_peer([0; 20], SocketAddr::from(([10, 0, 0, 1], 1)), true));
+        assert_eq!(rt.torrents[&[0; 20]].peers.len(), 1);
+        assert_eq!(rt.announcers[&ip(1).ip()], 1);
+
+        // A single IP can't fill our storage
+        for i in 1..MAX_IP_ANNOUNCES {
+            assert!(rt.store_peer([i as u8; 20], ip(1), false));
+        }
+        assert!(!rt.store_peer([0xff; 20], ip(1), false));
+
+        // Full torrents evict their oldest peer
+        for i in 2..MAX_TORRENT_PEERS + 10 {
+            assert!(rt.store_peer([0; 20], ip(i), false));
+        }
+        assert_eq!(rt.torrents[&[0; 20]].peers.len(), MAX_TORRENT_PEERS);
+        let stored: usize = rt.torrents.values().map(|t| t.peers.len()).sum();
+        assert_eq!(rt.announcers.values().sum::<usize>(), stored);
+    }
+}
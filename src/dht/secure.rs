@@ -0,0 +1,111 @@
+//! Node ID restrictions from BEP 42, which tie a node's ID to its IP
+//! so that an attacker can't choose IDs to surround a target.
+
+use std::net::IpAddr;
+
+use num_bigint::BigUint;
+use rand::{self, Rng};
+
+use super::ID;
+use crate::util::crc32c;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Generates a random node ID which is valid for the given IP.
+pub fn node_id(ip: IpAddr) -> ID {
+    let mut rng = rand::thread_rng();
+    let mut id = [0u8; 20];
+    for b in &mut id {
+        *b = rng.gen();
+    }
+    let crc = ip_crc(ip, id[19] & 0x7);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x7);
+    BigUint::from_bytes_be(&id)
+}
+
+/// Checks whether a node's ID is permitted for its IP. Nodes on local
+/// networks are exempt, since their external IP is unknown.
+pub fn valid_id(id: &ID, ip: IpAddr) -> bool {
+    if local(ip) {
+        return true;
+    }
+    let bytes = id.to_bytes_be();
+    if bytes.len() > 20 {
+        return false;
+    }
+    let mut id = [0u8; 20];
+    id[20 - bytes.len()..].copy_from_slice(&bytes);
+    let crc = ip_crc(ip, id[19] & 0x7);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut data = ip.octets();
+            for (b, m) in data.iter_mut().zip(V4_MASK.iter()) {
+                *b &= m;
+            }
+            data[0] |= r << 5;
+            crc32c(&data)
+        }
+        IpAddr::V6(ip) => {
+            let mut data = [0u8; 8];
+            data.copy_from_slice(&ip.octets()[..8]);
+            for (b, m) in data.iter_mut().zip(V6_MASK.iter()) {
+                *b &= m;
+            }
+            data[0] |= r << 5;
+            crc32c(&data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: [u8; 3], last: u8) -> ID {
+        let mut id = [0u8; 20];
+        id[..3].copy_from_slice(&prefix);
+        id[19] = last;
+        BigUint::from_bytes_be(&id)
+    }
+
+    #[test]
+    fn test_bep42_vectors() {
+        let cases = [
+            ("124.31.75.21", [0x5f, 0xbf, 0xbf], 0x01),
+            ("21.75.31.124", [0x5a, 0x3c, 0xe9], 0x56),
+            ("65.23.51.170", [0xa5, 0xd4, 0x32], 0x16),
+            ("84.124.73.14", [0x1b, 0x03, 0x21], 0x41),
+            ("43.213.53.83", [0xe5, 0x6f, 0x6c], 0x5a),
+        ];
+        for &(ip, prefix, last) in &cases {
+            let ip = ip.parse().unwrap();
+            assert!(valid_id(&id(prefix, last), ip));
+            assert!(!valid_id(&id([0, 0, 0], last), ip));
+        }
+    }
+
+    #[test]
+    fn test_node_id() {
+        let ip = "124.31.75.21".parse().unwrap();
+        for _ in 0..20 {
+            assert!(valid_id(&node_id(ip), ip));
+        }
+        assert!(valid_id(&id([0, 0, 0], 0), "192.168.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,94 @@
+//! Write tokens for announce_peer (BEP 5). A token is a hash of the
+//! querying node's IP and a secret which rotates every five minutes, so
+//! only a node which recently asked us for peers from the same IP can
+//! announce, and no per-node state needs to be kept.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+use crate::util::sha1_hash;
+
+const ROTATE_SECS: u64 = 5 * 60;
+
+#[derive(Clone, Debug)]
+pub struct Tokens {
+    secret: [u8; 20],
+    /// Tokens handed out just before a rotation remain valid until the next
+    prev: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    pub fn new() -> Tokens {
+        let secret = Tokens::secret();
+        Tokens {
+            secret,
+            prev: secret,
+            rotated: Instant::now(),
+        }
+    }
+
+    pub fn token(&self, ip: IpAddr) -> Vec<u8> {
+        Tokens::hash(&self.secret, ip)
+    }
+
+    pub fn valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == &Tokens::hash(&self.secret, ip)[..] || token == &Tokens::hash(&self.prev, ip)[..]
+    }
+
+    /// Rotates the secret if it's due.
+    pub fn tick(&mut self) {
+        if self.rotated.elapsed() >= Duration::from_secs(ROTATE_SECS) {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.prev = self.secret;
+        self.secret = Tokens::secret();
+        self.rotated = Instant::now();
+    }
+
+    fn secret() -> [u8; 20] {
+        let mut secret = [0u8; 20];
+        rand::thread_rng().fill(&mut secret);
+        secret
+    }
+
+    fn hash(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+        let mut data = secret.to_vec();
+        match ip {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+        sha1_hash(&data)[..8].to_vec()
+    }
+}
+
+impl Default for Tokens {
+    fn default() -> Tokens {
+        Tokens::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let mut t = Tokens::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let tok = t.token(ip);
+        assert!(t.valid(&tok, ip));
+        assert!(!t.valid(&tok, "10.0.0.2".parse().unwrap()));
+
+        t.rotate();
+        assert!(t.valid(&tok, ip));
+        assert_ne!(t.token(ip), tok);
+        t.rotate();
+        assert!(!t.valid(&tok, ip));
+    }
+}
@@ -2,11 +2,19 @@ use std::ffi::OsString;
 use std::{fs, io, mem, path};
 
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Instant;
 
-use crate::util::{native, MHashMap};
+use super::Storage;
+use crate::config::SyncPolicy;
+use crate::torrent::LocIter;
+use crate::util::{io_err, native, MHashMap};
 use crate::CONFIG;
 
 const PB_LEN: usize = 256;
+/// Size of the windows files are mapped in
+const MAP_WINDOW: u64 = 16 * 1024 * 1024;
+const EXDEV: i32 = 18;
 
 pub struct BufCache {
     path_a: OsString,
@@ -20,9 +28,55 @@ pub struct FileCache {
 
 pub struct Entry {
     used: bool,
+    /// Written to since it was last synced
+    dirty: bool,
     alloc_failed: bool,
     sparse: bool,
     file: fs::File,
+    maps: MHashMap<u64, MapEntry>,
+}
+
+struct MapEntry {
+    used: bool,
+    map: native::Mapping,
+}
+
+/// Pieces read recently while seeding, so that peers requesting a piece
+/// block by block don't each hit the disk. Least recently used pieces are
+/// dropped once the cache outgrows `disk.read_cache`.
+pub struct ReadCache {
+    pieces: MHashMap<(usize, u32), CachedPiece>,
+    /// Bytes of piece data cached
+    size: usize,
+    /// The last piece each peer read, to detect sequential readers
+    readers: MHashMap<(usize, usize), u32>,
+    clock: u64,
+}
+
+struct CachedPiece {
+    data: Vec<u8>,
+    used: u64,
+}
+
+/// Blocks written recently, buffered per piece so that they reach the disk
+/// in as few writes as possible. Pieces are written out once the cache
+/// outgrows `disk.write_cache`, every `disk.write_flush_interval` seconds,
+/// and before any job which may depend on them.
+pub struct WriteCache {
+    pieces: MHashMap<(usize, u32), DirtyPiece>,
+    /// Bytes of data buffered
+    size: usize,
+    last_flush: Instant,
+}
+
+pub struct DirtyPiece {
+    pub tid: usize,
+    pub data: Vec<u8>,
+    /// Sorted, disjoint ranges of the piece which were written
+    pub ranges: Vec<(usize, usize)>,
+    /// Locations of the whole piece
+    pub locations: LocIter,
+    pub path: Option<String>,
 }
 
 pub struct TempPB<'a> {
@@ -92,6 +146,169 @@ impl BufCache {
     }
 }
 
+/// Each disk worker's share of a limit.
+fn share(total: usize) -> usize {
+    total / CONFIG.disk.workers.max(1)
+}
+
+impl ReadCache {
+    pub fn new() -> ReadCache {
+        ReadCache {
+            pieces: MHashMap::default(),
+            size: 0,
+            readers: MHashMap::default(),
+            clock: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        CONFIG.disk.read_cache > 0
+    }
+
+    pub fn get(&mut self, tid: usize, piece: u32) -> Option<&[u8]> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.pieces.get_mut(&(tid, piece)).map(|p| {
+            p.used = clock;
+            &p.data[..]
+        })
+    }
+
+    pub fn contains(&self, tid: usize, piece: u32) -> bool {
+        self.pieces.contains_key(&(tid, piece))
+    }
+
+    pub fn insert(&mut self, tid: usize, piece: u32, data: Vec<u8>) {
+        let limit = share(CONFIG.disk.read_cache * 1_048_576);
+        self.invalidate(tid, piece);
+        while self.size + data.len() > limit {
+            let lru = self
+                .pieces
+                .iter()
+                .min_by_key(|(_, p)| p.used)
+                .map(|(k, _)| *k);
+            match lru {
+                Some((t, p)) => self.invalidate(t, p),
+                None => return,
+            }
+        }
+        self.clock += 1;
+        self.size += data.len();
+        let used = self.clock;
+        self.pieces.insert((tid, piece), CachedPiece { data, used });
+    }
+
+    pub fn invalidate(&mut self, tid: usize, piece: u32) {
+        if let Some(p) = self.pieces.remove(&(tid, piece)) {
+            self.size -= p.data.len();
+        }
+    }
+
+    pub fn invalidate_torrent(&mut self, tid: usize) {
+        let size = &mut self.size;
+        self.pieces.retain(|&(t, _), p| {
+            if t == tid {
+                *size -= p.data.len();
+            }
+            t != tid
+        });
+        self.readers.retain(|&(t, _), _| t != tid);
+    }
+
+    /// Records that a peer read a piece, returning whether it moved on
+    /// from the previous piece, i.e. the peer is reading sequentially.
+    pub fn sequential(&mut self, tid: usize, pid: usize, piece: u32) -> bool {
+        // Peers come and go, so forget them all every once in a while
+        if self.readers.len() >= 4096 {
+            self.readers.clear();
+        }
+        let prev = self.readers.insert((tid, pid), piece);
+        piece > 0 && prev == Some(piece - 1)
+    }
+}
+
+impl WriteCache {
+    pub fn new() -> WriteCache {
+        WriteCache {
+            pieces: MHashMap::default(),
+            size: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        CONFIG.disk.write_cache > 0
+    }
+
+    /// Buffers a block, given the locations of the whole piece it's in.
+    pub fn insert(
+        &mut self,
+        tid: usize,
+        begin: usize,
+        block: &[u8],
+        (locations, len): (LocIter, usize),
+        path: Option<String>,
+    ) {
+        let piece = self
+            .pieces
+            .entry((tid, locations.piece()))
+            .or_insert_with(|| DirtyPiece {
+                tid,
+                data: vec![0; len],
+                ranges: Vec::new(),
+                locations,
+                path,
+            });
+        let end = begin + block.len();
+        piece.data[begin..end].copy_from_slice(block);
+        let written: usize = piece.ranges.iter().map(|(s, e)| e - s).sum();
+        piece.ranges.push((begin, end));
+        piece.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(piece.ranges.len());
+        for &(s, e) in &piece.ranges {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        piece.ranges = merged;
+        self.size += piece.ranges.iter().map(|(s, e)| e - s).sum::<usize>() - written;
+    }
+
+    pub fn full(&self) -> bool {
+        self.size > share(CONFIG.disk.write_cache * 1_048_576)
+    }
+
+    pub fn due(&self) -> bool {
+        !self.pieces.is_empty()
+            && self.last_flush.elapsed().as_secs() >= CONFIG.disk.write_flush_interval
+    }
+
+    /// Removes the buffered pieces of a torrent, or of one of its pieces,
+    /// or every piece if no torrent is given.
+    pub fn take(&mut self, tid: Option<usize>, piece: Option<u32>) -> Vec<DirtyPiece> {
+        if tid.is_none() {
+            self.last_flush = Instant::now();
+        }
+        let keys: Vec<_> = self
+            .pieces
+            .keys()
+            .filter(|&&(t, p)| {
+                tid.map(|tid| tid == t).unwrap_or(true)
+                    && piece.map(|piece| piece == p).unwrap_or(true)
+            })
+            .cloned()
+            .collect();
+        let mut taken = Vec::with_capacity(keys.len());
+        for key in keys {
+            let p = self.pieces.remove(&key).unwrap();
+            self.size -= p.ranges.iter().map(|(s, e)| e - s).sum::<usize>();
+            taken.push(p);
+        }
+        taken
+    }
+}
+
 impl FileCache {
     pub fn new() -> FileCache {
         FileCache {
@@ -99,39 +316,100 @@ impl FileCache {
         }
     }
 
-    pub fn read_file_range(
-        &mut self,
-        path: &path::Path,
-        offset: u64,
-        buf: &mut [u8],
-    ) -> io::Result<()> {
-        self.ensure_exists(path, Err(0))?;
-        let entry = self.files.get_mut(path).unwrap();
-        entry.file.seek(SeekFrom::Start(offset))?;
-        entry.file.read_exact(buf)?;
-        Ok(())
+    /// Copies a range of an open file out of its mappings, mapping the
+    /// windows it spans as needed. Returns false if the range isn't
+    /// covered, i.e. it's past the end of the file.
+    fn read_mapped(&mut self, path: &path::Path, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+        let mut pos = offset;
+        let mut done = 0;
+        while done < buf.len() {
+            let window = pos / MAP_WINDOW;
+            let start = window * MAP_WINDOW;
+            if !self.files[path].maps.contains_key(&window) {
+                let windows =
+                    (share(CONFIG.disk.mmap_cache * 1_048_576) as u64 / MAP_WINDOW).max(1);
+                if self
+                    .files
+                    .values()
+                    .map(|e| e.maps.len() as u64)
+                    .sum::<u64>()
+                    >= windows
+                {
+                    self.unmap_one();
+                }
+                let entry = self.files.get_mut(path).unwrap();
+                let len = entry.file.metadata()?.len();
+                if start >= len {
+                    return Ok(false);
+                }
+                let map = native::Mapping::new(
+                    &entry.file,
+                    start,
+                    (len - start).min(MAP_WINDOW) as usize,
+                )?;
+                entry.maps.insert(window, MapEntry { used: true, map });
+            }
+            let file = self.files.get_mut(path).unwrap();
+            let mapped = file.maps[&window].map.data().len() as u64;
+            if pos - start >= mapped {
+                // The file may have grown since the window was mapped
+                if mapped < MAP_WINDOW && file.file.metadata()?.len() > start + mapped {
+                    file.maps.remove(&window);
+                    continue;
+                }
+                return Ok(false);
+            }
+            let entry = file.maps.get_mut(&window).unwrap();
+            entry.used = true;
+            let data = &entry.map.data()[(pos - start) as usize..];
+            let amnt = data.len().min(buf.len() - done);
+            buf[done..done + amnt].copy_from_slice(&data[..amnt]);
+            done += amnt;
+            pos += amnt as u64;
+        }
+        Ok(true)
     }
 
-    pub fn write_file_range(
-        &mut self,
-        path: &path::Path,
-        size: Result<u64, u64>,
-        offset: u64,
-        buf: &[u8],
-    ) -> io::Result<()> {
-        self.ensure_exists(path, size)?;
-        let entry = self.files.get_mut(path).unwrap();
-        entry.file.seek(SeekFrom::Start(offset))?;
-        entry.file.write_all(&buf)?;
-        Ok(())
+    /// Unmaps a window which wasn't used recently, in the same way
+    /// files are closed.
+    fn unmap_one(&mut self) {
+        let mut removal = None;
+        for (id, entry) in &mut self.files {
+            for (&window, map) in &mut entry.maps {
+                if map.used {
+                    map.used = false;
+                } else {
+                    removal = Some((id.clone(), window));
+                }
+            }
+        }
+        if let Some((f, window)) = removal {
+            self.files.get_mut(&f).map(|e| e.maps.remove(&window));
+        }
     }
 
-    pub fn remove_file(&mut self, path: &path::Path) {
-        self.files.remove(path);
+    /// Opens a file for reading, returning its descriptor, which stays
+    /// valid until the file is closed or evicted.
+    pub fn read_fd(&mut self, path: &path::Path) -> io::Result<RawFd> {
+        if !self.files.contains_key(path) && !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "file not found"));
+        }
+        self.open(path, Err(0))?;
+        Ok(self.files[path].file.as_raw_fd())
     }
 
-    pub fn flush_file(&mut self, path: &path::Path) {
-        self.files.get_mut(path).map(|e| e.file.sync_all().ok());
+    /// Opens a file for writing like `write`, returning its descriptor.
+    pub fn write_fd(&mut self, path: &path::Path, size: Result<u64, u64>) -> io::Result<RawFd> {
+        self.open(path, size)?;
+        let entry = self.files.get_mut(path).unwrap();
+        entry.dirty = true;
+        Ok(entry.file.as_raw_fd())
+    }
+
+    /// Whether opening a file would close another one.
+    pub fn would_evict(&self, path: &path::Path) -> bool {
+        !self.files.contains_key(path)
+            && self.files.len() >= share(CONFIG.net.max_open_files).max(1)
     }
 
     fn ensure_exists(&mut self, path: &path::Path, len: Result<u64, u64>) -> io::Result<()> {
@@ -141,7 +419,7 @@ impl FileCache {
             len.err().unwrap()
         };
         if !self.files.contains_key(path) {
-            if self.files.len() >= CONFIG.net.max_open_files {
+            if self.files.len() >= share(CONFIG.net.max_open_files).max(1) {
                 let mut removal = None;
                 // We rely on random iteration order to prove us something close to a "clock hand"
                 // like algorithm
@@ -153,7 +431,11 @@ impl FileCache {
                     }
                 }
                 if let Some(f) = removal {
-                    self.remove_file(&f);
+                    // Periodic syncs would miss the file once it's closed
+                    if CONFIG.disk.sync == SyncPolicy::Periodic {
+                        self.sync(&f);
+                    }
+                    self.close(&f);
                 }
             }
 
@@ -184,6 +466,8 @@ impl FileCache {
                     used: true,
                     sparse,
                     alloc_failed,
+                    dirty: false,
+                    maps: MHashMap::default(),
                 },
             );
         } else if len.is_ok() {
@@ -201,9 +485,142 @@ impl FileCache {
     }
 }
 
+impl Storage for FileCache {
+    fn open(&mut self, path: &path::Path, size: Result<u64, u64>) -> io::Result<()> {
+        self.ensure_exists(path, size)
+    }
+
+    fn read(&mut self, path: &path::Path, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        // Reading mustn't leave behind files which were never written
+        if !self.files.contains_key(path) && !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "file not found"));
+        }
+        self.ensure_exists(path, Err(0))?;
+        if CONFIG.disk.mmap && self.read_mapped(path, offset, buf)? {
+            return Ok(());
+        }
+        let entry = self.files.get_mut(path).unwrap();
+        entry.file.seek(SeekFrom::Start(offset))?;
+        entry.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: &path::Path,
+        size: Result<u64, u64>,
+        offset: u64,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        self.ensure_exists(path, size)?;
+        let entry = self.files.get_mut(path).unwrap();
+        entry.dirty = true;
+        entry.file.seek(SeekFrom::Start(offset))?;
+        entry.file.write_all(buf)?;
+        Ok(())
+    }
+
+    fn sync(&mut self, path: &path::Path) {
+        if let Some(e) = self.files.get_mut(path) {
+            e.file.sync_all().ok();
+            e.dirty = false;
+        }
+    }
+
+    fn sync_all(&mut self) {
+        for e in self.files.values_mut().filter(|e| e.dirty) {
+            e.file.sync_all().ok();
+            e.dirty = false;
+        }
+    }
+
+    fn rename(&mut self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        self.close_dir(from);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::rename(from, to) {
+            Ok(()) => {}
+            // Across filesystems, copy then delete
+            Err(ref e) if e.raw_os_error() == Some(EXDEV) => {
+                if let Err(e) = copy_all(from, to) {
+                    error!("FS copy failed: {:?}", e);
+                    remove_all(to).ok();
+                    return io_err("Failed to copy across filesystems!");
+                }
+                remove_all(from)?;
+            }
+            Err(e) => {
+                error!("FS rename failed: {:?}", e);
+                return Err(e);
+            }
+        }
+        sync_dir(from);
+        sync_dir(to);
+        Ok(())
+    }
+
+    fn close(&mut self, path: &path::Path) {
+        self.files.remove(path);
+    }
+
+    fn close_dir(&mut self, dir: &path::Path) {
+        self.files.retain(|p, _| !p.starts_with(dir));
+    }
+
+    fn is_open(&self, path: &path::Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn exists(&self, path: &path::Path) -> bool {
+        self.files.contains_key(path) || path.exists()
+    }
+
+    fn len(&self, path: &path::Path) -> Option<u64> {
+        fs::metadata(path).map(|m| m.len()).ok()
+    }
+
+    fn remove(&mut self, path: &path::Path) -> io::Result<()> {
+        self.close(path);
+        fs::remove_file(path)
+    }
+}
+
+/// Copies a file, or a folder along with everything in it, to exactly
+/// the given path.
+fn copy_all(from: &path::Path, to: &path::Path) -> Result<(), fs_extra::error::Error> {
+    if from.is_dir() {
+        let mut opts = fs_extra::dir::CopyOptions::new();
+        opts.copy_inside = true;
+        fs_extra::dir::copy(from, to, &opts)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn remove_all(path: &path::Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Syncs the directory holding a path which was just renamed, if
+/// `disk.sync_dirs` is set, so that the rename survives a crash.
+pub fn sync_dir(path: &path::Path) {
+    if let (true, Some(dir)) = (CONFIG.disk.sync_dirs, path.parent()) {
+        fs::File::open(dir).and_then(|d| d.sync_all()).ok();
+    }
+}
+
 impl Drop for FileCache {
     fn drop(&mut self) {
-        for (_, entry) in self.files.drain() {
+        if CONFIG.disk.sync == SyncPolicy::Never {
+            return;
+        }
+        for (_, entry) in self.files.drain().filter(|(_, e)| e.dirty) {
             entry.file.sync_all().ok();
         }
     }
@@ -212,6 +629,8 @@ impl Drop for FileCache {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::torrent::Info;
+    use std::sync::Arc;
 
     #[test]
     fn test_tempbuf() {
@@ -223,4 +642,140 @@ mod tests {
         assert_eq!(buf.get(30).len(), 30);
         assert_eq!(buf.get(10).len(), 10);
     }
+
+    #[test]
+    fn test_sparse_write() {
+        let dir = std::env::temp_dir().join(format!("synapse-sparse-{}", std::process::id()));
+        let path = dir.join("f");
+        let mut fc = FileCache::new();
+        fc.write(&path, Err(1 << 30), 1 << 20, &[1; 16_384])
+            .unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.len(), 1 << 30);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(meta.blocks() * 512 < 1 << 20);
+        }
+        fc.close(&path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let mut rc = ReadCache::new();
+        let piece = share(CONFIG.disk.read_cache * 1_048_576) / 4;
+        for i in 0..4 {
+            rc.insert(0, i, vec![i as u8; piece]);
+        }
+        assert_eq!(rc.get(0, 0), Some(&vec![0; piece][..]));
+        // The least recently used piece makes room for new ones
+        rc.insert(1, 0, vec![4; piece]);
+        assert!(rc.contains(0, 0));
+        assert!(!rc.contains(0, 1));
+        rc.invalidate_torrent(0);
+        assert_eq!(rc.size, piece);
+        assert_eq!(rc.pieces.len(), 1);
+
+        assert!(!rc.sequential(0, 0, 3));
+        assert!(!rc.sequential(0, 0, 3));
+        assert!(rc.sequential(0, 0, 4));
+        assert!(!rc.sequential(0, 1, 5));
+    }
+
+    #[test]
+    fn test_write_cache() {
+        let mut info = Info::with_pieces(2);
+        info.piece_len = 65_536;
+        info.total_len = 131_072;
+        info.files[0].length = 131_072;
+        info.piece_idx = vec![(0, 0), (0, 65_536)];
+        let info = Arc::new(info);
+        let piece = |idx| Info::piece_disk_locs(&info, idx);
+        let len = info.piece_len as usize;
+        let mut wc = WriteCache::new();
+        wc.insert(0, 16_384, &[1; 16_384], (piece(0), len), None);
+        wc.insert(0, 0, &[2; 16_384], (piece(0), len), None);
+        wc.insert(0, 0, &[3; 16_384], (piece(0), len), None);
+        wc.insert(0, 0, &[4; 16_384], (piece(1), len), None);
+        wc.insert(1, 0, &[5; 16_384], (piece(0), len), None);
+        assert_eq!(wc.size, 4 * 16_384);
+
+        // Contiguous blocks are merged into one run
+        let taken = wc.take(Some(0), Some(0));
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].ranges, vec![(0, 32_768)]);
+        assert_eq!(taken[0].data[0], 3);
+        assert_eq!(wc.size, 2 * 16_384);
+
+        assert_eq!(wc.take(Some(0), None).len(), 1);
+        assert_eq!(wc.take(None, None).len(), 1);
+        assert_eq!(wc.size, 0);
+    }
+
+    #[test]
+    fn test_flush_dirty() {
+        let dir = std::env::temp_dir().join(format!("synapse-dirty-{}", std::process::id()));
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let mut fc = FileCache::new();
+        fc.write(&a, Err(16_384), 0, &[1; 16_384]).unwrap();
+        fc.write_fd(&b, Err(16_384)).unwrap();
+        assert!(fc.files.values().all(|e| e.dirty));
+        fc.sync(&a);
+        assert!(!fc.files[&a].dirty);
+        fc.sync_all();
+        assert!(fc.files.values().all(|e| !e.dirty));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = std::env::temp_dir().join(format!("synapse-rename-{}", std::process::id()));
+        let (a, b) = (dir.join("a/f"), dir.join("b/c/f"));
+        let mut fc = FileCache::new();
+        fc.write(&a, Err(4), 0, &[1; 4]).unwrap();
+        fc.rename(&a, &b).unwrap();
+        assert!(!fc.is_open(&a));
+        assert!(!fc.exists(&a));
+        let mut buf = [0; 4];
+        fc.read(&b, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 4]);
+
+        // Folders are moved along with the files in them
+        fc.rename(&dir.join("b"), &dir.join("d")).unwrap();
+        assert!(!fc.is_open(&b));
+        assert!(fc.exists(&dir.join("d/c/f")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_mapped() {
+        let dir = std::env::temp_dir().join(format!("synapse-mmap-{}", std::process::id()));
+        let path = dir.join("f");
+        let mut fc = FileCache::new();
+        let data: Vec<u8> = (0..64u8).collect();
+        fc.write(&path, Err(2 * MAP_WINDOW), MAP_WINDOW - 32, &data)
+            .unwrap();
+
+        // Reads spanning two windows map both of them
+        let mut buf = [0u8; 64];
+        assert!(fc.read_mapped(&path, MAP_WINDOW - 32, &mut buf).unwrap());
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(fc.files[&path].maps.len(), 2);
+
+        // Ranges past the end of the file aren't served from the mapping
+        assert!(!fc
+            .read_mapped(&path, 2 * MAP_WINDOW - 32, &mut buf)
+            .unwrap());
+
+        // Windows mapped before the file grew are mapped again
+        fc.write(&path, Err(2 * MAP_WINDOW), MAP_WINDOW + 32, &data)
+            .unwrap();
+        let mut buf = [0u8; 32];
+        assert!(fc.read_mapped(&path, MAP_WINDOW + 64, &mut buf).unwrap());
+        assert_eq!(&buf[..], &data[32..]);
+
+        fc.close(&path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
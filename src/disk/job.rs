@@ -1,21 +1,32 @@
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, fs, path, time};
 
 use fs2;
 use http_range::HttpRange;
+use nix::libc;
 use sha1::{Digest, Sha1};
 use sstream::SStream;
 
-use super::{BufCache, FileCache, JOB_TIME_SLICE};
+use super::cache::{sync_dir, DirtyPiece, TempPB, WriteCache};
+use super::{BufCache, FileCache, ReadCache, Ring, Storage, JOB_TIME_SLICE};
 use crate::buffers::Buffer;
+use crate::config::{Allocation, SyncPolicy};
+use crate::rpc::resource::ErrorCode;
+use crate::session::resume::{Resume, Stamp};
 use crate::torrent::{Info, LocIter};
-use crate::util::{hash_to_id, io_err};
+use crate::util::{hash_to_id, io_err, native};
 use crate::CONFIG;
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
-const EXDEV: i32 = 18;
+const ENOSPC: i32 = 28;
+/// Ioctl cloning all of a file's extents into another
+const FICLONE: libc::c_ulong = 0x4004_9409;
+/// Bytes of a file allocated at once, between which progress is reported
+const ALLOC_CHUNK: u64 = 64 * 1024 * 1024;
 
 pub struct Location {
     /// Info file index
@@ -29,6 +40,11 @@ pub struct Location {
     pub end: usize,
     /// This file should be fully allocated if possible
     pub allocate: bool,
+    /// Offset into the torrent's data, which is also where the location
+    /// is kept in the part file
+    pub torrent_offset: u64,
+    /// The data is kept in the part file, since the file is skipped
+    pub part: bool,
     info: Arc<Info>,
 }
 
@@ -63,9 +79,29 @@ pub enum Request {
         to: String,
         target: String,
     },
+    /// Copies a torrent's files to another folder a slice at a time, while
+    /// its other jobs keep using them where they are.
+    Relocate {
+        tid: usize,
+        info: Arc<Info>,
+        from: String,
+        to: String,
+        file: usize,
+        offset: u64,
+    },
+    /// Completes a relocation, copying the pieces written since it began
+    /// again before the files are removed from where they were.
+    FinishRelocation {
+        tid: usize,
+        info: Arc<Info>,
+        from: String,
+        to: String,
+        pieces: Vec<u32>,
+    },
     Validate {
         tid: usize,
         info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
         path: Option<String>,
         idx: u32,
         invalid: Vec<u32>,
@@ -73,9 +109,106 @@ pub enum Request {
     ValidatePiece {
         tid: usize,
         info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
         path: Option<String>,
         piece: u32,
     },
+    /// Moves data of skipped files in shared pieces into the part file,
+    /// or out of it into the files once they're wanted again.
+    MoveParts {
+        tid: usize,
+        locations: Vec<Location>,
+        path: Option<String>,
+    },
+    Repair {
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+        /// Files whose pieces are hashed even if they look intact
+        check: Vec<bool>,
+        /// Pieces left to hash, None until the files have been inspected
+        pieces: Option<Vec<u32>>,
+        idx: usize,
+        invalid: Vec<u32>,
+    },
+    /// Fully allocates the wanted files of a torrent, a chunk at a time.
+    Allocate {
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+        /// File being allocated, and how much of it is done
+        file: usize,
+        offset: u64,
+    },
+    /// Records the size and modification time of a torrent's files as
+    /// its fast resume data.
+    SaveResume {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+    },
+    /// Checks whether a torrent's files changed since its fast resume
+    /// data was saved. Torrents without resume data are assumed to be
+    /// unchanged.
+    CheckResume {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+    },
+    /// Reports the files of a torrent which changed since its fast resume
+    /// data was saved, then saves it again so that changes are reported
+    /// once. Torrents without resume data only have it saved.
+    CheckModified {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+    },
+    /// Compares the free space of the filesystem a torrent is downloaded
+    /// to with the space its wanted files still take up.
+    CheckSpace {
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+    },
+    /// Renames a completed file's `.part` copy into place.
+    FinishFile {
+        tid: usize,
+        file: PathBuf,
+        path: Option<String>,
+    },
+    /// Renames a file or folder of a torrent, along with the `.part` copy
+    /// of a file which isn't complete yet.
+    Rename {
+        tid: usize,
+        from: PathBuf,
+        to: PathBuf,
+        path: Option<String>,
+    },
+    /// Puts files existing torrents already downloaded in place of a new
+    /// torrent's files, given as the existing file and the new file's path.
+    Link {
+        tid: usize,
+        files: Vec<(PathBuf, PathBuf)>,
+        path: Option<String>,
+        hardlink: bool,
+    },
+    /// Keeps a torrent's data in memory rather than on disk, sent before
+    /// any of its other jobs.
+    Memory {
+        tid: usize,
+    },
+    /// Compares the size of each file on disk to its expected length,
+    /// answering an RPC client's integrity report.
+    CheckSizes {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        client: usize,
+        serial: u64,
+    },
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
@@ -97,8 +230,18 @@ pub enum Request {
 pub enum Response {
     Read { context: Ctx, data: Buffer },
     ValidationComplete { tid: usize, invalid: Vec<u32> },
+    RepairComplete { tid: usize, invalid: Vec<u32> },
+    SizesChecked(SizeCheck),
     PieceValidated { tid: usize, piece: u32, valid: bool },
-    ValidationUpdate { tid: usize, percent: f32 },
+    ValidationUpdate(Progress),
+    AllocationUpdate { tid: usize, percent: f32 },
+    AllocationComplete { tid: usize },
+    RelocationUpdate { tid: usize, percent: f32 },
+    RelocationCopied { tid: usize },
+    ResumeChecked { tid: usize, changed: bool },
+    FilesModified { tid: usize, files: Vec<usize> },
+    SpaceChecked { tid: usize, needed: u64, free: u64 },
+    Linked { tid: usize, files: usize },
     Moved { tid: usize, path: String },
     FreeSpace(u64),
     Error { tid: usize, err: io::Error },
@@ -112,9 +255,28 @@ pub struct Ctx {
     pub length: u32,
 }
 
+/// How far a validation or repair got
+pub struct Progress {
+    pub tid: usize,
+    pub percent: f32,
+    /// Pieces found intact since the last update
+    pub intact: Vec<u32>,
+}
+
+/// Result of a size check, for the RPC request which asked for it
+pub struct SizeCheck {
+    pub tid: usize,
+    pub client: usize,
+    pub serial: u64,
+    /// Files which are missing or of the wrong size
+    pub mismatched: Vec<usize>,
+}
+
 pub enum JobRes {
     Resp(Response),
     Update(Request, Response),
+    /// Like `Update`, but the job only continues at the given time
+    Throttled(Request, Response, time::Instant),
     Done,
     Paused(Request),
 }
@@ -142,30 +304,181 @@ impl Request {
         Request::Serialize { tid, data, hash }
     }
 
-    pub fn validate(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+    pub fn validate(
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+    ) -> Request {
         Request::Validate {
             tid,
             info,
+            priorities,
             path,
             idx: 0,
             invalid: Vec::new(),
         }
     }
 
+    pub fn allocate(
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+    ) -> Request {
+        Request::Allocate {
+            tid,
+            info,
+            priorities,
+            path,
+            file: 0,
+            offset: 0,
+        }
+    }
+
     pub fn validate_piece(
         tid: usize,
         info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
         path: Option<String>,
         piece: u32,
     ) -> Request {
         Request::ValidatePiece {
             tid,
             info,
+            priorities,
             path,
             piece,
         }
     }
 
+    pub fn relocate(tid: usize, info: Arc<Info>, from: String, to: String) -> Request {
+        Request::Relocate {
+            tid,
+            info,
+            from,
+            to,
+            file: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn finish_relocation(
+        tid: usize,
+        info: Arc<Info>,
+        from: String,
+        to: String,
+        pieces: Vec<u32>,
+    ) -> Request {
+        Request::FinishRelocation {
+            tid,
+            info,
+            from,
+            to,
+            pieces,
+        }
+    }
+
+    pub fn move_parts(tid: usize, locations: Vec<Location>, path: Option<String>) -> Request {
+        Request::MoveParts {
+            tid,
+            locations,
+            path,
+        }
+    }
+
+    pub fn repair(
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+        check: Vec<bool>,
+    ) -> Request {
+        Request::Repair {
+            tid,
+            info,
+            priorities,
+            path,
+            check,
+            pieces: None,
+            idx: 0,
+            invalid: Vec::new(),
+        }
+    }
+
+    pub fn save_resume(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+        Request::SaveResume { tid, info, path }
+    }
+
+    pub fn check_resume(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+        Request::CheckResume { tid, info, path }
+    }
+
+    pub fn check_modified(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+        Request::CheckModified { tid, info, path }
+    }
+
+    pub fn check_space(
+        tid: usize,
+        info: Arc<Info>,
+        priorities: Arc<Vec<u8>>,
+        path: Option<String>,
+    ) -> Request {
+        Request::CheckSpace {
+            tid,
+            info,
+            priorities,
+            path,
+        }
+    }
+
+    pub fn finish_file(tid: usize, file: PathBuf, path: Option<String>) -> Request {
+        Request::FinishFile { tid, file, path }
+    }
+
+    pub fn rename(tid: usize, from: PathBuf, to: PathBuf, path: Option<String>) -> Request {
+        Request::Rename {
+            tid,
+            from,
+            to,
+            path,
+        }
+    }
+
+    pub fn link(
+        tid: usize,
+        files: Vec<(PathBuf, PathBuf)>,
+        path: Option<String>,
+        hardlink: bool,
+    ) -> Request {
+        Request::Link {
+            tid,
+            files,
+            path,
+            hardlink,
+        }
+    }
+
+    pub fn memory(tid: usize) -> Request {
+        Request::Memory { tid }
+    }
+
+    pub fn check_sizes(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        client: usize,
+        serial: u64,
+    ) -> Request {
+        Request::CheckSizes {
+            tid,
+            info,
+            path,
+            client,
+            serial,
+        }
+    }
+
     pub fn delete(
         tid: usize,
         hash: [u8; 20],
@@ -272,21 +585,419 @@ impl Request {
 
     pub fn concurrent(&self) -> bool {
         match self {
-            Request::Validate { .. } => false,
+            Request::Validate { .. } | Request::Repair { .. } => false,
             _ => true,
         }
     }
 
-    pub fn execute(self, fc: &mut FileCache, bc: &mut BufCache) -> io::Result<JobRes> {
+    /// Whether the job should be run ahead of other torrents' jobs.
+    pub fn urgent(&self) -> bool {
+        matches!(self, Request::ValidatePiece { .. })
+    }
+
+    /// Whether the job's I/O can be queued on a ring along with other
+    /// jobs', rather than executed on its own.
+    pub fn batched(&self) -> bool {
+        match self {
+            Request::Write { .. } => true,
+            // Mapped reads don't need a system call to begin with
+            Request::Read { .. } => !CONFIG.disk.mmap && CONFIG.disk.read_cache == 0,
+            _ => false,
+        }
+    }
+
+    /// Queues a batched job's I/O on the ring under token. Once the ring
+    /// has been waited on, `finish` produces the job's result. The job
+    /// must be kept alive until then, since the ring refers to its data.
+    pub fn submit(
+        &mut self,
+        fc: &mut FileCache,
+        bc: &mut BufCache,
+        ring: &mut Ring,
+        token: u64,
+    ) -> io::Result<()> {
+        let sd = &CONFIG.disk.session;
+        let dd = &CONFIG.disk.directory;
+        let (_, mut tpb, _) = bc.data();
+        match self {
+            Request::Write {
+                data,
+                locations,
+                path,
+                ..
+            } => {
+                for loc in locations.filter(|loc| !loc.pad()) {
+                    let (pb, size, offset) = if loc.part {
+                        let pb = tpb.get(sd);
+                        pb.push(loc.part_file());
+                        (pb, Err(0), loc.torrent_offset)
+                    } else {
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        staged(fc, pb);
+                        let size = if loc.allocate && CONFIG.disk.allocation != Allocation::Sparse {
+                            Ok(loc.file_len)
+                        } else {
+                            Err(loc.file_len)
+                        };
+                        (pb, size, loc.offset)
+                    };
+                    // Queued operations mustn't have their file closed
+                    if ring.pending() && fc.would_evict(pb) {
+                        ring.wait()?;
+                    }
+                    let fd = fc.write_fd(pb, size)?;
+                    unsafe {
+                        ring.write(fd, &data[loc.start..loc.end], offset, token)?;
+                    }
+                }
+            }
+            Request::Read {
+                data,
+                locations,
+                path,
+                ..
+            } => {
+                for loc in locations {
+                    if loc.pad() {
+                        for b in &mut data[loc.start..loc.end] {
+                            *b = 0;
+                        }
+                        continue;
+                    }
+                    let (pb, offset) = if loc.part {
+                        let pb = tpb.get(sd);
+                        pb.push(loc.part_file());
+                        (pb, loc.torrent_offset)
+                    } else {
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        staged(fc, pb);
+                        (pb, loc.offset)
+                    };
+                    if ring.pending() && fc.would_evict(pb) {
+                        ring.wait()?;
+                    }
+                    let fd = fc.read_fd(pb)?;
+                    unsafe {
+                        ring.read(fd, &mut data[loc.start..loc.end], offset, token)?;
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Produces the result of a batched job whose I/O has completed.
+    pub fn finish(self) -> JobRes {
+        match self {
+            Request::Read { context, data, .. } => JobRes::Resp(Response::read(context, data)),
+            _ => JobRes::Done,
+        }
+    }
+
+    /// Directory holding the files the job works on, if any.
+    pub fn dir(&self) -> Option<&str> {
+        match self {
+            Request::Write { path, .. }
+            | Request::Read { path, .. }
+            | Request::Delete { path, .. }
+            | Request::Validate { path, .. }
+            | Request::ValidatePiece { path, .. }
+            | Request::MoveParts { path, .. }
+            | Request::Repair { path, .. }
+            | Request::Allocate { path, .. }
+            | Request::SaveResume { path, .. }
+            | Request::CheckResume { path, .. }
+            | Request::CheckModified { path, .. }
+            | Request::CheckSpace { path, .. }
+            | Request::FinishFile { path, .. }
+            | Request::Rename { path, .. }
+            | Request::Link { path, .. }
+            | Request::CheckSizes { path, .. } => {
+                Some(path.as_deref().unwrap_or(&CONFIG.disk.directory))
+            }
+            Request::Move { from, .. }
+            | Request::Relocate { from, .. }
+            | Request::FinishRelocation { from, .. } => Some(from),
+            Request::Download { file_path, .. } => Some(file_path),
+            _ => None,
+        }
+    }
+
+    /// Buffers a write in the write cache instead of executing it,
+    /// returning any other job.
+    pub fn buffer(self, wc: &mut WriteCache) -> Option<Request> {
+        match self {
+            Request::Write {
+                tid,
+                data,
+                locations,
+                path,
+            } => {
+                let begin = locations.begin() as usize;
+                let piece = locations.whole_piece(locations.piece()).unwrap();
+                let len = cmp::min(data.len(), piece.1 - begin);
+                wc.insert(tid, begin, &data[..len], piece, path);
+                None
+            }
+            job => Some(job),
+        }
+    }
+
+    /// The buffered writes which must reach the disk before the job
+    /// runs, those of a torrent or just those of one of its pieces.
+    pub fn flushes(&self) -> Option<(usize, Option<u32>)> {
+        match *self {
+            Request::ValidatePiece { tid, piece, .. } => Some((tid, Some(piece))),
+            // Reads are only of pieces which were already validated
+            Request::Write { .. } | Request::Read { .. } | Request::Serialize { .. } => None,
+            _ => self.tid().map(|tid| (tid, None)),
+        }
+    }
+
+    /// Drops data the job makes stale from the read cache.
+    pub fn invalidate(&self, rc: &mut ReadCache) {
+        match self {
+            Request::Write { tid, locations, .. } => rc.invalidate(*tid, locations.piece()),
+            Request::Delete { tid, .. }
+            | Request::Validate { tid, .. }
+            | Request::Repair { tid, .. }
+            | Request::MoveParts { tid, .. } => rc.invalidate_torrent(*tid),
+            _ => {}
+        }
+    }
+
+    pub fn execute(
+        self,
+        fc: &mut dyn Storage,
+        bc: &mut BufCache,
+        rc: &mut ReadCache,
+    ) -> io::Result<JobRes> {
         let sd = &CONFIG.disk.session;
         let dd = &CONFIG.disk.directory;
         let (mut tb, mut tpb, mut tpb2) = bc.data();
         match self {
-            Request::Ping => {}
+            Request::Ping | Request::Memory { .. } => {}
             Request::FreeSpace => {
                 let free_space = fs2::available_space(dd.as_str())?;
                 return Ok(JobRes::Resp(Response::FreeSpace(free_space)));
             }
+            Request::Allocate {
+                tid,
+                info,
+                priorities,
+                path,
+                mut file,
+                mut offset,
+            } => {
+                let start = time::Instant::now();
+                let wanted = |i: usize| priorities[i] != 0 && !info.files[i].pad;
+                while file < info.files.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    if !wanted(file) {
+                        file += 1;
+                        continue;
+                    }
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(&info.files[file].path);
+                    staged(fc, pb);
+                    fs::create_dir_all(pb.parent().unwrap())?;
+                    let f = fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(&pb)?;
+                    let len = cmp::min(ALLOC_CHUNK, info.files[file].length - offset);
+                    if len != 0 {
+                        native::preallocate(&f, offset, len)?;
+                    }
+                    offset += len;
+                    if offset == info.files[file].length {
+                        file += 1;
+                        offset = 0;
+                    }
+                }
+                if file == info.files.len() {
+                    return Ok(JobRes::Resp(Response::AllocationComplete { tid }));
+                }
+                let total: u64 = (0..info.files.len())
+                    .filter(|&i| wanted(i))
+                    .map(|i| info.files[i].length)
+                    .sum();
+                let done: u64 = (0..file)
+                    .filter(|&i| wanted(i))
+                    .map(|i| info.files[i].length)
+                    .sum::<u64>()
+                    + offset;
+                let percent = done as f32 / total as f32;
+                return Ok(JobRes::Update(
+                    Request::Allocate {
+                        tid,
+                        info,
+                        priorities,
+                        path,
+                        file,
+                        offset,
+                    },
+                    Response::AllocationUpdate { tid, percent },
+                ));
+            }
+            Request::SaveResume { info, path, .. } => {
+                let files = stamps(fc, &mut tpb, &info, path.as_ref().unwrap_or(dd));
+                write_resume(&mut tpb, &mut tpb2, &info.hash, Resume { files })?;
+            }
+            Request::CheckModified { tid, info, path } => {
+                let current = stamps(fc, &mut tpb, &info, path.as_ref().unwrap_or(dd));
+                // Without a record to compare with, one is only made
+                let saved = read_resume(&mut tpb, &info.hash)
+                    .map(|r| r.files)
+                    .filter(|files| files.len() == current.len());
+                let files = match saved {
+                    Some(ref saved) => (0..current.len())
+                        .filter(|&i| saved[i] != current[i])
+                        .collect(),
+                    None => Vec::new(),
+                };
+                // Each change is only reported once
+                if saved.as_ref() != Some(&current) {
+                    write_resume(&mut tpb, &mut tpb2, &info.hash, Resume { files: current })?;
+                }
+                return Ok(JobRes::Resp(Response::FilesModified { tid, files }));
+            }
+            Request::CheckResume { tid, info, path } => {
+                let changed = match read_resume(&mut tpb2, &info.hash) {
+                    Some(saved) => {
+                        saved.files.len() != info.files.len()
+                            || info.files.iter().zip(saved.files).any(|(file, saved)| {
+                                if file.pad {
+                                    return false;
+                                }
+                                let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                                pb.push(&file.path);
+                                staged(fc, pb);
+                                stamp(pb) != saved
+                            })
+                    }
+                    None => false,
+                };
+                return Ok(JobRes::Resp(Response::ResumeChecked { tid, changed }));
+            }
+            Request::CheckSpace {
+                tid,
+                info,
+                priorities,
+                path,
+            } => {
+                let dir = path.as_ref().unwrap_or(dd);
+                let mut needed = 0;
+                for (i, file) in info.files.iter().enumerate() {
+                    if priorities[i] == 0 || file.pad {
+                        continue;
+                    }
+                    let pb = tpb.get(dir);
+                    pb.push(&file.path);
+                    staged(fc, pb);
+                    // Blocks rather than length, sparse files take up less
+                    let used = fs::metadata(&pb).map(|m| m.blocks() * 512).unwrap_or(0);
+                    needed += file.length.saturating_sub(used);
+                }
+                // The directory is only created once something is written
+                let mut existing = Path::new(dir);
+                while let (false, Some(parent)) = (existing.exists(), existing.parent()) {
+                    existing = parent;
+                }
+                let free = fs2::available_space(existing)?;
+                return Ok(JobRes::Resp(Response::SpaceChecked { tid, needed, free }));
+            }
+            Request::FinishFile { file, path, .. } => {
+                let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                pb.push(&file);
+                let part = tpb2.get(&pb);
+                part_path(part);
+                fc.close(part);
+                if fc.exists(part) {
+                    fc.rename(part, pb)?;
+                }
+                if CONFIG.disk.sync == SyncPolicy::Complete {
+                    fs::File::open(&pb).and_then(|f| f.sync_all()).ok();
+                }
+            }
+            Request::Rename { from, to, path, .. } => {
+                let dir = path.as_ref().unwrap_or(dd);
+                let mut moves = vec![(Path::new(dir).join(&from), Path::new(dir).join(&to))];
+                if CONFIG.disk.part_suffix {
+                    let (mut fp, mut tp) = moves[0].clone();
+                    part_path(&mut fp);
+                    part_path(&mut tp);
+                    moves.push((fp, tp));
+                }
+                for (fp, tp) in moves {
+                    if !fc.exists(&fp) {
+                        continue;
+                    }
+                    if fc.exists(&tp) {
+                        return io_err("Rename target already exists!");
+                    }
+                    fc.rename(&fp, &tp)?;
+                    // Drop folders left empty, but never the download directory
+                    if let (true, Some(parent)) = (from.components().count() > 1, fp.parent()) {
+                        fs::remove_dir(parent).ok();
+                    }
+                }
+            }
+            Request::Link {
+                tid,
+                files,
+                path,
+                hardlink,
+            } => {
+                let dir = path.as_ref().unwrap_or(dd);
+                let mut linked = 0;
+                for (src, file) in files {
+                    let pb = tpb.get(dir);
+                    pb.push(&file);
+                    if pb.exists() {
+                        continue;
+                    }
+                    if let Some(parent) = pb.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    match link_file(&src, pb, hardlink) {
+                        Ok(()) => linked += 1,
+                        Err(e) => error!("Failed to reuse {:?} for {:?}: {}", src, pb, e),
+                    }
+                }
+                return Ok(JobRes::Resp(Response::Linked { tid, files: linked }));
+            }
+            Request::CheckSizes {
+                tid,
+                info,
+                path,
+                client,
+                serial,
+            } => {
+                let mut mismatched = Vec::new();
+                for (i, file) in info.files.iter().enumerate() {
+                    if file.pad {
+                        continue;
+                    }
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(&file.path);
+                    staged(fc, pb);
+                    if fc.len(pb) != Some(file.length) {
+                        mismatched.push(i);
+                    }
+                }
+                return Ok(JobRes::Resp(Response::SizesChecked(SizeCheck {
+                    tid,
+                    client,
+                    serial,
+                    mismatched,
+                })));
+            }
             Request::WriteFile { path, data } => {
                 let p = tpb.get(path.iter());
                 p.set_extension("temp");
@@ -297,7 +1008,9 @@ impl Request {
                     .map(|mut f| f.write(&data[..]));
                 match res {
                     Ok(Ok(_)) => {
-                        fs::rename(&p, &path).ok();
+                        if fs::rename(&p, &path).is_ok() {
+                            sync_dir(&path);
+                        }
                     }
                     Ok(Err(e)) => {
                         error!("Failed to write disk job: {}", e);
@@ -314,12 +1027,19 @@ impl Request {
                 path,
                 ..
             } => {
-                for loc in locations {
+                for loc in locations.filter(|loc| !loc.pad()) {
+                    if loc.part {
+                        let pb = tpb.get(sd);
+                        pb.push(loc.part_file());
+                        fc.write(pb, Err(0), loc.torrent_offset, &data[loc.start..loc.end])?;
+                        continue;
+                    }
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
-                    fc.write_file_range(
+                    staged(fc, pb);
+                    fc.write(
                         &pb,
-                        if loc.allocate {
+                        if loc.allocate && CONFIG.disk.allocation != Allocation::Sparse {
                             Ok(loc.file_len)
                         } else {
                             Err(loc.file_len)
@@ -327,8 +1047,25 @@ impl Request {
                         loc.offset,
                         &data[loc.start..loc.end],
                     )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(&pb);
+                }
+            }
+            Request::MoveParts {
+                locations, path, ..
+            } => {
+                // Each location is moved to where it's now kept
+                for loc in locations {
+                    let buf = tb.get(loc.end - loc.start);
+                    let fp = tpb.get(path.as_ref().unwrap_or(dd));
+                    fp.push(loc.path());
+                    staged(fc, fp);
+                    let pp = tpb2.get(sd);
+                    pp.push(loc.part_file());
+                    if loc.part {
+                        fc.read(fp, loc.offset, buf)?;
+                        fc.write(pp, Err(0), loc.torrent_offset, buf)?;
+                    } else {
+                        fc.read(pp, loc.torrent_offset, buf)?;
+                        fc.write(fp, Err(loc.file_len), loc.offset, buf)?;
                     }
                 }
             }
@@ -339,10 +1076,38 @@ impl Request {
                 path,
                 ..
             } => {
-                for loc in locations {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut data[loc.start..loc.end])?;
+                if !rc.enabled() {
+                    read_locs(
+                        fc,
+                        &mut tpb,
+                        locations,
+                        path.as_ref().unwrap_or(dd),
+                        &mut data,
+                    )?;
+                    return Ok(JobRes::Resp(Response::read(context, data)));
+                }
+                let (tid, piece) = (context.tid, context.idx);
+                let dir = path.as_ref().unwrap_or(dd);
+                if !rc.contains(tid, piece) {
+                    let (locs, len) = locations.whole_piece(piece).unwrap();
+                    let mut buf = vec![0; len];
+                    read_locs(fc, &mut tpb, locs, dir, &mut buf)?;
+                    rc.insert(tid, piece, buf);
+                }
+                if rc.sequential(tid, context.pid, piece) && !rc.contains(tid, piece + 1) {
+                    if let Some((locs, len)) = locations.whole_piece(piece + 1) {
+                        let mut buf = vec![0; len];
+                        // The piece may well not have been downloaded
+                        if read_locs(fc, &mut tpb, locs, dir, &mut buf).is_ok() {
+                            rc.insert(tid, piece + 1, buf);
+                        }
+                    }
+                }
+                let (begin, len) = (context.begin as usize, context.length as usize);
+                match rc.get(tid, piece) {
+                    Some(cached) => data[..len].copy_from_slice(&cached[begin..begin + len]),
+                    // The read ahead piece didn't fit
+                    None => read_locs(fc, &mut tpb, locations, dir, &mut data)?,
                 }
                 return Ok(JobRes::Resp(Response::read(context, data)));
             }
@@ -356,25 +1121,117 @@ impl Request {
                 let tp = tpb2.get(&to);
                 fp.push(target.clone());
                 tp.push(target);
-                match fs::rename(&fp, &tp) {
-                    Ok(_) => {}
-                    // Cross filesystem move, try to copy then delete
-                    Err(ref e) if e.raw_os_error() == Some(EXDEV) => {
-                        match fs_extra::dir::copy(&fp, &tp, &fs_extra::dir::CopyOptions::new()) {
-                            Ok(_) => {
-                                fs::remove_dir_all(&fp)?;
-                            }
-                            Err(e) => {
-                                fs::remove_dir_all(&tp)?;
-                                error!("FS copy failed: {:?}", e);
-                                return io_err("Failed to copy directory across filesystems!");
-                            }
+                fc.rename(fp, tp)?;
+                return Ok(JobRes::Resp(Response::moved(tid, to)));
+            }
+            Request::Relocate {
+                tid,
+                info,
+                from,
+                to,
+                mut file,
+                mut offset,
+            } => {
+                let buf = tb.get(info.piece_len as usize);
+                let start = time::Instant::now();
+                while file < info.files.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let length = info.files[file].length;
+                    let src = tpb.get(&from);
+                    src.push(&info.files[file].path);
+                    staged(fc, src);
+                    let len = fc.len(src).unwrap_or(0).min(length);
+                    if info.files[file].pad || offset >= len {
+                        file += 1;
+                        offset = 0;
+                        continue;
+                    }
+                    let dst = tpb2.get(&to);
+                    dst.push(src.strip_prefix(&from).unwrap());
+                    if offset == 0 {
+                        fs::create_dir_all(dst.parent().unwrap())?;
+                        // Leftovers of an earlier attempt may hold other data
+                        fc.remove(dst).ok();
+                        // On the same filesystem the data is shared rather than copied
+                        if fs::hard_link(&*src, &*dst).is_ok() {
+                            file += 1;
+                            continue;
                         }
                     }
-                    Err(e) => {
-                        error!("FS rename failed: {:?}", e);
-                        return Err(e);
+                    let n = cmp::min(len - offset, buf.len() as u64) as usize;
+                    fc.read(src, offset, &mut buf[..n])?;
+                    // Runs of zeroes are left as holes, like in the original
+                    if buf[..n].iter().any(|&b| b != 0) {
+                        fc.write(dst, Err(length), offset, &buf[..n])?;
+                    } else {
+                        fc.open(dst, Err(length))?;
+                    }
+                    offset += n as u64;
+                }
+                if file == info.files.len() {
+                    return Ok(JobRes::Resp(Response::RelocationCopied { tid }));
+                }
+                let done = info.files[..file].iter().map(|f| f.length).sum::<u64>() + offset;
+                let percent = done as f32 / info.total_len as f32;
+                return Ok(JobRes::Update(
+                    Request::Relocate {
+                        tid,
+                        info,
+                        from,
+                        to,
+                        file,
+                        offset,
+                    },
+                    Response::RelocationUpdate { tid, percent },
+                ));
+            }
+            Request::FinishRelocation {
+                tid,
+                info,
+                from,
+                to,
+                pieces,
+            } => {
+                let buf = tb.get(info.piece_len as usize);
+                for piece in pieces {
+                    for loc in Info::piece_disk_locs(&info, piece).filter(|l| !l.pad()) {
+                        let src = tpb.get(&from);
+                        src.push(loc.path());
+                        staged(fc, src);
+                        if !fc.exists(src) {
+                            continue;
+                        }
+                        let dst = tpb2.get(&to);
+                        dst.push(src.strip_prefix(&from).unwrap());
+                        let data = &mut buf[loc.start..loc.end];
+                        fc.read(src, loc.offset, data)?;
+                        fc.write(dst, Err(loc.file_len), loc.offset, data)?;
+                    }
+                }
+                fc.sync_all();
+                for file in info.files.iter().filter(|f| !f.pad) {
+                    let src = tpb.get(&from);
+                    src.push(&file.path);
+                    staged(fc, src);
+                    if !fc.exists(src) {
+                        continue;
+                    }
+                    let dst = tpb2.get(&to);
+                    dst.push(src.strip_prefix(&from).unwrap());
+                    if !fc.exists(dst) {
+                        // Completed files were renamed from their part file meanwhile
+                        let mut part = dst.clone();
+                        part_path(&mut part);
+                        if fc.exists(&part) {
+                            fc.rename(&part, dst)?;
+                        } else {
+                            fs::create_dir_all(dst.parent().unwrap())?;
+                            link_file(src, dst, true)?;
+                        }
                     }
+                    fc.remove(src)?;
+                    prune_dirs(src, Path::new(&from));
                 }
                 return Ok(JobRes::Resp(Response::moved(tid, to)));
             }
@@ -388,7 +1245,8 @@ impl Request {
                 f.write_all(&data)?;
                 let actual = tpb2.get(sd);
                 actual.push(hash_to_id(&hash));
-                fs::rename(temp, actual)?;
+                fs::rename(temp, &actual)?;
+                sync_dir(actual);
             }
             Request::Delete {
                 hash,
@@ -403,15 +1261,26 @@ impl Request {
                     fs::remove_file(&spb).ok();
                     spb.set_extension("torrent");
                     fs::remove_file(&spb).ok();
+                    spb.set_extension("parts");
+                    fc.remove(spb).ok();
+                    spb.set_extension("resume");
+                    fs::remove_file(&spb).ok();
                 }
 
                 for file in &files {
                     let pb = tpb2.get(path.as_ref().unwrap_or(dd));
                     pb.push(&file);
-                    fc.remove_file(&pb);
-                    if artifacts {
-                        if let Err(e) = fs::remove_file(&pb) {
-                            debug!("Failed to delete file: {:?}, {}", pb, e);
+                    if !artifacts {
+                        fc.close(pb);
+                    } else if let Err(e) = fc.remove(pb) {
+                        debug!("Failed to delete file: {:?}, {}", pb, e);
+                    }
+                    if CONFIG.disk.part_suffix {
+                        part_path(pb);
+                        if !artifacts {
+                            fc.close(pb);
+                        } else {
+                            fc.remove(pb).ok();
                         }
                     }
                 }
@@ -427,56 +1296,77 @@ impl Request {
             Request::ValidatePiece {
                 tid,
                 info,
+                priorities,
                 path,
                 piece,
             } => {
                 let buf = tb.get(info.piece_len as usize);
                 let mut ctx = Sha1::new();
-                let locs = Info::piece_disk_locs(&info, piece);
+                let locs = Info::piece_disk_locs_pri(&info, &priorities, piece);
                 for loc in locs {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
+                    staged(fc, pb);
+                    loc.read(fc, pb, &mut buf[loc.start..loc.end])
                         .map(|_| ctx.update(&buf[loc.start..loc.end]))
                         .ok();
                 }
-                let digest = ctx.finalize();
-                return Ok(JobRes::Resp(Response::PieceValidated {
-                    tid,
-                    piece,
-                    valid: digest[..] == info.hashes[piece as usize][..],
-                }));
+                let valid = ctx.finalize()[..] == info.hashes[piece as usize][..];
+                if valid && CONFIG.disk.sync == SyncPolicy::Piece {
+                    for loc in Info::piece_disk_locs_pri(&info, &priorities, piece) {
+                        let pb = if loc.part {
+                            let pb = tpb.get(sd);
+                            pb.push(loc.part_file());
+                            pb
+                        } else {
+                            let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                            pb.push(loc.path());
+                            staged(fc, pb);
+                            pb
+                        };
+                        fc.sync(pb);
+                    }
+                }
+                return Ok(JobRes::Resp(Response::PieceValidated { tid, piece, valid }));
             }
             Request::Validate {
                 tid,
                 info,
+                priorities,
                 path,
                 mut idx,
                 mut invalid,
             } => {
                 let buf = tb.get(info.piece_len as usize);
                 let start = time::Instant::now();
+                let mut read = 0;
+                let mut intact = Vec::new();
 
                 while idx < info.pieces()
                     && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                    && read < recheck_quota()
                 {
                     let mut valid = true;
                     let mut ctx = Sha1::new();
-                    let locs = Info::piece_disk_locs(&info, idx);
+                    let locs = Info::piece_disk_locs_pri(&info, &priorities, idx);
                     for loc in locs {
                         if !valid {
                             break;
                         }
+                        read += (loc.end - loc.start) as u64;
                         let pb = tpb.get(path.as_ref().unwrap_or(dd));
                         pb.push(loc.path());
-                        valid &= fc
-                            .read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
+                        staged(fc, pb);
+                        valid &= loc
+                            .read(fc, pb, &mut buf[loc.start..loc.end])
                             .map(|_| ctx.update(&buf[loc.start..loc.end]))
                             .is_ok();
                     }
                     let digest = ctx.finalize();
                     if !valid || digest[..] != info.hashes[idx as usize][..] {
                         invalid.push(idx);
+                    } else {
+                        intact.push(idx);
                     }
 
                     idx += 1;
@@ -485,18 +1375,122 @@ impl Request {
                     return Ok(JobRes::Resp(Response::validation_complete(tid, invalid)));
                 } else {
                     let pieces = info.pieces();
-                    return Ok(JobRes::Update(
+                    return Ok(recheck_update(
                         Request::Validate {
                             tid,
                             info,
+                            priorities,
                             path,
                             idx,
                             invalid,
                         },
-                        Response::ValidationUpdate {
+                        Response::ValidationUpdate(Progress {
                             tid,
                             percent: idx as f32 / pieces as f32,
+                            intact,
+                        }),
+                        start,
+                        read,
+                    ));
+                }
+            }
+            Request::Repair {
+                tid,
+                info,
+                priorities,
+                path,
+                check,
+                pieces,
+                mut idx,
+                mut invalid,
+            } => {
+                let pieces = match pieces {
+                    Some(pieces) => pieces,
+                    None => {
+                        // Pieces spanning missing or truncated files can't be intact,
+                        // so only the rest need hashing.
+                        let mut damaged = Vec::with_capacity(info.files.len());
+                        for file in &info.files {
+                            if file.pad {
+                                damaged.push(false);
+                                continue;
+                            }
+                            let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                            pb.push(&file.path);
+                            staged(fc, pb);
+                            // Drop any handle to a file which was deleted from under us
+                            fc.close(pb);
+                            damaged.push(!matches!(fc.len(pb), Some(len) if len >= file.length));
+                        }
+                        let mut pieces = Vec::new();
+                        for piece in 0..info.pieces() {
+                            let files: Vec<_> = Info::piece_disk_locs(&info, piece)
+                                .map(|l| l.file)
+                                .collect();
+                            if files.iter().any(|&f| damaged[f]) {
+                                invalid.push(piece);
+                            } else if files.iter().any(|&f| check[f]) {
+                                pieces.push(piece);
+                            }
+                        }
+                        pieces
+                    }
+                };
+
+                let buf = tb.get(info.piece_len as usize);
+                let start = time::Instant::now();
+                let mut read = 0;
+                let mut intact = Vec::new();
+                while idx < pieces.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                    && read < recheck_quota()
+                {
+                    let piece = pieces[idx];
+                    let mut valid = true;
+                    let mut ctx = Sha1::new();
+                    for loc in Info::piece_disk_locs_pri(&info, &priorities, piece) {
+                        if !valid {
+                            break;
+                        }
+                        read += (loc.end - loc.start) as u64;
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        staged(fc, pb);
+                        valid &= loc
+                            .read(fc, pb, &mut buf[loc.start..loc.end])
+                            .map(|_| ctx.update(&buf[loc.start..loc.end]))
+                            .is_ok();
+                    }
+                    let digest = ctx.finalize();
+                    if !valid || digest[..] != info.hashes[piece as usize][..] {
+                        invalid.push(piece);
+                    } else {
+                        intact.push(piece);
+                    }
+                    idx += 1;
+                }
+                if idx == pieces.len() {
+                    return Ok(JobRes::Resp(Response::RepairComplete { tid, invalid }));
+                } else {
+                    let percent = idx as f32 / pieces.len() as f32;
+                    return Ok(recheck_update(
+                        Request::Repair {
+                            tid,
+                            info,
+                            priorities,
+                            path,
+                            check,
+                            pieces: Some(pieces),
+                            idx,
+                            invalid,
                         },
+                        Response::ValidationUpdate(Progress {
+                            tid,
+                            percent,
+                            intact,
+                        }),
+                        start,
+                        read,
                     ));
                 }
             }
@@ -537,7 +1531,7 @@ impl Request {
                     let chunk_len = cmp::min(1024 * 128, cur_range.length) as usize;
                     buf.resize(chunk_len, 0);
                     buf_idx = 0;
-                    fc.read_file_range(path::Path::new(&file_path), cur_range.start, &mut buf)?;
+                    fc.read(path::Path::new(&file_path), cur_range.start, &mut buf)?;
                     cur_range.length -= buf.len() as u64;
                     cur_range.start += buf.len() as u64;
 
@@ -599,8 +1593,22 @@ impl Request {
             Request::Serialize { tid, .. }
             | Request::Validate { tid, .. }
             | Request::ValidatePiece { tid, .. }
+            | Request::Repair { tid, .. }
+            | Request::CheckSizes { tid, .. }
+            | Request::Allocate { tid, .. }
+            | Request::FinishFile { tid, .. }
+            | Request::Rename { tid, .. }
+            | Request::Link { tid, .. }
+            | Request::Memory { tid, .. }
+            | Request::SaveResume { tid, .. }
+            | Request::CheckResume { tid, .. }
+            | Request::CheckModified { tid, .. }
+            | Request::CheckSpace { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. }
+            | Request::Relocate { tid, .. }
+            | Request::FinishRelocation { tid, .. }
+            | Request::MoveParts { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
             | Request::Download { .. }
@@ -617,6 +1625,181 @@ impl fmt::Debug for Request {
     }
 }
 
+/// Puts a copy of src at dst, sharing its data through a hard link or a
+/// copy-on-write clone where the filesystem allows, and copying it
+/// otherwise.
+fn link_file(src: &Path, dst: &Path, hardlink: bool) -> io::Result<()> {
+    if hardlink && fs::hard_link(src, dst).is_ok() {
+        return Ok(());
+    }
+    let mut from = fs::File::open(src)?;
+    let mut to = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    if !hardlink && unsafe { libc::ioctl(to.as_raw_fd(), FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    io::copy(&mut from, &mut to)?;
+    Ok(())
+}
+
+/// Removes the folders leading up to a removed file which it left empty,
+/// stopping at root.
+fn prune_dirs(file: &Path, root: &Path) {
+    for dir in file.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Points a path at the file's `.part` copy, if `disk.part_suffix` is
+/// set and the file hasn't been completed and renamed into place yet.
+fn staged(fc: &dyn Storage, pb: &mut PathBuf) {
+    if !CONFIG.disk.part_suffix || fc.is_open(pb) {
+        return;
+    }
+    let mut part = pb.clone();
+    part_path(&mut part);
+    if fc.is_open(&part) || !fc.exists(pb) {
+        // In place, the buffer is reused by later jobs
+        part_path(pb);
+    }
+}
+
+/// Writes out a piece buffered by the write cache, in one write per
+/// contiguous run of data in a file.
+pub fn write_dirty(fc: &mut dyn Storage, bc: &mut BufCache, piece: DirtyPiece) -> io::Result<()> {
+    let sd = &CONFIG.disk.session;
+    let dir = piece.path.as_ref().unwrap_or(&CONFIG.disk.directory);
+    let (_, mut tpb, _) = bc.data();
+    for loc in piece.locations.filter(|loc| !loc.pad()) {
+        for &(start, end) in &piece.ranges {
+            let (start, end) = (start.max(loc.start), end.min(loc.end));
+            if start >= end {
+                continue;
+            }
+            let data = &piece.data[start..end];
+            let delta = (start - loc.start) as u64;
+            if loc.part {
+                let pb = tpb.get(sd);
+                pb.push(loc.part_file());
+                fc.write(pb, Err(0), loc.torrent_offset + delta, data)?;
+                continue;
+            }
+            let pb = tpb.get(dir);
+            pb.push(loc.path());
+            staged(fc, pb);
+            let size = if loc.allocate && CONFIG.disk.allocation != Allocation::Sparse {
+                Ok(loc.file_len)
+            } else {
+                Err(loc.file_len)
+            };
+            fc.write(pb, size, loc.offset + delta, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bytes a recheck may hash per time slice, which is unlimited unless
+/// `disk.recheck_rate` is set.
+fn recheck_quota() -> u64 {
+    match CONFIG.disk.recheck_rate {
+        0 => u64::MAX,
+        rate => rate * 1024 * 1024 * JOB_TIME_SLICE / 1000,
+    }
+}
+
+/// Continues a recheck which read this much since it last resumed, once
+/// doing so keeps it within `disk.recheck_rate`.
+fn recheck_update(job: Request, resp: Response, start: time::Instant, read: u64) -> JobRes {
+    let rate = CONFIG.disk.recheck_rate * 1024 * 1024;
+    if rate == 0 {
+        return JobRes::Update(job, resp);
+    }
+    let until = start + time::Duration::from_micros(read * 1_000_000 / rate);
+    if until <= time::Instant::now() {
+        return JobRes::Update(job, resp);
+    }
+    JobRes::Throttled(job, resp, until)
+}
+
+/// Reads a block or piece into buf.
+fn read_locs(
+    fc: &mut dyn Storage,
+    tpb: &mut TempPB<'_>,
+    locations: LocIter,
+    dir: &str,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    for loc in locations {
+        let pb = tpb.get(dir);
+        pb.push(loc.path());
+        staged(fc, pb);
+        loc.read(fc, pb, &mut buf[loc.start..loc.end])?;
+    }
+    Ok(())
+}
+
+/// The stamps of each of a torrent's files, none for padding files.
+fn stamps(fc: &dyn Storage, tpb: &mut TempPB<'_>, info: &Info, dir: &str) -> Vec<Option<Stamp>> {
+    info.files
+        .iter()
+        .map(|file| {
+            if file.pad {
+                return None;
+            }
+            let pb = tpb.get(dir);
+            pb.push(&file.path);
+            staged(fc, pb);
+            stamp(pb)
+        })
+        .collect()
+}
+
+/// The fast resume data last saved for a torrent, if any.
+fn read_resume(tpb: &mut TempPB<'_>, hash: &[u8; 20]) -> Option<Resume> {
+    let rp = tpb.get(&CONFIG.disk.session);
+    rp.push(hash_to_id(hash) + ".resume");
+    let data = fs::read(&rp).ok()?;
+    bincode::deserialize(&data).ok()
+}
+
+fn write_resume(
+    tpb: &mut TempPB<'_>,
+    tpb2: &mut TempPB<'_>,
+    hash: &[u8; 20],
+    resume: Resume,
+) -> io::Result<()> {
+    let sd = &CONFIG.disk.session;
+    let data = bincode::serialize(&resume).expect("Serialization failed!");
+    let temp = tpb.get(sd);
+    temp.push(hash_to_id(hash) + ".resume.temp");
+    fs::write(&temp, &data)?;
+    let actual = tpb2.get(sd);
+    actual.push(hash_to_id(hash) + ".resume");
+    fs::rename(temp, &actual)?;
+    sync_dir(actual);
+    Ok(())
+}
+
+/// The size and modification time of a file, if it exists.
+fn stamp(pb: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(pb).ok()?;
+    Some(Stamp {
+        len: meta.len(),
+        mtime_secs: meta.mtime(),
+        mtime_nanos: meta.mtime_nsec() as u32,
+    })
+}
+
+fn part_path(pb: &mut PathBuf) {
+    let mut name = pb.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    pb.set_file_name(name);
+}
+
 impl Location {
     pub fn new(
         file: usize,
@@ -626,6 +1809,8 @@ impl Location {
         end: u64,
         info: Arc<Info>,
         allocate: bool,
+        torrent_offset: u64,
+        part: bool,
     ) -> Location {
         Location {
             file,
@@ -635,12 +1820,40 @@ impl Location {
             end: end as usize,
             info,
             allocate,
+            torrent_offset,
+            part,
         }
     }
 
     pub fn path(&self) -> &Path {
         &self.info.files[self.file].path
     }
+
+    /// Name of the torrent's part file in the session directory.
+    fn part_file(&self) -> String {
+        hash_to_id(&self.info.hash) + ".parts"
+    }
+
+    /// Whether this location lies in a padding file.
+    pub fn pad(&self) -> bool {
+        self.info.files[self.file].pad
+    }
+
+    /// Reads the location's data into buf, synthesizing padding. Data of
+    /// skipped files is read from the part file instead of path.
+    fn read(&self, fc: &mut dyn Storage, path: &mut PathBuf, buf: &mut [u8]) -> io::Result<()> {
+        if self.pad() {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            return Ok(());
+        }
+        if self.part {
+            *path = Path::new(&CONFIG.disk.session).join(self.part_file());
+            return fc.read(path, self.torrent_offset, buf);
+        }
+        fc.read(path, self.offset, buf)
+    }
 }
 
 impl fmt::Debug for Location {
@@ -673,9 +1886,19 @@ impl Response {
     pub fn tid(&self) -> usize {
         match *self {
             Response::Read { ref context, .. } => context.tid,
+            Response::SizesChecked(ref check) => check.tid,
             Response::ValidationComplete { tid, .. }
+            | Response::RepairComplete { tid, .. }
             | Response::Moved { tid, .. }
-            | Response::ValidationUpdate { tid, .. }
+            | Response::ValidationUpdate(Progress { tid, .. })
+            | Response::AllocationUpdate { tid, .. }
+            | Response::AllocationComplete { tid }
+            | Response::RelocationUpdate { tid, .. }
+            | Response::RelocationCopied { tid }
+            | Response::ResumeChecked { tid, .. }
+            | Response::FilesModified { tid, .. }
+            | Response::SpaceChecked { tid, .. }
+            | Response::Linked { tid, .. }
             | Response::PieceValidated { tid, .. }
             | Response::Error { tid, .. } => tid,
             Response::FreeSpace(_) => unreachable!(),
@@ -683,6 +1906,16 @@ impl Response {
     }
 }
 
+/// The code a disk error is reported to RPC clients with.
+pub fn error_code(err: &io::Error) -> ErrorCode {
+    match err.kind() {
+        io::ErrorKind::NotFound => ErrorCode::DiskNotFound,
+        io::ErrorKind::PermissionDenied => ErrorCode::DiskPermissionDenied,
+        _ if err.raw_os_error() == Some(ENOSPC) => ErrorCode::DiskFull,
+        _ => ErrorCode::DiskIo,
+    }
+}
+
 impl fmt::Debug for Response {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "disk::Response")
@@ -0,0 +1,207 @@
+//! Storage keeping torrent data in memory rather than on disk, for
+//! torrents added with `memory` set. Its contents are lost on restart,
+//! and all of them together are limited to `disk.memory_limit`, past
+//! which writes fail as if the disk were full.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::libc;
+
+use super::Storage;
+use crate::util::MHashMap;
+use crate::CONFIG;
+
+pub struct MemStorage {
+    files: MHashMap<PathBuf, File>,
+    /// Bytes held by all files
+    used: u64,
+    limit: u64,
+}
+
+#[derive(Default)]
+struct File {
+    /// Data written so far, the rest of the file reads as zeroes
+    data: Vec<u8>,
+    len: u64,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::with_limit(CONFIG.disk.memory_limit * 1024 * 1024)
+    }
+
+    fn with_limit(limit: u64) -> MemStorage {
+        MemStorage {
+            files: MHashMap::default(),
+            used: 0,
+            limit,
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<&File> {
+        self.files
+            .get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&mut self, path: &Path, size: Result<u64, u64>) -> io::Result<()> {
+        let f = self.files.entry(path.to_path_buf()).or_default();
+        // Like sparse files, nothing is allocated until it's written
+        let len = size.unwrap_or_else(|len| len);
+        if f.len < len {
+            f.len = len;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, path: &Path, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let f = self.file(path)?;
+        if offset + buf.len() as u64 > f.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the file",
+            ));
+        }
+        let start = (offset as usize).min(f.data.len());
+        let end = (offset as usize + buf.len()).min(f.data.len());
+        let (held, rest) = buf.split_at_mut(end - start);
+        held.copy_from_slice(&f.data[start..end]);
+        for b in rest {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        size: Result<u64, u64>,
+        offset: u64,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        self.open(path, size)?;
+        let end = offset as usize + buf.len();
+        let f = self.files.get_mut(path).unwrap();
+        if end > f.data.len() {
+            let grown = (end - f.data.len()) as u64;
+            if self.used + grown > self.limit {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            self.used += grown;
+            f.data.resize(end, 0);
+        }
+        f.data[offset as usize..end].copy_from_slice(buf);
+        f.len = f.len.max(end as u64);
+        Ok(())
+    }
+
+    fn sync(&mut self, _: &Path) {}
+
+    fn sync_all(&mut self) {}
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let moved: Vec<_> = self
+            .files
+            .keys()
+            .filter(|p| p.starts_with(from))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "file not found"));
+        }
+        for p in moved {
+            let f = self.files.remove(&p).unwrap();
+            match p.strip_prefix(from).unwrap() {
+                rel if rel.as_os_str().is_empty() => self.files.insert(to.to_path_buf(), f),
+                rel => self.files.insert(to.join(rel), f),
+            };
+        }
+        Ok(())
+    }
+
+    // Closing a file mustn't lose its data
+    fn close(&mut self, _: &Path) {}
+
+    fn close_dir(&mut self, _: &Path) {}
+
+    fn is_open(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn len(&self, path: &Path) -> Option<u64> {
+        self.files.get(path).map(|f| f.len)
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let f = self
+            .files
+            .remove(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        self.used -= f.data.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let mut ms = MemStorage::with_limit(1 << 20);
+        let path = Path::new("/dl/a");
+        let mut buf = [1u8; 8];
+        assert_eq!(
+            ms.read(path, 0, &mut buf).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        ms.write(path, Err(64), 16, &[7; 8]).unwrap();
+        assert_eq!(ms.len(path), Some(64));
+        ms.read(path, 12, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 7, 7, 7, 7]);
+        // Unwritten data past what's held reads as zeroes
+        ms.read(path, 56, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+        assert!(ms.read(path, 60, &mut buf).is_err());
+        ms.remove(path).unwrap();
+        assert!(!ms.exists(path));
+        assert_eq!(ms.used, 0);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut ms = MemStorage::with_limit(1 << 20);
+        ms.write(Path::new("/dl/t/a"), Err(4), 0, b"abcd").unwrap();
+        ms.write(Path::new("/dl/t/b/c"), Err(4), 0, b"efgh")
+            .unwrap();
+        ms.rename(Path::new("/dl/t/a"), Path::new("/dl/t/d"))
+            .unwrap();
+        assert!(!ms.exists(Path::new("/dl/t/a")));
+        ms.rename(Path::new("/dl/t"), Path::new("/new/t")).unwrap();
+        let mut buf = [0u8; 4];
+        ms.read(Path::new("/new/t/d"), 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        ms.read(Path::new("/new/t/b/c"), 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"efgh");
+        assert!(ms.rename(Path::new("/dl/t"), Path::new("/x")).is_err());
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut ms = MemStorage::with_limit(16);
+        ms.write(Path::new("a"), Err(32), 0, &[1; 12]).unwrap();
+        let err = ms.write(Path::new("b"), Err(8), 0, &[1; 8]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        // Overwriting what's already held takes no more memory
+        ms.write(Path::new("a"), Err(32), 4, &[2; 8]).unwrap();
+        ms.remove(Path::new("a")).unwrap();
+        ms.write(Path::new("b"), Err(8), 0, &[1; 8]).unwrap();
+    }
+}
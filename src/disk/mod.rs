@@ -1,29 +1,57 @@
 mod cache;
 mod job;
+mod memory;
+mod sched;
+mod storage;
+mod uring;
 
+pub use self::job::error_code;
 pub use self::job::Ctx;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::storage::Storage;
 
 use std::collections::VecDeque;
-use std::{fs, io, thread};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Instant;
+use std::{fs, io, mem, thread};
 
-use self::cache::{BufCache, FileCache};
+use self::cache::{BufCache, FileCache, ReadCache, WriteCache};
 use self::job::JobRes;
+use self::memory::MemStorage;
+use self::sched::Sched;
+use self::uring::Ring;
+use crate::config::SyncPolicy;
+use crate::util::{FHashMap, UHashSet};
 use crate::{handle, CONFIG};
 
 const POLL_INT_MS: usize = 1000;
 const JOB_TIME_SLICE: u64 = 150;
+/// Operations queued on the io_uring at once, which also caps the jobs
+/// completed together
+const QUEUE_DEPTH: u32 = 64;
 
 pub struct Disk {
     poll: amy::Poller,
     ch: handle::Handle<Request, Response>,
     jobs: amy::Receiver<Request>,
     files: FileCache,
-    active: VecDeque<Request>,
+    memory: MemStorage,
+    /// Torrents whose data is kept in memory
+    in_memory: UHashSet,
+    active: Sched,
     sequential: VecDeque<Request>,
     bufs: BufCache,
+    reads: ReadCache,
+    writes: WriteCache,
+    last_sync: Instant,
+    ring: Option<Ring>,
+    /// Jobs whose I/O is queued on the ring, and the first error each hit
+    batch: Vec<(Option<usize>, Request, Option<io::Error>)>,
+    /// Recheck waiting to stay within `disk.recheck_rate`, and until when
+    throttled: Option<(Instant, Request)>,
 }
 
 impl Disk {
@@ -37,18 +65,39 @@ impl Disk {
             ch,
             jobs,
             files: FileCache::new(),
+            memory: MemStorage::new(),
+            in_memory: UHashSet::default(),
             bufs: BufCache::new(),
-            active: VecDeque::new(),
+            reads: ReadCache::new(),
+            writes: WriteCache::new(),
+            last_sync: Instant::now(),
+            active: Sched::new(),
             sequential: VecDeque::new(),
+            ring: None,
+            batch: Vec::new(),
+            throttled: None,
         }
     }
 
     pub fn run(&mut self) {
         let sd = &CONFIG.disk.session;
         fs::create_dir_all(sd).unwrap();
+        if CONFIG.disk.io_uring {
+            match Ring::new(QUEUE_DEPTH) {
+                Ok(ring) => self.ring = Some(ring),
+                Err(e) => error!("io_uring unavailable, using synchronous I/O: {}", e),
+            }
+        }
 
         loop {
-            match self.poll.wait(POLL_INT_MS) {
+            let timeout = match self.throttled {
+                Some((until, _)) => {
+                    let wait = until.saturating_duration_since(Instant::now());
+                    (wait.as_millis() as usize).min(POLL_INT_MS)
+                }
+                None => POLL_INT_MS,
+            };
+            match self.poll.wait(timeout) {
                 Ok(_) => {
                     if self.handle_events() {
                         break;
@@ -58,52 +107,117 @@ impl Disk {
                     error!("Failed to poll for events: {}", e);
                 }
             }
+            if let Some((until, _)) = self.throttled {
+                if until <= Instant::now() {
+                    let (_, job) = self.throttled.take().unwrap();
+                    self.active.push(job);
+                }
+            }
             if !self.active.is_empty() && self.handle_active() {
                 break;
             }
+            if self.writes.due() {
+                self.flush_writes(None, None);
+            }
+            if CONFIG.disk.sync == SyncPolicy::Periodic
+                && self.last_sync.elapsed().as_secs() >= CONFIG.disk.sync_interval
+            {
+                self.files.sync_all();
+                self.last_sync = Instant::now();
+            }
         }
 
         // Try to finish up remaining jobs
-        for job in self.active.drain(..) {
+        self.complete_batch();
+        self.flush_writes(None, None);
+        for job in self.active.drain() {
             if job.concurrent() {
-                job.execute(&mut self.files, &mut self.bufs).ok();
+                let fc = storage(
+                    &mut self.files,
+                    &mut self.memory,
+                    &self.in_memory,
+                    job.tid(),
+                );
+                job.execute(fc, &mut self.bufs, &mut self.reads).ok();
             }
         }
     }
 
     fn enqueue_req(&mut self, req: Request) {
-        if req.concurrent() || self.active.iter().find(|r| !r.concurrent()).is_none() {
-            self.active.push_back(req);
+        if let Request::Memory { tid } = req {
+            self.in_memory.insert(tid);
+            return;
+        }
+        if req.concurrent() || (!self.active.sequential() && self.throttled.is_none()) {
+            self.active.push(req);
         } else {
             self.sequential.push_back(req);
         }
     }
 
     fn handle_active(&mut self) -> bool {
-        let mut rotate = 1;
-        while let Some(j) = self.active.pop_front() {
-            let tid = j.tid();
+        while let Some((tid, j)) = self.active.pop() {
+            j.invalidate(&mut self.reads);
+            if let Some((t, piece)) = j.flushes() {
+                self.flush_writes(Some(t), piece);
+            }
+            let mut j = if self.writes.enabled() {
+                match j.buffer(&mut self.writes) {
+                    Some(j) => j,
+                    None => {
+                        if self.writes.full() || self.writes.due() {
+                            self.flush_writes(None, None);
+                        }
+                        if self.poll_events() {
+                            return true;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                j
+            };
+            let memory = matches!(tid, Some(t) if self.in_memory.contains(&t));
+            if let Some(ring) = self.ring.as_mut().filter(|_| j.batched() && !memory) {
+                let token = self.batch.len() as u64;
+                let err = j.submit(&mut self.files, &mut self.bufs, ring, token).err();
+                let failed = ring.failed();
+                self.batch.push((tid, j, err));
+                if failed || self.batch.len() >= QUEUE_DEPTH as usize {
+                    self.complete_batch();
+                }
+                if self.poll_events() {
+                    return true;
+                }
+                continue;
+            }
+            // Other jobs may depend on the batch's writes
+            self.complete_batch();
             let seq = !j.concurrent();
+            let deleted = matches!(j, Request::Delete { .. });
             let mut done = false;
-            match j.execute(&mut self.files, &mut self.bufs) {
+            let start = Instant::now();
+            let fc = storage(&mut self.files, &mut self.memory, &self.in_memory, tid);
+            let res = j.execute(fc, &mut self.bufs, &mut self.reads);
+            if let (true, Some(t)) = (deleted, tid) {
+                self.in_memory.remove(&t);
+            }
+            let elapsed = start.elapsed();
+            match res {
                 Ok(JobRes::Resp(r)) => {
                     done = true;
                     self.ch.send(r).ok();
                 }
                 Ok(JobRes::Update(s, r)) => {
                     self.ch.send(r).ok();
-                    if rotate % 3 == 0 {
-                        self.active.push_back(s);
-                    } else {
-                        self.active.push_front(s);
-                    }
+                    self.active.push(s);
+                }
+                Ok(JobRes::Throttled(s, r, until)) => {
+                    self.ch.send(r).ok();
+                    self.throttled = Some((until, s));
                 }
                 Ok(JobRes::Paused(s)) => {
-                    if rotate % 3 == 0 {
-                        self.active.push_back(s);
-                    } else {
-                        self.active.push_front(s);
-                    }
+                    self.active.push(s);
                 }
                 Ok(JobRes::Done) => {
                     done = true;
@@ -117,24 +231,98 @@ impl Disk {
                     }
                 }
             }
+            // Charge after requeueing so continuations keep their debt
+            self.active.charge(tid, elapsed);
             if done && seq {
                 if let Some(r) = self.sequential.pop_front() {
-                    self.active.push_back(r);
+                    self.active.push(r);
                 }
             }
-            match self.poll.wait(0) {
-                Ok(_) => {
-                    if self.handle_events() {
-                        return true;
+            if self.poll_events() {
+                return true;
+            }
+        }
+        self.complete_batch();
+        false
+    }
+
+    /// Handles any new events, returning whether the disk thread should
+    /// shut down.
+    fn poll_events(&mut self) -> bool {
+        match self.poll.wait(0) {
+            Ok(_) => self.handle_events(),
+            Err(e) => {
+                error!("Failed to poll for events: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Writes out buffered pieces, see `WriteCache::take`.
+    fn flush_writes(&mut self, tid: Option<usize>, piece: Option<u32>) {
+        let dirty = self.writes.take(tid, piece);
+        if dirty.is_empty() {
+            return;
+        }
+        // Queued I/O may refer to files which would otherwise be closed
+        self.complete_batch();
+        for dirty in dirty {
+            let t = dirty.tid;
+            let fc = storage(&mut self.files, &mut self.memory, &self.in_memory, Some(t));
+            if let Err(e) = job::write_dirty(fc, &mut self.bufs, dirty) {
+                self.ch.send(Response::error(t, e)).ok();
+            }
+        }
+    }
+
+    /// Waits for the batched jobs' I/O and sends their results. Should the
+    /// ring fail, the jobs are failed and further I/O is done synchronously.
+    fn complete_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let mut batch = mem::take(&mut self.batch);
+        let ring = self.ring.as_mut().unwrap();
+        let start = Instant::now();
+        if let Err(e) = ring.wait() {
+            error!("io_uring failed, using synchronous I/O: {}", e);
+            for job in &mut batch {
+                job.2
+                    .get_or_insert_with(|| io::Error::new(e.kind(), e.to_string()));
+            }
+        }
+        for (token, res) in ring.completions() {
+            if let Err(e) = res {
+                batch[token as usize].2.get_or_insert(e);
+            }
+        }
+        // The kernel may still be using a failed ring's buffers, and closing
+        // the ring doesn't guarantee it stops, so both are leaked
+        let failed = ring.failed();
+        if failed {
+            mem::forget(self.ring.take());
+        }
+        let elapsed = start.elapsed() / batch.len() as u32;
+        for (tid, job, err) in batch {
+            self.active.charge(tid, elapsed);
+            match err {
+                None => {
+                    if let JobRes::Resp(r) = job.finish() {
+                        self.ch.send(r).ok();
                     }
                 }
-                Err(e) => {
-                    error!("Failed to poll for events: {:?}", e);
+                Some(e) => {
+                    if let Some(t) = tid {
+                        self.ch.send(Response::error(t, e)).ok();
+                    } else {
+                        error!("Disk job failed: {}", e);
+                    }
+                    if failed {
+                        mem::forget(job);
+                    }
                 }
             }
-            rotate += 1;
         }
-        false
     }
 
     pub fn handle_events(&mut self) -> bool {
@@ -166,6 +354,123 @@ impl Disk {
     }
 }
 
+/// The storage holding a torrent's data.
+fn storage<'a>(
+    files: &'a mut FileCache,
+    memory: &'a mut MemStorage,
+    in_memory: &UHashSet,
+    tid: Option<usize>,
+) -> &'a mut dyn Storage {
+    match tid {
+        Some(t) if in_memory.contains(&t) => memory,
+        _ => files,
+    }
+}
+
+/// Routes jobs between several disk threads. Each torrent's jobs go to
+/// the worker serving the device its files are on, so that a slow device
+/// only holds up its own torrents while jobs still run in order per
+/// torrent and per device.
+struct Pool {
+    poll: amy::Poller,
+    ch: handle::Handle<Request, Response>,
+    jobs: amy::Receiver<Request>,
+    workers: Vec<Worker>,
+    /// Worker each torrent was assigned to
+    torrents: FHashMap<usize, usize>,
+    /// Worker serving each device
+    devices: FHashMap<u64, usize>,
+}
+
+struct Worker {
+    jobs: amy::Sender<Request>,
+    broadcast: amy::Sender<Request>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Pool {
+    fn run(&mut self) {
+        'outer: loop {
+            if let Err(e) = self.poll.wait(POLL_INT_MS) {
+                error!("Failed to poll for events: {}", e);
+            }
+            while let Ok(r) = self.ch.recv() {
+                let w = match r {
+                    Request::Shutdown => break 'outer,
+                    Request::Ping => continue,
+                    ref r => self.route(r),
+                };
+                if self.workers[w].jobs.send(r).is_err() {
+                    error!("Disk worker crashed!");
+                    break 'outer;
+                }
+            }
+            while let Ok(r) = self.jobs.try_recv() {
+                let w = self.route(&r);
+                self.workers[w].broadcast.send(r).ok();
+            }
+        }
+        for worker in &self.workers {
+            worker.jobs.send(Request::Shutdown).ok();
+        }
+        for worker in self.workers.drain(..) {
+            worker.thread.join().ok();
+        }
+    }
+
+    fn route(&mut self, req: &Request) -> usize {
+        let tid = req.tid();
+        // All of the memory is kept by the first worker
+        if let Request::Memory { tid } = *req {
+            self.torrents.insert(tid, 0);
+            return 0;
+        }
+        let deleted = matches!(req, Request::Delete { .. });
+        if let Some(t) = tid {
+            if let Some(&w) = self.torrents.get(&t) {
+                if deleted {
+                    self.torrents.remove(&t);
+                }
+                return w;
+            }
+        }
+        let dev = match req.dir() {
+            Some(dir) => Path::new(dir)
+                .ancestors()
+                .find_map(|p| fs::metadata(p).ok())
+                .map(|m| m.dev())
+                .unwrap_or(0),
+            None => return 0,
+        };
+        let next = self.devices.len() % self.workers.len();
+        let w = *self.devices.entry(dev).or_insert(next);
+        if let (Some(t), false) = (tid, deleted) {
+            self.torrents.insert(t, w);
+        }
+        w
+    }
+}
+
+impl Worker {
+    fn start(tx: &amy::Sender<Response>) -> io::Result<Worker> {
+        let poll = amy::Poller::new()?;
+        let mut reg = poll.get_registrar();
+        let (jobs, rx) = reg.channel()?;
+        let (broadcast, brx) = reg.channel()?;
+        let h = handle::Handle {
+            tx: tx.clone(),
+            rx,
+            reg,
+        };
+        let thread = h.run("disk worker", move |h| Disk::new(poll, h, brx).run())?;
+        Ok(Worker {
+            jobs,
+            broadcast,
+            thread,
+        })
+    }
+}
+
 pub fn start(
     creg: &mut amy::Registrar,
 ) -> io::Result<(
@@ -177,6 +482,66 @@ pub fn start(
     let mut reg = poll.get_registrar();
     let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
     let (tx, rx) = reg.channel()?;
-    let h = dh.run("disk", move |h| Disk::new(poll, h, rx).run())?;
+    let h = if CONFIG.disk.workers > 1 {
+        let workers = (0..CONFIG.disk.workers)
+            .map(|_| Worker::start(&dh.tx))
+            .collect::<io::Result<_>>()?;
+        dh.run("disk", move |h| {
+            Pool {
+                poll,
+                ch: h,
+                jobs: rx,
+                workers,
+                torrents: FHashMap::default(),
+                devices: FHashMap::default(),
+            }
+            .run()
+        })?
+    } else {
+        dh.run("disk", move |h| Disk::new(poll, h, rx).run())?
+    };
     Ok((ch, tx, h))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffers::Buffer;
+    use crate::torrent::fixtures::TorrentBuilder;
+    use crate::torrent::Info;
+    use nix::libc;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ring_failure() {
+        let ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            // Kernels without io_uring, or sandboxes which forbid it
+            Err(_) => return,
+        };
+        let poll = amy::Poller::new().unwrap();
+        let mut reg = poll.get_registrar();
+        let (mut ch, dh) = handle::Handle::new(&mut reg.clone(), &mut reg).unwrap();
+        let (_tx, rx) = reg.channel().unwrap();
+        let mut disk = Disk::new(poll, dh, rx);
+        disk.ring = Some(ring);
+
+        let dir = std::env::temp_dir().join(format!("synapse-ring-fail-{}", std::process::id()));
+        let info = TorrentBuilder::single("data", 16_384).build();
+        let locs = Info::block_disk_locs(&Arc::new(info), 0, 0);
+        let dir_s = dir.to_string_lossy().into_owned();
+        let mut job = Request::write(0, Buffer::get().unwrap(), locs, Some(dir_s));
+        let ring = disk.ring.as_mut().unwrap();
+        job.submit(&mut disk.files, &mut disk.bufs, ring, 0)
+            .unwrap();
+
+        // The job fails, and further I/O doesn't go through the ring
+        ring.fail(libc::EIO);
+        disk.batch.push((Some(0), job, None));
+        disk.complete_batch();
+        assert!(disk.ring.is_none());
+        assert!(disk.batch.is_empty());
+        assert_matches!(ch.recv(), Ok(Response::Error { tid: 0, .. }));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
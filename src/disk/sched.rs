@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::Request;
+use crate::util::FHashMap;
+
+/// Disk time, in microseconds, each torrent is credited per round
+const QUANTUM_US: i64 = 20_000;
+
+/// Deficit round robin scheduler for disk jobs.
+///
+/// Jobs are queued per torrent and each torrent is charged for the time its
+/// jobs spend executing, so a torrent with a long running recheck only gets
+/// its share of the disk rather than starving everyone else's writes.
+pub struct Sched {
+    queues: FHashMap<Option<usize>, TQueue>,
+    /// Torrents with pending jobs, in service order
+    order: VecDeque<Option<usize>>,
+}
+
+struct TQueue {
+    jobs: VecDeque<Request>,
+    deficit: i64,
+}
+
+impl Sched {
+    pub fn new() -> Sched {
+        Sched {
+            queues: FHashMap::default(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Whether any queued job must be run on its own.
+    pub fn sequential(&self) -> bool {
+        self.queues
+            .values()
+            .any(|q| q.jobs.iter().any(|j| !j.concurrent()))
+    }
+
+    pub fn push(&mut self, req: Request) {
+        let tid = req.tid();
+        let order = &mut self.order;
+        self.queues
+            .entry(tid)
+            .or_insert_with(|| {
+                order.push_back(tid);
+                TQueue {
+                    jobs: VecDeque::new(),
+                    deficit: QUANTUM_US,
+                }
+            })
+            .jobs
+            .push_back(req);
+    }
+
+    /// Returns the next job to execute, and the torrent to charge for it.
+    pub fn pop(&mut self) -> Option<(Option<usize>, Request)> {
+        // Verifying a piece is cheap and unblocks a torrent's downloads,
+        // so it's served ahead of everyone's turn.
+        let queues = &self.queues;
+        let urgent = self.order.iter().position(|tid| {
+            queues[tid]
+                .jobs
+                .front()
+                .map(Request::urgent)
+                .unwrap_or(false)
+        });
+        if let Some(pos) = urgent {
+            let tid = self.order[pos];
+            return self.take(tid).map(|j| (tid, j));
+        }
+
+        loop {
+            let tid = *self.order.front()?;
+            let q = self.queues.get_mut(&tid).unwrap();
+            if q.deficit > 0 {
+                return self.take(tid).map(|j| (tid, j));
+            }
+            q.deficit += QUANTUM_US;
+            self.order.rotate_left(1);
+        }
+    }
+
+    /// Charges a torrent for time spent on one of its jobs.
+    pub fn charge(&mut self, tid: Option<usize>, time: Duration) {
+        if let Some(q) = self.queues.get_mut(&tid) {
+            q.deficit -= time.as_micros() as i64;
+        }
+    }
+
+    pub fn drain(&mut self) -> Vec<Request> {
+        self.order.clear();
+        self.queues
+            .drain()
+            .flat_map(|(_, q)| q.jobs.into_iter())
+            .collect()
+    }
+
+    fn take(&mut self, tid: Option<usize>) -> Option<Request> {
+        let q = self.queues.get_mut(&tid)?;
+        let job = q.jobs.pop_front();
+        // Idle torrents don't accumulate credit
+        if q.jobs.is_empty() {
+            self.queues.remove(&tid);
+            self.order.retain(|t| *t != tid);
+        }
+        job
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Info;
+    use std::sync::Arc;
+
+    fn job(tid: usize) -> Request {
+        Request::serialize(tid, vec![], [0; 20])
+    }
+
+    fn order(s: &mut Sched, cost: u64) -> Vec<Option<usize>> {
+        let mut res = vec![];
+        while let Some((tid, _)) = s.pop() {
+            s.charge(tid, Duration::from_millis(cost));
+            res.push(tid);
+        }
+        res
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut s = Sched::new();
+        for _ in 0..3 {
+            s.push(job(0));
+        }
+        s.push(job(1));
+        s.push(job(1));
+        // Every job uses up a torrent's turn
+        assert_eq!(
+            order(&mut s, 20),
+            vec![Some(0), Some(1), Some(0), Some(1), Some(0)]
+        );
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn test_deficit() {
+        let mut s = Sched::new();
+        for _ in 0..4 {
+            s.push(job(0));
+        }
+        s.push(job(1));
+        // Cheap jobs can run several to a turn
+        assert_eq!(order(&mut s, 7)[..3], [Some(0), Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn test_urgent() {
+        let mut s = Sched::new();
+        s.push(job(0));
+        s.push(job(0));
+        s.push(job(1));
+        s.push(Request::validate_piece(
+            1,
+            Arc::new(Info::with_pieces(1)),
+            Arc::new(vec![3]),
+            None,
+            0,
+        ));
+        // Torrent 1's queued write still precedes its verification,
+        // which then runs despite torrent 1 having used up its turn.
+        let mut res = vec![];
+        while let Some((tid, j)) = s.pop() {
+            s.charge(tid, Duration::from_millis(20));
+            res.push((tid, j.urgent()));
+        }
+        assert_eq!(
+            res,
+            vec![
+                (Some(0), false),
+                (Some(1), false),
+                (Some(1), true),
+                (Some(0), false)
+            ]
+        );
+    }
+}
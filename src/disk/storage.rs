@@ -0,0 +1,54 @@
+//! Where torrent data is kept. Disk jobs read and write the blocks of a
+//! torrent's files through a `Storage` rather than the filesystem, so that
+//! other backends can keep the data elsewhere. The file cache, which keeps
+//! the files on the local filesystem, is the default one.
+
+use std::io;
+use std::path::Path;
+
+/// Files of torrent data, addressed by their path.
+pub trait Storage {
+    /// Opens a file, creating it if it doesn't exist. A size of `Ok` is
+    /// allocated up front, while `Err` only sets the length of new files.
+    fn open(&mut self, path: &Path, size: Result<u64, u64>) -> io::Result<()>;
+
+    /// Reads a block of an existing file.
+    fn read(&mut self, path: &Path, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Writes a block of a file, which is opened with the given size.
+    fn write(
+        &mut self,
+        path: &Path,
+        size: Result<u64, u64>,
+        offset: u64,
+        buf: &[u8],
+    ) -> io::Result<()>;
+
+    /// Makes the writes to a file durable.
+    fn sync(&mut self, path: &Path);
+
+    /// Makes every write since the last sync durable.
+    fn sync_all(&mut self);
+
+    /// Moves a file, or a folder along with everything in it, creating the
+    /// folders leading up to its new path.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Closes a file, e.g. before it's removed.
+    fn close(&mut self, path: &Path);
+
+    /// Closes every file in a folder.
+    fn close_dir(&mut self, dir: &Path);
+
+    /// Whether a file is open.
+    fn is_open(&self, path: &Path) -> bool;
+
+    /// Whether a file exists, open or not.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Length of a file, if it exists.
+    fn len(&self, path: &Path) -> Option<u64>;
+
+    /// Closes and deletes a file.
+    fn remove(&mut self, path: &Path) -> io::Result<()>;
+}
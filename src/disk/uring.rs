@@ -0,0 +1,423 @@
+//! A minimal io_uring queue, which lets the disk thread batch the reads
+//! and writes of several jobs into a single system call rather than
+//! blocking on each of them in turn.
+//!
+//! Operations refer to their buffers by pointer, so a buffer must outlive
+//! its operation, which is only guaranteed to have completed once `wait`
+//! returns. If `wait` fails instead, the ring is left `failed` and the
+//! operations in flight may never be reaped, so their buffers must not be
+//! freed at all. Every queued operation is waited on before its submission
+//! slot is reused, so the number of operations in flight never exceeds
+//! the queue depth.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{io, mem, ptr};
+
+use nix::libc::{self, c_void};
+
+use crate::util::io_err;
+
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region of the ring shared with the kernel.
+struct Region {
+    ptr: *mut u8,
+    len: usize,
+}
+
+pub struct Ring {
+    /// Only accessed through the pointers below, but kept mapped
+    _sq: Region,
+    _cq: Region,
+    sqes: Region,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    /// The buffer, token and expected length of the operation in each slot
+    slots: Vec<(libc::iovec, u64, usize)>,
+    /// Operations queued but not submitted yet
+    queued: u32,
+    /// Operations submitted but not completed yet
+    inflight: u32,
+    done: Vec<(u64, io::Result<()>)>,
+    /// The error which made `wait` fail, after which the ring is unusable
+    error: Option<i32>,
+    fd: File,
+}
+
+// The shared regions are only accessed through the ring, which owns them
+unsafe impl Send for Ring {}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        if !cfg!(target_os = "linux") {
+            return io_err("io_uring is only supported on Linux");
+        }
+        let mut p = Params::default();
+        let fd = unsafe { libc::syscall(SYS_IO_URING_SETUP, entries, &mut p as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let sq = Region::new(
+            &fd,
+            p.sq_off.array as usize + p.sq_entries as usize * mem::size_of::<u32>(),
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq = Region::new(
+            &fd,
+            p.cq_off.cqes as usize + p.cq_entries as usize * mem::size_of::<Cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = Region::new(
+            &fd,
+            p.sq_entries as usize * mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        let empty = iovec(ptr::null_mut(), 0);
+        unsafe {
+            Ok(Ring {
+                sq_tail: sq.at(p.sq_off.tail),
+                sq_mask: *sq.at::<u32>(p.sq_off.ring_mask),
+                sq_array: sq.at(p.sq_off.array),
+                cq_head: cq.at(p.cq_off.head),
+                cq_tail: cq.at(p.cq_off.tail),
+                cq_mask: *cq.at::<u32>(p.cq_off.ring_mask),
+                cqes: cq.at(p.cq_off.cqes),
+                slots: vec![(empty, 0, 0); p.sq_entries as usize],
+                queued: 0,
+                inflight: 0,
+                done: Vec::new(),
+                error: None,
+                _sq: sq,
+                _cq: cq,
+                sqes,
+                fd,
+            })
+        }
+    }
+
+    /// Whether any operations haven't completed yet.
+    pub fn pending(&self) -> bool {
+        self.queued + self.inflight > 0
+    }
+
+    /// Whether `wait` has failed, leaving the operations in flight in an
+    /// unknown state.
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Queues a read into buf, which must stay valid until the next
+    /// `wait` returns.
+    pub unsafe fn read(
+        &mut self,
+        fd: RawFd,
+        buf: &mut [u8],
+        off: u64,
+        token: u64,
+    ) -> io::Result<()> {
+        self.reserve(1)?;
+        self.push(
+            IORING_OP_READV,
+            fd,
+            iovec(buf.as_mut_ptr(), buf.len()),
+            off,
+            token,
+        );
+        Ok(())
+    }
+
+    /// Queues a write of buf, which must stay valid until the next `wait`
+    /// returns.
+    pub unsafe fn write(&mut self, fd: RawFd, buf: &[u8], off: u64, token: u64) -> io::Result<()> {
+        self.reserve(1)?;
+        let ptr = buf.as_ptr() as *mut u8;
+        self.push(IORING_OP_WRITEV, fd, iovec(ptr, buf.len()), off, token);
+        Ok(())
+    }
+
+    /// Submits all queued operations and waits for every operation in
+    /// flight to complete.
+    pub fn wait(&mut self) -> io::Result<()> {
+        if let Some(errno) = self.error {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        while self.pending() {
+            let res = unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd.as_raw_fd(),
+                    self.queued,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<c_void>(),
+                    0,
+                )
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                    errno => {
+                        self.error = Some(errno.unwrap_or(libc::EIO));
+                        return Err(err);
+                    }
+                }
+            } else {
+                self.queued -= res as u32;
+                self.inflight += res as u32;
+            }
+            self.reap();
+        }
+        Ok(())
+    }
+
+    /// The tokens and results of the operations completed so far.
+    pub fn completions(&mut self) -> impl Iterator<Item = (u64, io::Result<()>)> + '_ {
+        self.done.drain(..)
+    }
+
+    fn reserve(&mut self, n: u32) -> io::Result<()> {
+        if let Some(errno) = self.error {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        if self.queued + self.inflight + n > self.slots.len() as u32 {
+            self.wait()?;
+        }
+        Ok(())
+    }
+
+    unsafe fn push(&mut self, opcode: u8, fd: RawFd, buf: libc::iovec, off: u64, token: u64) {
+        let tail = (*self.sq_tail).load(Ordering::Relaxed);
+        let idx = tail & self.sq_mask;
+        let slot = &mut self.slots[idx as usize];
+        *slot = (buf, token, buf.iov_len);
+        let (addr, iovs) = if buf.iov_base.is_null() {
+            (0, 0)
+        } else {
+            (&slot.0 as *const libc::iovec as u64, 1)
+        };
+        let sqe = (self.sqes.ptr as *mut Sqe).add(idx as usize);
+        ptr::write(
+            sqe,
+            Sqe {
+                opcode,
+                flags: 0,
+                ioprio: 0,
+                fd,
+                off,
+                addr,
+                len: iovs,
+                rw_flags: 0,
+                user_data: u64::from(idx),
+                pad: [0; 3],
+            },
+        );
+        *self.sq_array.add(idx as usize) = idx;
+        (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+    }
+
+    /// Breaks the ring as if `wait` had failed with errno.
+    #[cfg(test)]
+    pub fn fail(&mut self, errno: i32) {
+        self.error = Some(errno);
+    }
+
+    fn reap(&mut self) {
+        unsafe {
+            let mut head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            while head != tail {
+                let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+                let (_, token, len) = self.slots[cqe.user_data as usize];
+                let res = if cqe.res < 0 {
+                    Err(io::Error::from_raw_os_error(-cqe.res))
+                } else if cqe.res as usize != len {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Short read or write",
+                    ))
+                } else {
+                    Ok(())
+                };
+                self.done.push((token, res));
+                self.inflight -= 1;
+                head = head.wrapping_add(1);
+            }
+            (*self.cq_head).store(head, Ordering::Release);
+        }
+    }
+}
+
+fn iovec(ptr: *mut u8, len: usize) -> libc::iovec {
+    libc::iovec {
+        iov_base: ptr as *mut c_void,
+        iov_len: len,
+    }
+}
+
+impl Region {
+    fn new(fd: &File, len: usize, off: libc::off_t) -> io::Result<Region> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                off,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Region {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    unsafe fn at<T>(&self, off: u32) -> *mut T {
+        self.ptr.add(off as usize) as *mut T
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_ring() {
+        let mut ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            // Kernels without io_uring, or sandboxes which forbid it
+            Err(_) => return,
+        };
+        let path = std::env::temp_dir().join(format!("synapse-uring-{}", std::process::id()));
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        unsafe {
+            for i in 0..6 {
+                ring.write(f.as_raw_fd(), &data, i * 256, i).unwrap();
+            }
+        }
+        ring.wait().unwrap();
+        let done: Vec<_> = ring.completions().collect();
+        assert_eq!(done.len(), 6);
+        assert!(done.iter().all(|(_, res)| res.is_ok()));
+
+        let (mut buf, mut past) = (vec![0u8; 256], [0u8; 16]);
+        unsafe {
+            ring.read(f.as_raw_fd(), &mut buf, 5 * 256, 0).unwrap();
+            ring.read(f.as_raw_fd(), &mut past, 6 * 256, 1).unwrap();
+        }
+        ring.wait().unwrap();
+        let done: Vec<_> = ring.completions().collect();
+        assert_eq!(buf, data);
+        assert!(done.iter().any(|&(t, ref res)| t == 1 && res.is_err()));
+
+        // A failed ring refuses further use
+        ring.fail(libc::EIO);
+        assert!(ring.wait().is_err());
+        assert!(unsafe { ring.write(f.as_raw_fd(), &data, 0, 0) }.is_err());
+        assert!(ring.failed());
+        fs::remove_file(&path).unwrap();
+    }
+}
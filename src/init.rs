@@ -4,7 +4,7 @@ use std::{io, process, thread};
 use ctrlc;
 
 use crate::control::acio;
-use crate::{args, control, disk, log, rpc, throttle, tracker};
+use crate::{args, control, dht, disk, log, rpc, throttle, tracker};
 use crate::{CONFIG, SHUTDOWN, THROT_TOKS};
 
 pub fn init(args: args::Args) -> Result<(), ()> {
@@ -53,6 +53,7 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
     let (dh, disk_broadcast, dhj) = disk::start(&mut creg)?;
     let (rh, rhj) = rpc::RPC::start(&mut creg, disk_broadcast.clone())?;
     let (th, thj) = tracker::Tracker::start(&mut creg, disk_broadcast.clone())?;
+    let (dhth, dhtj) = dht::Dht::start(&mut creg, disk_broadcast.clone())?;
     let chans = acio::ACChans {
         disk_tx: dh.tx,
         disk_rx: dh.rx,
@@ -60,6 +61,8 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
         rpc_rx: rh.rx,
         trk_tx: th.tx,
         trk_rx: th.rx,
+        dht_tx: dhth.tx,
+        dht_rx: dhth.rx,
     };
     let (tx, rx) = mpsc::channel();
     let cdb = disk_broadcast.clone();
@@ -81,7 +84,7 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
         .unwrap();
     rx.recv().unwrap()?;
 
-    Ok(vec![chj, dhj, rhj, thj])
+    Ok(vec![chj, dhj, rhj, thj, dhtj])
 }
 
 fn init_signals() -> Result<(), ctrlc::Error> {
@@ -34,6 +34,7 @@ mod args;
 mod buffers;
 mod config;
 mod control;
+mod dht;
 mod disk;
 mod handle;
 mod init;
@@ -43,12 +44,16 @@ mod stat;
 mod throttle;
 mod torrent;
 mod tracker;
+mod utp;
 
 use std::process;
 use std::sync::atomic;
 
 pub use crate::protocol::DHT_EXT;
 pub use crate::protocol::EXT_PROTO;
+pub use crate::protocol::FAST_EXT;
+pub use crate::protocol::LT_DONTHAVE_ID;
+pub use crate::protocol::UT_HOLEPUNCH_ID;
 pub use crate::protocol::UT_META_ID;
 pub use crate::protocol::UT_PEX_ID;
 
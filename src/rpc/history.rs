@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+use crate::util::SHashMap;
+
+/// Number of peer removals remembered for diffing
+const MAX_REMOVED: usize = 4096;
+
+/// Tracks when peers were added, changed and removed, so that clients can
+/// fetch a torrent's peers as a diff against a generation they've seen.
+pub struct PeerHistory {
+    generation: u64,
+    peers: SHashMap<PeerGen>,
+    removed: VecDeque<Removed>,
+    /// Oldest generation which can still be diffed against
+    horizon: u64,
+}
+
+struct PeerGen {
+    torrent_id: String,
+    added: u64,
+    changed: u64,
+}
+
+struct Removed {
+    generation: u64,
+    id: String,
+    torrent_id: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff<'a> {
+    pub generation: u64,
+    /// Whether the diff is a full listing, because the client's
+    /// generation was absent or too old to diff against
+    pub full: bool,
+    pub added: Vec<&'a str>,
+    pub changed: Vec<&'a str>,
+    pub removed: Vec<&'a str>,
+}
+
+impl PeerHistory {
+    pub fn new() -> PeerHistory {
+        PeerHistory {
+            generation: 0,
+            peers: SHashMap::default(),
+            removed: VecDeque::new(),
+            horizon: 0,
+        }
+    }
+
+    pub fn added(&mut self, id: &str, torrent_id: &str) {
+        self.generation += 1;
+        self.peers.insert(
+            id.to_owned(),
+            PeerGen {
+                torrent_id: torrent_id.to_owned(),
+                added: self.generation,
+                changed: self.generation,
+            },
+        );
+    }
+
+    pub fn changed(&mut self, id: &str) {
+        if let Some(peer) = self.peers.get_mut(id) {
+            self.generation += 1;
+            peer.changed = self.generation;
+        }
+    }
+
+    pub fn removed(&mut self, id: &str) {
+        let peer = match self.peers.remove(id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        self.generation += 1;
+        self.removed.push_back(Removed {
+            generation: self.generation,
+            id: id.to_owned(),
+            torrent_id: peer.torrent_id,
+        });
+        if self.removed.len() > MAX_REMOVED {
+            if let Some(r) = self.removed.pop_front() {
+                self.horizon = r.generation;
+            }
+        }
+    }
+
+    /// Computes the changes to a torrent's peers since the given generation.
+    pub fn diff(&self, torrent_id: &str, since: Option<u64>) -> Diff<'_> {
+        let mut diff = Diff {
+            generation: self.generation,
+            ..Default::default()
+        };
+        let since = match since {
+            Some(g) if g >= self.horizon && g <= self.generation => g,
+            _ => {
+                diff.full = true;
+                0
+            }
+        };
+        for (id, peer) in &self.peers {
+            if peer.torrent_id != torrent_id {
+                continue;
+            }
+            if peer.added > since {
+                diff.added.push(id);
+            } else if peer.changed > since {
+                diff.changed.push(id);
+            }
+        }
+        if !diff.full {
+            diff.removed = self
+                .removed
+                .iter()
+                .filter(|r| r.generation > since && r.torrent_id == torrent_id)
+                .map(|r| r.id.as_str())
+                .collect();
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let mut h = PeerHistory::new();
+        h.added("p1", "t1");
+        h.added("p2", "t1");
+        h.added("p3", "t2");
+        let d = h.diff("t1", None);
+        assert!(d.full);
+        assert_eq!(d.added.len(), 2);
+
+        let gen = d.generation;
+        h.changed("p1");
+        h.removed("p2");
+        h.added("p4", "t1");
+        h.changed("p3");
+        let d = h.diff("t1", Some(gen));
+        assert!(!d.full);
+        assert_eq!(d.added, vec!["p4"]);
+        assert_eq!(d.changed, vec!["p1"]);
+        assert_eq!(d.removed, vec!["p2"]);
+
+        let d = h.diff("t1", Some(d.generation));
+        assert_eq!(d.added.len() + d.changed.len() + d.removed.len(), 0);
+        // Generations from the future can't be trusted
+        assert!(h.diff("t1", Some(d.generation + 1)).full);
+    }
+
+    #[test]
+    fn test_horizon() {
+        let mut h = PeerHistory::new();
+        for i in 0..=MAX_REMOVED {
+            let id = i.to_string();
+            h.added(&id, "t1");
+            h.removed(&id);
+        }
+        assert!(h.diff("t1", Some(1)).full);
+        assert!(!h.diff("t1", Some(h.horizon)).full);
+    }
+}
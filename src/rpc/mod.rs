@@ -1,5 +1,6 @@
 mod client;
 mod errors;
+mod history;
 mod processor;
 pub mod proto;
 mod reader;
@@ -19,6 +20,7 @@ use url::Url;
 use self::client::{Client, Incoming, IncomingStatus};
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
 use self::processor::{Processor, TransferKind};
+pub use self::proto::message::LinkMode;
 use self::proto::message::{self, SMessage};
 pub use self::proto::resource;
 use self::proto::ws;
@@ -91,6 +93,7 @@ pub enum CtlMessage {
         serial: u64,
     },
     Error {
+        code: resource::ErrorCode,
         reason: String,
         client: usize,
         serial: u64,
@@ -100,6 +103,16 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    IntegrityReport {
+        client: usize,
+        report: message::IntegrityReport,
+    },
+    DeadlineExpired {
+        id: String,
+        piece: u32,
+        client: usize,
+        serial: u64,
+    },
     Ping,
     Shutdown,
 }
@@ -115,7 +128,11 @@ pub enum Message {
     UpdateFile {
         id: String,
         torrent_id: String,
-        priority: u8,
+        priority: Option<u8>,
+        /// Playback position to stream the file from, or None to stop
+        position: Option<Option<u64>>,
+        /// New path of the file, relative to the torrent's directory
+        path: Option<String>,
     },
     RemoveTorrent {
         id: String,
@@ -126,6 +143,21 @@ pub enum Message {
     Pause(String),
     Resume(String),
     Validate(Vec<String>),
+    /// Torrents to repair, along with any files to hash
+    Repair(Vec<(String, Vec<String>)>),
+    IntegrityReport {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    /// Deadline in milliseconds from now, or None to clear it
+    SetPieceDeadline {
+        id: String,
+        piece: u32,
+        deadline: Option<u64>,
+        client: usize,
+        serial: u64,
+    },
     AddPeer {
         id: String,
         client: usize,
@@ -161,6 +193,10 @@ pub enum Message {
         path: Option<String>,
         start: bool,
         import: bool,
+        /// How to reuse files existing torrents already downloaded
+        link: Option<LinkMode>,
+        /// Keep the torrent's data in memory rather than on disk
+        memory: bool,
     },
     PurgeDNS,
 }
@@ -340,6 +376,8 @@ impl RPC {
                 serial,
                 start,
                 import,
+                link,
+                memory,
             } => {
                 debug!("Got torrent via HTTP transfer!");
                 if self.reg.deregister(&conn).is_err() {
@@ -356,6 +394,8 @@ impl RPC {
                                     path,
                                     start,
                                     import,
+                                    link,
+                                    memory,
                                     client,
                                     serial,
                                 })
@@ -371,6 +411,7 @@ impl RPC {
                                     serde_json::to_string(&SMessage::TransferFailed(
                                         message::Error {
                                             serial: Some(serial),
+                                            code: resource::ErrorCode::InvalidTorrent,
                                             reason: format!(
                                                 "Invalid torrent file uploaded, {}.",
                                                 e
@@ -388,6 +429,7 @@ impl RPC {
                             c.send(ws::Frame::Text(
                                 serde_json::to_string(&SMessage::TransferFailed(message::Error {
                                     serial: Some(serial),
+                                    code: resource::ErrorCode::InvalidTorrent,
                                     reason: format!(
                                         "Invalid torrent file uploaded, bad bencoded data: {}.",
                                         e
@@ -465,6 +507,8 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                link,
+                                memory,
                             },
                         )) => {
                             debug!("Torrent transfer initiated");
@@ -478,6 +522,8 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                link,
+                                memory,
                             );
                             // Since a succesful result means the buffer hasn't been flushed,
                             // immediatly attempt to handle the transfer as if it was ready
@@ -579,6 +625,7 @@ impl RPC {
                 if e.is_syntax() || e.is_eof() {
                     let msg = SMessage::InvalidSchema(message::Error {
                         serial: None,
+                        code: resource::ErrorCode::MalformedJson,
                         reason: format!("JSON decode error: {}", e),
                     });
                     if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
@@ -599,6 +646,7 @@ impl RPC {
 
                     let msg = SMessage::InvalidSchema(message::Error {
                         serial,
+                        code: resource::ErrorCode::MalformedMessage,
                         reason: format!("Invalid message format: {}", e),
                     });
                     if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
@@ -3,16 +3,17 @@ use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem;
-use std::path::Path;
+use std::path::{Component, Path};
 
 use crate::rpc_lib;
 use chrono::{DateTime, Duration, Utc};
 use serde_json as json;
 use url::Url;
 
+use super::history::PeerHistory;
 use super::proto::criterion::{self, Criterion, Operation};
-use super::proto::message::{CMessage, Error, SMessage};
-use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate};
+use super::proto::message::{CMessage, Error, LinkMode, SMessage};
+use super::proto::resource::{merge_json, ErrorCode, Resource, ResourceKind, SResourceUpdate};
 use super::{CtlMessage, Message};
 use crate::disk;
 use crate::torrent::info::Info;
@@ -20,6 +21,8 @@ use crate::util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
 use crate::CONFIG;
 
 const USER_DATA_FILE: &str = "rpc_user_data";
+/// Highest torrent and file priority, 0 being the lowest
+const MAX_PRIORITY: u8 = 5;
 type RpcDiskFmt = SHashMap<Vec<u8>>;
 
 // TODO: Figure out a way to reduce allocations
@@ -38,6 +41,7 @@ pub struct Processor {
     tokens: SHashMap<BearerToken>,
     db: amy::Sender<disk::Request>,
     user_data: SHashMap<json::Value>,
+    peer_history: PeerHistory,
 }
 
 struct Filter {
@@ -59,6 +63,8 @@ pub enum TransferKind {
         path: Option<String>,
         start: bool,
         import: bool,
+        link: Option<LinkMode>,
+        memory: bool,
     },
     UploadFiles {
         size: u64,
@@ -115,6 +121,7 @@ impl Processor {
             kinds: vec![MHashSet::default(); 6],
             db,
             user_data,
+            peer_history: PeerHistory::new(),
         }
     }
 
@@ -170,6 +177,7 @@ impl Processor {
                     } else {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
+                            code: ErrorCode::UnknownResource,
                             reason: format!("unknown resource id {}", id),
                         }));
                     }
@@ -188,6 +196,7 @@ impl Processor {
                     } else {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
+                            code: ErrorCode::UnknownResource,
                             reason: format!("unknown resource id {}", id),
                         }));
                     }
@@ -202,6 +211,31 @@ impl Processor {
                     self.subs.get_mut(&id).map(|s| s.remove(&client));
                 }
             }
+            CMessage::UpdateResource {
+                serial,
+                ref resource,
+            } if resource.priority.map(|p| p > MAX_PRIORITY).unwrap_or(false) => {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::MalformedMessage,
+                    reason: format!("priority must be at most {}", MAX_PRIORITY),
+                }));
+            }
+            CMessage::UpdateResource {
+                serial,
+                ref resource,
+            } if resource
+                .name
+                .as_ref()
+                .map(|n| !relative(n) || Path::new(n).components().count() != 1)
+                .unwrap_or(false) =>
+            {
+                resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::MalformedMessage,
+                    reason: "name must be a single path component".to_owned(),
+                }));
+            }
             CMessage::UpdateResource {
                 serial,
                 mut resource,
@@ -235,11 +269,28 @@ impl Processor {
                     }
                     Some(&Resource::File(ref f)) => {
                         // TODO: Validate other fields(make sure they're not present)
-                        if let Some(p) = resource.priority {
+                        if resource
+                            .path
+                            .as_ref()
+                            .map(|p| !relative(p))
+                            .unwrap_or(false)
+                        {
+                            resp.push(SMessage::InvalidRequest(Error {
+                                serial: Some(serial),
+                                code: ErrorCode::MalformedMessage,
+                                reason: "file path must be relative to the torrent's directory"
+                                    .to_owned(),
+                            }));
+                        } else if resource.priority.is_some()
+                            || resource.position.is_some()
+                            || resource.path.is_some()
+                        {
                             rmsg = Some(Message::UpdateFile {
                                 id: resource.id,
                                 torrent_id: f.torrent_id.to_owned(),
-                                priority: p,
+                                priority: resource.priority,
+                                position: resource.position,
+                                path: resource.path,
                             });
                         }
                     }
@@ -254,6 +305,7 @@ impl Processor {
                     None => {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
+                            code: ErrorCode::UnknownResource,
                             reason: format!("unknown resource id {}", resource.id),
                         }));
                     }
@@ -291,12 +343,14 @@ impl Processor {
                 Some(_) => {
                     resp.push(SMessage::InvalidResource(Error {
                         serial: Some(serial),
+                        code: ErrorCode::WrongResourceKind,
                         reason: format!("Only torrents, trackers, and peers may be removed"),
                     }));
                 }
                 None => {
                     resp.push(SMessage::UnknownResource(Error {
                         serial: Some(serial),
+                        code: ErrorCode::UnknownResource,
                         reason: format!("unknown resource id {}", id),
                     }));
                 }
@@ -375,10 +429,12 @@ impl Processor {
                 Some(&Resource::Torrent(_)) => rmsg = Some(Message::Pause(id)),
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
                     reason: "Only torrents can be paused".to_owned(),
                 })),
                 None => resp.push(SMessage::UnknownResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
                     reason: format!("Unknown resource {}", id),
                 })),
             },
@@ -386,10 +442,12 @@ impl Processor {
                 Some(&Resource::Torrent(_)) => rmsg = Some(Message::Resume(id)),
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
                     reason: "Only torrents can be resumed".to_owned(),
                 })),
                 None => resp.push(SMessage::UnknownResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
                     reason: format!("Unknown resource {}", id),
                 })),
             },
@@ -405,15 +463,18 @@ impl Processor {
                     }
                     Err(_) => resp.push(SMessage::InvalidRequest(Error {
                         serial: Some(serial),
+                        code: ErrorCode::InvalidPeerAddress,
                         reason: format!("Invalid peer IP address: {}", ip),
                     })),
                 },
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
                     reason: "ADD_PEER not used with torrent".to_owned(),
                 })),
                 None => resp.push(SMessage::UnknownResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
                     reason: format!("Unknown resource {}", id),
                 })),
             },
@@ -429,15 +490,18 @@ impl Processor {
                     }
                     Err(_) => resp.push(SMessage::InvalidRequest(Error {
                         serial: Some(serial),
+                        code: ErrorCode::InvalidTrackerUrl,
                         reason: format!("Invalid tracker URI: {}", uri),
                     })),
                 },
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
                     reason: "ADD_TRACKER not used with torrent".to_owned(),
                 })),
                 None => resp.push(SMessage::UnknownResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
                     reason: format!("Unknown resource {}", id),
                 })),
             },
@@ -450,10 +514,12 @@ impl Processor {
                 }
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
                     reason: "UPDATE_TRACKER not used with tracker".to_owned(),
                 })),
                 None => resp.push(SMessage::UnknownResource(Error {
                     serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
                     reason: format!("Unknown resource {}", id),
                 })),
             },
@@ -463,6 +529,7 @@ impl Processor {
                     Some(_) => {
                         resp.push(SMessage::InvalidResource(Error {
                             serial: Some(serial),
+                            code: ErrorCode::WrongResourceKind,
                             reason: "Only torrents can be validated".to_owned(),
                         }));
                         false
@@ -470,6 +537,7 @@ impl Processor {
                     None => {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
+                            code: ErrorCode::UnknownResource,
                             reason: format!("Unknown resource {}", id),
                         }));
                         false
@@ -477,12 +545,50 @@ impl Processor {
                 });
                 rmsg = Some(Message::Validate(ids));
             }
+            CMessage::RepairResources { serial, ids } => {
+                let mut torrents: Vec<(String, Vec<String>)> = Vec::new();
+                for id in ids {
+                    let tid = match self.resources.get(&id) {
+                        Some(Resource::Torrent(t)) => t.id.clone(),
+                        Some(Resource::File(f)) => f.torrent_id.clone(),
+                        Some(_) => {
+                            resp.push(SMessage::InvalidResource(Error {
+                                serial: Some(serial),
+                                code: ErrorCode::WrongResourceKind,
+                                reason: "Only torrents and files can be repaired".to_owned(),
+                            }));
+                            continue;
+                        }
+                        None => {
+                            resp.push(SMessage::UnknownResource(Error {
+                                serial: Some(serial),
+                                code: ErrorCode::UnknownResource,
+                                reason: format!("Unknown resource {}", id),
+                            }));
+                            continue;
+                        }
+                    };
+                    let pos = match torrents.iter().position(|(t, _)| *t == tid) {
+                        Some(pos) => pos,
+                        None => {
+                            torrents.push((tid.clone(), Vec::new()));
+                            torrents.len() - 1
+                        }
+                    };
+                    if tid != id {
+                        torrents[pos].1.push(id);
+                    }
+                }
+                rmsg = Some(Message::Repair(torrents));
+            }
             CMessage::UploadTorrent {
                 serial,
                 size,
                 path,
                 start,
                 import,
+                link,
+                memory,
             } => {
                 resp.push(self.new_transfer(
                     client,
@@ -492,6 +598,8 @@ impl Processor {
                         path,
                         start,
                         import,
+                        link,
+                        memory,
                     },
                 ));
             }
@@ -500,6 +608,7 @@ impl Processor {
                 uri,
                 path,
                 start,
+                memory,
             } => match Info::from_magnet(&uri) {
                 Ok(info) => {
                     rmsg = Some(Message::Torrent {
@@ -507,6 +616,8 @@ impl Processor {
                         path,
                         start,
                         import: false,
+                        link: None,
+                        memory,
                         client,
                         serial,
                     })
@@ -514,6 +625,7 @@ impl Processor {
                 Err(e) => {
                     resp.push(SMessage::InvalidRequest(Error {
                         serial: Some(serial),
+                        code: ErrorCode::InvalidMagnet,
                         reason: format!("Invalid magnet: {}", e),
                     }));
                 }
@@ -528,6 +640,81 @@ impl Processor {
             CMessage::PurgeDns { .. } => {
                 rmsg = Some(Message::PurgeDNS);
             }
+            CMessage::GetPeerDiff {
+                serial,
+                id,
+                generation,
+            } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    let diff = self.peer_history.diff(&id, generation);
+                    let resources = &self.resources;
+                    let peers = |ids: Vec<&str>| {
+                        ids.into_iter()
+                            .filter_map(|id| resources.get(id))
+                            .map(|r| SResourceUpdate::Resource(Cow::Borrowed(r)))
+                            .collect()
+                    };
+                    resp.push(SMessage::PeerDiff {
+                        serial,
+                        generation: diff.generation,
+                        full: diff.full,
+                        added: peers(diff.added),
+                        changed: peers(diff.changed),
+                        removed: diff.removed.into_iter().map(str::to_owned).collect(),
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
+                    reason: "GET_PEER_DIFF not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetIntegrityReport { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::IntegrityReport { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
+                    reason: "GET_INTEGRITY_REPORT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::SetPieceDeadline {
+                serial,
+                id,
+                piece,
+                deadline,
+            } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::SetPieceDeadline {
+                        id,
+                        piece,
+                        deadline,
+                        client,
+                        serial,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::WrongResourceKind,
+                    reason: "SET_PIECE_DEADLINE not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    code: ErrorCode::UnknownResource,
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
         }
         (resp, rmsg)
     }
@@ -566,6 +753,9 @@ impl Processor {
                     if let Some(user_data) = self.user_data.get(&id) {
                         *r.user_data() = user_data.clone();
                     }
+                    if let Resource::Peer(ref p) = r {
+                        self.peer_history.added(&id, &p.torrent_id);
+                    }
                     self.resources.insert(id, r);
                 }
                 // We have to make a new vec which points to the resource struct
@@ -588,6 +778,9 @@ impl Processor {
                         clients.get_mut(c).unwrap().push(update.clone());
                     }
                     if let Some(res) = self.resources.get_mut(update.id()) {
+                        if res.kind() == ResourceKind::Peer {
+                            self.peer_history.changed(res.id());
+                        }
                         res.update(update);
                     }
                 }
@@ -616,6 +809,9 @@ impl Processor {
 
                 for id in r {
                     let r = self.resources.remove(&id).unwrap();
+                    if r.kind() == ResourceKind::Peer {
+                        self.peer_history.removed(&id);
+                    }
                     if self.user_data.remove(&id).is_some() {
                         self.serialize();
                     }
@@ -663,6 +859,7 @@ impl Processor {
                 }
             }
             CtlMessage::Error {
+                code,
                 reason,
                 serial,
                 client,
@@ -671,6 +868,7 @@ impl Processor {
                     client,
                     SMessage::InvalidRequest(Error {
                         serial: Some(serial),
+                        code,
                         reason,
                     }),
                 ));
@@ -678,6 +876,17 @@ impl Processor {
             CtlMessage::Pending { id, serial, client } => {
                 msgs.push((client, SMessage::ResourcePending { serial, id }));
             }
+            CtlMessage::IntegrityReport { client, report } => {
+                msgs.push((client, SMessage::IntegrityReport(report)));
+            }
+            CtlMessage::DeadlineExpired {
+                id,
+                piece,
+                client,
+                serial,
+            } => {
+                msgs.push((client, SMessage::PieceDeadlineExpired { serial, id, piece }));
+            }
             CtlMessage::Ping => unreachable!("ping must be handled before rpc processor"),
             CtlMessage::Shutdown => unreachable!("shutdown must be handled before rpc processor"),
         }
@@ -800,3 +1009,11 @@ impl Filter {
         })
     }
 }
+
+/// Whether a path is relative and stays below the directory it's relative
+/// to.
+fn relative(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components().next().is_some()
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
@@ -3,7 +3,8 @@ use std::time;
 
 use sstream::SStream;
 
-use super::proto::message::Error;
+use super::proto::message::{Error, LinkMode};
+use super::proto::resource::ErrorCode;
 use super::EMPTY_HTTP_RESP;
 
 use crate::util::{aread, UHashMap, IOR};
@@ -17,6 +18,8 @@ pub enum TransferResult {
         conn: SStream,
         start: bool,
         import: bool,
+        link: Option<LinkMode>,
+        memory: bool,
         data: Vec<u8>,
         path: Option<String>,
         client: usize,
@@ -38,6 +41,8 @@ struct TorrentTx {
     buf: Vec<u8>,
     start: bool,
     import: bool,
+    link: Option<LinkMode>,
+    memory: bool,
     path: Option<String>,
     last_action: time::Instant,
 }
@@ -62,6 +67,8 @@ impl Transfers {
         size: u64,
         start: bool,
         import: bool,
+        link: Option<LinkMode>,
+        memory: bool,
     ) {
         let pos = data.len();
         // Given that this requires an authenticated connection
@@ -78,6 +85,8 @@ impl Transfers {
                 path,
                 start,
                 import,
+                link,
+                memory,
                 last_action: time::Instant::now(),
             },
         );
@@ -103,6 +112,8 @@ impl Transfers {
                     serial: tx.serial,
                     start: tx.start,
                     import: tx.import,
+                    link: tx.link,
+                    memory: tx.memory,
                 }
             }
             Some(Ok(false)) => TransferResult::Incomplete,
@@ -113,6 +124,7 @@ impl Transfers {
                     client: tx.client,
                     err: Error {
                         serial: Some(tx.serial),
+                        code: ErrorCode::TransferFailed,
                         reason: e.to_owned(),
                     },
                 }
@@ -136,6 +148,7 @@ impl Transfers {
                 id,
                 Error {
                     serial: Some(tx.serial),
+                    code: ErrorCode::TransferTimeout,
                     reason: "Timeout".to_owned(),
                 },
             ));
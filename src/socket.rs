@@ -1,19 +1,25 @@
 use std::io::{self, ErrorKind};
 use std::net::{SocketAddr, TcpStream};
-use std::os::unix::io::{AsRawFd, RawFd};
 
 use net2::{TcpBuilder, TcpStreamExt};
 
 use crate::throttle::Throttle;
+use crate::utp;
 
 /// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
 /// rate limiting, etc.
 pub struct Socket {
-    conn: TcpStream,
+    conn: Conn,
     addr: SocketAddr,
     pub throttle: Option<Throttle>,
 }
 
+enum Conn {
+    Tcp(TcpStream),
+    /// uTP streams are only opened once bound to the uTP socket
+    Utp(Option<utp::Stream>),
+}
+
 const EINPROGRESS: i32 = 115;
 
 impl Socket {
@@ -31,17 +37,26 @@ impl Socket {
             }
         }
         Ok(Socket {
-            conn,
+            conn: Conn::Tcp(conn),
             throttle: None,
             addr: *addr,
         })
     }
 
+    /// Creates a uTP socket, which connects once `bind_utp` is called.
+    pub fn new_utp(addr: &SocketAddr) -> Socket {
+        Socket {
+            conn: Conn::Utp(None),
+            throttle: None,
+            addr: *addr,
+        }
+    }
+
     #[cfg(test)]
     pub fn empty() -> Socket {
         let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
         Socket {
-            conn,
+            conn: Conn::Tcp(conn),
             throttle: None,
             addr: "127.0.0.1:0".parse().unwrap(),
         }
@@ -55,16 +70,69 @@ impl Socket {
         conn.set_nonblocking(true)?;
         let addr = conn.peer_addr()?;
         Ok(Socket {
-            conn,
+            conn: Conn::Tcp(conn),
             throttle: None,
             addr,
         })
     }
+
+    pub fn from_utp(stream: utp::Stream) -> Socket {
+        Socket {
+            addr: stream.addr(),
+            conn: Conn::Utp(Some(stream)),
+            throttle: None,
+        }
+    }
+
+    /// The TCP stream to poll on, if this is a TCP socket.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self.conn {
+            Conn::Tcp(ref c) => Some(c),
+            Conn::Utp(_) => None,
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        self.tcp().is_none()
+    }
+
+    /// Attaches a uTP socket to `utp`, connecting it if it's outgoing.
+    /// Readiness is reported with `token`.
+    pub fn bind_utp(&mut self, utp: &utp::Utp, token: usize) {
+        if let Conn::Utp(ref mut s) = self.conn {
+            match s {
+                Some(s) => s.set_token(token),
+                None => *s = Some(utp.connect(self.addr, token)),
+            }
+        }
+    }
+}
+
+impl io::Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(c) => c.read(buf),
+            Conn::Utp(Some(s)) => s.read(buf),
+            Conn::Utp(None) => Err(ErrorKind::NotConnected.into()),
+        }
+    }
 }
 
-impl AsRawFd for Socket {
-    fn as_raw_fd(&self) -> RawFd {
-        self.conn.as_raw_fd()
+impl io::Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(c) => c.write(buf),
+            Conn::Utp(Some(s)) => s.write(buf),
+            Conn::Utp(None) => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(c) => c.flush(),
+            Conn::Utp(Some(s)) => s.flush(),
+            Conn::Utp(None) => Ok(()),
+        }
     }
 }
 
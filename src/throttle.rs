@@ -1,5 +1,6 @@
 use amy::Registrar;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashSet;
 use std::rc::Rc;
 
@@ -189,6 +190,17 @@ impl Throttle {
         self.dl_tier.borrow_mut().rate
     }
 
+    /// The upload rate this throttle is held to, taking the global limit
+    /// into account, or None if it's unlimited.
+    pub fn ul_limit(&self) -> Option<u64> {
+        let global = self.ul_data.borrow().rate.filter(|&r| r >= 0);
+        match self.ul_rate() {
+            Some(r) if r < 0 => None,
+            Some(r) => Some(global.map_or(r, |g| cmp::min(r, g)) as u64),
+            None => global.map(|g| g as u64),
+        }
+    }
+
     pub fn set_ul_rate(&mut self, rate: Option<i64>) {
         self.ul_tier.borrow_mut().rate = rate;
     }
@@ -0,0 +1,120 @@
+//! Banning of peers which send corrupt data.
+//!
+//! Every address which sent blocks of a piece is remembered until the piece
+//! is hashed. Each failed piece is a strike against its contributors, and
+//! an address which collects enough strikes is refused for a while. Peers
+//! which sent a whole bad piece by themselves are certainly at fault, so
+//! that counts double.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::util::{FHashMap, FHashSet};
+use crate::CONFIG;
+
+#[derive(Debug, Default)]
+pub struct Bans {
+    /// Addresses which sent blocks of each piece being downloaded
+    contributors: FHashMap<u32, FHashSet<IpAddr>>,
+    /// Strikes collected by each address
+    strikes: FHashMap<IpAddr, u32>,
+    /// When each banned address was banned
+    banned: FHashMap<IpAddr, Instant>,
+}
+
+impl Bans {
+    pub fn new() -> Bans {
+        Default::default()
+    }
+
+    /// Records that an address sent a block of a piece.
+    pub fn contributed(&mut self, piece: u32, ip: IpAddr) {
+        self.contributors.entry(piece).or_default().insert(ip);
+    }
+
+    /// Records the hash check result of a piece, returning the addresses
+    /// which were newly banned by it.
+    pub fn validated(&mut self, piece: u32, valid: bool) -> Vec<IpAddr> {
+        let contributors = match self.contributors.remove(&piece) {
+            Some(c) => c,
+            None => return vec![],
+        };
+        if valid || CONFIG.peer.ban_strikes == 0 {
+            return vec![];
+        }
+        let penalty = if contributors.len() == 1 { 2 } else { 1 };
+        let mut banned = Vec::new();
+        for ip in contributors {
+            let strikes = self.strikes.entry(ip).or_insert(0);
+            *strikes += penalty;
+            if *strikes >= CONFIG.peer.ban_strikes {
+                self.strikes.remove(&ip);
+                self.banned.insert(ip, Instant::now());
+                banned.push(ip);
+            }
+        }
+        banned.sort_unstable();
+        banned
+    }
+
+    /// Addresses which sent blocks of a piece being downloaded.
+    pub fn contributors(&self, piece: u32) -> Vec<IpAddr> {
+        self.contributors
+            .get(&piece)
+            .map(|c| c.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forgets who sent blocks of a piece, e.g. when its download restarts
+    /// for reasons other than a failed hash check.
+    pub fn reset(&mut self, piece: u32) {
+        self.contributors.remove(&piece);
+    }
+
+    pub fn banned(&self, ip: IpAddr) -> bool {
+        self.banned
+            .get(&ip)
+            .map(|at| at.elapsed() < Duration::from_secs(CONFIG.peer.ban_secs))
+            .unwrap_or(false)
+    }
+
+    /// Lifts expired bans.
+    pub fn tick(&mut self) {
+        let duration = Duration::from_secs(CONFIG.peer.ban_secs);
+        self.banned.retain(|_, at| at.elapsed() < duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strikes() {
+        let mut b = Bans::new();
+        let (bad, good) = ("1.2.3.4".parse().unwrap(), "5.6.7.8".parse().unwrap());
+
+        // Shared blame builds up slowly, and passing pieces cost nothing
+        b.contributed(0, bad);
+        b.contributed(0, good);
+        assert!(b.validated(0, false).is_empty());
+        b.contributed(1, good);
+        assert!(b.validated(1, true).is_empty());
+        b.contributed(2, bad);
+        b.contributed(2, good);
+        assert!(b.validated(2, false).is_empty());
+        assert!(!b.banned(bad));
+
+        // A bad piece from a single peer is certainly its fault
+        b.contributed(3, bad);
+        assert_eq!(b.contributors(3), vec![bad]);
+        assert_eq!(b.validated(3, false), vec![bad]);
+        assert!(b.banned(bad) && !b.banned(good));
+        b.tick();
+        assert!(b.banned(bad));
+
+        b.contributed(4, good);
+        b.reset(4);
+        assert!(b.validated(4, false).is_empty());
+    }
+}
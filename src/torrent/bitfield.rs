@@ -21,6 +21,11 @@ impl Bitfield {
         }
     }
 
+    /// A bitfield with every bit set.
+    pub fn full(len: u64) -> Bitfield {
+        Bitfield::C { len }
+    }
+
     pub fn from(b: &[u8], len: u64) -> Bitfield {
         let size = div_round_up!(len, 8);
         let mut vec = b.to_vec();
@@ -1,12 +1,25 @@
+//! Unchoking of interested peers.
+//!
+//! A fixed number of interested peers are unchoked at a time. Every round
+//! one of them may give up its slot to a waiting peer, with the choice of
+//! who goes and who comes in left to a `Strategy`.
+
 use std::time::{Duration, Instant};
 
+use crate::config::Choking;
 use crate::control::cio;
 use crate::torrent::Peer;
-use crate::util::{random_sample, FHashSet, UHashMap};
+use crate::util::{random_sample, UHashMap};
+use crate::CONFIG;
 
 pub struct Choker {
+    /// Number of peers to keep unchoked
+    slots: usize,
+    /// Unchoked peers, in the order they were unchoked
     unchoked: Vec<usize>,
-    interested: FHashSet<usize>,
+    /// Peers waiting to be unchoked, in the order they started waiting
+    interested: Vec<usize>,
+    strategy: Box<dyn Strategy>,
     last_updated: Instant,
 }
 
@@ -16,35 +29,181 @@ pub struct SwapRes {
     pub unchoked: usize,
 }
 
+/// Transfer rates of an unchoked peer over the last round
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rates {
+    pub ul: u32,
+    pub dl: u32,
+}
+
+/// Decides which peers hold the unchoke slots.
+pub trait Strategy {
+    /// Picks the unchoked peer which gives up its slot this round, as an
+    /// index into rates, which are in the order peers were unchoked.
+    fn choke(&mut self, rates: &[Rates], seeding: bool) -> Option<usize>;
+
+    /// Picks the waiting peer to unchoke, as an index into the number of
+    /// waiting peers, which are in the order they started waiting.
+    fn unchoke(&mut self, waiting: usize) -> Option<usize>;
+
+    /// Whether the number of slots is tuned to throughput.
+    fn tuned(&self) -> bool {
+        true
+    }
+}
+
+/// Reciprocates by choking the peer we get the least from while
+/// downloading, or send the least to while seeding, and unchokes a random
+/// peer so that new peers get a chance to prove themselves.
+pub struct TitForTat;
+
+impl Strategy for TitForTat {
+    fn choke(&mut self, rates: &[Rates], seeding: bool) -> Option<usize> {
+        slowest(rates, seeding)
+    }
+
+    fn unchoke(&mut self, waiting: usize) -> Option<usize> {
+        random_sample(0..waiting)
+    }
+}
+
+/// Rotates slots through every waiting peer while seeding, choking
+/// whoever has been unchoked longest, so that upload capacity is shared
+/// evenly rather than going to the fastest downloaders. Reciprocates
+/// like tit-for-tat while downloading.
+pub struct RoundRobin;
+
+impl Strategy for RoundRobin {
+    fn choke(&mut self, rates: &[Rates], seeding: bool) -> Option<usize> {
+        if seeding {
+            if rates.is_empty() {
+                None
+            } else {
+                Some(0)
+            }
+        } else {
+            slowest(rates, seeding)
+        }
+    }
+
+    fn unchoke(&mut self, waiting: usize) -> Option<usize> {
+        if waiting == 0 {
+            None
+        } else {
+            Some(0)
+        }
+    }
+}
+
+/// Keeps a fixed number of slots and never rotates them, a slot is only
+/// handed on once its peer loses interest or disconnects.
+pub struct FixedSlots;
+
+impl Strategy for FixedSlots {
+    fn choke(&mut self, _: &[Rates], _: bool) -> Option<usize> {
+        None
+    }
+
+    fn unchoke(&mut self, waiting: usize) -> Option<usize> {
+        if waiting == 0 {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    fn tuned(&self) -> bool {
+        false
+    }
+}
+
+/// The peer transferring the least in the direction which matters.
+fn slowest(rates: &[Rates], seeding: bool) -> Option<usize> {
+    rates
+        .iter()
+        .enumerate()
+        .min_by_key(|&(_, r)| if seeding { r.ul } else { r.dl })
+        .map(|(idx, _)| idx)
+}
+
 impl Choker {
     pub fn new() -> Choker {
+        let strategy: Box<dyn Strategy> = match CONFIG.peer.choking {
+            Choking::TitForTat => Box::new(TitForTat),
+            Choking::RoundRobin => Box::new(RoundRobin),
+            Choking::Fixed => Box::new(FixedSlots),
+        };
+        Choker::with_strategy(strategy)
+    }
+
+    pub fn with_strategy(strategy: Box<dyn Strategy>) -> Choker {
         Choker {
+            slots: 5,
             unchoked: Vec::with_capacity(5),
-            interested: FHashSet::default(),
+            interested: Vec::new(),
+            strategy,
             last_updated: Instant::now(),
         }
     }
 
+    /// Whether the number of slots should be tuned to throughput.
+    pub fn tuned(&self) -> bool {
+        self.strategy.tuned()
+    }
+
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &mut Peer<T>) {
-        if self.unchoked.len() < 5 {
+        if self.unchoked.contains(&peer.id()) || self.interested.contains(&peer.id()) {
+            return;
+        }
+        if self.unchoked.len() < self.slots {
             self.unchoked.push(peer.id());
             peer.flush();
             peer.unchoke();
         } else {
-            self.interested.insert(peer.id());
+            self.interested.push(peer.id());
         }
     }
 
-    fn unchoke_random<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<usize> {
-        if let Some(random_id) = random_sample(self.interested.iter()).cloned() {
-            peers.get_mut(&random_id).map(|mut peer| {
-                self.interested.remove(&random_id);
-                self.add_peer(&mut peer);
-                random_id
+    /// Number of interested peers waiting to be unchoked.
+    pub fn waiting(&self) -> usize {
+        self.interested.len()
+    }
+
+    /// Changes the number of unchoked peers, choking the most recently
+    /// unchoked peers or unchoking waiting ones to match.
+    pub fn set_slots<T: cio::CIO>(&mut self, slots: usize, peers: &mut UHashMap<Peer<T>>) {
+        self.slots = slots;
+        while self.unchoked.len() > slots {
+            let id = self.unchoked.pop().unwrap();
+            if let Some(peer) = peers.get_mut(&id) {
+                peer.choke();
+            }
+            self.interested.push(id);
+        }
+        while self.unchoked.len() < slots && self.unchoke_next(peers).is_some() {}
+    }
+
+    /// Unchokes the waiting peer picked by the strategy. Peers which are
+    /// snubbing us are passed over unless nobody else is waiting.
+    fn unchoke_next<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<usize> {
+        let candidates: Vec<_> = (0..self.interested.len())
+            .filter(|&i| {
+                peers
+                    .get(&self.interested[i])
+                    .map(|p| !p.snubbed())
+                    .unwrap_or(false)
             })
+            .collect();
+        let idx = if candidates.is_empty() {
+            self.strategy.unchoke(self.interested.len())?
         } else {
-            None
-        }
+            candidates[self.strategy.unchoke(candidates.len())?]
+        };
+        let id = self.interested.remove(idx);
+        peers.get_mut(&id).map(|peer| {
+            self.add_peer(peer);
+            id
+        })
     }
 
     pub fn remove_peer<T: cio::CIO>(
@@ -55,19 +214,19 @@ impl Choker {
         if let Some(idx) = self.unchoked.iter().position(|&id| id == peer.id()) {
             self.unchoked.remove(idx);
             peer.choke();
-            self.unchoke_random(peers).map(|unchoked| SwapRes {
+            self.unchoke_next(peers).map(|unchoked| SwapRes {
                 choked: peer.id(),
                 unchoked,
             })
         } else {
-            self.interested.remove(&peer.id());
+            self.interested.retain(|&id| id != peer.id());
             None
         }
     }
 
     fn update_timer(&mut self) -> Result<(), ()> {
         if self.last_updated.elapsed() < Duration::from_secs(10)
-            || self.unchoked.len() < 5
+            || self.unchoked.len() < self.slots
             || self.interested.is_empty()
         {
             Err(())
@@ -77,39 +236,26 @@ impl Choker {
         }
     }
 
-    pub fn update_upload<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<SwapRes> {
-        if self.update_timer().is_err() {
-            return None;
-        }
-        if self.interested.is_empty() {
-            return None;
-        }
-        let (slowest, _) = self.unchoked.iter().enumerate().fold(
-            (0, std::u32::MAX),
-            |(slowest, min), (idx, id)| match peers.get_mut(id).map(Peer::flush) {
-                Some((ul, _)) if ul < min => (idx, ul),
-                _ => (slowest, min),
-            },
-        );
-        self.swap_peer(slowest, peers)
-    }
-
-    pub fn update_download<T: cio::CIO>(
+    /// Lets the strategy hand one slot over to a waiting peer, at most
+    /// once every round.
+    pub fn update<T: cio::CIO>(
         &mut self,
         peers: &mut UHashMap<Peer<T>>,
+        seeding: bool,
     ) -> Option<SwapRes> {
         if self.update_timer().is_err() {
             return None;
         }
-
-        let (slowest, _) = self.unchoked.iter().enumerate().fold(
-            (0, std::u32::MAX),
-            |(slowest, min), (idx, id)| match peers.get_mut(id).map(Peer::flush) {
-                Some((_, dl)) if dl < min => (idx, dl),
-                _ => (slowest, min),
-            },
-        );
-        self.swap_peer(slowest, peers)
+        let rates: Vec<_> = self
+            .unchoked
+            .iter()
+            .map(|id| match peers.get_mut(id).map(Peer::flush) {
+                Some((ul, dl)) => Rates { ul, dl },
+                None => Rates { ul: 0, dl: 0 },
+            })
+            .collect();
+        let idx = self.strategy.choke(&rates, seeding)?;
+        self.swap_peer(idx, peers)
     }
 
     fn swap_peer<T: cio::CIO>(
@@ -122,23 +268,19 @@ impl Choker {
             peers.get_mut(&id).map(Peer::choke);
         }
 
-        // Unchoke one random interested peer
-        self.unchoke_random(peers).map(|unchoked| {
-            self.interested.insert(id);
-            SwapRes {
-                choked: id,
-                unchoked,
-            }
+        let unchoked = self.unchoke_next(peers);
+        self.interested.push(id);
+        unchoked.map(|unchoked| SwapRes {
+            choked: id,
+            unchoked,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Choker, SwapRes};
-    use crate::torrent::{Bitfield, Peer};
-    use crate::util::UHashMap;
-    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::torrent::Bitfield;
 
     #[test]
     fn test_add_peers() {
@@ -176,19 +318,36 @@ mod tests {
         assert_eq!(c.unchoked.contains(&v[0].id()), false);
     }
 
+    #[test]
+    fn test_set_slots() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        for i in 0..8 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        c.set_slots(3, &mut h);
+        assert_eq!(c.unchoked.len(), 3);
+        assert_eq!(c.waiting(), 5);
+        c.set_slots(6, &mut h);
+        assert_eq!(c.unchoked.len(), 6);
+        assert_eq!(c.waiting(), 2);
+    }
+
     #[test]
     fn test_update_upload() {
         let mut c = Choker::new();
         let mut h = UHashMap::default();
-        assert_eq!(c.update_upload(&mut h).is_none(), true);
+        assert_eq!(c.update(&mut h, true).is_none(), true);
         for i in 0..6 {
             let mut p = Peer::test_from_stats(i, i as u32, 6 - i as u32);
             c.add_peer(&mut p);
             h.insert(i, p);
         }
-        assert_eq!(c.update_upload(&mut h).is_none(), true);
+        assert_eq!(c.update(&mut h, true).is_none(), true);
         c.last_updated = Instant::now() - Duration::from_secs(11);
-        let res = c.update_upload(&mut h).unwrap();
+        let res = c.update(&mut h, true).unwrap();
         assert_eq!(res.choked, 0);
         assert_eq!(res.unchoked, 5);
     }
@@ -197,16 +356,69 @@ mod tests {
     fn test_update_download() {
         let mut c = Choker::new();
         let mut h = UHashMap::default();
-        assert_eq!(c.update_download(&mut h).is_none(), true);
+        assert_eq!(c.update(&mut h, false).is_none(), true);
         for i in 0..6 {
             let mut p = Peer::test_from_stats(i, 6 - i as u32, i as u32);
             c.add_peer(&mut p);
             h.insert(i, p);
         }
-        assert_eq!(c.update_download(&mut h).is_none(), true);
+        assert_eq!(c.update(&mut h, false).is_none(), true);
         c.last_updated = Instant::now() - Duration::from_secs(11);
-        let res = c.update_download(&mut h).unwrap();
+        let res = c.update(&mut h, false).unwrap();
         assert_eq!(res.choked, 0);
         assert_eq!(res.unchoked, 5);
     }
+
+    #[test]
+    fn test_round_robin() {
+        let mut c = Choker::with_strategy(Box::new(RoundRobin));
+        let mut h = UHashMap::default();
+        for i in 0..7 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        // Slots go to whoever waited longest, taken from whoever held
+        // one longest
+        for i in 0..3 {
+            c.last_updated = Instant::now() - Duration::from_secs(11);
+            let res = c.update(&mut h, true).unwrap();
+            assert_eq!(
+                res,
+                SwapRes {
+                    choked: i,
+                    unchoked: [5, 6, 0][i],
+                }
+            );
+        }
+        assert_eq!(c.unchoked, vec![3, 4, 5, 6, 0]);
+        assert_eq!(c.interested, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_fixed_slots() {
+        let mut c = Choker::with_strategy(Box::new(FixedSlots));
+        assert!(!c.tuned());
+        let mut h = UHashMap::default();
+        for i in 0..7 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        c.last_updated = Instant::now() - Duration::from_secs(11);
+        assert!(c.update(&mut h, true).is_none());
+        let mut p = Peer::test_from_stats(2, 0, 0);
+        let res = c.remove_peer(&mut p, &mut h).unwrap();
+        assert_eq!(res.unchoked, 5);
+    }
+
+    #[test]
+    fn test_slowest() {
+        let rates = [Rates { ul: 5, dl: 1 }, Rates { ul: 1, dl: 5 }];
+        assert_eq!(TitForTat.choke(&rates, false), Some(0));
+        assert_eq!(TitForTat.choke(&rates, true), Some(1));
+        assert_eq!(RoundRobin.choke(&rates, false), Some(0));
+        assert_eq!(RoundRobin.choke(&rates, true), Some(0));
+        assert_eq!(TitForTat.choke(&[], true), None);
+    }
 }
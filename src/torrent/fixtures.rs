@@ -0,0 +1,380 @@
+//! Synthetic torrents for tests.
+//!
+//! `TorrentBuilder` produces the metainfo of a torrent along with its
+//! content, so tests don't need checked in .torrent files or hand built
+//! file layouts. Piece hashes are computed over the generated content,
+//! which makes the fixtures usable for hash checking as well.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::slice;
+
+use crate::bencode::BEncode;
+use crate::torrent::Info;
+use crate::util::sha1_hash;
+
+const DEFAULT_PIECE_LEN: u32 = 16_384;
+
+pub struct TorrentBuilder {
+    name: String,
+    /// Single file torrents have no files list and store their data in `name`
+    multi: bool,
+    files: Vec<FileSpec>,
+    piece_len: u32,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+    nodes: Vec<SocketAddr>,
+    private: bool,
+    hybrid: bool,
+}
+
+struct FileSpec {
+    path: Vec<String>,
+    length: u64,
+    pad: bool,
+}
+
+impl TorrentBuilder {
+    pub fn single(name: &str, length: u64) -> TorrentBuilder {
+        let mut b = TorrentBuilder::new(name, false);
+        b.files.push(FileSpec {
+            path: vec![],
+            length,
+            pad: false,
+        });
+        b
+    }
+
+    pub fn multi(name: &str) -> TorrentBuilder {
+        TorrentBuilder::new(name, true)
+    }
+
+    /// A multi file torrent of `count` files of `length` bytes each.
+    pub fn many_files(name: &str, count: usize, length: u64) -> TorrentBuilder {
+        (0..count).fold(TorrentBuilder::multi(name), |b, i| {
+            b.file(&format!("{:06}.bin", i), length)
+        })
+    }
+
+    fn new(name: &str, multi: bool) -> TorrentBuilder {
+        TorrentBuilder {
+            name: name.to_owned(),
+            multi,
+            files: Vec::new(),
+            piece_len: DEFAULT_PIECE_LEN,
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            nodes: Vec::new(),
+            private: false,
+            hybrid: false,
+        }
+    }
+
+    /// Adds a file, with `/` separating the components of its path.
+    pub fn file(mut self, path: &str, length: u64) -> TorrentBuilder {
+        assert!(self.multi, "Single file torrents can't have more files");
+        self.files.push(FileSpec {
+            path: path.split('/').map(str::to_owned).collect(),
+            length,
+            pad: false,
+        });
+        self
+    }
+
+    /// Adds a BEP47 pad file of the given length.
+    pub fn pad(mut self, length: u64) -> TorrentBuilder {
+        assert!(self.multi, "Single file torrents can't have pad files");
+        self.files.push(FileSpec {
+            path: vec![".pad".to_owned(), length.to_string()],
+            length,
+            pad: true,
+        });
+        self
+    }
+
+    /// Pads the torrent so that the next file starts on a piece boundary.
+    pub fn align(self) -> TorrentBuilder {
+        let rem = self.total_len() % u64::from(self.piece_len);
+        if rem == 0 {
+            self
+        } else {
+            let len = u64::from(self.piece_len) - rem;
+            self.pad(len)
+        }
+    }
+
+    pub fn piece_len(mut self, piece_len: u32) -> TorrentBuilder {
+        self.piece_len = piece_len;
+        self
+    }
+
+    pub fn announce(mut self, url: &str) -> TorrentBuilder {
+        self.announce = Some(url.to_owned());
+        self
+    }
+
+    pub fn tier(mut self, urls: &[&str]) -> TorrentBuilder {
+        self.announce_list
+            .push(urls.iter().map(|u| (*u).to_owned()).collect());
+        self
+    }
+
+    pub fn web_seed(mut self, url: &str) -> TorrentBuilder {
+        self.web_seeds.push(url.to_owned());
+        self
+    }
+
+    pub fn http_seed(mut self, url: &str) -> TorrentBuilder {
+        self.http_seeds.push(url.to_owned());
+        self
+    }
+
+    pub fn node(mut self, addr: &str) -> TorrentBuilder {
+        self.nodes.push(addr.parse().unwrap());
+        self
+    }
+
+    pub fn private(mut self) -> TorrentBuilder {
+        self.private = true;
+        self
+    }
+
+    /// Adds the BEP52 v2 keys alongside the v1 ones, as a hybrid torrent
+    /// would. Only the v1 half is understood, so the v2 pieces roots are
+    /// placeholders rather than real merkle roots.
+    pub fn hybrid(mut self) -> TorrentBuilder {
+        self.hybrid = true;
+        self
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+
+    /// The torrent's data. Pad files are zeroed, everything else follows a
+    /// pattern which doesn't repeat on piece boundaries.
+    pub fn content(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.total_len() as usize);
+        for f in &self.files {
+            for _ in 0..f.length {
+                let b = if f.pad { 0 } else { (data.len() % 251) as u8 };
+                data.push(b);
+            }
+        }
+        data
+    }
+
+    pub fn info_bencode(&self) -> BEncode {
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::from_str(&self.name));
+        info.insert(
+            "piece length".to_owned(),
+            BEncode::Int(i64::from(self.piece_len)),
+        );
+        let pieces = self
+            .content()
+            .chunks(self.piece_len as usize)
+            .flat_map(|p| sha1_hash(p).to_vec())
+            .collect();
+        info.insert("pieces".to_owned(), BEncode::String(pieces));
+        if self.private {
+            info.insert("private".to_owned(), BEncode::Int(1));
+        }
+        if self.multi {
+            let files = self
+                .files
+                .iter()
+                .map(|f| {
+                    let mut d = BTreeMap::new();
+                    d.insert("length".to_owned(), BEncode::Int(f.length as i64));
+                    let path = f.path.iter().map(|p| BEncode::from_str(p)).collect();
+                    d.insert("path".to_owned(), BEncode::List(path));
+                    if f.pad {
+                        d.insert("attr".to_owned(), BEncode::from_str("p"));
+                    }
+                    BEncode::Dict(d)
+                })
+                .collect();
+            info.insert("files".to_owned(), BEncode::List(files));
+        } else {
+            info.insert(
+                "length".to_owned(),
+                BEncode::Int(self.files[0].length as i64),
+            );
+        }
+        if self.hybrid {
+            info.insert("meta version".to_owned(), BEncode::Int(2));
+            info.insert("file tree".to_owned(), self.file_tree());
+        }
+        BEncode::Dict(info)
+    }
+
+    fn file_tree(&self) -> BEncode {
+        let mut tree = BTreeMap::new();
+        for f in self.files.iter().filter(|f| !f.pad) {
+            let path = if self.multi {
+                &f.path[..]
+            } else {
+                slice::from_ref(&self.name)
+            };
+            insert_tree(&mut tree, path, f.length);
+        }
+        BEncode::Dict(tree)
+    }
+
+    pub fn bencode(&self) -> BEncode {
+        let mut torrent = BTreeMap::new();
+        torrent.insert("info".to_owned(), self.info_bencode());
+        if let Some(ref url) = self.announce {
+            torrent.insert("announce".to_owned(), BEncode::from_str(url));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|t| BEncode::List(t.iter().map(|u| BEncode::from_str(u)).collect()))
+                .collect();
+            torrent.insert("announce-list".to_owned(), BEncode::List(tiers));
+        }
+        if !self.web_seeds.is_empty() {
+            let seeds = self
+                .web_seeds
+                .iter()
+                .map(|u| BEncode::from_str(u))
+                .collect();
+            torrent.insert("url-list".to_owned(), BEncode::List(seeds));
+        }
+        if !self.http_seeds.is_empty() {
+            let seeds = self
+                .http_seeds
+                .iter()
+                .map(|u| BEncode::from_str(u))
+                .collect();
+            torrent.insert("httpseeds".to_owned(), BEncode::List(seeds));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|a| {
+                    BEncode::List(vec![
+                        BEncode::from_str(&a.ip().to_string()),
+                        BEncode::Int(i64::from(a.port())),
+                    ])
+                })
+                .collect();
+            torrent.insert("nodes".to_owned(), BEncode::List(nodes));
+        }
+        BEncode::Dict(torrent)
+    }
+
+    /// The encoded .torrent file.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bencode().encode_to_buf()
+    }
+
+    pub fn build(&self) -> Info {
+        Info::from_bencode(self.bencode()).unwrap()
+    }
+}
+
+/// Inserts a file into a BEP52 file tree, creating its directories as needed.
+fn insert_tree(tree: &mut BTreeMap<String, BEncode>, path: &[String], length: u64) {
+    let dir = match tree
+        .entry(path[0].clone())
+        .or_insert_with(|| BEncode::Dict(BTreeMap::new()))
+    {
+        BEncode::Dict(d) => d,
+        _ => unreachable!(),
+    };
+    if path.len() > 1 {
+        return insert_tree(dir, &path[1..], length);
+    }
+    let mut leaf = BTreeMap::new();
+    leaf.insert("length".to_owned(), BEncode::Int(length as i64));
+    if length > 0 {
+        leaf.insert("pieces root".to_owned(), BEncode::String(vec![0; 32]));
+    }
+    dir.insert(String::new(), BEncode::Dict(leaf));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_golden_single() {
+        let b = TorrentBuilder::single("a", 5);
+        let mut golden = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        golden.extend_from_slice(&sha1_hash(&[0, 1, 2, 3, 4]));
+        golden.extend_from_slice(b"ee");
+        assert_eq!(b.bytes(), golden);
+
+        let info = b.build();
+        assert_eq!(info.name, "a");
+        assert_eq!(info.total_len, 5);
+        assert_eq!(info.pieces(), 1);
+        let info_bytes = b.info_bencode().encode_to_buf();
+        assert_eq!(info.hash, sha1_hash(&info_bytes));
+    }
+
+    #[test]
+    fn test_multi_padded() {
+        let b = TorrentBuilder::multi("dir")
+            .piece_len(16_384)
+            .file("a/x", 10_000)
+            .align()
+            .file("b", 20_000)
+            .announce("http://tracker.example.com/announce")
+            .web_seed("http://seed.example.com/");
+        let info = b.build();
+        assert_eq!(info.files.len(), 3);
+        assert_eq!(info.files[0].path, PathBuf::from("dir/a/x"));
+        assert!(info.files[1].pad);
+        assert_eq!(info.files[1].length, 6_384);
+        assert_eq!(info.total_len, 36_384);
+        assert_eq!(info.pieces(), 3);
+        assert_eq!(info.web_seeds.len(), 1);
+        assert!(info.announce.is_some());
+
+        // Pad files are zeroed and the hashes match the content
+        let data = b.content();
+        assert!(data[10_000..16_384].iter().all(|b| *b == 0));
+        for (i, piece) in data.chunks(16_384).enumerate() {
+            assert_eq!(&info.hashes[i][..], &sha1_hash(piece)[..]);
+        }
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let b = TorrentBuilder::multi("dir")
+            .file("a/x", 10)
+            .file("a/y", 0)
+            .file("b", 20)
+            .hybrid();
+        let info = b.info_bencode().into_dict().unwrap();
+        assert_eq!(info.get("meta version"), Some(&BEncode::Int(2)));
+        let tree = info["file tree"].as_dict().unwrap();
+        let a = tree["a"].as_dict().unwrap();
+        assert_eq!(a.len(), 2);
+        let y = a["y"].as_dict().unwrap()[""].as_dict().unwrap();
+        assert!(!y.contains_key("pieces root"));
+        assert!(tree["b"].as_dict().unwrap().contains_key(""));
+
+        // The v1 half still parses
+        assert_eq!(b.build().files.len(), 3);
+    }
+
+    #[test]
+    fn test_many_files() {
+        let info = TorrentBuilder::many_files("many", 10_000, 3).build();
+        assert_eq!(info.files.len(), 10_000);
+        assert_eq!(info.total_len, 30_000);
+        assert_eq!(info.pieces(), 2);
+    }
+}
@@ -0,0 +1,124 @@
+//! NAT holepunching (BEP 55).
+//!
+//! A peer which can't reach another directly asks a relay connected to both
+//! of them for a rendezvous. The relay then tells each side to connect to
+//! the other over uTP at the same time, so that both NATs see outgoing
+//! traffic and let the other side's packets through.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// Most rendezvous requested for the peers in a single PEX message
+pub const MAX_RENDEZVOUS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Msg {
+    /// Asks the relay to connect us with a peer
+    Rendezvous(SocketAddr),
+    /// Tells us to connect to a peer, which is connecting to us too
+    Connect(SocketAddr),
+    /// The relay couldn't arrange a rendezvous with a peer
+    Error(SocketAddr, ErrorCode),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// The peer isn't known to the relay
+    NoSuchPeer = 1,
+    /// The relay isn't connected to the peer
+    NotConnected = 2,
+    /// The peer doesn't support holepunching
+    NoSupport = 3,
+    /// The peer is the one asking for the rendezvous
+    NoSelf = 4,
+}
+
+impl Msg {
+    pub fn decode(payload: &[u8]) -> Option<Msg> {
+        let (&kind, rest) = payload.split_first()?;
+        let (&addr_type, rest) = rest.split_first()?;
+        let ip_len = match addr_type {
+            0 => 4,
+            1 => 16,
+            _ => return None,
+        };
+        if rest.len() < ip_len + 6 {
+            return None;
+        }
+        let ip = if ip_len == 4 {
+            IpAddr::V4(Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]))
+        } else {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rest[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        };
+        let addr = SocketAddr::new(ip, BigEndian::read_u16(&rest[ip_len..]));
+        match kind {
+            0 => Some(Msg::Rendezvous(addr)),
+            1 => Some(Msg::Connect(addr)),
+            2 => {
+                let code = match BigEndian::read_u32(&rest[ip_len + 2..]) {
+                    1 => ErrorCode::NoSuchPeer,
+                    2 => ErrorCode::NotConnected,
+                    3 => ErrorCode::NoSupport,
+                    4 => ErrorCode::NoSelf,
+                    _ => return None,
+                };
+                Some(Msg::Error(addr, code))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, addr, code) = match *self {
+            Msg::Rendezvous(addr) => (0, addr, 0),
+            Msg::Connect(addr) => (1, addr, 0),
+            Msg::Error(addr, code) => (2, addr, code as u32),
+        };
+        let mut b = vec![kind];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                b.push(0);
+                b.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                b.push(1);
+                b.extend_from_slice(&ip.octets());
+            }
+        }
+        b.extend_from_slice(&addr.port().to_be_bytes());
+        b.extend_from_slice(&code.to_be_bytes());
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msg() {
+        let v4: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let v6: SocketAddr = "[::1]:6881".parse().unwrap();
+        let msgs = [
+            Msg::Rendezvous(v4),
+            Msg::Connect(v6),
+            Msg::Error(v4, ErrorCode::NoSelf),
+        ];
+        for msg in &msgs {
+            assert_eq!(Msg::decode(&msg.encode()), Some(*msg));
+        }
+        assert_eq!(
+            Msg::Connect(v4).encode(),
+            vec![1, 0, 1, 2, 3, 4, 0, 5, 0, 0, 0, 0]
+        );
+
+        // Truncated messages and unknown types are rejected
+        let buf = Msg::Rendezvous(v6).encode();
+        assert_eq!(Msg::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(Msg::decode(&[3, 0, 1, 2, 3, 4, 0, 5, 0, 0, 0, 0]), None);
+        assert_eq!(Msg::decode(&[2, 0, 1, 2, 3, 4, 0, 5, 0, 0, 0, 9]), None);
+    }
+}
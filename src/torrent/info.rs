@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
@@ -26,6 +27,12 @@ pub struct Info {
     /// Maps piece idx -> file idx + file offset
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Arc<Url>>>,
+    /// BEP19 web seed URLs
+    pub web_seeds: Vec<Arc<Url>>,
+    /// BEP17 HTTP seed URLs
+    pub http_seeds: Vec<Arc<Url>>,
+    /// DHT nodes embedded in the metainfo, used to bootstrap trackerless torrents
+    pub nodes: Vec<SocketAddr>,
 }
 
 impl fmt::Debug for Info {
@@ -54,16 +61,35 @@ impl fmt::Debug for Info {
 pub struct File {
     pub path: PathBuf,
     pub length: u64,
+    /// Padding file, whose zeroes are never written to disk
+    pub pad: bool,
 }
 
 impl File {
+    /// The path inside the torrent's folder, or the name of a single file
+    /// torrent's file. Cross-seeded torrents share these even when named
+    /// differently.
+    pub fn inner_path(&self) -> &Path {
+        let mut components = self.path.components();
+        components.next();
+        match components.as_path() {
+            p if p.as_os_str().is_empty() => &self.path,
+            p => p,
+        }
+    }
+
     fn from_bencode(data: BEncode) -> Result<File, &'static str> {
         let mut d = data.into_dict().ok_or("File must be a dictionary type!")?;
+        let pad = match d.remove("attr") {
+            Some(BEncode::String(attr)) => attr.contains(&b'p'),
+            _ => false,
+        };
         match (d.remove("name"), d.remove("path"), d.remove("length")) {
             (Some(v), None, Some(l)) => {
                 let f = File {
                     path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pad,
                 };
                 Ok(f)
             }
@@ -78,6 +104,7 @@ impl File {
                 let f = File {
                     path: p,
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pad,
                 };
                 Ok(f)
             }
@@ -122,6 +149,13 @@ impl Info {
             .collect();
         rand::thread_rng().shuffle(&mut url_list[..]);
 
+        let web_seeds = url
+            .query_pairs()
+            .filter(|(k, _)| k == "ws")
+            .filter_map(|(_, v)| Url::parse(&v).ok())
+            .map(Arc::new)
+            .collect();
+
         let name = url
             .query_pairs()
             .find(|&(ref k, _)| k == "dn")
@@ -141,6 +175,9 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![url_list],
+            web_seeds,
+            http_seeds: vec![],
+            nodes: vec![],
         })
     }
 
@@ -157,6 +194,35 @@ impl Info {
                 BEncode::String(url.as_str().as_bytes().to_owned()),
             )
         });
+        if !self.web_seeds.is_empty() {
+            let seeds = self
+                .web_seeds
+                .iter()
+                .map(|url| BEncode::String(url.as_str().as_bytes().to_owned()))
+                .collect();
+            torrent.insert("url-list".to_owned(), BEncode::List(seeds));
+        }
+        if !self.http_seeds.is_empty() {
+            let seeds = self
+                .http_seeds
+                .iter()
+                .map(|url| BEncode::String(url.as_str().as_bytes().to_owned()))
+                .collect();
+            torrent.insert("httpseeds".to_owned(), BEncode::List(seeds));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|addr| {
+                    BEncode::List(vec![
+                        BEncode::String(addr.ip().to_string().into_bytes()),
+                        BEncode::Int(i64::from(addr.port())),
+                    ])
+                })
+                .collect();
+            torrent.insert("nodes".to_owned(), BEncode::List(nodes));
+        }
         torrent.insert("info".to_owned(), info);
         BEncode::Dict(torrent)
     }
@@ -190,6 +256,9 @@ impl Info {
                 .map(|f| {
                     let mut fb = BTreeMap::new();
                     fb.insert("length".to_owned(), BEncode::Int(f.length as i64));
+                    if f.pad {
+                        fb.insert("attr".to_owned(), BEncode::String(b"p".to_vec()));
+                    }
                     fb.insert(
                         "path".to_owned(),
                         BEncode::String(
@@ -311,6 +380,32 @@ impl Info {
                     })
                     .collect();
 
+                // url-list may either be a single URL or a list of them
+                let web_seeds = match d.remove("url-list") {
+                    Some(BEncode::List(l)) => l,
+                    Some(s) => vec![s],
+                    None => vec![],
+                }
+                .into_iter()
+                .filter_map(BEncode::into_string)
+                .filter_map(|s| Url::parse(&s).ok().map(Arc::new))
+                .collect();
+
+                let http_seeds = d
+                    .remove("httpseeds")
+                    .and_then(BEncode::into_list)
+                    .unwrap_or_else(Vec::new)
+                    .into_iter()
+                    .filter_map(BEncode::into_string)
+                    .filter_map(|s| Url::parse(&s).ok().map(Arc::new))
+                    .collect();
+
+                let nodes = d
+                    .remove("nodes")
+                    .and_then(BEncode::into_list)
+                    .map(parse_bencode_nodes)
+                    .unwrap_or_default();
+
                 Ok(Info {
                     name,
                     comment,
@@ -325,6 +420,9 @@ impl Info {
                     be_name,
                     piece_idx,
                     url_list,
+                    web_seeds,
+                    http_seeds,
+                    nodes,
                 })
             })
     }
@@ -359,6 +457,7 @@ impl Info {
                 File {
                     path: PathBuf::new(),
                     length: 16_384 * pieces as u64,
+                    pad: false,
                 };
                 1
             ],
@@ -366,6 +465,9 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
+            http_seeds: vec![],
+            nodes: vec![],
         }
     }
 
@@ -385,6 +487,9 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
+            http_seeds: vec![],
+            nodes: vec![],
         }
     }
 
@@ -420,6 +525,7 @@ impl Info {
     }
 
     /// Calculates the file offsets for a given block at index/begin
+    #[cfg(test)]
     pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
         let len = info.block_len(index, begin);
         LocIter::new(info.clone(), None, index, begin, len)
@@ -440,11 +546,23 @@ impl Info {
         let len = info.piece_len(index);
         LocIter::new(info.clone(), None, index, 0, len)
     }
+
+    pub fn piece_disk_locs_pri(info: &Arc<Info>, priorities: &Arc<Vec<u8>>, index: u32) -> LocIter {
+        let len = info.piece_len(index);
+        LocIter::new(info.clone(), Some(priorities.clone()), index, 0, len)
+    }
 }
 
+/// Iterates over the file locations of a block. Given file priorities,
+/// the locations in skipped files of pieces shared with wanted ones are
+/// marked to be kept in the torrent's part file.
 pub struct LocIter {
     info: Arc<Info>,
     priorities: Option<Arc<Vec<u8>>>,
+    /// Offset of the block in the torrent
+    base: u64,
+    /// Whether the piece spans a wanted file
+    shared: bool,
     state: LocIterState,
 }
 
@@ -484,12 +602,58 @@ impl LocIter {
             file,
         };
 
+        let shared = priorities
+            .as_ref()
+            .map(|pri| {
+                LocIter::new(info.clone(), None, index, 0, info.piece_len(index))
+                    .any(|loc| pri[loc.file] != 0)
+            })
+            .unwrap_or(false);
         LocIter {
+            base: u64::from(index) * u64::from(info.piece_len) + u64::from(begin),
             info,
             priorities,
+            shared,
             state: LocIterState::P(p),
         }
     }
+
+    /// Index of the piece the locations lie in.
+    pub fn piece(&self) -> u32 {
+        (self.base / u64::from(self.info.piece_len)) as u32
+    }
+
+    /// Offset of the locations in their piece.
+    pub fn begin(&self) -> u32 {
+        (self.base % u64::from(self.info.piece_len)) as u32
+    }
+
+    /// Locations of a whole piece of the same torrent and its length,
+    /// if it exists.
+    pub fn whole_piece(&self, index: u32) -> Option<(LocIter, usize)> {
+        if index >= self.info.pieces() {
+            return None;
+        }
+        let len = self.info.piece_len(index);
+        let locs = LocIter::new(self.info.clone(), self.priorities.clone(), index, 0, len);
+        Some((locs, len as usize))
+    }
+
+    fn location(&self, p: &LocIterPos, len: u64) -> disk::Location {
+        let wanted = self.priorities.as_ref().map(|pri| pri[p.file] != 0);
+        let part = wanted == Some(false) && self.shared && !self.info.files[p.file].pad;
+        disk::Location::new(
+            p.file,
+            self.info.files[p.file].length,
+            p.fidx,
+            p.data_start,
+            p.data_start + len,
+            self.info.clone(),
+            wanted.unwrap_or(false),
+            self.base + p.data_start,
+            part,
+        )
+    }
 }
 
 impl Iterator for LocIter {
@@ -504,32 +668,10 @@ impl Iterator for LocIter {
                 if file_write_len == p.len {
                     // The file is longer than our len, just write to it,
                     // exit loop
-                    Some(disk::Location::new(
-                        p.file,
-                        self.info.files[p.file].length,
-                        p.fidx,
-                        p.data_start,
-                        p.data_start + file_write_len,
-                        self.info.clone(),
-                        self.priorities
-                            .as_ref()
-                            .map(|pri| pri[p.file] != 0)
-                            .unwrap_or(false),
-                    ))
+                    Some(self.location(&p, file_write_len))
                 } else {
                     // Write to the end of file, continue
-                    let res = disk::Location::new(
-                        p.file,
-                        self.info.files[p.file].length,
-                        p.fidx,
-                        p.data_start,
-                        p.data_start + file_write_len,
-                        self.info.clone(),
-                        self.priorities
-                            .as_ref()
-                            .map(|pri| pri[p.file] != 0)
-                            .unwrap_or(false),
-                    );
+                    let res = self.location(&p, file_write_len);
 
                     // Use the next file, updating state as needed
                     p.fidx -= self.info.files[p.file].length - file_write_len;
@@ -567,9 +709,31 @@ fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>,
     }
 }
 
+/// Parses the BEP5 `nodes` list of `[host, port]` pairs. Only IP literals
+/// are accepted, since the DHT has no way to resolve hostnames.
+fn parse_bencode_nodes(nodes: Vec<BEncode>) -> Vec<SocketAddr> {
+    nodes
+        .into_iter()
+        .filter_map(BEncode::into_list)
+        .filter_map(|mut n| {
+            if n.len() != 2 {
+                return None;
+            }
+            let port = n.pop().and_then(BEncode::into_int)?;
+            let ip = n.pop().and_then(BEncode::into_string)?;
+            if port <= 0 || port > i64::from(u16::MAX) {
+                return None;
+            }
+            let ip: IpAddr = ip.parse().ok()?;
+            Some(SocketAddr::new(ip, port as u16))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::torrent::fixtures::TorrentBuilder;
 
     #[test]
     fn correct_piece_len() {
@@ -597,10 +761,12 @@ mod tests {
         info.files.push(File {
             path: PathBuf::from(""),
             length: 40000,
+            pad: false,
         });
         info.files.push(File {
             path: PathBuf::from(""),
             length: 10000,
+            pad: false,
         });
         info.total_len = 50000;
         info.piece_idx =
@@ -642,4 +808,134 @@ mod tests {
         assert_eq!(n.file, 1);
         assert_eq!(n.offset, 16384 - 7232);
     }
+
+    #[test]
+    fn loc_iter_parts() {
+        let info = TorrentBuilder::multi("t")
+            .file("a", 24_576)
+            .file("b", 16_384)
+            .file("c", 24_576)
+            .build();
+        let info = Arc::new(info);
+        let pri = Arc::new(vec![3, 0, 0]);
+
+        // Only the skipped file's share of a wanted piece goes to the part file
+        let locs: Vec<_> = Info::piece_disk_locs_pri(&info, &pri, 1).collect();
+        assert_eq!(locs.len(), 2);
+        assert!(!locs[0].part);
+        assert!(locs[1].part);
+        assert_eq!(locs[1].torrent_offset, 24_576);
+        let n = Info::block_disk_locs_pri(&info, &pri, 1, 12_288)
+            .next()
+            .unwrap();
+        assert!(n.part);
+        assert_eq!(n.offset, 4_096);
+        assert_eq!(n.torrent_offset, 28_672);
+        assert!(Info::piece_disk_locs_pri(&info, &pri, 2).all(|l| !l.part));
+        assert!(Info::piece_disk_locs(&info, 1).all(|l| !l.part));
+    }
+
+    #[test]
+    fn parse_web_seeds() {
+        let mut info = Info::with_pieces(1);
+        info.be_name = Some(b"file".to_vec());
+        info.files[0].path = PathBuf::from("file");
+        info.hashes = vec![vec![0u8; 20]];
+        info.web_seeds = vec![
+            Arc::new(Url::parse("http://a.com/file").unwrap()),
+            Arc::new(Url::parse("http://b.com/").unwrap()),
+        ];
+        let parsed = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(parsed.web_seeds, info.web_seeds);
+
+        let mut torrent = info.to_torrent_bencode().into_dict().unwrap();
+        torrent.insert(
+            "url-list".to_owned(),
+            BEncode::String(b"http://c.com/file".to_vec()),
+        );
+        let parsed = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert_eq!(parsed.web_seeds.len(), 1);
+        assert_eq!(parsed.web_seeds[0].as_str(), "http://c.com/file");
+
+        info.web_seeds = vec![];
+        info.http_seeds = vec![Arc::new(Url::parse("http://d.com/seed.php").unwrap())];
+        let parsed = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert!(parsed.web_seeds.is_empty());
+        assert_eq!(parsed.http_seeds, info.http_seeds);
+    }
+
+    #[test]
+    fn parse_pad_files() {
+        let file = |attr: Option<&[u8]>| {
+            let mut d = BTreeMap::new();
+            d.insert("name".to_owned(), BEncode::String(b"f".to_vec()));
+            d.insert("length".to_owned(), BEncode::Int(100));
+            if let Some(a) = attr {
+                d.insert("attr".to_owned(), BEncode::String(a.to_vec()));
+            }
+            File::from_bencode(BEncode::Dict(d)).unwrap()
+        };
+        assert!(!file(None).pad);
+        assert!(!file(Some(b"x")).pad);
+        assert!(file(Some(b"p")).pad);
+        assert!(file(Some(b"xp")).pad);
+    }
+
+    #[test]
+    fn file_inner_path() {
+        let file = |path: &str| File {
+            path: PathBuf::from(path),
+            length: 1,
+            pad: false,
+        };
+        assert_eq!(file("a.iso").inner_path(), Path::new("a.iso"));
+        assert_eq!(file("root/a.iso").inner_path(), Path::new("a.iso"));
+        assert_eq!(file("root/sub/a.iso").inner_path(), Path::new("sub/a.iso"));
+    }
+
+    #[test]
+    fn parse_nodes() {
+        let mut info = Info::with_pieces(1);
+        info.be_name = Some(b"file".to_vec());
+        info.files[0].path = PathBuf::from("file");
+        info.hashes = vec![vec![0u8; 20]];
+        info.nodes = vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+        ];
+        let parsed = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(parsed.nodes, info.nodes);
+
+        let node = |host: &[u8], port| {
+            BEncode::List(vec![BEncode::String(host.to_vec()), BEncode::Int(port)])
+        };
+        let mut torrent = info.to_torrent_bencode().into_dict().unwrap();
+        torrent.insert(
+            "nodes".to_owned(),
+            BEncode::List(vec![
+                node(b"router.example.com", 6881),
+                node(b"10.0.0.1", 70_000),
+                node(b"10.0.0.2", 6881),
+            ]),
+        );
+        let parsed = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert_eq!(parsed.nodes, vec!["10.0.0.2:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_metainfo() {
+        let info = TorrentBuilder::single("file", 100)
+            .private()
+            .tier(&["http://a.com/announce", "udp://b.com:80"])
+            .tier(&["http://c.com/announce"])
+            .http_seed("http://d.com/seed.php")
+            .node("10.0.0.1:6881")
+            .build();
+        assert!(info.private);
+        assert_eq!(info.url_list.len(), 2);
+        assert_eq!(info.url_list[0].len(), 2);
+        assert_eq!(info.url_list[1][0].as_str(), "http://c.com/announce");
+        assert_eq!(info.nodes, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(info.http_seeds[0].as_str(), "http://d.com/seed.php");
+    }
 }
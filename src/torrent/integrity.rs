@@ -0,0 +1,169 @@
+//! Tracking of how much of a torrent's data is known to be intact.
+//!
+//! Pieces are hashed as they're downloaded and whenever the torrent is
+//! validated. The results are kept here so that a torrent can be audited
+//! without hashing everything again.
+
+use chrono::{DateTime, Utc};
+
+use super::Bitfield;
+
+#[derive(Debug)]
+pub struct Integrity {
+    /// Pieces whose last hash check failed, until they pass one
+    failed: Bitfield,
+    /// Pieces assumed to be intact without having been hashed
+    unverified: Bitfield,
+    /// When every piece was last hashed
+    last_scrub: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Counts {
+    pub ok: u64,
+    pub failed: u64,
+    pub unverified: u64,
+}
+
+impl Integrity {
+    pub fn new(pieces: u64) -> Integrity {
+        Integrity::from_parts(Bitfield::new(pieces), Bitfield::new(pieces), None)
+    }
+
+    pub fn from_parts(
+        failed: Bitfield,
+        unverified: Bitfield,
+        last_scrub: Option<DateTime<Utc>>,
+    ) -> Integrity {
+        Integrity {
+            failed,
+            unverified,
+            last_scrub,
+        }
+    }
+
+    pub fn failed(&self) -> &Bitfield {
+        &self.failed
+    }
+
+    pub fn unverified(&self) -> &Bitfield {
+        &self.unverified
+    }
+
+    pub fn last_scrub(&self) -> Option<DateTime<Utc>> {
+        self.last_scrub
+    }
+
+    /// Records the result of hashing a single piece.
+    pub fn validated(&mut self, piece: u32, valid: bool) {
+        let piece = u64::from(piece);
+        self.unverified.unset_bit(piece);
+        if valid {
+            self.failed.unset_bit(piece);
+        } else {
+            self.failed.set_bit(piece);
+        }
+    }
+
+    /// Marks a piece as unverified, since its data changed after it was
+    /// hashed.
+    pub fn unverify(&mut self, piece: u32) {
+        self.unverified.set_bit(u64::from(piece));
+    }
+
+    /// Marks every piece but the one checked as unverified, for imported
+    /// data which is only spot checked.
+    pub fn imported(&mut self, checked: u32) {
+        for i in 0..self.unverified.len() {
+            if i != u64::from(checked) {
+                self.unverified.set_bit(i);
+            }
+        }
+    }
+
+    /// Records a full validation. Invalid pieces only count as failed if
+    /// we believed we had them, otherwise they're simply missing.
+    pub fn scrubbed(&mut self, had: &Bitfield, invalid: &[u32]) {
+        let mut failed = Bitfield::new(self.failed.len());
+        for &piece in invalid {
+            let piece = u64::from(piece);
+            if had.has_bit(piece) || self.failed.has_bit(piece) {
+                failed.set_bit(piece);
+            }
+        }
+        self.failed = failed;
+        self.unverified = Bitfield::new(self.unverified.len());
+        self.last_scrub = Some(Utc::now());
+    }
+
+    /// Counts pieces by state, given the pieces we have.
+    pub fn counts(&self, pieces: &Bitfield) -> Counts {
+        let unverified = pieces
+            .iter()
+            .filter(|&p| self.unverified.has_bit(p))
+            .count() as u64;
+        Counts {
+            ok: pieces.set() - unverified,
+            failed: self.failed.set(),
+            unverified,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let mut pieces = Bitfield::new(8);
+        let mut i = Integrity::new(8);
+        for p in 0..8 {
+            pieces.set_bit(p);
+        }
+        i.imported(0);
+        assert_eq!(
+            i.counts(&pieces),
+            Counts {
+                ok: 1,
+                failed: 0,
+                unverified: 7
+            }
+        );
+
+        // Failed pieces are no longer had
+        i.validated(1, true);
+        i.validated(2, false);
+        pieces.unset_bit(2);
+        assert_eq!(
+            i.counts(&pieces),
+            Counts {
+                ok: 2,
+                failed: 1,
+                unverified: 5
+            }
+        );
+        assert!(i.last_scrub().is_none());
+
+        // Only pieces we had or which already failed fail a scrub
+        pieces.unset_bit(7);
+        i.scrubbed(&pieces, &[2, 3, 7]);
+        pieces.unset_bit(3);
+        assert_eq!(
+            i.counts(&pieces),
+            Counts {
+                ok: 5,
+                failed: 2,
+                unverified: 0
+            }
+        );
+        assert!(i.failed().has_bit(2) && i.failed().has_bit(3));
+        assert!(i.last_scrub().is_some());
+
+        // Pieces of changed files are unverified until hashed again
+        i.unverify(4);
+        assert_eq!(i.counts(&pieces).unverified, 1);
+        i.validated(4, true);
+        assert_eq!(i.counts(&pieces).unverified, 0);
+    }
+}
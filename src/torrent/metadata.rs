@@ -0,0 +1,263 @@
+//! Metadata exchange (BEP 9).
+//!
+//! Magnets start out with only the info hash. The info dictionary is fetched
+//! from peers in 16 KiB pieces over the extension protocol, and only used
+//! once its hash matches.
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::{mem, time};
+
+use crate::bencode::{self, BEncode};
+use crate::util::{sha1_hash, FHashSet};
+
+pub const PIECE_LEN: usize = 16_384;
+/// Largest info dictionary we're willing to fetch
+const MAX_SIZE: u64 = 100 * 1000 * 1000;
+/// Seconds before a piece may be requested from another peer
+const REQ_TIMEOUT: u64 = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum Msg<'a> {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: &'a [u8],
+    },
+    Reject(u32),
+}
+
+/// An info dictionary being fetched from peers.
+#[derive(Debug, Default)]
+pub struct Fetch {
+    /// Empty until a peer tells us the size
+    data: Vec<u8>,
+    pieces: Vec<Piece>,
+    /// Peers which rejected a request, and so likely lack the metadata
+    rejected: FHashSet<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Piece {
+    Missing,
+    Requested { pid: usize, at: time::Instant },
+    Received,
+}
+
+impl<'a> Msg<'a> {
+    /// Decodes a message, whose piece data follows the bencoded dict.
+    pub fn decode(payload: &'a [u8]) -> Option<Msg<'a>> {
+        let mut c = Cursor::new(payload);
+        let mut d = bencode::decode_first(&mut c).ok()?.into_dict()?;
+        let piece = d
+            .remove("piece")
+            .and_then(|v| v.into_int())
+            .filter(|&p| p >= 0 && p <= i64::from(u32::MAX))? as u32;
+        match d.remove("msg_type").and_then(|v| v.into_int())? {
+            0 => Some(Msg::Request(piece)),
+            1 => {
+                let total_size = d
+                    .remove("total_size")
+                    .and_then(|v| v.into_int())
+                    .filter(|&s| s >= 0)? as u64;
+                Some(Msg::Data {
+                    piece,
+                    total_size,
+                    data: &payload[c.position() as usize..],
+                })
+            }
+            2 => Some(Msg::Reject(piece)),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match *self {
+            Msg::Request(piece) => (0, piece),
+            Msg::Data { piece, .. } => (1, piece),
+            Msg::Reject(piece) => (2, piece),
+        };
+        let mut d = BTreeMap::new();
+        d.insert("msg_type".to_owned(), BEncode::Int(msg_type));
+        d.insert("piece".to_owned(), BEncode::Int(i64::from(piece)));
+        if let Msg::Data {
+            total_size, data, ..
+        } = *self
+        {
+            d.insert("total_size".to_owned(), BEncode::Int(total_size as i64));
+            let mut buf = BEncode::Dict(d).encode_to_buf();
+            buf.extend_from_slice(data);
+            buf
+        } else {
+            BEncode::Dict(d).encode_to_buf()
+        }
+    }
+}
+
+/// A piece of our own info dictionary, for serving to peers.
+pub fn piece(info: &[u8], piece: u32) -> Option<&[u8]> {
+    info.chunks(PIECE_LEN).nth(piece as usize)
+}
+
+impl Fetch {
+    pub fn new() -> Fetch {
+        Default::default()
+    }
+
+    /// Sets the size of the info dictionary from a peer's extended
+    /// handshake, returning false if it's unreasonable. Only the first size
+    /// given is used, peers claiming otherwise fail to send valid pieces.
+    pub fn set_size(&mut self, size: u64) -> bool {
+        if size == 0 || size > MAX_SIZE {
+            return false;
+        }
+        if self.data.is_empty() {
+            self.data = vec![0; size as usize];
+            self.pieces = vec![Piece::Missing; self.data.chunks(PIECE_LEN).count()];
+        }
+        true
+    }
+
+    /// Picks a piece to request from a peer. Peers get one request at a time,
+    /// so that the pieces are spread out over them.
+    pub fn pick(&mut self, pid: usize) -> Option<u32> {
+        if self.rejected.contains(&pid) {
+            return None;
+        }
+        let timeout = time::Duration::from_secs(REQ_TIMEOUT);
+        if self.pieces.iter().any(|p| match *p {
+            Piece::Requested { pid: id, at } => id == pid && at.elapsed() < timeout,
+            _ => false,
+        }) {
+            return None;
+        }
+        let idx = self.pieces.iter().position(|p| match *p {
+            Piece::Missing => true,
+            Piece::Requested { at, .. } => at.elapsed() >= timeout,
+            Piece::Received => false,
+        })?;
+        self.pieces[idx] = Piece::Requested {
+            pid,
+            at: time::Instant::now(),
+        };
+        Some(idx as u32)
+    }
+
+    /// Stores a received piece, returning whether every piece has been
+    /// received. Pieces which don't fit the size we expect are an error.
+    pub fn received(&mut self, piece: u32, total_size: u64, data: &[u8]) -> Result<bool, ()> {
+        let idx = piece as usize;
+        if idx >= self.pieces.len() || total_size != self.data.len() as u64 {
+            return Err(());
+        }
+        let start = idx * PIECE_LEN;
+        let len = (self.data.len() - start).min(PIECE_LEN);
+        if data.len() != len {
+            return Err(());
+        }
+        self.data[start..start + len].copy_from_slice(data);
+        self.pieces[idx] = Piece::Received;
+        Ok(self.pieces.iter().all(|p| *p == Piece::Received))
+    }
+
+    pub fn rejected(&mut self, pid: usize, piece: u32) {
+        self.rejected.insert(pid);
+        self.release(pid, Some(piece as usize));
+    }
+
+    pub fn remove_peer(&mut self, pid: usize) {
+        self.rejected.remove(&pid);
+        self.release(pid, None);
+    }
+
+    /// Returns the info dictionary if it matches the hash. Otherwise it's
+    /// discarded and fetched again, as there's no telling which peer sent
+    /// the bad piece.
+    pub fn finish(&mut self, hash: &[u8; 20]) -> Option<Vec<u8>> {
+        if &sha1_hash(&self.data) == hash {
+            return Some(mem::take(&mut self.data));
+        }
+        for p in &mut self.pieces {
+            *p = Piece::Missing;
+        }
+        None
+    }
+
+    fn release(&mut self, pid: usize, piece: Option<usize>) {
+        for (i, p) in self.pieces.iter_mut().enumerate() {
+            if let Piece::Requested { pid: id, .. } = *p {
+                if id == pid && piece.map(|idx| idx == i).unwrap_or(true) {
+                    *p = Piece::Missing;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msg() {
+        let req = Msg::Request(3);
+        assert_eq!(req.encode(), b"d8:msg_typei0e5:piecei3ee".to_vec());
+        assert_eq!(Msg::decode(&req.encode()), Some(req));
+
+        // The data of a piece follows the dict, and may contain anything
+        let data = Msg::Data {
+            piece: 1,
+            total_size: 16_387,
+            data: b"eee",
+        };
+        assert_eq!(Msg::decode(&data.encode()), Some(data));
+
+        assert_eq!(Msg::decode(b"d8:msg_typei1e5:piecei0ee"), None);
+        assert_eq!(Msg::decode(b"d8:msg_typei2e5:piecei-1ee"), None);
+        assert_eq!(Msg::decode(b"d8:msg_typei3e5:piecei0ee"), None);
+    }
+
+    #[test]
+    fn test_fetch() {
+        let info = vec![7u8; PIECE_LEN + 10];
+        let hash = sha1_hash(&info);
+        let mut f = Fetch::new();
+        assert_eq!(f.pick(0), None);
+        assert!(!f.set_size(MAX_SIZE + 1));
+        assert!(f.set_size(info.len() as u64));
+
+        // Peers get a piece each, and none once all are requested
+        assert_eq!(f.pick(0), Some(0));
+        assert_eq!(f.pick(0), None);
+        assert_eq!(f.pick(1), Some(1));
+        assert_eq!(f.pick(2), None);
+
+        // Rejecting peers aren't asked again
+        f.rejected(1, 1);
+        assert_eq!(f.pick(1), None);
+        assert_eq!(f.pick(2), Some(1));
+        f.remove_peer(2);
+        assert_eq!(f.pick(3), Some(1));
+
+        assert!(f.received(1, info.len() as u64, &info[..9]).is_err());
+        assert!(f.received(1, info.len() as u64 - 1, &info[..10]).is_err());
+        assert_eq!(
+            f.received(1, info.len() as u64, &info[PIECE_LEN..]),
+            Ok(false)
+        );
+        assert_eq!(f.received(0, info.len() as u64, &[0; PIECE_LEN]), Ok(true));
+
+        // Bad metadata is fetched over again
+        assert_eq!(f.finish(&hash), None);
+        assert_eq!(f.pick(0), Some(0));
+        f.received(0, info.len() as u64, &info[..PIECE_LEN])
+            .unwrap();
+        f.received(1, info.len() as u64, &info[PIECE_LEN..])
+            .unwrap();
+        assert_eq!(f.finish(&hash), Some(info.clone()));
+
+        assert_eq!(piece(&info, 1), Some(&info[PIECE_LEN..]));
+        assert_eq!(piece(&info, 2), None);
+    }
+}
@@ -1,20 +1,29 @@
+mod ban;
 pub mod bitfield;
 mod choker;
+#[cfg(test)]
+pub mod fixtures;
+mod holepunch;
 pub mod info;
+mod integrity;
+mod metadata;
 pub mod peer;
+mod pex;
 mod picker;
+mod superseed;
+mod tuner;
+mod webseed;
 
 use std::borrow::Cow;
 use std::collections::{BTreeMap, VecDeque};
-use std::fmt;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use std::{cmp, fmt, mem};
 
-use crate::bencode::BEncode;
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use url::Url;
 
 pub use self::bitfield::Bitfield;
@@ -23,18 +32,30 @@ pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
 
+use self::ban::Bans;
+use self::integrity::Integrity;
+use self::metadata::Fetch;
+use self::peer::caps::Bit;
+use self::peer::ext::{self, Ext};
+use self::peer::priority::{self, ExternalIp};
+use self::pex::Pex;
 use self::picker::Picker;
+use self::superseed::SuperSeed;
+use self::tuner::Tuner;
+use self::webseed::WebSeeds;
 use crate::buffers::Buffer;
-use crate::control::cio;
-use crate::rpc::resource::{self, Resource, SResourceUpdate};
+use crate::config::Allocation;
+use crate::control::{cio, score};
+use crate::rpc::resource::{self, ErrorCode, Resource, SResourceUpdate};
 use crate::session::torrent::current::Session;
 use crate::throttle::Throttle;
 use crate::tracker::{self, TrackerResponse};
-use crate::util::{FHashSet, UHashMap};
-use crate::{bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
+use crate::util::{FHashMap, FHashSet, UHashMap};
+use crate::{bencode, dht, disk, rpc, util, CONFIG};
 use crate::{session, stat};
 
-const MAX_PEERS: usize = 50;
+/// Delay used for trackers which ask never to be retried, roughly a year
+const RETRY_NEVER_SECS: u64 = 365 * 24 * 60 * 60;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
@@ -42,9 +63,10 @@ pub enum TrackerStatus {
     Ok {
         seeders: u32,
         leechers: u32,
+        downloaded: u32,
         interval: u32,
     },
-    Failure(String),
+    Failure(ErrorCode, String),
 }
 
 pub struct Torrent<T: cio::CIO> {
@@ -55,7 +77,18 @@ pub struct Torrent<T: cio::CIO> {
     cio: T,
     uploaded: u64,
     downloaded: u64,
+    /// Bytes of the pieces we have
+    verified: u64,
+    /// Bytes downloaded for pieces which then failed their hash check
+    corrupt: u64,
+    /// Bytes of blocks received which we already had
     wasted: u64,
+    /// Bytes of outstanding requests cancelled, having gotten the block
+    /// from another source or no longer wanting it
+    cancelled: u64,
+    /// Bytes of uploads cancelled by peers before we sent them
+    ul_cancelled: u64,
+    integrity: Integrity,
     stat: stat::EMA,
     files: Files,
     priority: u8,
@@ -65,20 +98,63 @@ pub struct Torrent<T: cio::CIO> {
     peers: UHashMap<Peer<T>>,
     leechers: FHashSet<usize>,
     picker: Picker,
+    web_seeds: WebSeeds,
     status: Status,
     choker: choker::Choker,
+    tuner: Tuner,
     dirty: bool,
     path: Option<String>,
+    /// Directory to move the torrent to once complete, overriding
+    /// `disk.completed`
+    completed_path: Option<String>,
+    /// Encoded info dictionary, empty for magnets
     info_bytes: Vec<u8>,
-    info_idx: Option<usize>,
+    /// The info as published, once files were renamed and their paths in
+    /// `info` no longer match it
+    orig: Option<Arc<Info>>,
+    /// Metadata being fetched from peers, for magnets
+    metadata: Option<Fetch>,
+    pex: Pex,
+    /// Super-seeding state, if enabled
+    super_seed: Option<SuperSeed>,
     created: DateTime<Utc>,
+    /// Seeders and leechers estimated from DHT scrapes
+    dht_swarm: (u32, u32),
+    /// Our address as reported by peers
+    external_ip: ExternalIp,
+    /// Addresses refused for sending corrupt data
+    bans: Bans,
+    /// Unverified pieces being hashed before they're uploaded, and the
+    /// peers and offsets of the blocks requested from them
+    lazy_checks: FHashMap<u32, Vec<(usize, u32)>>,
+    /// What was learned about peer addresses, until control collects it
+    score_reports: Vec<(IpAddr, score::Report)>,
+    /// File being streamed and its playback position
+    stream: Option<(usize, u64)>,
+    /// Pieces RPC clients want by a deadline, soonest first
+    deadlines: Vec<Deadline>,
+    /// Piece availability map last sent to RPC clients
+    availability_map: Vec<(u16, bool, u32)>,
+    /// Whether the first and last pieces of wanted files are downloaded
+    /// first
+    first_last: bool,
+    /// Whether the data is kept in memory rather than on disk
+    memory: bool,
+    /// Folder the files are being moved to in the background, and the
+    /// pieces written since the move began
+    relocation: Option<(String, FHashSet<u32>)>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Status {
     pub paused: bool,
     pub validating: Option<f32>,
+    /// Progress of allocating the torrent's files up front
+    pub allocating: Option<f32>,
+    /// Progress of moving the torrent's files to another folder
+    pub moving: Option<f32>,
     pub error: Option<String>,
+    pub error_code: Option<ErrorCode>,
     pub state: StatusState,
 }
 
@@ -97,6 +173,31 @@ pub struct Tracker {
     pub update: Option<Instant>,
 }
 
+impl Tracker {
+    /// Seeders, leechers and completed downloads as last reported by the tracker.
+    pub fn swarm(&self) -> (u32, u32, u32) {
+        match self.status {
+            TrackerStatus::Ok {
+                seeders,
+                leechers,
+                downloaded,
+                ..
+            } => (seeders, leechers, downloaded),
+            _ => (0, 0, 0),
+        }
+    }
+}
+
+/// A piece an RPC client wants by some time, and where to report it if
+/// the time passes before the piece is downloaded.
+struct Deadline {
+    piece: u32,
+    at: Instant,
+    client: usize,
+    serial: u64,
+    expired: bool,
+}
+
 struct Files {
     done: Vec<u64>,
     dirty: FHashSet<usize>,
@@ -121,15 +222,14 @@ impl Status {
         self.paused || self.error.is_some()
     }
 
+    /// Whether the torrent is seeding. A complete torrent keeps seeding
+    /// while it's validated, only holding back pieces not yet rechecked.
     pub fn completed(&self) -> bool {
-        match self.state {
-            StatusState::Complete => self.validating.is_none(),
-            _ => false,
-        }
+        matches!(self.state, StatusState::Complete)
     }
 
     pub fn should_dl(&self) -> bool {
-        self.leeching() && !self.stopped() && self.validating.is_none()
+        self.leeching() && !self.stopped() && self.validating.is_none() && self.allocating.is_none()
     }
 
     pub fn as_rpc(&self, ul: u64, dl: u64) -> rpc::resource::Status {
@@ -139,6 +239,12 @@ impl Status {
         if self.validating.is_some() {
             return rpc::resource::Status::Hashing;
         }
+        if self.allocating.is_some() {
+            return rpc::resource::Status::Allocating;
+        }
+        if self.moving.is_some() {
+            return rpc::resource::Status::Moving;
+        }
         if self.error.is_some() {
             return rpc::resource::Status::Error;
         }
@@ -214,15 +320,20 @@ impl<T: cio::CIO> Torrent<T> {
         cio: T,
         start: bool,
         import: bool,
+        memory: bool,
     ) -> Torrent<T> {
         debug!("Creating {:?}", info);
+        let path = path.map(|p| shellexpand::tilde(&p).into_owned());
         let peers = UHashMap::default();
         let pieces = Bitfield::new(u64::from(info.pieces()));
         let leechers = FHashSet::default();
         let mut status = Status {
             paused: !start,
             validating: None,
+            allocating: None,
+            moving: None,
             error: None,
+            error_code: None,
             state: if import {
                 StatusState::Import
             } else {
@@ -230,19 +341,20 @@ impl<T: cio::CIO> Torrent<T> {
             },
         };
         let priorities = Arc::new(vec![3; info.files.len()]);
-        let info_idx = if info.complete() {
+        let metadata = if info.complete() {
             None
         } else {
             status.state = StatusState::Magnet;
-            Some(std::usize::MAX)
+            Some(Fetch::new())
         };
-        let info_bytes = if info_idx.is_none() {
+        let info_bytes = if metadata.is_none() {
             info.to_bencode().encode_to_buf()
         } else {
             vec![]
         };
         let info = Arc::new(info);
-        let picker = Picker::new(&info, &pieces, &priorities);
+        let mut picker = Picker::new(&info, &pieces, &priorities);
+        picker.set_first_last(CONFIG.picker.first_last, &priorities, &info);
 
         let mut trackers = VecDeque::with_capacity(1);
         if !info.url_list.is_empty() {
@@ -268,20 +380,31 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         let files = Files::new(&info, &pieces);
+        let web_seeds = WebSeeds::new(&info.web_seeds, &info.http_seeds);
+        let tuner = Tuner::new(info.piece_len / 16_384);
+        let integrity = Integrity::new(pieces.len());
 
         let mut t = Torrent {
             id,
             info,
             path,
+            completed_path: None,
+            orig: None,
             peers,
             pieces,
             validating: FHashSet::default(),
             picker,
+            web_seeds,
             priority: 3,
             priorities,
             uploaded: 0,
             downloaded: 0,
+            verified: 0,
+            corrupt: 0,
             wasted: 0,
+            cancelled: 0,
+            ul_cancelled: 0,
+            integrity,
             files,
             stat: stat::EMA::new(),
             cio,
@@ -289,22 +412,42 @@ impl<T: cio::CIO> Torrent<T> {
             throttle,
             trackers,
             choker: choker::Choker::new(),
+            tuner,
             dirty: true,
             status,
             info_bytes,
-            info_idx,
+            metadata,
+            pex: Pex::new(),
+            super_seed: None,
+            external_ip: ExternalIp::new(),
+            bans: Bans::new(),
+            lazy_checks: FHashMap::default(),
+            score_reports: Vec::new(),
+            stream: None,
+            deadlines: Vec::new(),
+            availability_map: Vec::new(),
+            first_last: CONFIG.picker.first_last,
+            memory,
+            relocation: None,
             created: Utc::now(),
+            dht_swarm: (0, 0),
         };
+        if memory {
+            t.cio.msg_disk(disk::Request::memory(t.id));
+        }
         t.start(true);
+        t.check_space();
         if import {
+            t.integrity.imported(0);
             t.cio.msg_disk(disk::Request::validate_piece(
                 t.id,
                 t.info.clone(),
+                t.priorities.clone(),
                 t.path.clone(),
                 0,
             ));
             t.validating.insert(0);
-        } else if CONFIG.disk.validate && t.info_idx.is_none() {
+        } else if CONFIG.disk.validate && t.metadata.is_none() {
             t.validate();
         } else {
             t.announce_start();
@@ -343,26 +486,64 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|f| info::File {
                     path: f.path,
                     length: f.length,
+                    pad: f.pad,
                 })
                 .collect(),
             private: d.info.private,
             be_name: d.info.be_name,
             piece_idx: d.info.piece_idx,
             url_list: vec![],
+            web_seeds: d
+                .web_seeds
+                .iter()
+                .filter_map(|url| Url::parse(url).ok().map(Arc::new))
+                .collect(),
+            http_seeds: d
+                .http_seeds
+                .iter()
+                .filter_map(|url| Url::parse(url).ok().map(Arc::new))
+                .collect(),
+            // Nodes are only used to bootstrap the DHT when first added
+            nodes: vec![],
         });
 
-        let info_idx = if info.complete() {
+        let orig = if d.renamed.is_empty() {
             None
         } else {
-            Some(std::usize::MAX)
+            let mut orig = (*info).clone();
+            for (file, path) in d.renamed {
+                if let Some(f) = orig.files.get_mut(file) {
+                    f.path = path;
+                }
+            }
+            Some(Arc::new(orig))
         };
-        let info_bytes = if info_idx.is_none() {
-            info.to_bencode().encode_to_buf()
+
+        let metadata = if info.complete() {
+            None
+        } else {
+            Some(Fetch::new())
+        };
+        let info_bytes = if metadata.is_none() {
+            orig.as_ref().unwrap_or(&info).to_bencode().encode_to_buf()
         } else {
             vec![]
         };
-        let pieces = Bitfield::from(&d.pieces.data, d.pieces.len);
-        let picker = picker::Picker::new(&info, &pieces, &d.priorities);
+        // Data kept in memory didn't survive the restart
+        let (pieces, integrity) = if d.memory {
+            let pieces = Bitfield::new(d.pieces.len);
+            let integrity = Integrity::new(pieces.len());
+            (pieces, integrity)
+        } else {
+            let integrity = Integrity::from_parts(
+                Bitfield::from(&d.failed.data, d.pieces.len),
+                Bitfield::from(&d.unverified.data, d.pieces.len),
+                d.last_scrub,
+            );
+            (Bitfield::from(&d.pieces.data, d.pieces.len), integrity)
+        };
+        let mut picker = picker::Picker::new(&info, &pieces, &d.priorities);
+        picker.set_first_last(d.first_last, &d.priorities, &info);
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
 
@@ -391,6 +572,9 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         let files = Files::new(&info, &pieces);
+        let web_seeds = WebSeeds::new(&info.web_seeds, &info.http_seeds);
+        let mut tuner = Tuner::new(info.piece_len / 16_384);
+        tuner.pin_slots(d.upload_slots.map(usize::from));
 
         let mut t = Torrent {
             id,
@@ -399,9 +583,15 @@ impl<T: cio::CIO> Torrent<T> {
             pieces,
             validating: FHashSet::default(),
             picker,
+            web_seeds,
             uploaded: d.uploaded,
             downloaded: d.downloaded,
+            verified: 0,
+            corrupt: d.corrupt,
             wasted: 0,
+            cancelled: 0,
+            ul_cancelled: 0,
+            integrity,
             files,
             stat: stat::EMA::new(),
             priorities: Arc::new(d.priorities),
@@ -411,28 +601,63 @@ impl<T: cio::CIO> Torrent<T> {
             throttle,
             trackers,
             choker: choker::Choker::new(),
+            tuner,
             dirty: false,
             status: Status {
                 paused: d.status.paused,
                 validating: None,
+                allocating: None,
+                moving: None,
                 error: d.status.error,
+                error_code: None,
                 state: match d.status.state {
                     session::torrent::current::StatusState::Magnet => StatusState::Magnet,
-                    session::torrent::current::StatusState::Incomplete => StatusState::Incomplete,
-                    session::torrent::current::StatusState::Complete => StatusState::Complete,
+                    session::torrent::current::StatusState::Complete if !d.memory => {
+                        StatusState::Complete
+                    }
+                    _ => StatusState::Incomplete,
                 },
             },
             path: d.path,
+            completed_path: d.completed_path,
             info_bytes,
-            info_idx,
+            orig,
+            metadata,
+            pex: Pex::new(),
+            super_seed: None,
+            external_ip: ExternalIp::new(),
+            bans: Bans::new(),
+            lazy_checks: FHashMap::default(),
+            score_reports: Vec::new(),
+            stream: None,
+            deadlines: Vec::new(),
+            availability_map: Vec::new(),
+            first_last: d.first_last,
+            memory: d.memory,
+            relocation: None,
             created: d.created,
+            dht_swarm: (0, 0),
         };
+        if t.memory {
+            t.cio.msg_disk(disk::Request::memory(t.id));
+        }
         t.status.error = None;
+        t.status.error_code = None;
+        t.count_verified();
         t.start(false);
+        if !d.status.validating && !t.memory && t.info.complete() {
+            // Checked before allocation, which may touch the files
+            t.cio.msg_disk(disk::Request::check_resume(
+                t.id,
+                t.info.clone(),
+                t.path.clone(),
+            ));
+        }
+        t.check_space();
         if d.status.validating {
             t.validate();
         } else {
-            t.announce_start();
+            t.announce_startup();
         }
         Some(t)
     }
@@ -456,6 +681,7 @@ impl<T: cio::CIO> Torrent<T> {
                     .map(|f| session::torrent::current::File {
                         path: f.path,
                         length: f.length,
+                        pad: f.pad,
                     })
                     .collect(),
                 private: self.info.private,
@@ -468,6 +694,16 @@ impl<T: cio::CIO> Torrent<T> {
             },
             uploaded: self.uploaded,
             downloaded: self.downloaded,
+            corrupt: self.corrupt,
+            failed: session::torrent::Bitfield {
+                data: self.integrity.failed().data(),
+                len: self.integrity.failed().len(),
+            },
+            unverified: session::torrent::Bitfield {
+                data: self.integrity.unverified().data(),
+                len: self.integrity.unverified().len(),
+            },
+            last_scrub: self.integrity.last_scrub(),
             status: session::torrent::current::Status {
                 paused: self.status.paused,
                 validating: self.status.validating.is_some(),
@@ -481,6 +717,8 @@ impl<T: cio::CIO> Torrent<T> {
                 },
             },
             path: self.path.clone(),
+            completed_path: self.completed_path.clone(),
+            renamed: self.renamed(),
             priorities: self.priorities.as_ref().clone(),
             priority: self.priority,
             created: self.created,
@@ -491,6 +729,21 @@ impl<T: cio::CIO> Torrent<T> {
                 .iter()
                 .map(|trk| trk.url.as_str().to_owned())
                 .collect(),
+            web_seeds: self
+                .info
+                .web_seeds
+                .iter()
+                .map(|url| url.as_str().to_owned())
+                .collect(),
+            http_seeds: self
+                .info
+                .http_seeds
+                .iter()
+                .map(|url| url.as_str().to_owned())
+                .collect(),
+            upload_slots: self.tuner.pinned_slots().map(|s| s as u16),
+            first_last: self.first_last,
+            memory: self.memory,
         };
         let data = bincode::serialize(&d).expect("Serialization failed!");
         debug!("Sending serialization request!");
@@ -514,7 +767,8 @@ impl<T: cio::CIO> Torrent<T> {
             self.info.hash,
             files,
             self.path.clone(),
-            artifacts,
+            // Data in memory would never be freed otherwise
+            artifacts || self.memory,
         ));
     }
 
@@ -537,10 +791,11 @@ impl<T: cio::CIO> Torrent<T> {
             Ok(ref r) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got valid response for {}", tracker.url);
-                    time += Duration::from_secs(u64::from(r.interval));
+                    time += tracker::jitter(r.interval);
                     tracker.status = TrackerStatus::Ok {
                         seeders: r.seeders,
                         leechers: r.leechers,
+                        downloaded: r.downloaded,
                         interval: r.interval,
                     };
                     tracker.update = Some(time);
@@ -550,23 +805,28 @@ impl<T: cio::CIO> Torrent<T> {
                     }
                 }
             }
-            Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
+            Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s, retry), _)) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got tracker level error for {}", tracker.url);
-                    time += Duration::from_secs(300);
+                    time += match retry {
+                        Some(tracker::Retry::In(mins)) => Duration::from_secs(u64::from(mins) * 60),
+                        // Only a manual update will contact the tracker again
+                        Some(tracker::Retry::Never) => Duration::from_secs(RETRY_NEVER_SECS),
+                        None => tracker::jitter(300),
+                    };
                     tracker.update = Some(time);
-                    tracker.status = TrackerStatus::Failure(s.clone());
+                    tracker.status = TrackerStatus::Failure(ErrorCode::TrackerRejected, s.clone());
                     tracker.last_announce = Utc::now();
                 }
             }
             Err(ref e) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     error!("Failed to query tracker {}: {}", tracker.url, e);
-                    // Wait 5 minutes before trying again
-                    time += Duration::from_secs(300);
+                    // Wait around 5 minutes before trying again
+                    time += tracker::jitter(300);
                     tracker.update = Some(time);
                     let reason = format!("Couldn't contact tracker: {}", e);
-                    tracker.status = TrackerStatus::Failure(reason);
+                    tracker.status = TrackerStatus::Failure(e.kind().code(), reason);
                     tracker.last_announce = Utc::now();
                 }
             }
@@ -590,6 +850,10 @@ impl<T: cio::CIO> Torrent<T> {
             let cur = Instant::now();
             if cur >= end {
                 self.update_tracker();
+            } else if self.status.magnet() {
+                // Magnets can't start until peers are found to fetch metadata
+                // from, so don't wait on the tracker interval to search again
+                self.dht_get_peers();
             }
         } else {
             self.update_tracker();
@@ -606,6 +870,119 @@ impl<T: cio::CIO> Torrent<T> {
         self.dht_announce();
     }
 
+    /// Hands out whole pieces to any web seeds with spare capacity.
+    pub fn update_web_seeds(&mut self) {
+        if self.web_seeds.is_empty() || !self.status.should_dl() || !self.info.complete() {
+            return;
+        }
+        while let Some(source) = self.web_seeds.next_source() {
+            let piece = match self.picker.pick_whole(webseed::peer_id(source)) {
+                Some(p) => p,
+                None => break,
+            };
+            let info = self.orig.as_ref().unwrap_or(&self.info);
+            let reqs = self.web_seeds.fetch(self.id, info, source, piece);
+            if reqs.is_empty() {
+                self.picker.release_piece(piece, webseed::peer_id(source));
+            }
+            for req in reqs {
+                self.cio.msg_trk(tracker::Request::WebSeed(req));
+            }
+        }
+    }
+
+    pub fn set_web_seed_response(
+        &mut self,
+        piece: u32,
+        offset: u32,
+        resp: tracker::Result<Vec<u8>>,
+    ) {
+        let (source, data) = match resp {
+            Ok(data) => match self.web_seeds.received(piece, offset, &data) {
+                Some(r) => r,
+                None => return,
+            },
+            Err(e) => {
+                let retry = match *e.kind() {
+                    tracker::ErrorKind::Unavailable(retry) => retry,
+                    _ => None,
+                };
+                if let Some(source) = self.web_seeds.failed(piece, retry) {
+                    debug!("Web seed request for piece {} failed: {}", piece, e);
+                    self.picker.release_piece(piece, webseed::peer_id(source));
+                }
+                return;
+            }
+        };
+        let pid = webseed::peer_id(source);
+
+        if self.pieces.has_bit(u64::from(piece))
+            || self.validating.contains(&piece)
+            || self.status.stopped()
+            || self.status.completed()
+        {
+            self.picker.release_piece(piece, pid);
+            return;
+        }
+
+        let mut bufs = Vec::with_capacity(data.len() / 16_384 + 1);
+        for chunk in data.chunks(16_384) {
+            match Buffer::get() {
+                Some(mut buf) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    bufs.push(buf);
+                }
+                None => {
+                    self.picker.release_piece(piece, pid);
+                    return;
+                }
+            }
+        }
+
+        let mut piece_done = false;
+        let mut all_blocks = true;
+        for (i, buf) in bufs.into_iter().enumerate() {
+            let begin = i as u32 * 16_384;
+            let length = self.info.block_len(piece, begin);
+            let pr = {
+                let peers = &mut self.peers;
+                let cancelled = &mut self.cancelled;
+                self.picker.completed(Block::new(piece, begin), pid, |id| {
+                    if let Some(p) = peers.get_mut(&id) {
+                        p.cancel_request(piece, begin, length);
+                        *cancelled += u64::from(length);
+                    }
+                })
+            };
+            match pr {
+                Ok(done) => piece_done = done,
+                Err(()) => {
+                    all_blocks = false;
+                    continue;
+                }
+            }
+            self.write_piece(piece, begin, buf);
+            self.downloaded += u64::from(length);
+            self.stat.add_dl(u64::from(length));
+        }
+        self.dirty = true;
+
+        // Only hold the source accountable for pieces it wrote in full
+        if all_blocks {
+            self.web_seeds.validating(piece, source);
+        }
+        if piece_done {
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.priorities.clone(),
+                self.path.clone(),
+                piece,
+            ));
+            self.validating.insert(piece);
+        }
+    }
+
     pub fn remove_peer(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let cio = &mut self.cio;
@@ -642,6 +1019,38 @@ impl<T: cio::CIO> Torrent<T> {
         id
     }
 
+    /// Adds the trackers of another copy of this torrent which aren't
+    /// already known, returning how many were added. They're added as
+    /// fallbacks rather than announced to immediately.
+    pub fn merge_trackers(&mut self, info: &Info) -> usize {
+        let mut res = Vec::new();
+        for url in info.announce.iter().chain(info.url_list.iter().flatten()) {
+            if self.trackers.iter().any(|trk| trk.url == *url) {
+                continue;
+            }
+            res.push(resource::Resource::Tracker(resource::Tracker {
+                id: util::trk_rpc_id(&self.info.hash, url.as_str()),
+                torrent_id: self.rpc_id(),
+                url: url.as_ref().clone(),
+                last_report: Utc::now(),
+                error: None,
+                ..Default::default()
+            }));
+            self.trackers.push_back(Tracker {
+                status: TrackerStatus::Updating,
+                update: None,
+                last_announce: Utc::now(),
+                url: url.clone(),
+            });
+        }
+        let added = res.len();
+        if added > 0 {
+            self.dirty = true;
+            self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
+        }
+        added
+    }
+
     pub fn remove_tracker(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let mut res = None;
@@ -690,6 +1099,20 @@ impl<T: cio::CIO> Torrent<T> {
         self.downloaded
     }
 
+    /// Bytes downloaded as reported to trackers, which excludes
+    /// data discarded after failing its hash check.
+    pub fn announce_downloaded(&self) -> u64 {
+        self.downloaded.saturating_sub(self.corrupt)
+    }
+
+    fn count_verified(&mut self) {
+        self.verified = self
+            .pieces
+            .iter()
+            .map(|p| u64::from(self.info.piece_len(p as u32)))
+            .sum();
+    }
+
     pub fn info(&self) -> &Info {
         &self.info
     }
@@ -703,6 +1126,10 @@ impl<T: cio::CIO> Torrent<T> {
             disk::Response::Read { context, data } => {
                 trace!("Received piece from disk, uploading!");
                 if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    if !peer.read_done(context.idx, context.begin) {
+                        // Cancelled by the peer while reading
+                        return;
+                    }
                     let p = Message::piece(context.idx, context.begin, context.length, data);
                     // This may not be 100% accurate, but close enough for now.
                     self.uploaded += u64::from(context.length);
@@ -713,8 +1140,13 @@ impl<T: cio::CIO> Torrent<T> {
             }
             disk::Response::Moved { path, .. } => {
                 debug!("Moved torrent!");
+                if self.status.moving.take().is_some() {
+                    self.announce_status();
+                }
                 let id = self.rpc_id();
                 self.path = Some(path.clone());
+                // Copies across filesystems don't keep modification times
+                self.save_resume();
                 self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
                     resource::SResourceUpdate::TorrentPath {
                         id,
@@ -725,6 +1157,27 @@ impl<T: cio::CIO> Torrent<T> {
             }
             disk::Response::PieceValidated { piece, valid, .. } => {
                 self.validating.remove(&piece);
+                if !valid {
+                    for ip in self.bans.contributors(piece) {
+                        self.score_reports.push((ip, score::Report::Corrupt));
+                    }
+                }
+                for ip in self.bans.validated(piece, valid) {
+                    info!(
+                        "{:?}: Banning {} for sending corrupt data",
+                        self.rpc_id(),
+                        ip
+                    );
+                    for peer in self.peers.values().filter(|p| p.addr().ip() == ip) {
+                        self.cio.remove_peer(peer.id());
+                    }
+                }
+                self.web_seeds.validated(piece, valid);
+                self.integrity.validated(piece, valid);
+                if let Some(waiting) = self.lazy_checks.remove(&piece) {
+                    self.lazily_checked(piece, valid, waiting);
+                    return;
+                }
                 if let StatusState::Import = self.status.state {
                     self.status.state = StatusState::Incomplete;
                     info!("Torrent imported!");
@@ -735,14 +1188,17 @@ impl<T: cio::CIO> Torrent<T> {
                         self.check_complete();
                     } else {
                         info!("Invalid torrent imported, redownloading!");
+                        self.integrity = Integrity::new(self.pieces.len());
                     }
                     self.announce_start();
                     self.files.rebuild(&self.info, &self.pieces);
+                    self.count_verified();
                     self.update_rpc_transfer();
                     return;
                 }
                 if valid {
                     self.pieces.set_bit(u64::from(piece));
+                    self.verified += u64::from(self.info.piece_len(piece));
                     // Tell all relevant peers we got the piece
                     let m = Message::Have(piece);
                     for pid in &self.leechers {
@@ -753,19 +1209,72 @@ impl<T: cio::CIO> Torrent<T> {
                         }
                     }
                     self.files.update(&self.info, piece);
+                    if CONFIG.disk.part_suffix {
+                        for loc in Info::piece_disk_locs(&self.info, piece) {
+                            self.finish_file(loc.file);
+                        }
+                    }
+                    if self.deadlines.iter().any(|d| d.piece == piece) {
+                        self.deadlines.retain(|d| d.piece != piece);
+                        self.apply_deadlines();
+                    }
                     self.check_complete();
                 } else {
-                    // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
+                    self.corrupt += u64::from(self.info.piece_len(piece));
                     self.picker.invalidate_piece(piece);
                     if !self.stat.active() {
                         self.request_all();
                     }
                 }
             }
-            disk::Response::ValidationUpdate { percent, .. } => {
-                self.status.validating = Some(percent);
+            disk::Response::ValidationUpdate(progress) => {
+                self.status.validating = Some(progress.percent);
+                // Rechecked pieces can be uploaded without hashing them again
+                for piece in progress.intact {
+                    if !self.lazy_checks.contains_key(&piece) {
+                        self.integrity.validated(piece, true);
+                    }
+                }
+                self.update_rpc_transfer();
+            }
+            disk::Response::AllocationUpdate { percent, .. } => {
+                self.status.allocating = Some(percent);
+                self.update_rpc_transfer();
+            }
+            disk::Response::RelocationUpdate { percent, .. } => {
+                self.status.moving = Some(percent);
+                self.update_rpc_transfer();
+            }
+            disk::Response::RelocationCopied { .. } => {
+                // The move was abandoned after a disk error
+                let (to, written) = match self.relocation.take() {
+                    Some(r) => r,
+                    None => return,
+                };
+                let from = self
+                    .path
+                    .clone()
+                    .unwrap_or_else(|| CONFIG.disk.directory.clone());
+                let mut pieces: Vec<_> = written.into_iter().collect();
+                pieces.sort_unstable();
+                self.cio.msg_disk(disk::Request::finish_relocation(
+                    self.id,
+                    self.info.clone(),
+                    from,
+                    to.clone(),
+                    pieces,
+                ));
+                // Jobs issued from now on run after the switch
+                self.path = Some(to);
+                self.dirty = true;
+            }
+            disk::Response::AllocationComplete { .. } => {
+                debug!("{}: Files allocated", self.rpc_id());
+                self.status.allocating = None;
                 self.update_rpc_transfer();
+                self.announce_status();
+                self.request_all();
             }
             disk::Response::ValidationComplete { mut invalid, .. } => {
                 debug!("Validation completed!");
@@ -776,6 +1285,7 @@ impl<T: cio::CIO> Torrent<T> {
                 invalid.retain(|i| {
                     Info::piece_disk_locs(&self.info, *i).any(|loc| self.priorities[loc.file] != 0)
                 });
+                self.integrity.scrubbed(&self.pieces, &invalid);
                 if invalid.is_empty() {
                     debug!("Torrent succesfully validated!");
                     if !self.complete() {
@@ -815,7 +1325,10 @@ impl<T: cio::CIO> Torrent<T> {
                     } else {
                         for piece in invalid {
                             self.picker.invalidate_piece(piece);
-                            self.pieces.unset_bit(u64::from(piece));
+                            if self.pieces.has_bit(u64::from(piece)) {
+                                self.pieces.unset_bit(u64::from(piece));
+                                self.send_dont_have(piece);
+                            }
                         }
                         self.request_all();
                     }
@@ -823,15 +1336,132 @@ impl<T: cio::CIO> Torrent<T> {
                 }
                 // update the RPC stats once done
                 self.files.rebuild(&self.info, &self.pieces);
+                if CONFIG.disk.part_suffix {
+                    for file in 0..self.info.files.len() {
+                        self.finish_file(file);
+                    }
+                }
+                self.count_verified();
+                self.update_rpc_transfer();
+                self.rpc_update_pieces();
+                self.announce_status();
+            }
+            disk::Response::RepairComplete { invalid, .. } => {
+                self.status.validating = None;
+                let mut lost = 0;
+                for piece in invalid {
+                    if self.pieces.has_bit(u64::from(piece)) {
+                        self.pieces.unset_bit(u64::from(piece));
+                        self.picker.invalidate_piece(piece);
+                        self.integrity.validated(piece, false);
+                        self.send_dont_have(piece);
+                        lost += 1;
+                    }
+                }
+                info!("Repair found {} missing pieces in {}", lost, self.rpc_id());
+                if lost != 0 {
+                    self.dirty = true;
+                    // Reverts a complete torrent to downloading the missing pieces
+                    self.check_complete();
+                    self.request_all();
+                }
+                self.files.rebuild(&self.info, &self.pieces);
+                self.count_verified();
                 self.update_rpc_transfer();
                 self.rpc_update_pieces();
                 self.announce_status();
             }
+            disk::Response::SizesChecked(check) => {
+                let counts = self.integrity.counts(&self.pieces);
+                let files_mismatched = check
+                    .mismatched
+                    .into_iter()
+                    .filter(|&f| self.priorities[f] != 0)
+                    .map(|f| self.file_id(f))
+                    .collect();
+                let report = rpc::proto::message::IntegrityReport {
+                    serial: check.serial,
+                    id: self.rpc_id(),
+                    pieces_ok: counts.ok,
+                    pieces_failed: counts.failed,
+                    pieces_unverified: counts.unverified,
+                    files_mismatched,
+                    last_scrub: self.integrity.last_scrub(),
+                };
+                self.cio.msg_rpc(rpc::CtlMessage::IntegrityReport {
+                    client: check.client,
+                    report,
+                });
+            }
+            disk::Response::ResumeChecked { changed, .. } => {
+                if changed && self.status.validating.is_none() {
+                    info!(
+                        "{}: Files changed since shutdown, rechecking",
+                        self.rpc_id()
+                    );
+                    self.validate();
+                }
+            }
+            disk::Response::FilesModified { files, .. } => {
+                if !files.is_empty() {
+                    info!(
+                        "{}: {} files changed on disk, rechecking their pieces",
+                        self.rpc_id(),
+                        files.len()
+                    );
+                    self.recheck_files(&files);
+                }
+            }
+            disk::Response::Linked { files, .. } => {
+                info!("{}: Reused {} existing files", self.rpc_id(), files);
+                // Without a check the reused data is only found on restart
+                let import = matches!(self.status.state, StatusState::Import);
+                if files > 0 && !import && self.status.validating.is_none() {
+                    self.validate();
+                }
+            }
+            disk::Response::SpaceChecked { needed, free, .. } => {
+                if needed <= free {
+                    self.allocate();
+                    return;
+                }
+                info!(
+                    "{}: {} bytes needed, only {} free",
+                    self.rpc_id(),
+                    needed,
+                    free
+                );
+                self.status.error = Some(format!(
+                    "Not enough disk space: {} MiB needed, {} MiB free",
+                    (needed + (1 << 20) - 1) >> 20,
+                    free >> 20
+                ));
+                self.status.error_code = Some(ErrorCode::DiskFull);
+                self.announce_status();
+            }
             disk::Response::Error { err, .. } => {
                 error!("Disk error: {:?}", err);
+                if self.relocation.take().is_some() {
+                    info!(
+                        "{}: Stopped moving files, they stay where they were",
+                        self.rpc_id()
+                    );
+                    self.status.moving = None;
+                }
                 self.status.error = Some(format!("{}", err));
+                self.status.error_code = Some(disk::error_code(&err));
                 self.announce_status();
+                for (piece, waiting) in self.lazy_checks.drain() {
+                    for (pid, begin) in waiting {
+                        if let Some(peer) = self.peers.get_mut(&pid) {
+                            let length = self.info.block_len(piece, begin);
+                            peer.read_done(piece, begin);
+                            peer.reject_request(piece, begin, length);
+                        }
+                    }
+                }
                 for piece in self.validating.drain() {
+                    self.bans.reset(piece);
                     self.picker.invalidate_piece(piece);
                     self.pieces.unset_bit(u64::from(piece));
                 }
@@ -864,7 +1494,13 @@ impl<T: cio::CIO> Torrent<T> {
             self.status.state = StatusState::Incomplete;
             let seq = self.picker.is_sequential();
             self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
+            self.picker
+                .set_first_last(self.first_last, &self.priorities, &self.info);
             self.change_picker(seq);
+            self.apply_stream();
+            self.apply_deadlines();
+            self.check_space();
+            self.send_ext_handshakes();
             self.announce_status();
             self.announce_start();
             self.request_all();
@@ -873,7 +1509,12 @@ impl<T: cio::CIO> Torrent<T> {
     /// Signal that we've downloaded and verified the torrent
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
-        debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
+        debug!(
+            "Wasted: {} MiB, corrupt: {} MiB, cancelled: {} MiB",
+            self.wasted / (1024 * 1024),
+            self.corrupt / (1024 * 1024),
+            self.cancelled / (1024 * 1024)
+        );
         if let Some(req) = tracker::Request::completed(self) {
             self.cio.msg_trk(req);
         }
@@ -883,13 +1524,14 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.state = StatusState::Complete;
         self.announce_status();
 
-        // Remove all seeding peers.
+        // Remove all seeding peers, including partial seeds which don't
+        // want anything from us either.
         let leechers = &self.leechers;
         {
             let seeders = self
                 .peers
                 .iter()
-                .filter(|&(id, _)| !leechers.contains(id))
+                .filter(|&(id, p)| !leechers.contains(id) || p.exts().upload_only)
                 .map(|(id, _)| *id);
             for seeder in seeders {
                 self.cio.remove_peer(seeder);
@@ -900,12 +1542,32 @@ impl<T: cio::CIO> Torrent<T> {
         for pid in leechers {
             if let Some(peer) = self.peers.get_mut(pid) {
                 for i in 0..self.pieces.len() {
-                    if !peer.pieces().has_bit(i) {
+                    if self.pieces.has_bit(i) && !peer.pieces().has_bit(i) {
                         peer.send_message(Message::Have(i as u32));
                     }
                 }
             }
         }
+        self.send_ext_handshakes();
+        // Later changes to the files are found by comparing with this
+        self.save_resume();
+        self.move_completed();
+    }
+
+    /// Sends our extended handshake again to every peer, which tells them
+    /// when we start or stop being upload only.
+    fn send_ext_handshakes(&mut self) {
+        let payload = self.ext_handshake();
+        for peer in self
+            .peers
+            .values_mut()
+            .filter(|p| p.caps().has(Bit::Extensions))
+        {
+            peer.send_message(Message::Extension {
+                id: ext::HANDSHAKE_ID,
+                payload: payload.clone(),
+            });
+        }
     }
 
     pub fn peer_ev(&mut self, pid: cio::PID, evt: cio::Result<Message>) -> Result<(), ()> {
@@ -942,36 +1604,26 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn handle_msg(&mut self, msg: Message, peer: &mut Peer<T>) -> Result<(), ()> {
         trace!("Received {:?} from peer", msg);
         match msg {
-            Message::Handshake { rsv, .. } => {
-                if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        "ut_metadata".to_owned(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            "ut_pex".to_owned(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert("m".to_owned(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        "metadata_size".to_owned(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+            Message::Handshake { .. } => {
+                let hiding = self.hiding(peer.id());
+                peer.handshake_done(&self.pieces, self.info.complete() && !hiding);
+                self.send_ext_handshake(peer);
+                if hiding {
+                    self.super_seed_have(peer, None);
                 }
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
             }
-            Message::Bitfield(_) => {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                if self.info.complete() && !self.hiding(peer.id()) {
+                    peer.allow_fast(&self.pieces, &self.info.hash);
+                }
+                // The peer may already have the piece it was offered
+                let offered = self.super_seed.as_ref().and_then(|s| s.offered(peer.id()));
+                if let Some(piece) = offered.filter(|&p| peer.pieces().has_bit(u64::from(p))) {
+                    self.super_seed_have(peer, Some((piece, false)));
+                }
                 if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
                     peer.interested();
                 }
@@ -989,6 +1641,13 @@ impl<T: cio::CIO> Torrent<T> {
                 if self.info.complete() {
                     self.picker.piece_available(idx);
                 }
+                if self.super_seed.is_some() {
+                    let needed = self
+                        .peers
+                        .values()
+                        .any(|p| !p.pieces().has_bit(u64::from(idx)));
+                    self.super_seed_have(peer, Some((idx, needed)));
+                }
                 if peer.pieces().complete() {
                     self.leechers.remove(&peer.id());
                     // If they're now a seeder and we're also seeding, drop the conn
@@ -1000,11 +1659,19 @@ impl<T: cio::CIO> Torrent<T> {
                     peer.interested();
                 }
             }
-            Message::Unchoke => {
+            Message::Unchoke | Message::AllowedFast(_) | Message::SuggestPiece(_) => {
                 if self.status.should_dl() && self.info.complete() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
+            Message::RejectRequest { index, begin, .. } => {
+                if self.picker.rejected(Block::new(index, begin), peer.id()) {
+                    peer.request_rejected(index, begin);
+                    if self.status.should_dl() {
+                        Torrent::make_requests(peer, &mut self.picker, &self.info);
+                    }
+                }
+            }
             Message::Piece {
                 index,
                 begin,
@@ -1013,7 +1680,7 @@ impl<T: cio::CIO> Torrent<T> {
             } => {
                 // Ignore a piece we already have, this could happen from endgame
                 if self.pieces.has_bit(u64::from(index)) || self.validating.contains(&index) {
-                    self.wasted += 1;
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
@@ -1037,14 +1704,12 @@ impl<T: cio::CIO> Torrent<T> {
                 let pr = {
                     let picker = &mut self.picker;
                     let peers = &mut self.peers;
+                    let cancelled = &mut self.cancelled;
 
-                    picker.completed(Block::new(index, begin), |pid| {
+                    picker.completed(Block::new(index, begin), peer.id(), |pid| {
                         if let Some(p) = peers.get_mut(&pid) {
-                            p.send_message(Message::Cancel {
-                                index,
-                                begin,
-                                length,
-                            })
+                            p.cancel_request(index, begin, length);
+                            *cancelled += u64::from(length);
                         }
                     })
                 };
@@ -1055,6 +1720,7 @@ impl<T: cio::CIO> Torrent<T> {
                 };
 
                 self.dirty = true;
+                self.bans.contributed(index, peer.addr().ip());
                 self.write_piece(index, begin, data);
 
                 self.downloaded += u64::from(length);
@@ -1064,6 +1730,7 @@ impl<T: cio::CIO> Torrent<T> {
                     self.cio.msg_disk(disk::Request::validate_piece(
                         self.id,
                         self.info.clone(),
+                        self.priorities.clone(),
                         self.path.clone(),
                         index,
                     ));
@@ -1079,20 +1746,37 @@ impl<T: cio::CIO> Torrent<T> {
                 begin,
                 length,
             } => {
-                if !self.pieces.has_bit(u64::from(index)) {
-                    return Err(());
+                let hidden = self
+                    .super_seed
+                    .as_ref()
+                    .map(|s| !s.revealed(peer.id(), index))
+                    .unwrap_or(false);
+                if !self.pieces.has_bit(u64::from(index)) || hidden {
+                    // Fast peers may ask for anything, and get rejected
+                    if !peer.caps().has(Bit::Fast) {
+                        return Err(());
+                    }
+                    peer.reject_request(index, begin, length);
+                    return Ok(());
                 }
                 if length != self.info.block_len(index, begin) {
                     return Err(());
                 }
-                if !self.status.stopped() {
+                if !self.status.stopped() && peer.can_upload(index) {
+                    if self.integrity.unverified().has_bit(u64::from(index)) {
+                        peer.reading(index, begin, length);
+                        self.check_lazily(peer.id(), index, begin);
+                        return Ok(());
+                    }
                     if let Some(buf) = Buffer::get() {
                         self.request_read(peer.id(), index, begin, buf);
+                        peer.reading(index, begin, length);
                         return Ok(());
                     }
                 }
 
                 // TODO: add this to a queue to fulfill later
+                peer.reject_request(index, begin, length);
             }
             Message::Interested => {
                 self.choker.add_peer(peer);
@@ -1100,229 +1784,480 @@ impl<T: cio::CIO> Torrent<T> {
             Message::Uninterested => {
                 self.choker.remove_peer(peer, &mut self.peers);
             }
+            // The upload is dropped at the peer level, just count it here
+            Message::Cancel { .. } => {
+                self.ul_cancelled += peer.take_cancelled();
+            }
 
             // These messages are all handled at the peer level, not the torrent level,
             // so just ignore here
-            Message::KeepAlive | Message::Choke | Message::Cancel { .. } | Message::Port(_) => {}
+            Message::KeepAlive | Message::Choke | Message::Port(_) => {}
         }
         Ok(())
     }
 
-    fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
-        if id == 0 {
-            const MAX_INFO_BYTES: i64 = 100 * 1000 * 1000;
-            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let m = d.remove("m").and_then(|v| v.into_dict()).ok_or(())?;
-            if m.contains_key("ut_metadata") {
-                let size = d
-                    .remove("metadata_size")
-                    .and_then(|v| v.into_int())
-                    .ok_or(())?;
-                if let Some(std::usize::MAX) = self.info_idx {
-                    if size % 16_384 == 0 {
-                        self.info_idx = Some(size as usize / 16_384 - 1);
-                    } else {
-                        self.info_idx = Some(size as usize / 16_384);
-                    }
-                    if size > MAX_INFO_BYTES {
-                        debug!("UT metadata too large, {} MBs", size / (1000 * 1000));
-                        return Err(());
-                    }
-                    self.info_bytes.resize(size as usize, 0u8);
-                }
-                if !self.info.complete() {
-                    // Request the first index chunk to see if they have it
-                    let mut respb = BTreeMap::new();
-                    respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                    respb.insert("piece".to_owned(), bencode::BEncode::Int(0));
-                    let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                    let utm_id = if let Some(i) = peer.exts().ut_meta {
-                        i
-                    } else {
-                        return Err(());
-                    };
-                    peer.send_message(Message::Extension {
-                        id: utm_id,
-                        payload,
-                    });
-                }
-            }
-        } else if id == UT_META_ID {
-            let utm_id = if let Some(i) = peer.exts().ut_meta {
-                i
-            } else {
-                return Ok(());
-            };
-            let buf = bencode::decode_buf_first(&payload).map_err(|_| ())?;
-            let mut dict = buf.into_dict().ok_or(())?;
-            let msg = dict
-                .remove("msg_type")
-                .and_then(|v| v.into_int())
-                .ok_or(())?;
-            let piece_len = dict.remove("piece").and_then(|v| v.into_int()).ok_or(())? as usize;
-            if piece_len * 16_384 >= self.info_bytes.len() {
-                return Err(());
-            }
-            // Our metadata request strategy is as follows: after requesting the first
-            // index chunk, we attempt to request every single subsequent chunk from
-            // a peer which responds succesfully. This is slightly wasteful, but
-            // simplifies logic (since we don't have to do "index piece picking").
-            match msg {
-                0 => {
-                    let mut respb = BTreeMap::new();
-                    if self.info_idx.is_none() {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(1));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(piece_len as i64));
-                        let size = if self.info_bytes.len() / 16_384 == piece_len {
-                            self.info_bytes.len() % 16_384
-                        } else {
-                            16_384
-                        };
-                        let total_size = self.info_bytes.len() as i64;
-                        respb.insert("total_size".to_owned(), bencode::BEncode::Int(total_size));
-                        let mut payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        let s = piece_len * 16_384;
-                        payload.extend_from_slice(&self.info_bytes[s..s + size]);
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    } else {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(2));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(piece_len as i64));
-                        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    }
-                }
-                1 => {
-                    if let Some(idx) = self.info_idx {
-                        let data_idx = util::find_subseq(&payload[..], b"ee").unwrap() + 2;
-                        if payload.len() - data_idx > self.info_bytes.len() - piece_len * 16_384 {
-                            debug!(
-                                "Metadata bounds invalid, goes to: {}, ibl: {}",
-                                payload.len() - data_idx,
-                                self.info_bytes.len() - piece_len * 16_384,
-                            );
-                            return Err(());
-                        }
-                        let size = if piece_len == idx {
-                            self.info_bytes.len() - piece_len * 16_384
-                        } else {
-                            16_384
-                        };
-                        (&mut self.info_bytes[piece_len * 16_384..piece_len * 16_384 + size])
-                            .copy_from_slice(&payload[data_idx..]);
-                        if piece_len == idx {
-                            let mut b = BTreeMap::new();
-                            let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
-                            b.insert(
-                                "announce".to_owned(),
-                                bencode::BEncode::String(
-                                    self.info
-                                        .announce
-                                        .as_ref()
-                                        .map(|u| u.as_str())
-                                        .unwrap_or("")
-                                        .as_bytes()
-                                        .to_vec(),
-                                ),
-                            );
-                            b.insert("info".to_owned(), bni);
-                            let ni =
-                                Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
-                                debug!("Magnet file acquired succesfully!");
-                                self.info_idx = None;
-                                self.info = Arc::new(ni);
-                                self.magnet_complete();
-                            } else {
-                                return Err(());
-                            }
-                        } else if piece_len == 0 {
-                            for i in 1..=idx {
-                                let mut respb = BTreeMap::new();
-                                respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                                respb.insert("piece".to_owned(), bencode::BEncode::Int(i as i64));
-                                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                                peer.send_message(Message::Extension {
-                                    id: utm_id,
-                                    payload,
-                                });
-                            }
-                        }
+    /// Whether super-seeding should hide our pieces from new peers.
+    fn super_seeding(&self) -> bool {
+        self.super_seed.is_some() && self.pieces.complete()
+    }
+
+    /// Whether our pieces are being hidden from a peer.
+    fn hiding(&self, pid: usize) -> bool {
+        self.super_seed
+            .as_ref()
+            .map(|s| s.hiding(pid))
+            .unwrap_or(false)
+    }
+
+    pub fn set_super_seeding(&mut self, enabled: bool) {
+        if enabled == self.super_seed.is_some() {
+            return;
+        }
+        if enabled {
+            self.super_seed = Some(SuperSeed::new());
+        } else if let Some(s) = self.super_seed.take() {
+            // Peers get told about everything we kept from them
+            for peer in self.peers.values_mut().filter(|p| s.hiding(p.id())) {
+                for piece in self.pieces.iter() {
+                    if !s.revealed(peer.id(), piece as u32) && !peer.pieces().has_bit(piece) {
+                        peer.send_message(Message::Have(piece as u32));
                     }
                 }
-                2 => {}
-                i => {
-                    debug!("Got unknown ut_meta id: {}", i);
-                }
-            }
-        } else if id == UT_PEX_ID {
-            const PEX_SEED: u8 = 0x02;
-            const PEX_OUTGOING: u8 = 0x10;
-            if peer.exts().ut_pex.is_none() {
-                return Ok(());
             }
-            if self.info.private {
-                return Err(());
-            }
-            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let mut peers = vec![];
-            let flags = d
-                .remove("added.f")
-                .and_then(bencode::BEncode::into_bytes)
-                .unwrap_or_else(|| vec![0; 50]);
-            if let Some(bencode::BEncode::String(ref data)) = d.remove("added") {
-                for (p, flag) in data.chunks(6).zip(flags) {
-                    if (flag & PEX_SEED != 0) && self.complete() {
-                        continue;
-                    }
-                    if flag & PEX_OUTGOING == 0 {
-                        continue;
-                    }
+        }
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSuperSeeding {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                super_seeding: enabled,
+            },
+        ]));
+    }
 
-                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                    let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                    peers.push(SocketAddr::V4(socket));
-                }
-            }
-            if !peers.is_empty() {
-                self.cio
-                    .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
-                        tid: self.id,
-                        peers,
-                    })));
-            }
-        } else {
-            debug!("Got unknown extension id: {}", id);
+    /// Sets whether the first and last pieces of wanted files are
+    /// downloaded first.
+    pub fn set_first_last(&mut self, enabled: bool) {
+        if enabled == self.first_last {
+            return;
         }
-        Ok(())
+        self.first_last = enabled;
+        self.picker
+            .set_first_last(enabled, &self.priorities, &self.info);
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentFirstLast {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                first_last: enabled,
+            },
+        ]));
     }
 
-    /// Periodically called to update peers, choking the slowest one and
-    /// optimistically unchoking a new peer
-    pub fn update_unchoked(&mut self) {
-        if self.complete() {
-            self.choker.update_download(&mut self.peers)
-        } else {
-            self.choker.update_upload(&mut self.peers)
-        };
+    /// Pins the number of unchoked peers, or leaves it to the config and
+    /// tuning if None.
+    pub fn set_upload_slots(&mut self, slots: Option<u16>) {
+        self.tuner.pin_slots(slots.map(usize::from));
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentUploadSlots {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                upload_slots: slots,
+            },
+        ]));
     }
 
-    pub fn rpc_update(&mut self, u: rpc::proto::resource::CResourceUpdate) {
-        if u.throttle_up.is_some() || u.throttle_down.is_some() {
-            let tu = u.throttle_up.unwrap_or_else(|| self.throttle.ul_rate());
-            let td = u.throttle_down.unwrap_or_else(|| self.throttle.dl_rate());
-            self.set_throttle(tu, td);
+    /// Offers new pieces to super-seeded peers once `peer` has a piece,
+    /// given along with whether any other peer still needs it. Without a
+    /// piece, `peer` is just given its first offer.
+    fn super_seed_have(&mut self, peer: &mut Peer<T>, have: Option<(u32, bool)>) {
+        let mut counts = vec![0u32; self.pieces.len() as usize];
+        for p in self.peers.values() {
+            for piece in p.pieces().iter() {
+                counts[piece as usize] += 1;
+            }
         }
-
-        if let Some(p) = u.path {
-            self.set_path(p);
+        let s = match self.super_seed {
+            Some(ref mut s) => s,
+            None => return,
+        };
+        let offers = match have {
+            Some((piece, needed)) => s.have(peer.id(), piece, needed),
+            None => vec![peer.id()],
+        };
+        for pid in offers {
+            let p = if pid == peer.id() {
+                &mut *peer
+            } else if let Some(p) = self.peers.get_mut(&pid) {
+                p
+            } else {
+                continue;
+            };
+            if let Some(piece) = s.offer(pid, p.pieces(), &counts) {
+                p.send_message(Message::Have(piece));
+            }
+        }
+    }
+
+    fn send_ext_handshake(&self, peer: &mut Peer<T>) {
+        if peer.caps().has(Bit::Extensions) {
+            peer.send_message(Message::Extension {
+                id: ext::HANDSHAKE_ID,
+                payload: self.ext_handshake(),
+            });
+        }
+    }
+
+    fn ext_handshake(&self) -> Vec<u8> {
+        ext::handshake(self.info.private, self.info_bytes.len(), self.complete())
+    }
+
+    /// Dispatches an extension message to the handler of its extension.
+    fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
+        if id == ext::HANDSHAKE_ID {
+            return self.handle_ext_handshake(&payload, peer);
+        }
+        match Ext::from_id(id, self.info.private) {
+            Some(Ext::Metadata) => self.handle_ut_meta(&payload, peer),
+            Some(Ext::Pex) => self.handle_ut_pex(&payload, peer),
+            Some(Ext::Holepunch) => self.handle_ut_holepunch(&payload, peer),
+            Some(Ext::DontHave) => self.handle_lt_donthave(&payload, peer),
+            None => {
+                debug!("Got unknown extension id: {}", id);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_ext_handshake(&mut self, payload: &[u8], peer: &mut Peer<T>) -> Result<(), ()> {
+        if !peer.caps().has(Bit::Extensions) {
+            return Ok(());
+        }
+        if let Err(e) = peer.update_exts(payload, self.info.private) {
+            debug!("Invalid extended handshake: {}", e);
+            return Err(());
+        }
+        self.dedup_dual_stack(peer)?;
+        // Neither side wants anything from the other
+        if peer.exts().upload_only && self.complete() {
+            return Err(());
+        }
+        if let Some(ip) = peer.exts().yourip {
+            self.external_ip.vote(ip);
+        }
+        if let Some(ref mut m) = self.metadata {
+            // Only peers which have the metadata know its size
+            if let Some(size) = peer.exts().metadata_size {
+                if !m.set_size(size) {
+                    debug!("UT metadata too large, {} MBs", size / (1000 * 1000));
+                    return Err(());
+                }
+            }
+            Torrent::request_metadata(m, peer);
+        }
+        Ok(())
+    }
+
+    /// Keeps a single connection to dual-stack peers which we've connected
+    /// to over both IPv4 and IPv6, on the preferred address family.
+    fn dedup_dual_stack(&mut self, peer: &Peer<T>) -> Result<(), ()> {
+        let alt = match peer.alt_addr() {
+            Some(alt) => alt,
+            None => return Ok(()),
+        };
+        let other = match self.peers.values().find(|p| p.listen_addr() == Some(alt)) {
+            Some(p) => p.id(),
+            None => return Ok(()),
+        };
+        debug!("Peer {} is also connected as {}", peer.addr(), alt);
+        if peer.addr().is_ipv6() == CONFIG.net.prefer_ipv6 {
+            self.cio.remove_peer(other);
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    fn handle_ut_meta(&mut self, payload: &[u8], peer: &mut Peer<T>) -> Result<(), ()> {
+        let utm_id = if let Some(i) = peer.exts().get(Ext::Metadata) {
+            i
+        } else {
+            return Ok(());
+        };
+        match metadata::Msg::decode(payload).ok_or(())? {
+            metadata::Msg::Request(piece) => {
+                let data = if self.metadata.is_none() {
+                    metadata::piece(&self.info_bytes, piece)
+                } else {
+                    None
+                };
+                let resp = match data {
+                    Some(data) => metadata::Msg::Data {
+                        piece,
+                        total_size: self.info_bytes.len() as u64,
+                        data,
+                    },
+                    None => metadata::Msg::Reject(piece),
+                };
+                peer.send_message(Message::Extension {
+                    id: utm_id,
+                    payload: resp.encode(),
+                });
+            }
+            metadata::Msg::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let done = match self.metadata {
+                    Some(ref mut m) => m.received(piece, total_size, data)?,
+                    None => return Ok(()),
+                };
+                if done {
+                    self.metadata_fetched()?;
+                } else if let Some(ref mut m) = self.metadata {
+                    Torrent::request_metadata(m, peer);
+                }
+            }
+            metadata::Msg::Reject(piece) => {
+                if let Some(ref mut m) = self.metadata {
+                    m.rejected(peer.id(), piece);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests a piece of the metadata from a peer, if it supports
+    /// ut_metadata and isn't busy with another piece.
+    fn request_metadata(m: &mut Fetch, peer: &mut Peer<T>) {
+        let utm_id = match peer.exts().get(Ext::Metadata) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(piece) = m.pick(peer.id()) {
+            peer.send_message(Message::Extension {
+                id: utm_id,
+                payload: metadata::Msg::Request(piece).encode(),
+            });
+        }
+    }
+
+    /// Starts the torrent once all the metadata has been received, if it
+    /// matches the info hash.
+    fn metadata_fetched(&mut self) -> Result<(), ()> {
+        let hash = self.info.hash;
+        let info_bytes = match self.metadata.as_mut().and_then(|m| m.finish(&hash)) {
+            Some(b) => b,
+            None => {
+                debug!("Magnet metadata failed hash check, refetching");
+                return Ok(());
+            }
+        };
+        let mut b = BTreeMap::new();
+        b.insert(
+            "announce".to_owned(),
+            bencode::BEncode::String(
+                self.info
+                    .announce
+                    .as_ref()
+                    .map(|u| u.as_str())
+                    .unwrap_or("")
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+        let bni = bencode::decode_buf(&info_bytes).map_err(|_| ())?;
+        b.insert("info".to_owned(), bni);
+        let mut ni = Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
+        ni.web_seeds = self.info.web_seeds.clone();
+        debug!("Magnet file acquired succesfully!");
+        self.metadata = None;
+        self.info_bytes = info_bytes;
+        self.info = Arc::new(ni);
+        self.tuner.set_scale(self.info.piece_len / 16_384);
+        self.magnet_complete();
+        Ok(())
+    }
+
+    /// Handles a peer retracting a piece it advertised. Requests for the
+    /// piece are left for the peer to reject.
+    fn handle_lt_donthave(&mut self, payload: &[u8], peer: &mut Peer<T>) -> Result<(), ()> {
+        if payload.len() != 4 {
+            return Err(());
+        }
+        let idx = BigEndian::read_u32(payload);
+        if u64::from(idx) >= peer.pieces().len() || !peer.pieces().has_bit(u64::from(idx)) {
+            return Ok(());
+        }
+        if self.info.complete() {
+            self.picker.piece_unavailable(peer, idx);
+        }
+        peer.dont_have(idx);
+        self.leechers.insert(peer.id());
+        Ok(())
+    }
+
+    /// Tells peers we no longer have a piece we advertised, e.g. after it
+    /// failed a hash check.
+    fn send_dont_have(&mut self, piece: u32) {
+        let payload = piece.to_be_bytes().to_vec();
+        for peer in self.peers.values_mut() {
+            if let Some(id) = peer.exts().get(Ext::DontHave) {
+                peer.send_message(Message::Extension {
+                    id,
+                    payload: payload.clone(),
+                });
+            }
+        }
+    }
+
+    fn handle_ut_pex(&mut self, payload: &[u8], peer: &mut Peer<T>) -> Result<(), ()> {
+        if peer.exts().get(Ext::Pex).is_none() {
+            return Ok(());
+        }
+        let msg = pex::Msg::decode(payload).ok_or(())?;
+        let complete = self.complete();
+        let added: Vec<_> = msg
+            .added
+            .into_iter()
+            // Seeds are no use to us once we're seeding too
+            .filter(|&(_, flags)| !(complete && flags & pex::FLAG_SEED != 0))
+            .collect();
+        // Peers which can only make outgoing connections are likely behind
+        // a NAT, so the sender is asked to put us in touch as well
+        if let Some(id) = peer.exts().get(Ext::Holepunch) {
+            let nated = added
+                .iter()
+                .filter(|&&(addr, flags)| {
+                    flags & pex::FLAG_UTP != 0 && flags & pex::FLAG_REACHABLE == 0 && addr.is_ipv4()
+                })
+                .filter(|&&(addr, _)| !self.peers.values().any(|p| p.addr() == addr))
+                .take(holepunch::MAX_RENDEZVOUS);
+            for &(addr, _) in nated {
+                peer.send_message(Message::Extension {
+                    id,
+                    payload: holepunch::Msg::Rendezvous(addr).encode(),
+                });
+            }
+        }
+        let peers: Vec<_> = added.into_iter().map(|(addr, _)| addr).collect();
+        if !peers.is_empty() {
+            self.cio
+                .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
+                    tid: self.id,
+                    peers,
+                })));
+        }
+        Ok(())
+    }
+
+    fn handle_ut_holepunch(&mut self, payload: &[u8], peer: &mut Peer<T>) -> Result<(), ()> {
+        let id = match peer.exts().get(Ext::Holepunch) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        match holepunch::Msg::decode(payload).ok_or(())? {
+            holepunch::Msg::Rendezvous(addr) => {
+                let reply = self.rendezvous(addr, peer);
+                peer.send_message(Message::Extension {
+                    id,
+                    payload: reply.encode(),
+                });
+            }
+            holepunch::Msg::Connect(addr) => {
+                if addr.is_ipv4() && !self.peers.values().any(|p| p.addr() == addr) {
+                    debug!("Holepunching to {} via {}", addr, peer.addr());
+                    self.cio
+                        .propagate(cio::Event::Tracker(Ok(tracker::Response::Holepunch {
+                            tid: self.id,
+                            peer: addr,
+                        })));
+                }
+            }
+            holepunch::Msg::Error(addr, code) => {
+                debug!(
+                    "Holepunch to {} via {} failed: {:?}",
+                    addr,
+                    peer.addr(),
+                    code
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Relays a rendezvous between `peer` and the peer at `addr`, returning
+    /// the reply for `peer`.
+    fn rendezvous(&mut self, addr: SocketAddr, peer: &Peer<T>) -> holepunch::Msg {
+        use self::holepunch::{ErrorCode, Msg};
+
+        let from = match peer.listen_addr() {
+            Some(from) => from,
+            None => return Msg::Error(addr, ErrorCode::NoSupport),
+        };
+        if addr == from || addr == peer.addr() {
+            return Msg::Error(addr, ErrorCode::NoSelf);
+        }
+        let target = match self
+            .peers
+            .values_mut()
+            .find(|p| p.addr() == addr || p.listen_addr() == Some(addr))
+        {
+            Some(target) => target,
+            None => return Msg::Error(addr, ErrorCode::NotConnected),
+        };
+        let id = match target.exts().get(Ext::Holepunch) {
+            Some(id) => id,
+            None => return Msg::Error(addr, ErrorCode::NoSupport),
+        };
+        target.send_message(Message::Extension {
+            id,
+            payload: Msg::Connect(from).encode(),
+        });
+        Msg::Connect(addr)
+    }
+
+    /// Periodically called to update peers, letting the choker hand a
+    /// slot over to a waiting peer
+    pub fn update_unchoked(&mut self) {
+        let seeding = self.complete();
+        let slots = match self.tuner.limited_slots(self.throttle.ul_limit()) {
+            Some(slots) => slots,
+            None if self.choker.tuned() => {
+                let rate = if seeding {
+                    self.stat.avg_ul()
+                } else {
+                    self.stat.avg_dl()
+                };
+                self.tuner.update_slots(rate, self.choker.waiting() > 0)
+            }
+            None => self.tuner.unchoke_slots(),
+        };
+        self.choker.set_slots(slots, &mut self.peers);
+        self.choker.update(&mut self.peers, seeding);
+    }
+
+    pub fn rpc_update(&mut self, u: rpc::proto::resource::CResourceUpdate) {
+        if u.throttle_up.is_some() || u.throttle_down.is_some() {
+            let tu = u.throttle_up.unwrap_or_else(|| self.throttle.ul_rate());
+            let td = u.throttle_down.unwrap_or_else(|| self.throttle.dl_rate());
+            self.set_throttle(tu, td);
+        }
+
+        if let Some(p) = u.path {
+            self.set_path(p);
+        }
+
+        if let Some(p) = u.completed_path {
+            self.set_completed_path(p);
+        }
+
+        if let Some(name) = u.name {
+            self.rename(name);
         }
 
         if let Some(p) = u.priority {
@@ -1335,6 +2270,18 @@ impl<T: cio::CIO> Torrent<T> {
             None => {}
         }
 
+        if let Some(enabled) = u.super_seeding {
+            self.set_super_seeding(enabled);
+        }
+
+        if let Some(slots) = u.upload_slots {
+            self.set_upload_slots(slots);
+        }
+
+        if let Some(enabled) = u.first_last {
+            self.set_first_last(enabled);
+        }
+
         if let Some(user_data) = u.user_data {
             let id = self.rpc_id();
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -1347,16 +2294,36 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
-    pub fn rpc_update_file(&mut self, id: String, priority: u8) {
-        for (i, f) in self.info.files.iter().enumerate() {
-            let fid =
-                util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
-            if fid == id {
-                Arc::make_mut(&mut self.priorities)[i] = priority;
-            }
+    pub fn rpc_update_file(
+        &mut self,
+        id: String,
+        priority: Option<u8>,
+        position: Option<Option<u64>>,
+        path: Option<String>,
+    ) {
+        let file =
+            (0..self.info.files.len()).find(|&f| !self.info.files[f].pad && self.file_id(f) == id);
+        let file = match file {
+            Some(f) => f,
+            None => return,
+        };
+        if let Some(path) = path {
+            self.rename_file(file, path);
         }
+        if let Some(position) = position {
+            self.set_stream(file, id.clone(), position);
+        }
+        let priority = match priority {
+            Some(p) => p,
+            None => return,
+        };
+        let old = self.priorities.clone();
+        Arc::make_mut(&mut self.priorities)[file] = priority;
+        self.move_parts(file, &old);
+        self.files.dirty.insert(file);
 
         self.picker.set_priorities(&self.priorities, &self.info);
+        self.cancel_unwanted();
         self.clear_piece_cache();
 
         self.check_complete();
@@ -1372,6 +2339,165 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Moves the data of the pieces we have around a file whose priority
+    /// changed, as locations in shared pieces enter or leave the part
+    /// file. Only pieces spanning the file can be affected.
+    fn move_parts(&mut self, file: usize, old: &Arc<Vec<u8>>) {
+        let len = self.info.files[file].length;
+        if len == 0 || (old[file] == 0) == (self.priorities[file] == 0) {
+            return;
+        }
+        let start: u64 = self.info.files[..file].iter().map(|f| f.length).sum();
+        let piece_len = u64::from(self.info.piece_len);
+        let mut locations = Vec::new();
+        for piece in (start / piece_len) as u32..=((start + len - 1) / piece_len) as u32 {
+            if !self.pieces.has_bit(u64::from(piece)) {
+                continue;
+            }
+            if let Some((_, written)) = self.relocation.as_mut() {
+                written.insert(piece);
+            }
+            let before = Info::piece_disk_locs_pri(&self.info, old, piece);
+            let after = Info::piece_disk_locs_pri(&self.info, &self.priorities, piece);
+            locations.extend(
+                before
+                    .zip(after)
+                    .filter(|(b, a)| b.part != a.part)
+                    .map(|(_, a)| a),
+            );
+        }
+        if !locations.is_empty() {
+            self.cio.msg_disk(disk::Request::move_parts(
+                self.id,
+                locations,
+                self.path.clone(),
+            ));
+        }
+    }
+
+    /// Starts streaming a file from a playback position, or stops if
+    /// there's none. Any file streamed before is no longer streamed.
+    fn set_stream(&mut self, file: usize, id: String, position: Option<u64>) {
+        let mut updates = Vec::new();
+        if let Some((prev, _)) = self.stream {
+            if prev != file {
+                updates.push(resource::SResourceUpdate::FilePosition {
+                    id: self.file_id(prev),
+                    kind: resource::ResourceKind::File,
+                    position: None,
+                });
+            }
+        }
+        self.stream = position.map(|p| (file, p));
+        self.apply_stream();
+        self.request_all();
+        updates.push(resource::SResourceUpdate::FilePosition {
+            id,
+            kind: resource::ResourceKind::File,
+            position,
+        });
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
+    /// Points the picker at the pieces in the window ahead of the streamed
+    /// file's playback position.
+    fn apply_stream(&mut self) {
+        let (file, position) = match self.stream {
+            Some(s) => s,
+            None => return self.picker.set_stream(Vec::new()),
+        };
+        let len = self.info.files[file].length;
+        if position >= len {
+            return self.picker.set_stream(Vec::new());
+        }
+        let start: u64 = self.info.files[..file].iter().map(|f| f.length).sum();
+        let window = cmp::max(CONFIG.picker.stream_window, 1);
+        let from = start + position;
+        let to = start + cmp::min(position.saturating_add(window), len);
+        let piece_len = u64::from(self.info.piece_len);
+        let pieces = (from / piece_len) as u32..=((to - 1) / piece_len) as u32;
+        self.picker.set_stream(pieces.collect());
+    }
+
+    /// Sets a deadline, in milliseconds from now, for a piece to be
+    /// downloaded by, or clears the piece's deadline if there's none. The
+    /// client is told if the deadline passes before the piece is done.
+    pub fn set_piece_deadline(
+        &mut self,
+        piece: u32,
+        deadline: Option<u64>,
+        client: usize,
+        serial: u64,
+    ) {
+        let error = if !self.info.complete() {
+            Some((
+                ErrorCode::MetadataMissing,
+                "Torrent metadata has not been fetched yet".to_owned(),
+            ))
+        } else if piece >= self.info.pieces() {
+            Some((
+                ErrorCode::MalformedMessage,
+                format!("Piece {} does not exist", piece),
+            ))
+        } else {
+            None
+        };
+        if let Some((code, reason)) = error {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                code,
+                reason,
+                client,
+                serial,
+            });
+            return;
+        }
+        self.deadlines.retain(|d| d.piece != piece);
+        if let Some(ms) = deadline {
+            if !self.pieces.has_bit(u64::from(piece)) {
+                self.deadlines.push(Deadline {
+                    piece,
+                    at: Instant::now() + Duration::from_millis(ms),
+                    client,
+                    serial,
+                    expired: false,
+                });
+                self.deadlines.sort_by_key(|d| d.at);
+            }
+        }
+        self.apply_deadlines();
+        self.request_all();
+    }
+
+    fn apply_deadlines(&mut self) {
+        let pieces = self.deadlines.iter().map(|d| d.piece).collect();
+        self.picker.set_deadlines(pieces);
+    }
+
+    /// Reports deadlines which passed before their pieces were downloaded.
+    /// The pieces stay urgent until they're done or their deadline is
+    /// cleared.
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        if !self.deadlines.iter().any(|d| !d.expired && d.at <= now) {
+            return;
+        }
+        let id = self.rpc_id();
+        for d in self
+            .deadlines
+            .iter_mut()
+            .filter(|d| !d.expired && d.at <= now)
+        {
+            debug!("{}: Deadline for piece {} expired", id, d.piece);
+            d.expired = true;
+            self.cio.msg_rpc(rpc::CtlMessage::DeadlineExpired {
+                id: id.clone(),
+                piece: d.piece,
+                client: d.client,
+                serial: d.serial,
+            });
+        }
+    }
+
     pub fn rpc_update_pieces(&mut self) {
         let id = self.rpc_id();
         let piece_field = self.pieces.b64();
@@ -1384,17 +2510,64 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Sends the piece availability map, if it changed since it was last
+    /// sent.
+    pub fn update_rpc_availability(&mut self) {
+        let map = self.availability_map();
+        if map == self.availability_map {
+            return;
+        }
+        self.availability_map = map.clone();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentAvailabilityMap {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                piece_availability: map,
+            },
+        ]));
+    }
+
+    /// Run length encodes how many connected peers have each piece, and
+    /// whether we have it.
+    fn availability_map(&self) -> Vec<(u16, bool, u32)> {
+        if self.status.magnet() {
+            return Vec::new();
+        }
+        // Seeders have every piece, so they're counted once for all
+        let mut seeders = 0u16;
+        let mut counts = vec![0u16; self.info.pieces() as usize];
+        for peer in self.peers.values() {
+            if peer.pieces().complete() {
+                seeders = seeders.saturating_add(1);
+                continue;
+            }
+            for piece in peer.pieces().iter() {
+                counts[piece as usize] = counts[piece as usize].saturating_add(1);
+            }
+        }
+        let mut map: Vec<(u16, bool, u32)> = Vec::new();
+        for (piece, count) in counts.into_iter().enumerate() {
+            let peers = count.saturating_add(seeders);
+            let have = self.pieces.has_bit(piece as u64);
+            match map.last_mut() {
+                Some(run) if run.0 == peers && run.1 == have => run.2 += 1,
+                _ => map.push((peers, have, 1)),
+            }
+        }
+        map
+    }
+
     fn start(&mut self, serialize: bool) {
         debug!("Starting torrent");
         // Update RPC of the torrent, tracker, files, and peers
         let mut resources = Vec::new();
         resources.push(self.rpc_info());
         resources.extend(self.rpc_trk_info());
-        if self.info_idx.is_none() {
+        if self.metadata.is_none() {
             resources.extend(self.rpc_rel_info());
         }
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
-        if self.info_idx.is_none() {
+        if self.metadata.is_none() {
             self.update_rpc_transfer();
         }
         if serialize {
@@ -1403,10 +2576,22 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn announce_start(&mut self) {
+        let req = tracker::Request::started(self);
+        self.send_started(req);
+    }
+
+    /// Announces a torrent loaded with the rest of the session, whose
+    /// announces are spread out by the tracker.
+    fn announce_startup(&mut self) {
+        let req = tracker::Request::startup(self);
+        self.send_started(req);
+    }
+
+    fn send_started(&mut self, req: Option<tracker::Request>) {
         if self.status.stopped() {
             return;
         }
-        if let Some(req) = tracker::Request::started(self) {
+        if let Some(req) = req {
             self.cio.msg_trk(req);
             self.dump_torrent_file();
         }
@@ -1418,13 +2603,20 @@ impl<T: cio::CIO> Torrent<T> {
             return;
         }
         if !self.info.private {
-            let mut req = tracker::Request::DHTAnnounce(self.info.hash);
-            self.cio.msg_trk(req);
-            req = tracker::Request::GetPeers(tracker::GetPeers {
-                id: self.id,
+            self.cio.msg_dht(dht::Request::Announce {
+                hash: self.info.hash,
+                seed: self.complete(),
+            });
+            self.dht_get_peers();
+        }
+    }
+
+    fn dht_get_peers(&mut self) {
+        if !self.info.private {
+            self.cio.msg_dht(dht::Request::GetPeers {
+                tid: self.id,
                 hash: self.info.hash,
             });
-            self.cio.msg_trk(req);
         }
     }
 
@@ -1432,6 +2624,12 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.completed()
     }
 
+    /// Whether every wanted piece is done, but some unwanted ones are
+    /// missing, see BEP 21.
+    pub fn partial_seed(&self) -> bool {
+        self.complete() && !self.pieces.complete()
+    }
+
     fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
@@ -1447,7 +2645,8 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn dump_torrent_file(&mut self) {
-        let data = self.info.to_torrent_bencode().encode_to_buf();
+        let info = self.orig.as_ref().unwrap_or(&self.info);
+        let data = info.to_torrent_bencode().encode_to_buf();
         let mut path = PathBuf::from(&CONFIG.disk.session);
         path.push(&util::hash_to_id(&self.info.hash));
         path.set_extension("torrent");
@@ -1458,6 +2657,8 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.state = StatusState::Incomplete;
         self.announce_status();
         self.pieces = Bitfield::new(u64::from(self.info.pieces()));
+        self.integrity = Integrity::new(self.pieces.len());
+        self.verified = 0;
         self.priorities = Arc::new(vec![3; self.info.files.len()]);
         for peer in self.peers.values_mut() {
             if peer.magnet_complete(&self.info).is_err() {
@@ -1476,24 +2677,229 @@ impl<T: cio::CIO> Torrent<T> {
 
         let seq = self.picker.is_sequential();
         self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
+        self.picker
+            .set_first_last(self.first_last, &self.priorities, &self.info);
         self.change_picker(seq);
         self.files = Files::new(&self.info, &self.pieces);
+        self.check_space();
         self.validate();
         self.dump_torrent_file();
     }
 
+    /// Moves the torrent's files to another folder. They're copied in the
+    /// background while the torrent keeps using them, and only switched
+    /// over once all of the data is there.
     fn set_path(&mut self, path: String) {
+        let path = shellexpand::tilde(&path).into_owned();
         let from = if let Some(ref p) = self.path {
             p.clone()
         } else {
             CONFIG.disk.directory.clone()
         };
+        if from == path || self.relocation.is_some() {
+            return;
+        }
+        // Memory is moved at once, as are magnets which have no files yet
+        if self.memory || !self.info.complete() {
+            self.cio.msg_disk(disk::Request::Move {
+                tid: self.id,
+                from,
+                to: path,
+                target: self.info.name.clone(),
+            });
+            return;
+        }
+        info!("Moving {} to {}", self.rpc_id(), path);
+        self.cio.msg_disk(disk::Request::relocate(
+            self.id,
+            self.info.clone(),
+            from,
+            path.clone(),
+        ));
+        self.relocation = Some((path, FHashSet::default()));
+        self.status.moving = Some(0.0);
+        self.announce_status();
+    }
+
+    /// Renames the torrent, along with the folder holding its files or its
+    /// only file.
+    fn rename(&mut self, name: String) {
+        let from = PathBuf::from(&self.info.name);
+        self.rename_path(from, PathBuf::from(name));
+    }
+
+    /// Renames a file, which is kept in the torrent's folder.
+    fn rename_file(&mut self, file: usize, path: String) {
+        let to = PathBuf::from(path);
+        if self.info.files.len() > 1 && (!to.starts_with(&self.info.name) || to == self.info.name) {
+            info!("Can't move {:?} out of the torrent's folder", to);
+            return;
+        }
+        let from = self.info.files[file].path.clone();
+        self.rename_path(from, to);
+    }
+
+    /// Renames a file or folder of the torrent. Jobs from now on use the new
+    /// paths, the disk moves the data over once the ones queued before it
+    /// are done.
+    fn rename_path(&mut self, from: PathBuf, to: PathBuf) {
+        if !self.info.complete() || from == to {
+            return;
+        }
+        if self.relocation.is_some() {
+            info!("Can't rename {:?} while the files are being moved", from);
+            return;
+        }
+        let clash = self.info.files.iter().any(|f| {
+            !f.path.starts_with(&from) && (f.path.starts_with(&to) || to.starts_with(&f.path))
+        });
+        if clash {
+            info!("Can't rename {:?} to {:?}, which is taken", from, to);
+            return;
+        }
+        if self.orig.is_none() {
+            self.orig = Some(self.info.clone());
+        }
+        let mut updates = Vec::new();
+        let info = Arc::make_mut(&mut self.info);
+        for f in info.files.iter_mut().filter(|f| f.path.starts_with(&from)) {
+            let rest = f.path.strip_prefix(&from).unwrap().to_owned();
+            f.path = if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            };
+        }
+        if from == Path::new(&info.name) {
+            info.name = to.to_string_lossy().into_owned();
+            updates.push(resource::SResourceUpdate::TorrentName {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                name: self.info.name.clone(),
+            });
+        }
+        for (i, f) in self.info.files.iter().enumerate() {
+            if f.path.starts_with(&to) {
+                updates.push(resource::SResourceUpdate::FilePath {
+                    id: self.file_id(i),
+                    kind: resource::ResourceKind::File,
+                    path: f.path.to_string_lossy().into_owned(),
+                });
+            }
+        }
+        self.cio
+            .msg_disk(disk::Request::rename(self.id, from, to, self.path.clone()));
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+        self.dirty = true;
+    }
+
+    /// RPC id of a file, derived from its path in the published info so
+    /// that it's kept when the file is renamed.
+    fn file_id(&self, file: usize) -> String {
+        let info = self.orig.as_ref().unwrap_or(&self.info);
+        util::file_rpc_id(
+            &self.info.hash,
+            info.files[file].path.to_string_lossy().as_ref(),
+        )
+    }
+
+    /// The published paths of the files which were renamed.
+    fn renamed(&self) -> Vec<(usize, PathBuf)> {
+        let orig = match self.orig {
+            Some(ref orig) => orig,
+            None => return vec![],
+        };
+        orig.files
+            .iter()
+            .zip(&self.info.files)
+            .enumerate()
+            .filter(|(_, (o, f))| o.path != f.path)
+            .map(|(i, (o, _))| (i, o.path.clone()))
+            .collect()
+    }
+
+    /// Wanted files which are fully downloaded, as published along with
+    /// where they are on disk, for new torrents to reuse.
+    pub fn complete_files(&self) -> impl Iterator<Item = (&info::File, PathBuf)> {
+        let orig = self.orig.as_ref().unwrap_or(&self.info);
+        let dir = Path::new(self.path.as_ref().unwrap_or(&CONFIG.disk.directory));
+        self.info
+            .files
+            .iter()
+            .enumerate()
+            .filter(move |&(i, f)| {
+                !self.memory
+                    && !f.pad
+                    && f.length != 0
+                    && self.priorities[i] != 0
+                    && self.files.done[i] == f.length
+            })
+            .map(move |(i, f)| (&orig.files[i], dir.join(&f.path)))
+    }
+
+    /// Renames a file with a .part suffix into place once all of it has
+    /// been downloaded.
+    fn finish_file(&mut self, file: usize) {
+        if self.files.done[file] != self.info.files[file].length || self.info.files[file].pad {
+            return;
+        }
+        self.cio.msg_disk(disk::Request::finish_file(
+            self.id,
+            self.info.files[file].path.clone(),
+            self.path.clone(),
+        ));
+    }
+
+    /// Sets the directory the torrent is moved to once complete, or None
+    /// to use the global one. A complete torrent is moved right away.
+    fn set_completed_path(&mut self, path: Option<String>) {
+        let path = path.map(|p| shellexpand::tilde(&p).into_owned());
+        self.completed_path = path.clone();
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentCompletedPath {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                completed_path: path,
+            },
+        ]));
+        if self.status.completed() {
+            self.move_completed();
+        }
+    }
+
+    /// Moves the torrent's files to the completed directory, unless it
+    /// has none or they're there already. Disk jobs issued afterwards run
+    /// after the move, so the new path is used right away.
+    fn move_completed(&mut self) {
+        if self.relocation.is_some() {
+            return;
+        }
+        let to = match self
+            .completed_path
+            .clone()
+            .or_else(|| CONFIG.disk.completed.clone())
+        {
+            Some(to) => to,
+            None => return,
+        };
+        let from = self
+            .path
+            .clone()
+            .unwrap_or_else(|| CONFIG.disk.directory.clone());
+        if from == to {
+            return;
+        }
+        info!("Moving {} to {}", self.rpc_id(), to);
         self.cio.msg_disk(disk::Request::Move {
             tid: self.id,
             from,
-            to: path,
+            to: to.clone(),
             target: self.info.name.clone(),
         });
+        self.path = Some(to);
+        self.dirty = true;
     }
 
     fn set_priority(&mut self, priority: u8) {
@@ -1509,13 +2915,13 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn rpc_info(&self) -> resource::Resource {
-        let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
+        let (name, size, pieces, piece_size, files) = if self.metadata.is_none() {
             (
                 Some(self.info.name.clone()),
                 Some(self.info.total_len),
                 Some(u64::from(self.info.pieces())),
                 Some(self.info.piece_len),
-                Some(self.info.files.len() as u32),
+                Some(self.info.files.iter().filter(|f| !f.pad).count() as u32),
             )
         } else {
             let name = if self.info.name == "" {
@@ -1531,10 +2937,12 @@ impl<T: cio::CIO> Torrent<T> {
             size,
             // TODO: Properly add this
             path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+            completed_path: self.completed_path.clone(),
             created: self.created,
             modified: Utc::now(),
             status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
             error: self.error(),
+            error_code: self.status.error_code,
             priority: self.priority,
             progress: self.progress(),
             availability: self.availability(),
@@ -1543,6 +2951,10 @@ impl<T: cio::CIO> Torrent<T> {
             } else {
                 resource::Strategy::Rarest
             },
+            super_seeding: self.super_seed.is_some(),
+            upload_slots: self.tuner.pinned_slots().map(|s| s as u16),
+            first_last: self.first_last,
+            memory: self.memory,
             rate_up: 0,
             rate_down: 0,
             throttle_up: self.throttle.ul_rate(),
@@ -1554,10 +2966,13 @@ impl<T: cio::CIO> Torrent<T> {
             pieces,
             piece_size,
             piece_field: self.pieces.b64(),
+            piece_availability: self.availability_map(),
             private: self.info.private,
             creator: self.info.creator.clone(),
             comment: self.info.comment.clone(),
             files,
+            dht_seeders: self.dht_swarm.0,
+            dht_leechers: self.dht_swarm.1,
             ..Default::default()
         })
     }
@@ -1575,11 +2990,12 @@ impl<T: cio::CIO> Torrent<T> {
             }
         }
 
+        // Padding files are never written, so they're left out of listings
         for (i, (done, total)) in files.into_iter().enumerate() {
-            let id = util::file_rpc_id(
-                &self.info.hash,
-                self.info.files[i].path.to_string_lossy().as_ref(),
-            );
+            if self.info.files[i].pad {
+                continue;
+            }
+            let id = self.file_id(i);
             let progress = if self.priorities[i] != 0 {
                 done as f32 / total as f32
             } else {
@@ -1609,12 +3025,16 @@ impl<T: cio::CIO> Torrent<T> {
                     return None;
                 }
                 seen_urls.insert(trk.url.as_str());
+                let (seeders, leechers, downloaded) = trk.swarm();
                 Some(resource::Resource::Tracker(resource::Tracker {
                     id: util::trk_rpc_id(&self.info.hash, trk.url.as_str()),
                     torrent_id: self.rpc_id(),
                     url: trk.url.as_ref().clone(),
                     last_report: trk.last_announce,
                     error: None,
+                    seeders,
+                    leechers,
+                    downloaded,
                     ..Default::default()
                 }))
             })
@@ -1624,10 +3044,10 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn send_rpc_removal(&mut self) {
         let mut r = Vec::new();
         r.push(self.rpc_id());
-        for f in &self.info.files {
-            let id =
-                util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
-            r.push(id)
+        for f in 0..self.info.files.len() {
+            if !self.info.files[f].pad {
+                r.push(self.file_id(f));
+            }
         }
         let mut seen_urls = FHashSet::default();
         for (_, tracker) in self.trackers.iter().enumerate() {
@@ -1652,13 +3072,53 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.magnet() {
             return 0.0;
         }
-        if let Some(amnt) = self.status.validating {
+        if let Some(amnt) = self
+            .status
+            .validating
+            .or(self.status.allocating)
+            .or(self.status.moving)
+        {
             amnt
+        } else if CONFIG.wanted_progress {
+            let (done, total) = self.wanted_bytes();
+            if total == 0 {
+                1.0
+            } else {
+                done as f32 / total as f32
+            }
+        } else {
+            self.verified as f32 / self.info.total_len as f32
+        }
+    }
+
+    /// Bytes left to download, as reported to trackers.
+    pub fn left(&self) -> u64 {
+        if CONFIG.wanted_progress {
+            let (done, total) = self.wanted_bytes();
+            total - done
         } else {
-            self.pieces.iter().count() as f32 / self.info.pieces() as f32
+            self.info.total_len - self.verified
         }
     }
 
+    /// Bytes downloaded and in total of the files selected for download.
+    /// Pad files are never wanted, since they're never written.
+    fn wanted_bytes(&self) -> (u64, u64) {
+        let wanted = |file: usize| self.priorities[file] != 0 && !self.info.files[file].pad;
+        let total = (0..self.info.files.len())
+            .filter(|f| wanted(*f))
+            .map(|f| self.info.files[f].length)
+            .sum();
+        let done = self
+            .pieces
+            .iter()
+            .flat_map(|p| Info::piece_disk_locs(&self.info, p as u32))
+            .filter(|loc| wanted(loc.file))
+            .map(|loc| (loc.end - loc.start) as u64)
+            .sum();
+        (done, total)
+    }
+
     fn availability(&self) -> f32 {
         if self.leechers.len() != self.peers.len() {
             return 1.0;
@@ -1684,9 +3144,37 @@ impl<T: cio::CIO> Torrent<T> {
         self.stat.tick();
         let mut active = self.stat.active();
         self.picker.tick();
-
-        for (_, peer) in self.peers.iter_mut() {
-            active |= peer.tick();
+        self.bans.tick();
+        self.check_deadlines();
+
+        let mut snubbed = Vec::new();
+        for (&pid, peer) in self.peers.iter_mut() {
+            active |= peer.tick(&self.tuner);
+            if peer.check_snubbed() {
+                snubbed.push(pid);
+            }
+        }
+        // Blocks held up by snubbing peers are requested elsewhere
+        if !snubbed.is_empty() {
+            debug!("{:?}: Peers {:?} are snubbing us", self.rpc_id(), snubbed);
+            for pid in snubbed {
+                self.picker.release_peer(pid);
+            }
+            if self.status.should_dl() {
+                for peer in self
+                    .peers
+                    .values_mut()
+                    .filter(|p| !p.snubbed() && !p.choked())
+                {
+                    Torrent::make_requests(peer, &mut self.picker, &self.info);
+                }
+            }
+        }
+        // Pieces whose requests timed out go to other peers
+        if let Some(ref mut m) = self.metadata {
+            for peer in self.peers.values_mut() {
+                Torrent::request_metadata(m, peer);
+            }
         }
         active
     }
@@ -1699,14 +3187,104 @@ impl<T: cio::CIO> Torrent<T> {
     /// piece offset begin, piece length of len, and data bytes.
     /// The disk send handle is also provided.
     fn write_piece(&mut self, index: u32, begin: u32, data: Buffer) {
+        if let Some((_, written)) = self.relocation.as_mut() {
+            written.insert(index);
+        }
         let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
         self.cio
             .msg_disk(disk::Request::write(self.id, data, locs, self.path.clone()));
     }
 
+    /// Cancels outstanding requests for pieces which are no longer wanted.
+    fn cancel_unwanted(&mut self) {
+        let peers = &mut self.peers;
+        let info = &self.info;
+        let cancelled = &mut self.cancelled;
+        self.picker.cancel_unwanted(|b, pid| {
+            if let Some(p) = peers.get_mut(&pid) {
+                let length = info.block_len(b.index, b.offset);
+                p.cancel_request(b.index, b.offset, length);
+                *cancelled += u64::from(length);
+            }
+        });
+    }
+
+    /// Hashes a piece which was imported without being verified before
+    /// a block of it is uploaded, so that seeding imported data doesn't
+    /// need a full recheck up front.
+    fn check_lazily(&mut self, pid: usize, index: u32, begin: u32) {
+        if !self.lazy_checks.contains_key(&index) {
+            debug!("{:?}: Checking unverified piece {}", self.rpc_id(), index);
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.priorities.clone(),
+                self.path.clone(),
+                index,
+            ));
+        }
+        self.lazy_checks
+            .entry(index)
+            .or_default()
+            .push((pid, begin));
+    }
+
+    /// Hashes the pieces we have of files which were changed underneath
+    /// us again, holding back their upload until then.
+    fn recheck_files(&mut self, files: &[usize]) {
+        for piece in 0..self.info.pieces() {
+            let changed =
+                Info::piece_disk_locs(&self.info, piece).any(|loc| files.contains(&loc.file));
+            if !changed
+                || !self.pieces.has_bit(u64::from(piece))
+                || self.lazy_checks.contains_key(&piece)
+            {
+                continue;
+            }
+            self.integrity.unverify(piece);
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.priorities.clone(),
+                self.path.clone(),
+                piece,
+            ));
+            self.lazy_checks.insert(piece, Vec::new());
+        }
+        self.dirty = true;
+    }
+
+    /// Answers the requests which were waiting on a piece to be hashed.
+    /// A corrupt piece is rejected and downloaded again.
+    fn lazily_checked(&mut self, piece: u32, valid: bool, waiting: Vec<(usize, u32)>) {
+        let serve = valid && !self.status.stopped();
+        for (pid, begin) in waiting {
+            let length = self.info.block_len(piece, begin);
+            let buf = if serve { Buffer::get() } else { None };
+            match (self.peers.get_mut(&pid), buf) {
+                (Some(_), Some(buf)) => self.request_read(pid, piece, begin, buf),
+                (Some(peer), None) => {
+                    peer.read_done(piece, begin);
+                    peer.reject_request(piece, begin, length);
+                }
+                (None, _) => {}
+            }
+        }
+        if !valid {
+            info!("{:?}: Unverified piece {} is corrupt", self.rpc_id(), piece);
+            self.pieces.unset_bit(u64::from(piece));
+            self.picker.invalidate_piece(piece);
+            self.send_dont_have(piece);
+            self.files.rebuild(&self.info, &self.pieces);
+            self.count_verified();
+            self.check_complete();
+            self.update_rpc_transfer();
+        }
+    }
+
     /// Issues a read request of the given torrent
     fn request_read(&mut self, id: usize, index: u32, begin: u32, data: Buffer) {
-        let locs = Info::block_disk_locs(&self.info, index, begin);
+        let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
         let len = self.info.block_len(index, begin);
         let ctx = disk::Ctx::new(id, self.id, index, begin, len);
         self.cio
@@ -1726,7 +3304,12 @@ impl<T: cio::CIO> Torrent<T> {
     fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info) {
         if let Some(m) = peer.queue_reqs() {
             for _ in 0..(m) {
-                if let Some(block) = picker.pick(peer) {
+                let block = if peer.choked() {
+                    picker.pick_allowed(peer)
+                } else {
+                    picker.pick(peer)
+                };
+                if let Some(block) = block {
                     peer.request_piece(
                         block.index,
                         block.offset,
@@ -1742,15 +3325,33 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_peer(&mut self, conn: PeerConn) -> Option<usize> {
-        if self.peers.len() >= MAX_PEERS {
+        let addr = conn.sock().addr();
+        if self
+            .peers
+            .values()
+            .any(|p| p.addr() == addr || p.alt_addr() == Some(addr))
+        {
+            return None;
+        }
+        self.add_racing_peer(conn)
+    }
+
+    /// Adds a connection to a peer which is already being connected to
+    /// over the other transport. Control drops whichever of the two
+    /// connections loses the race.
+    pub fn add_racing_peer(&mut self, conn: PeerConn) -> Option<usize> {
+        if self.peers.len() >= CONFIG.net.max_torrent_peers {
             return None;
         }
-        if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
+        if self.bans.banned(conn.sock().addr().ip()) {
             return None;
         }
         if let Ok(pid) = self.cio.add_peer(conn) {
             if let Ok(p) = Peer::new(pid, self, None, None) {
-                if self.info_idx.is_none() {
+                if self.super_seeding() {
+                    self.super_seed.as_mut().unwrap().add_peer(pid);
+                }
+                if self.metadata.is_none() {
                     self.picker.add_peer(&p);
                 }
                 self.peers.insert(pid, p);
@@ -1762,13 +3363,27 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn add_inc_peer(&mut self, pid: usize, id: [u8; 20], rsv: [u8; 8]) -> Option<usize> {
         if let Some(addr) = self.cio.get_peer(pid, |pconn| pconn.sock().addr()) {
+            if self.bans.banned(addr.ip()) {
+                debug!("{:?}: Rejecting banned peer {:?}", self.rpc_id(), addr);
+                return None;
+            }
             if self.peers.values().any(|p| p.addr() == addr) {
                 return None;
             }
         }
-        if let Ok(p) = Peer::new(pid, self, Some(id), Some(rsv)) {
+        if self.peers.len() >= CONFIG.net.max_torrent_peers && !self.evict_peer() {
+            debug!("{:?}: Too many peers, rejecting {:?}", self.rpc_id(), pid);
+            return None;
+        }
+        if let Ok(mut p) = Peer::new(pid, self, Some(id), Some(rsv)) {
             debug!("{:?}: Adding peer {:?}!", self.rpc_id(), pid);
-            if self.info_idx.is_none() {
+            self.send_ext_handshake(&mut p);
+            // Peer::new already hid our pieces from the peer
+            if self.super_seeding() {
+                self.super_seed.as_mut().unwrap().add_peer(pid);
+                self.super_seed_have(&mut p, None);
+            }
+            if self.metadata.is_none() {
                 self.picker.add_peer(&p);
             }
             self.peers.insert(pid, p);
@@ -1777,6 +3392,42 @@ impl<T: cio::CIO> Torrent<T> {
         None
     }
 
+    /// Disconnects the least useful peer to make room for another. Only
+    /// idle peers are evicted, since a working connection is worth more
+    /// than an unknown one, with ties going against the lowest canonical
+    /// priority.
+    fn evict_peer(&mut self) -> bool {
+        let victim = self
+            .peers
+            .values()
+            .filter(|p| p.idle())
+            .min_by_key(|p| {
+                let (ul, dl) = p.get_tx_rates();
+                let addr = p.listen_addr().unwrap_or_else(|| p.addr());
+                (ul + dl, self.peer_priority(addr), p.id())
+            })
+            .map(|p| p.id());
+        match victim {
+            Some(pid) => {
+                debug!("{:?}: Evicting idle peer {:?}", self.rpc_id(), pid);
+                self.cio.remove_peer(pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Our address as reported by peers, if any have.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip.get()
+    }
+
+    /// Canonical priority of a connection to a peer, see BEP 40.
+    fn peer_priority(&self, addr: SocketAddr) -> Option<u32> {
+        let ip = self.external_ip()?;
+        priority::canonical(SocketAddr::new(ip, CONFIG.port), addr)
+    }
+
     pub fn announce_status(&mut self) {
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -1784,21 +3435,52 @@ impl<T: cio::CIO> Torrent<T> {
                 id,
                 kind: resource::ResourceKind::Torrent,
                 error: self.status.error.clone(),
+                error_code: self.status.error_code,
                 status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
             },
         ]));
     }
 
+    pub fn set_dht_swarm(&mut self, seeders: u32, leechers: u32) {
+        if self.dht_swarm == (seeders, leechers) {
+            return;
+        }
+        self.dht_swarm = (seeders, leechers);
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSwarm {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                dht_seeders: seeders,
+                dht_leechers: leechers,
+            },
+        ]));
+    }
+
     pub fn update_rpc_peers(&mut self) {
         let availability = self.availability();
         let id = self.rpc_id();
+        let mut sources = [0u16; 6];
+        for peer in self.peers.values() {
+            sources[peer.source() as usize] += 1;
+        }
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             SResourceUpdate::TorrentPeers {
-                id,
+                id: id.clone(),
                 kind: resource::ResourceKind::Torrent,
                 peers: self.peers.len() as u16,
                 availability,
             },
+            SResourceUpdate::TorrentPeerSources {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                peers_tracker: sources[0],
+                peers_dht: sources[1],
+                peers_pex: sources[2],
+                peers_holepunch: sources[3],
+                peers_incoming: sources[4],
+                peers_manual: sources[5],
+            },
         ]));
     }
 
@@ -1808,15 +3490,20 @@ impl<T: cio::CIO> Torrent<T> {
             .iter()
             .map(|tracker| {
                 let id = util::trk_rpc_id(&self.info.hash, tracker.url.as_str());
-                let error = match tracker.status {
-                    TrackerStatus::Failure(ref r) => Some(r.clone()),
-                    _ => None,
+                let (error_code, error) = match tracker.status {
+                    TrackerStatus::Failure(code, ref r) => (Some(code), Some(r.clone())),
+                    _ => (None, None),
                 };
+                let (seeders, leechers, downloaded) = tracker.swarm();
                 SResourceUpdate::TrackerStatus {
                     id,
                     kind: resource::ResourceKind::Tracker,
                     last_report: tracker.last_announce,
                     error,
+                    error_code,
+                    seeders,
+                    leechers,
+                    downloaded,
                 }
             })
             .collect();
@@ -1852,20 +3539,42 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for (idx, done) in self.files.flush() {
-            let id = util::file_rpc_id(
-                &self.info.hash,
-                self.info.files[idx].path.to_string_lossy().as_ref(),
-            );
+            if self.info.files[idx].pad {
+                continue;
+            }
+            let id = self.file_id(idx);
+            // Pieces shared with wanted files don't complete skipped ones
+            let progress = if self.priorities[idx] != 0 {
+                done as f32 / self.info.files[idx].length as f32
+            } else {
+                0.
+            };
             updates.push(SResourceUpdate::FileProgress {
                 id,
                 kind: resource::ResourceKind::File,
-                progress: (done as f32 / self.info.files[idx].length as f32),
+                progress,
             });
         }
         self.announce_status();
         self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
     }
 
+    /// Takes what was learned about peer addresses since the last call.
+    pub fn take_score_reports(&mut self) -> Vec<(IpAddr, score::Report)> {
+        mem::replace(&mut self.score_reports, Vec::new())
+    }
+
+    /// Reports on connected peers as if they'd disconnected, so that their
+    /// rates are remembered across a shutdown.
+    pub fn report_connected(&mut self) {
+        for peer in self.peers.values().filter(|p| p.ready()) {
+            let report = score::Report::Disconnected {
+                rate: peer.best_dl(),
+            };
+            self.score_reports.push((peer.addr().ip(), report));
+        }
+    }
+
     fn cleanup_peer(&mut self, peer: &mut Peer<T>) {
         trace!("Removing {:?}!", peer);
         self.choker.remove_peer(peer, &mut self.peers);
@@ -1873,6 +3582,20 @@ impl<T: cio::CIO> Torrent<T> {
         if self.info.complete() {
             self.picker.remove_peer(peer);
         }
+        // Failed connection attempts are reported by control
+        if peer.ready() {
+            let report = score::Report::Disconnected {
+                rate: peer.best_dl(),
+            };
+            self.score_reports.push((peer.addr().ip(), report));
+        }
+        if let Some(ref mut m) = self.metadata {
+            m.remove_peer(peer.id());
+        }
+        if let Some(ref mut s) = self.super_seed {
+            s.remove_peer(peer.id());
+        }
+        self.pex.remove_peer(peer.id());
     }
 
     pub fn pause(&mut self) {
@@ -1892,6 +3615,7 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.error.is_some() || self.status.paused {
             if self.status.error.is_some() {
                 self.status.error = None;
+                self.status.error_code = None;
             }
             if self.status.paused {
                 debug!("Sending started request to trk");
@@ -1900,69 +3624,179 @@ impl<T: cio::CIO> Torrent<T> {
                 }
                 self.status.paused = false;
             }
+            self.check_space();
             self.request_all();
             self.announce_status();
             self.dht_announce();
         }
     }
 
+    /// Checks there's room left for the wanted files of a torrent which is
+    /// still downloading, then allocates them.
+    fn check_space(&mut self) {
+        if self.memory {
+            return;
+        }
+        if !CONFIG.disk.check_space {
+            self.allocate();
+            return;
+        }
+        if !self.info.complete() || self.status.state != StatusState::Incomplete {
+            return;
+        }
+        self.cio.msg_disk(disk::Request::check_space(
+            self.id,
+            self.info.clone(),
+            self.priorities.clone(),
+            self.path.clone(),
+        ));
+    }
+
+    /// Allocates every wanted file up front when configured to, before
+    /// anything is downloaded.
+    fn allocate(&mut self) {
+        if CONFIG.disk.allocation != Allocation::Full
+            || !self.info.complete()
+            || self.status.state != StatusState::Incomplete
+        {
+            return;
+        }
+        self.cio.msg_disk(disk::Request::allocate(
+            self.id,
+            self.info.clone(),
+            self.priorities.clone(),
+            self.path.clone(),
+        ));
+        self.status.allocating = Some(0.0);
+        self.announce_status();
+    }
+
+    /// Saves the fast resume data which lets the next startup skip
+    /// rehashing unchanged files.
+    pub fn save_resume(&mut self) {
+        if !self.info.complete() || self.memory {
+            return;
+        }
+        self.cio.msg_disk(disk::Request::save_resume(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+        ));
+    }
+
+    /// Asks the disk thread which files of a seeding torrent another
+    /// program changed since they were last checked.
+    pub fn check_modified(&mut self) {
+        if self.memory
+            || self.status.state != StatusState::Complete
+            || self.status.validating.is_some()
+        {
+            return;
+        }
+        self.cio.msg_disk(disk::Request::check_modified(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+        ));
+    }
+
+    /// Hashes every piece again. Until the check reaches a piece we have,
+    /// it's only uploaded after being hashed by itself.
     pub fn validate(&mut self) {
+        for piece in self.pieces.iter() {
+            self.integrity.unverify(piece as u32);
+        }
         self.cio.msg_disk(disk::Request::validate(
             self.id,
             self.info.clone(),
+            self.priorities.clone(),
             self.path.clone(),
         ));
         self.status.validating = Some(0.0);
         self.announce_status();
     }
 
-    pub fn num_peers(&self) -> usize {
-        self.peers.len()
+    /// Checks for files which were deleted or damaged externally and
+    /// redownloads the affected pieces. Pieces of the given files are
+    /// hashed, others are only checked for missing data.
+    pub fn repair(&mut self, files: &[String]) {
+        if !self.info.complete() {
+            return;
+        }
+        let check = (0..self.info.files.len())
+            .map(|f| files.contains(&self.file_id(f)))
+            .collect();
+        self.cio.msg_disk(disk::Request::repair(
+            self.id,
+            self.info.clone(),
+            self.priorities.clone(),
+            self.path.clone(),
+            check,
+        ));
+        self.status.validating = Some(0.0);
+        self.announce_status();
     }
 
-    pub fn peers(&self) -> &UHashMap<Peer<T>> {
-        &self.peers
+    /// Reports on the state of the torrent's data to an RPC client, once
+    /// the disk thread has checked the size of each file.
+    pub fn integrity_report(&mut self, client: usize, serial: u64) {
+        if !self.info.complete() {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                code: ErrorCode::MetadataMissing,
+                reason: "Torrent metadata has not been fetched yet".to_owned(),
+                client,
+                serial,
+            });
+            return;
+        }
+        self.cio.msg_disk(disk::Request::check_sizes(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            client,
+            serial,
+        ));
     }
 
-    pub fn update_pex(&mut self, added: &[SocketAddr], removed: &[SocketAddr]) {
-        let mut a = vec![];
-        let mut a6 = vec![];
-        let mut r = vec![];
-        let mut r6 = vec![];
-        for addr in added {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    a.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    a6.extend(&addr.ip().octets());
-                }
-            }
+    pub fn num_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Sends peers the changes to our peer list since the last update.
+    pub fn update_pex(&mut self) {
+        if !Ext::Pex.enabled(self.info.private) {
+            return;
         }
-        for addr in removed {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    r.extend(&addr.ip().octets());
+        let current = self
+            .peers
+            .values()
+            .filter_map(|p| {
+                let mut flags = 0;
+                if !p.incoming() {
+                    flags |= pex::FLAG_REACHABLE;
                 }
-                SocketAddr::V6(addr) => {
-                    r6.extend(&addr.ip().octets());
+                if p.pieces().complete() {
+                    flags |= pex::FLAG_SEED;
                 }
-            }
-        }
-        let mut dict = BTreeMap::new();
-        dict.insert("added".to_string(), BEncode::String(a));
-        dict.insert("added6".to_string(), BEncode::String(a6));
-        dict.insert("removed".to_string(), BEncode::String(r));
-        dict.insert("removed6".to_string(), BEncode::String(r6));
-        let payload = BEncode::Dict(dict).encode_to_buf();
-
+                if p.utp() {
+                    flags |= pex::FLAG_UTP;
+                }
+                Some((p.listen_addr()?, flags))
+            })
+            .collect();
+        let delta = self.pex.update(&current);
+        let payload = delta.encode();
         for peer in self.peers.values_mut() {
-            if let Some(id) = peer.exts().ut_pex {
-                peer.send_message(Message::Extension {
-                    id,
-                    payload: payload.clone(),
-                });
-            }
+            let id = match peer.exts().get(Ext::Pex) {
+                Some(id) => id,
+                None => continue,
+            };
+            let payload = match self.pex.sync(peer.id()) {
+                Some(msg) => msg.encode(),
+                None if !delta.is_empty() => payload.clone(),
+                None => continue,
+            };
+            peer.send_message(Message::Extension { id, payload });
         }
     }
 
@@ -1982,7 +3816,7 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn request_all(&mut self) {
-        if self.status.stopped() || self.info_idx.is_some() {
+        if self.status.stopped() || self.metadata.is_some() {
             return;
         }
         for pid in self.pids() {
@@ -2045,6 +3879,10 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             trace!("Removing peer {:?}", peer);
             self.leechers.remove(&id);
         }
+        debug!(
+            "Uploads cancelled by peers: {} MiB",
+            self.ul_cancelled / (1024 * 1024)
+        );
         if !self.status.paused {
             if let Some(msg) = tracker::Request::stopped(self) {
                 self.cio.msg_trk(msg);
@@ -0,0 +1,135 @@
+//! Capabilities negotiated with a peer.
+//!
+//! Support for an extension is signalled either by a reserved bit of the
+//! handshake, or for extensions built on the extension protocol, by the
+//! extended handshake. Both are tracked here so the rest of the peer code
+//! only ever asks whether a capability is shared with a peer.
+
+use super::ext::{Ext, ExtIDs, EXTENSIONS};
+use crate::{CONFIG, DHT_EXT, EXT_PROTO, FAST_EXT};
+
+/// Capabilities signalled through reserved handshake bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bit {
+    /// DHT port messages (BEP 5)
+    Dht,
+    /// Extension protocol (BEP 10)
+    Extensions,
+    /// Fast extension (BEP 6)
+    Fast,
+}
+
+pub const BITS: &[Bit] = &[Bit::Dht, Bit::Extensions, Bit::Fast];
+
+#[derive(Debug, Default)]
+pub struct Caps {
+    /// Reserved bits set by both us and the peer
+    rsv: [u8; 8],
+    pub exts: ExtIDs,
+}
+
+impl Bit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bit::Dht => "dht",
+            Bit::Extensions => "extension_protocol",
+            Bit::Fast => "fast",
+        }
+    }
+
+    /// Byte and mask of the bit in the reserved bytes
+    fn pos(self) -> (usize, u8) {
+        match self {
+            Bit::Dht => DHT_EXT,
+            Bit::Extensions => EXT_PROTO,
+            Bit::Fast => FAST_EXT,
+        }
+    }
+
+    fn enabled(self) -> bool {
+        let exts = &CONFIG.peer.extensions;
+        match self {
+            Bit::Dht => exts.dht,
+            Bit::Extensions => exts.extension_protocol,
+            Bit::Fast => exts.fast,
+        }
+    }
+}
+
+/// Reserved handshake bits for the capabilities enabled in the config.
+pub fn local_rsv() -> [u8; 8] {
+    let mut rsv = [0u8; 8];
+    for bit in BITS.iter().filter(|b| b.enabled()) {
+        let (byte, mask) = bit.pos();
+        rsv[byte] |= mask;
+    }
+    rsv
+}
+
+impl Caps {
+    pub fn new() -> Caps {
+        Default::default()
+    }
+
+    /// Records the reserved bits of the peer's handshake, keeping only
+    /// the capabilities we've enabled as well.
+    pub fn set_rsv(&mut self, rsv: [u8; 8]) {
+        let local = local_rsv();
+        for (i, b) in rsv.iter().enumerate() {
+            self.rsv[i] = b & local[i];
+        }
+    }
+
+    pub fn has(&self, bit: Bit) -> bool {
+        let (byte, mask) = bit.pos();
+        self.rsv[byte] & mask != 0
+    }
+
+    pub fn has_ext(&self, ext: Ext) -> bool {
+        self.exts.get(ext).is_some()
+    }
+
+    /// Names of the shared capabilities, for display over RPC.
+    pub fn names(&self) -> Vec<String> {
+        let bits = BITS.iter().filter(|b| self.has(**b)).map(|b| b.name());
+        let exts = EXTENSIONS
+            .iter()
+            .filter(|e| self.has_ext(**e))
+            .map(|e| e.name());
+        bits.chain(exts).map(str::to_owned).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::peer::ext;
+
+    #[test]
+    fn test_negotiate() {
+        let mut caps = Caps::new();
+        assert!(caps.names().is_empty());
+
+        // Unknown bits are ignored
+        let mut rsv = [0xff; 8];
+        rsv[DHT_EXT.0] &= !DHT_EXT.1;
+        caps.set_rsv(rsv);
+        assert!(!caps.has(Bit::Dht));
+        assert!(caps.has(Bit::Extensions));
+        assert!(caps.has(Bit::Fast));
+        assert_eq!(caps.rsv, {
+            let mut r = [0; 8];
+            r[EXT_PROTO.0] = EXT_PROTO.1;
+            r[FAST_EXT.0] |= FAST_EXT.1;
+            r
+        });
+
+        caps.exts
+            .update(&ext::handshake(true, 0, false), true)
+            .unwrap();
+        assert_eq!(
+            caps.names(),
+            vec!["extension_protocol", "fast", "ut_metadata", "lt_donthave"]
+        );
+    }
+}
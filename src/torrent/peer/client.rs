@@ -0,0 +1,155 @@
+//! Client identification from peer IDs.
+//!
+//! Most clients encode their name and version in the peer ID they send in
+//! the handshake. Azureus-style IDs look like `-qB4250-...`, a two letter
+//! client code and four version characters between dashes. Shadow-style
+//! IDs start with a single letter followed by up to five version
+//! characters and padding dashes, e.g. `S58B-----...`, and Mainline uses
+//! `M7-4-3--...`.
+
+/// Clients using Azureus-style peer IDs
+const AZUREUS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("SY", "Synapse"),
+    ("SZ", "Shareaza"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "uTorrent Mac"),
+    ("UT", "uTorrent"),
+    ("UW", "uTorrent Web"),
+    ("WD", "WebTorrent Desktop"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Clients using Shadow-style peer IDs
+const SHADOW: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Decodes the client name and version from a peer ID, if it follows one
+/// of the common conventions.
+pub fn name(id: &[u8; 20]) -> Option<String> {
+    azureus(id).or_else(|| mainline(id)).or_else(|| shadow(id))
+}
+
+fn azureus(id: &[u8; 20]) -> Option<String> {
+    if id[0] != b'-' || id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&id[1..3]).ok()?;
+    let version = id[3..7]
+        .iter()
+        .map(|&c| shadow_digit(c).filter(|&d| d < 36).map(|d| d.to_string()))
+        .collect::<Option<Vec<_>>>()?;
+    let client = AZUREUS
+        .iter()
+        .find(|&&(c, _)| c == code)
+        .map(|&(_, name)| name)?;
+    Some(format!("{} {}", client, version.join(".")))
+}
+
+fn mainline(id: &[u8; 20]) -> Option<String> {
+    if id[0] != b'M' {
+        return None;
+    }
+    // M<major>-<minor>-<patch>--, where each number may have two digits
+    let end = id.windows(2).position(|w| w == b"--")?;
+    let version = std::str::from_utf8(&id[1..end]).ok()?;
+    let parts: Vec<_> = version.split('-').collect();
+    if parts.len() != 3
+        || parts
+            .iter()
+            .any(|p| p.is_empty() || p.parse::<u8>().is_err())
+    {
+        return None;
+    }
+    Some(format!("Mainline {}", parts.join(".")))
+}
+
+fn shadow(id: &[u8; 20]) -> Option<String> {
+    let client = SHADOW
+        .iter()
+        .find(|&&(c, _)| c == id[0])
+        .map(|&(_, name)| name)?;
+    let end = id[1..6]
+        .iter()
+        .position(|&c| c == b'-')
+        .map(|p| p + 1)
+        .unwrap_or(6);
+    // The version is followed by padding dashes
+    if end < 2 || id[end..9].iter().any(|&c| c != b'-') {
+        return None;
+    }
+    let version = id[1..end]
+        .iter()
+        .map(|&c| shadow_digit(c).map(|d| d.to_string()))
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("{} {}", client, version.join(".")))
+}
+
+/// Decodes a version character: 0-9, then A-Z, a-z and '.'.
+fn shadow_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(s: &[u8]) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..s.len()].copy_from_slice(s);
+        id
+    }
+
+    #[test]
+    fn test_name() {
+        assert_eq!(
+            name(&id(b"-qB4250-")),
+            Some("qBittorrent 4.2.5.0".to_owned())
+        );
+        assert_eq!(
+            name(&id(b"-TR294Z-")),
+            Some("Transmission 2.9.4.35".to_owned())
+        );
+        assert_eq!(name(&id(b"-ZZ1000-")), None);
+        assert_eq!(name(&id(b"M7-10-3--")), Some("Mainline 7.10.3".to_owned()));
+        assert_eq!(name(&id(b"M7-10--")), None);
+        assert_eq!(name(&id(b"S58B-----")), Some("Shadow 5.8.11".to_owned()));
+        assert_eq!(
+            name(&id(b"T03I-----")),
+            Some("BitTornado 0.3.18".to_owned())
+        );
+        assert_eq!(name(&id(b"S58B-x---")), None);
+        assert_eq!(name(&id(b"")), None);
+    }
+}
@@ -0,0 +1,250 @@
+//! Extension protocol (BEP 10).
+//!
+//! Every extension we support is registered in `EXTENSIONS` along with the
+//! message ID peers should use when sending it to us. Peers announce the IDs
+//! they want in their own extended handshake, which are negotiated per peer
+//! in `ExtIDs`.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::bencode::{self, BEncode};
+use crate::{CONFIG, LT_DONTHAVE_ID, UT_HOLEPUNCH_ID, UT_META_ID, UT_PEX_ID};
+
+/// ID of the extended handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+/// Longest client name kept from a handshake
+const MAX_CLIENT_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ext {
+    /// Metadata exchange (BEP 9)
+    Metadata,
+    /// Peer exchange (BEP 11)
+    Pex,
+    /// NAT holepunching (BEP 55)
+    Holepunch,
+    /// Retracting advertised pieces (BEP 54)
+    DontHave,
+}
+
+pub const EXTENSIONS: &[Ext] = &[Ext::Metadata, Ext::Pex, Ext::Holepunch, Ext::DontHave];
+
+/// Extension message IDs negotiated with a peer, along with the
+/// other contents of its extended handshake which we care about.
+#[derive(Debug, Default)]
+pub struct ExtIDs {
+    ids: [Option<u8>; EXTENSIONS.len()],
+    /// Size of the info dictionary (BEP 9)
+    pub metadata_size: Option<u64>,
+    /// Port the peer listens on
+    pub port: Option<u16>,
+    /// Addresses the peer says it has, which lets dual-stack peers be
+    /// recognised under either one
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    /// Address the peer sees us connecting from
+    pub yourip: Option<IpAddr>,
+    /// Client name and version the peer reports
+    pub client: Option<String>,
+    /// Whether the peer doesn't download anything (BEP 21)
+    pub upload_only: bool,
+}
+
+impl Ext {
+    pub fn name(self) -> &'static str {
+        match self {
+            Ext::Metadata => "ut_metadata",
+            Ext::Pex => "ut_pex",
+            Ext::Holepunch => "ut_holepunch",
+            Ext::DontHave => "lt_donthave",
+        }
+    }
+
+    /// The ID peers send us this extension's messages with.
+    pub fn id(self) -> u8 {
+        match self {
+            Ext::Metadata => UT_META_ID,
+            Ext::Pex => UT_PEX_ID,
+            Ext::Holepunch => UT_HOLEPUNCH_ID,
+            Ext::DontHave => LT_DONTHAVE_ID,
+        }
+    }
+
+    /// Looks up the extension which a message sent to us belongs to.
+    pub fn from_id(id: u8, private: bool) -> Option<Ext> {
+        EXTENSIONS
+            .iter()
+            .cloned()
+            .find(|e| e.id() == id && e.enabled(private))
+    }
+
+    /// Whether the extension is enabled in the config and may be used by a
+    /// torrent. Private torrents only get their peers from the tracker.
+    /// Holepunching connects over uTP, so it needs uTP to be enabled.
+    pub fn enabled(self, private: bool) -> bool {
+        let exts = &CONFIG.peer.extensions;
+        if !exts.extension_protocol {
+            return false;
+        }
+        match self {
+            Ext::Metadata => exts.ut_metadata,
+            Ext::Pex => exts.ut_pex && !private,
+            Ext::Holepunch => exts.ut_holepunch && CONFIG.net.utp && !private,
+            Ext::DontHave => exts.lt_donthave,
+        }
+    }
+
+    fn idx(self) -> usize {
+        self as usize
+    }
+}
+
+/// Builds our extended handshake for a torrent. Seeds, including partial
+/// ones, tell peers they're only uploading (BEP 21).
+pub fn handshake(private: bool, metadata_size: usize, upload_only: bool) -> Vec<u8> {
+    let mut m = BTreeMap::new();
+    for ext in EXTENSIONS.iter().filter(|e| e.enabled(private)) {
+        m.insert(ext.name().to_owned(), BEncode::Int(i64::from(ext.id())));
+    }
+    let mut d = BTreeMap::new();
+    d.insert("m".to_owned(), BEncode::Dict(m));
+    d.insert("p".to_owned(), BEncode::Int(i64::from(CONFIG.port)));
+    if upload_only {
+        d.insert("upload_only".to_owned(), BEncode::Int(1));
+    }
+    d.insert(
+        "v".to_owned(),
+        BEncode::from_str(concat!("Synapse ", env!("CARGO_PKG_VERSION"))),
+    );
+    // Magnets don't know their metadata size until they've fetched it
+    if metadata_size > 0 {
+        d.insert(
+            "metadata_size".to_owned(),
+            BEncode::Int(metadata_size as i64),
+        );
+    }
+    BEncode::Dict(d).encode_to_buf()
+}
+
+impl ExtIDs {
+    /// The ID to send an extension's messages to the peer with, if it
+    /// supports the extension.
+    pub fn get(&self, ext: Ext) -> Option<u8> {
+        self.ids[ext.idx()]
+    }
+
+    /// Applies a peer's extended handshake. Peers may send the handshake
+    /// again later, in which case extensions it leaves out are unchanged
+    /// and ones mapped to 0 are disabled.
+    pub fn update(&mut self, payload: &[u8], private: bool) -> Result<(), &'static str> {
+        let b = bencode::decode_buf(payload).map_err(|_| "Invalid bencode in ext handshake")?;
+        let mut d = b
+            .into_dict()
+            .ok_or("Invalid bencode type in ext handshake")?;
+        let mut m = d
+            .remove("m")
+            .and_then(|v| v.into_dict())
+            .ok_or("Invalid m dict in ext handshake")?;
+        for ext in EXTENSIONS {
+            let id = match m.remove(ext.name()).and_then(|v| v.into_int()) {
+                Some(id) if (0..=255).contains(&id) => id as u8,
+                Some(_) => return Err("Invalid extension ID in ext handshake"),
+                None => continue,
+            };
+            // Extensions we've disabled are never recorded, so they go unused
+            self.ids[ext.idx()] = Some(id).filter(|&i| i != 0 && ext.enabled(private));
+        }
+        if let Some(size) = d.remove("metadata_size").and_then(|v| v.into_int()) {
+            self.metadata_size = Some(size as u64).filter(|_| size > 0);
+        }
+        if let Some(port) = d.remove("p").and_then(|v| v.into_int()) {
+            self.port = Some(port as u16).filter(|_| port > 0 && port <= 65535);
+        }
+        if let Some(ip) = d.remove("ipv4").and_then(|v| v.into_bytes()) {
+            if ip.len() == 4 {
+                self.ipv4 = Some(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]));
+            }
+        }
+        if let Some(ip) = d.remove("ipv6").and_then(|v| v.into_bytes()) {
+            if ip.len() == 16 {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&ip);
+                self.ipv6 = Some(Ipv6Addr::from(octets));
+            }
+        }
+        if let Some(u) = d.remove("upload_only").and_then(|v| v.into_int()) {
+            self.upload_only = u != 0;
+        }
+        if let Some(v) = d.remove("v").and_then(|v| v.into_bytes()) {
+            let v = String::from_utf8_lossy(&v);
+            self.client = Some(v.trim().chars().take(MAX_CLIENT_LEN).collect())
+                .filter(|c: &String| !c.is_empty());
+        }
+        if let Some(ip) = d.remove("yourip").and_then(|v| v.into_bytes()) {
+            self.yourip = match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))),
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&ip);
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                _ => None,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let mut ids = ExtIDs::default();
+        ids.update(&handshake(false, 1000, false), false).unwrap();
+        assert_eq!(ids.get(Ext::Metadata), Some(UT_META_ID));
+        assert_eq!(ids.get(Ext::Pex), Some(UT_PEX_ID));
+        assert_eq!(ids.get(Ext::Holepunch), Some(UT_HOLEPUNCH_ID));
+        assert_eq!(ids.get(Ext::DontHave), Some(LT_DONTHAVE_ID));
+        assert_eq!(ids.metadata_size, Some(1000));
+        assert_eq!(ids.port, Some(CONFIG.port));
+        assert!(!ids.upload_only);
+        ids.update(&handshake(false, 1000, true), false).unwrap();
+        assert!(ids.upload_only);
+
+        // Later handshakes only change the extensions they mention
+        ids.update(b"d1:md6:ut_pexi0eee", false).unwrap();
+        assert_eq!(ids.get(Ext::Metadata), Some(UT_META_ID));
+        assert_eq!(ids.get(Ext::Pex), None);
+        ids.update(b"d1:md6:ut_pexi3eee", true).unwrap();
+        assert_eq!(ids.get(Ext::Pex), None);
+
+        ids.update(b"d1:mde4:ipv44:\x01\x02\x03\x044:ipv62:\x01\x02e", false)
+            .unwrap();
+        assert_eq!(ids.ipv4, Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(ids.ipv6, None);
+
+        ids.update(b"d1:mde6:yourip4:\x05\x06\x07\x08e", false)
+            .unwrap();
+        assert_eq!(ids.yourip, Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))));
+        assert_eq!(ids.client.as_ref().map(|c| &c[..7]), Some("Synapse"));
+        ids.update(b"d1:mde1:v0:e", false).unwrap();
+        assert_eq!(ids.client, None);
+
+        assert!(ids.update(b"d1:md6:ut_pexi300eee", false).is_err());
+        assert!(ids.update(b"d1:v3:fooe", false).is_err());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        assert_eq!(Ext::from_id(UT_META_ID, true), Some(Ext::Metadata));
+        assert_eq!(Ext::from_id(UT_PEX_ID, false), Some(Ext::Pex));
+        assert_eq!(Ext::from_id(UT_PEX_ID, true), None);
+        assert_eq!(Ext::from_id(UT_HOLEPUNCH_ID, false), Some(Ext::Holepunch));
+        assert_eq!(Ext::from_id(UT_HOLEPUNCH_ID, true), None);
+        assert_eq!(Ext::from_id(LT_DONTHAVE_ID, true), Some(Ext::DontHave));
+        assert_eq!(Ext::from_id(HANDSHAKE_ID, false), None);
+    }
+}
@@ -0,0 +1,71 @@
+//! Fast extension (BEP 6).
+//!
+//! Peers which are just starting out get a small set of pieces they may
+//! request while choked, so they have something to trade sooner. The set
+//! is derived from the peer's IP and the info hash, so reconnecting
+//! doesn't get a peer a different set.
+
+use std::net::IpAddr;
+
+use crate::util::sha1_hash;
+
+/// Size of the allowed fast set we hand out
+pub const ALLOWED_FAST: u32 = 10;
+
+/// Computes the allowed fast set for a peer. Only IPv4 is specified,
+/// so peers on other addresses get none.
+pub fn allowed_set(ip: IpAddr, hash: &[u8; 20], pieces: u32, k: u32) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return Vec::new(),
+        },
+    };
+    let k = k.min(pieces) as usize;
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xFFFF_FF00).to_be_bytes().to_vec();
+    x.extend_from_slice(hash);
+    while set.len() < k {
+        let digest = sha1_hash(&x);
+        for y in digest.chunks_exact(4) {
+            if set.len() == k {
+                break;
+            }
+            let idx = u32::from_be_bytes([y[0], y[1], y[2], y[3]]) % pieces;
+            if !set.contains(&idx) {
+                set.push(idx);
+            }
+        }
+        x = digest.to_vec();
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_set() {
+        // Test vectors from BEP 6
+        let ip = "80.4.4.200".parse().unwrap();
+        let hash = [0xaa; 20];
+        assert_eq!(
+            allowed_set(ip, &hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_set(ip, &hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // The last octet is ignored
+        assert_eq!(
+            allowed_set("80.4.4.1".parse().unwrap(), &hash, 1313, 7),
+            allowed_set(ip, &hash, 1313, 7)
+        );
+
+        assert_eq!(allowed_set(ip, &hash, 3, 10).len(), 3);
+        assert!(allowed_set("::2".parse().unwrap(), &hash, 1313, 7).is_empty());
+    }
+}
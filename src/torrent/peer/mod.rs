@@ -1,23 +1,39 @@
+pub mod caps;
+pub mod client;
+pub mod ext;
+mod fast;
+pub mod priority;
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
-use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, fmt, io, mem, time};
 
+use rand::{self, Rng};
+
+use self::caps::Bit;
+pub use self::caps::Caps;
+pub use self::ext::ExtIDs;
 pub use self::message::Message;
 use self::reader::{RRes, Reader};
 use self::writer::Writer;
-use crate::bencode;
 use crate::control::cio;
+use crate::dht;
 use crate::rpc::{self, resource};
 use crate::socket::Socket;
 use crate::stat;
 use crate::throttle::Throttle;
+use crate::torrent::tuner::{self, Tuner};
 use crate::torrent::{Bitfield, Info, Torrent};
-use crate::tracker;
-use crate::util;
-use crate::{CONFIG, DHT_EXT, PEER_ID};
+use crate::util::{self, FHashMap, FHashSet};
+use crate::{CONFIG, PEER_ID};
+
+/// Time a peer has to answer a request before it's considered to be
+/// snubbing us
+const SNUB_SECS: u64 = 60;
+/// Most pieces left out of a lazy bitfield
+const MAX_LAZY_PIECES: usize = 8;
 
 error_chain! {
     errors {
@@ -28,9 +44,6 @@ error_chain! {
     }
 }
 
-const INIT_MAX_QUEUE: u16 = 5;
-const MAX_QUEUE_CAP: u16 = 600;
-
 pub mod message {
     use crate::buffers;
     use crate::protocol;
@@ -50,27 +63,42 @@ pub struct Peer<T: cio::CIO> {
     local_status: Status,
     /// Current number of queued requests
     queued: u16,
-    /// Maximum number of requests that can be queued
-    /// at a time.
-    max_queue: u16,
+    /// Piece and offset of queued requests, and when they were sent
+    requested: VecDeque<(u32, u32, time::Instant)>,
+    /// Whether the peer has sat on our requests for too long
+    snubbed: bool,
+    /// Maximum number of requests that can be queued at a time
+    queue: tuner::Queue,
+    /// Blocks the peer requested which are being read from disk, and
+    /// their lengths
+    reading: FHashMap<(u32, u32), u32>,
+    /// Bytes of uploads the peer cancelled before we sent them
+    cancelled: u64,
+    /// Pieces the peer allows us to request while choked
+    allowed_fast: FHashSet<u32>,
+    /// Pieces we allow the peer to request while choked
+    fast_set: Vec<u32>,
+    /// Number of pieces to pick ahead
+    prefetch: usize,
     pieces_updated: bool,
     tid: usize,
     downloaded: u32,
     uploaded: u32,
     stat: stat::EMA,
+    /// Fastest average download rate seen from the peer
+    best_dl: u64,
     addr: SocketAddr,
     t_hash: [u8; 20],
     cid: Option<[u8; 20]>,
-    rsv: Option<[u8; 8]>,
-    ext_ids: ExtIDs,
+    /// Whether the peer connected to us
+    incoming: bool,
+    /// Whether the connection is over uTP
+    utp: bool,
+    caps: Caps,
+    source: resource::PeerSource,
     pub rank: usize,
 }
 
-pub struct ExtIDs {
-    pub ut_meta: Option<u8>,
-    pub ut_pex: Option<u8>,
-}
-
 #[derive(Debug)]
 pub struct Status {
     pub choked: bool,
@@ -82,16 +110,19 @@ pub struct PeerConn {
     sock: Socket,
     reader: Reader,
     writer: Writer,
+    /// Where the peer was found
+    source: resource::PeerSource,
 }
 
 impl PeerConn {
-    pub fn new(sock: Socket) -> PeerConn {
+    pub fn new(sock: Socket, source: resource::PeerSource) -> PeerConn {
         let writer = Writer::new();
         let reader = Reader::new();
         PeerConn {
             sock,
             writer,
             reader,
+            source,
             last_action: time::Instant::now(),
         }
     }
@@ -105,6 +136,7 @@ impl PeerConn {
             sock: Socket::empty(),
             writer,
             reader,
+            source: resource::PeerSource::Manual,
         }
     }
 
@@ -122,14 +154,24 @@ impl PeerConn {
 
     /// Creates a new "outgoing" peer, which acts as a client.
     /// Once created, set_torrent should be called.
-    pub fn new_outgoing(ip: &SocketAddr) -> io::Result<PeerConn> {
-        Ok(PeerConn::new(Socket::new(ip)?))
+    pub fn new_outgoing(ip: &SocketAddr, source: resource::PeerSource) -> io::Result<PeerConn> {
+        // The uTP socket only speaks IPv4
+        if CONFIG.net.prefer_utp && ip.is_ipv4() {
+            Ok(PeerConn::new_utp(ip, source))
+        } else {
+            Ok(PeerConn::new(Socket::new(ip)?, source))
+        }
+    }
+
+    /// Creates an outgoing peer which connects over uTP.
+    pub fn new_utp(ip: &SocketAddr, source: resource::PeerSource) -> PeerConn {
+        PeerConn::new(Socket::new_utp(ip), source)
     }
 
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(sock: TcpStream) -> io::Result<PeerConn> {
-        Ok(PeerConn::new(Socket::from_stream(sock)?))
+    pub fn new_incoming(sock: Socket) -> PeerConn {
+        PeerConn::new(sock, resource::PeerSource::Incoming)
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
@@ -177,18 +219,28 @@ impl Peer<cio::test::TCIO> {
             uploaded,
             downloaded,
             stat: stat::EMA::new(),
+            best_dl: 0,
             addr: "127.0.0.1:0".parse().unwrap(),
             cio: cio::test::TCIO::new(),
             queued,
-            max_queue: queued,
+            requested: VecDeque::new(),
+            snubbed: false,
+            queue: tuner::Queue::new(),
+            reading: FHashMap::default(),
+            cancelled: 0,
+            allowed_fast: FHashSet::default(),
+            fast_set: Vec::new(),
+            prefetch: Tuner::new(1).prefetch(0),
             pieces,
             piece_cache: Vec::new(),
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
-            rsv: None,
             cid: None,
-            ext_ids: ExtIDs::new(),
+            incoming: false,
+            utp: false,
+            caps: Caps::new(),
+            source: resource::PeerSource::Tracker,
             pieces_updated: false,
             rank: 0,
         }
@@ -221,7 +273,7 @@ impl<T: cio::CIO> Peer<T> {
         rsv: Option<[u8; 8]>,
     ) -> cio::Result<Peer<T>> {
         let throttle = t.get_throttle(0);
-        let addr = Peer::setup_conn(&mut t.cio, id, throttle)?;
+        let (addr, utp, source) = Peer::setup_conn(&mut t.cio, id, throttle)?;
         let mut p = Peer {
             id,
             addr,
@@ -230,43 +282,105 @@ impl<T: cio::CIO> Peer<T> {
             uploaded: 0,
             downloaded: 0,
             stat: stat::EMA::new(),
+            best_dl: 0,
             cio: t.cio.new_handle(),
             queued: 0,
-            max_queue: INIT_MAX_QUEUE,
+            requested: VecDeque::new(),
+            snubbed: false,
+            queue: tuner::Queue::new(),
+            reading: FHashMap::default(),
+            cancelled: 0,
+            allowed_fast: FHashSet::default(),
+            fast_set: Vec::new(),
+            prefetch: t.tuner.prefetch(0),
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_cache: Vec::new(),
             piece_count: 0,
             tid: t.id,
             t_hash: t.info.hash,
-            rsv,
             cid,
-            ext_ids: ExtIDs::new(),
+            incoming: rsv.is_some(),
+            utp,
+            caps: Caps::new(),
+            source,
             pieces_updated: false,
             rank: t.num_peers(),
         };
-        p.send_message(Message::handshake(&*PEER_ID, &t.info.hash));
-        if t.info.complete() {
-            p.send_message(Message::Bitfield(t.pieces.clone()));
+        p.send_message(Message::Handshake {
+            rsv: caps::local_rsv(),
+            hash: t.info.hash,
+            id: *PEER_ID,
+        });
+        // Incoming peers have already sent their handshake
+        if let Some(rsv) = rsv {
+            p.caps.set_rsv(rsv);
+            p.handshake_done(&t.pieces, t.info.complete() && !t.super_seeding());
         }
         p.send_rpc_info();
         Ok(p)
     }
 
-    fn setup_conn(cio: &mut T, pid: usize, throttle: Throttle) -> cio::Result<SocketAddr> {
-        if let Some(addr) = cio.get_peer(pid, |pconn| {
+    fn setup_conn(
+        cio: &mut T,
+        pid: usize,
+        throttle: Throttle,
+    ) -> cio::Result<(SocketAddr, bool, resource::PeerSource)> {
+        if let Some(conn) = cio.get_peer(pid, |pconn| {
             pconn.set_throttle(throttle);
-            pconn.sock().addr()
+            let sock = pconn.sock();
+            (sock.addr(), sock.is_utp(), pconn.source)
         }) {
-            Ok(addr)
+            Ok(conn)
         } else {
             debug!("pid {} not found", pid);
             Err(cio::ErrorKind::IO.into())
         }
     }
 
+    /// Sends the messages which follow the handshakes: our pieces, which
+    /// must come first, and then the DHT port. Seeds using lazy bitfields
+    /// leave a few pieces out of the bitfield and send them as HAVEs.
+    pub fn handshake_done(&mut self, pieces: &Bitfield, have_info: bool) {
+        let withheld = if have_info && CONFIG.peer.lazy_bitfield && pieces.complete() {
+            lazy_pieces(pieces.len())
+        } else {
+            Vec::new()
+        };
+        if !withheld.is_empty() {
+            let mut lazy = pieces.clone();
+            for &piece in &withheld {
+                lazy.unset_bit(piece);
+            }
+            self.send_message(Message::Bitfield(lazy));
+        } else if self.caps.has(Bit::Fast) {
+            let msg = if !have_info || pieces.set() == 0 {
+                Message::HaveNone
+            } else if pieces.complete() {
+                Message::HaveAll
+            } else {
+                Message::Bitfield(pieces.clone())
+            };
+            self.send_message(msg);
+        } else if have_info {
+            self.send_message(Message::Bitfield(pieces.clone()));
+        }
+        if self.caps.has(Bit::Dht) {
+            self.send_message(Message::Port(CONFIG.dht.port));
+        }
+        for piece in withheld {
+            self.send_message(Message::Have(piece as u32));
+        }
+    }
+
     pub fn magnet_complete(&mut self, info: &Info) -> Result<()> {
         if self.pieces.len() == 0 {
-            self.pieces = Bitfield::new(u64::from(info.pieces()));
+            // Seeds may have told us so before we knew the piece count
+            self.pieces = if self.pieces.complete() {
+                Bitfield::full(u64::from(info.pieces()))
+            } else {
+                Bitfield::new(u64::from(info.pieces()))
+            };
+            self.piece_count = self.pieces.set() as usize;
         } else if !self.pieces.cap(u64::from(info.pieces())) {
             return Err(ErrorKind::ProtocolError("Invalid pieces size").into());
         }
@@ -278,27 +392,119 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    pub fn caps(&self) -> &Caps {
+        &self.caps
+    }
+
     pub fn exts(&self) -> &ExtIDs {
-        &self.ext_ids
+        &self.caps.exts
+    }
+
+    /// Applies the peer's extended handshake.
+    pub fn update_exts(&mut self, payload: &[u8], private: bool) -> Result<()> {
+        let client = self.client();
+        self.caps
+            .exts
+            .update(payload, private)
+            .map_err(ErrorKind::ProtocolError)?;
+        self.send_rpc_caps();
+        if self.client() != client {
+            self.send_rpc_client();
+        }
+        Ok(())
+    }
+
+    /// The peer's client name and version, preferably as reported in its
+    /// extended handshake, otherwise decoded from its peer ID.
+    pub fn client(&self) -> Option<String> {
+        self.caps
+            .exts
+            .client
+            .clone()
+            .or_else(|| self.cid.as_ref().and_then(client::name))
     }
 
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
 
+    /// The address the peer accepts connections on, if known. Peers which
+    /// connected to us only tell us this in their extended handshake.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        if !self.incoming {
+            return Some(self.addr);
+        }
+        let mut addr = self.addr;
+        addr.set_port(self.caps.exts.port?);
+        Some(addr)
+    }
+
+    /// The address the peer listens on in the other address family, if
+    /// it told us it has one.
+    pub fn alt_addr(&self) -> Option<SocketAddr> {
+        let ip = match self.addr {
+            SocketAddr::V4(_) => IpAddr::V6(self.caps.exts.ipv6?),
+            SocketAddr::V6(_) => IpAddr::V4(self.caps.exts.ipv4?),
+        };
+        Some(SocketAddr::new(ip, self.listen_addr()?.port()))
+    }
+
+    pub fn incoming(&self) -> bool {
+        self.incoming
+    }
+
+    pub fn utp(&self) -> bool {
+        self.utp
+    }
+
+    pub fn source(&self) -> resource::PeerSource {
+        self.source
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
 
+    /// Forgets a piece the peer told us it no longer has (BEP 54),
+    /// returning whether it had advertised the piece.
+    pub fn dont_have(&mut self, idx: u32) -> bool {
+        if u64::from(idx) >= self.pieces.len() || !self.pieces.has_bit(u64::from(idx)) {
+            return false;
+        }
+        self.pieces.unset_bit(u64::from(idx));
+        self.piece_count -= 1;
+        self.pieces_updated = true;
+        true
+    }
+
     pub fn piece_cache(&mut self) -> &mut Vec<u32> {
         &mut self.piece_cache
     }
 
+    /// Pieces which may be requested from the peer while it chokes us.
+    pub fn allowed_fast(&self) -> &FHashSet<u32> {
+        &self.allowed_fast
+    }
+
+    pub fn choked(&self) -> bool {
+        self.remote_status.choked
+    }
+
+    /// Number of pieces the picker should pick ahead for the peer.
+    pub fn prefetch(&self) -> usize {
+        self.prefetch
+    }
+
     #[cfg(test)]
     pub fn pieces_mut(&mut self) -> &mut Bitfield {
         &mut self.pieces
     }
 
+    #[cfg(test)]
+    pub fn allowed_fast_mut(&mut self) -> &mut FHashSet<u32> {
+        &mut self.allowed_fast
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -314,22 +520,22 @@ impl<T: cio::CIO> Peer<T> {
         self.stat.active()
     }
 
-    pub fn tick(&mut self) -> bool {
+    /// Whether neither side wants anything from the other, and nothing is
+    /// being transferred.
+    pub fn idle(&self) -> bool {
+        !self.local_status.interested && !self.remote_status.interested && !self.active()
+    }
+
+    pub fn tick(&mut self, tuner: &Tuner) -> bool {
         self.stat.tick();
         if !self.stat.active() {
             return false;
         }
-        let dl = self.stat.avg_dl();
-        let rate = (dl / 1024) as u16;
-        // Taken from rtorrent's pipeline calculation
-        let nmq = if rate < 20 { rate + 2 } else { rate / 5 + 18 };
-        // Clamp between -15 / +50 for queue len changes
-        self.max_queue = cmp::min(
-            cmp::max(nmq, self.max_queue.saturating_sub(15)),
-            self.max_queue + 50,
-        );
-        // Keep it under the max cap
-        self.max_queue = cmp::min(self.max_queue, MAX_QUEUE_CAP);
+        self.best_dl = cmp::max(self.best_dl, self.stat.avg_dl());
+        if !self.remote_status.choked {
+            self.queue.update(self.stat.avg_dl());
+            self.prefetch = tuner.prefetch(self.queue.depth());
+        }
         if self.pieces_updated {
             self.pieces_updated = false;
             self.send_rpc_update();
@@ -337,42 +543,88 @@ impl<T: cio::CIO> Peer<T> {
         true
     }
 
+    pub fn snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// Checks whether the peer has left its oldest request unanswered for
+    /// too long, returning true if it has only just started snubbing us.
+    pub fn check_snubbed(&mut self) -> bool {
+        if self.snubbed {
+            return false;
+        }
+        let timeout = time::Duration::from_secs(SNUB_SECS);
+        self.snubbed = self
+            .requested
+            .front()
+            .map(|&(_, _, at)| at.elapsed() >= timeout)
+            .unwrap_or(false);
+        self.snubbed
+    }
+
+    pub fn best_dl(&self) -> u64 {
+        self.best_dl
+    }
+
     pub fn get_tx_rates(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
     pub fn queue_reqs(&mut self) -> Option<u16> {
-        if self.remote_status.choked || self.queued > self.max_queue.saturating_sub(16) {
+        let depth = self.queue.depth();
+        // Refill in batches rather than a request at a time
+        let batch = cmp::min(16, depth / 2);
+        let choked = self.remote_status.choked && self.allowed_fast.is_empty();
+        if choked || self.queued > depth.saturating_sub(batch) {
             None
+        } else if self.snubbed {
+            // Keep a single request going to see if the peer recovers
+            if self.queued == 0 {
+                Some(1)
+            } else {
+                None
+            }
         } else {
-            let amnt = self.max_queue.saturating_sub(self.queued);
-            Some(amnt)
+            Some(depth.saturating_sub(self.queued))
         }
     }
 
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
         match *msg {
             Message::Handshake { rsv, id, .. } => {
-                if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
-                    self.send_message(Message::Port(CONFIG.dht.port));
-                }
-                self.rsv = Some(rsv);
+                self.caps.set_rsv(rsv);
                 self.cid = Some(id);
                 self.send_rpc_info();
             }
-            Message::Piece { length, .. } => {
+            Message::Piece {
+                index,
+                begin,
+                length,
+                ..
+            } => {
                 self.stat.add_dl(u64::from(length));
                 self.downloaded += 1;
-                self.queued -= 1;
+                self.snubbed = false;
+                // Cancelled requests may still be answered
+                if let Some(at) = self.unqueue(index, begin) {
+                    self.queue.sample(at.elapsed());
+                }
             }
+            // Peers with the fast extension are sent a reject instead
             Message::Request { .. } => {
-                if self.local_status.choked {
+                if self.local_status.choked && !self.caps.has(Bit::Fast) {
                     info!("Got request while choked!");
                     return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
                 }
             }
             Message::Choke => {
                 self.remote_status.choked = true;
+                // Requests are discarded by a choking peer, unless it
+                // rejects them explicitly
+                if !self.caps.has(Bit::Fast) {
+                    self.requested.clear();
+                    self.queued = 0;
+                }
             }
             Message::Unchoke => {
                 self.remote_status.choked = false;
@@ -406,48 +658,77 @@ impl<T: cio::CIO> Peer<T> {
                 self.piece_count = self.pieces.iter().count();
                 self.send_rpc_update();
             }
+            Message::HaveAll | Message::HaveNone if !self.caps.has(Bit::Fast) => {
+                return Err(ErrorKind::ProtocolError("Fast extension not negotiated").into());
+            }
+            Message::HaveAll => {
+                self.pieces = Bitfield::full(self.pieces.len());
+                self.piece_count = self.pieces.len() as usize;
+                self.send_rpc_update();
+            }
+            Message::HaveNone => {
+                self.send_rpc_update();
+            }
+            Message::SuggestPiece(_) | Message::RejectRequest { .. } | Message::AllowedFast(_)
+                if !self.caps.has(Bit::Fast) =>
+            {
+                return Err(ErrorKind::ProtocolError("Fast extension not negotiated").into());
+            }
+            Message::SuggestPiece(idx) => {
+                // Suggestions are picked next, if they're worth picking
+                if idx < self.pieces.len() as u32
+                    && self.pieces.has_bit(u64::from(idx))
+                    && !self.piece_cache.contains(&idx)
+                {
+                    self.piece_cache.push(idx);
+                }
+            }
+            // Rejected blocks are released by the torrent
+            Message::RejectRequest { .. } => {}
+            Message::AllowedFast(idx) => {
+                // Magnets can't check the index yet, but can't use it either
+                if idx < self.pieces.len() as u32 {
+                    self.allowed_fast.insert(idx);
+                }
+            }
             Message::KeepAlive => {
                 self.send_message(Message::KeepAlive);
             }
-            Message::Cancel { index, begin, .. } => {
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let mut found = self.reading.remove(&(index, begin)).is_some();
                 self.cio.get_peer(self.id, |conn| {
                     conn.writer.write_queue.retain(|m| {
                         if let Message::Piece {
                             index: i, begin: b, ..
                         } = *m
                         {
-                            return !(i == index && b == begin);
+                            if i == index && b == begin {
+                                found = true;
+                                return false;
+                            }
                         }
                         true
                     });
                 });
+                if found {
+                    self.cancelled += u64::from(length);
+                    // Fast peers expect every request to be answered
+                    self.reject_request(index, begin, length);
+                }
             }
             Message::Port(p) => {
-                let mut s = self.addr();
-                s.set_port(p);
-                self.cio.msg_trk(tracker::Request::AddNode(s));
-            }
-            Message::Extension { id, ref payload } => {
-                if id == 0 {
-                    let b = bencode::decode_buf(payload).map_err(|_| {
-                        ErrorKind::ProtocolError("Invalid bencode in ext handshake")
-                    })?;
-                    let mut d = b.into_dict().ok_or_else(|| {
-                        ErrorKind::ProtocolError("Invalid bencode type in ext handshake")
-                    })?;
-                    let mut m = d.remove("m").and_then(|v| v.into_dict()).ok_or_else(|| {
-                        ErrorKind::ProtocolError("Invalid metadata in in ext handshake")
-                    })?;
-                    self.ext_ids.ut_meta = m
-                        .remove("ut_metadata")
-                        .and_then(|v| v.into_int())
-                        .map(|v| v as u8);
-                    self.ext_ids.ut_pex = m
-                        .remove("ut_pex")
-                        .and_then(|v| v.into_int())
-                        .map(|v| v as u8);
+                if CONFIG.peer.extensions.dht {
+                    let mut s = self.addr();
+                    s.set_port(p);
+                    self.cio.msg_dht(dht::Request::AddNode(s));
                 }
             }
+            // Extensions are negotiated and dispatched by the torrent
+            Message::Extension { .. } => {}
         }
         Ok(())
     }
@@ -455,13 +736,134 @@ impl<T: cio::CIO> Peer<T> {
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
         self.queued += 1;
+        self.requested
+            .push_back((idx, offset, time::Instant::now()));
+        if self.queued >= self.queue.depth() {
+            self.queue.saturated();
+        }
         self.send_message(m);
     }
 
+    /// Cancels an outstanding request, freeing its slot in the queue.
+    pub fn cancel_request(&mut self, idx: u32, offset: u32, len: u32) {
+        self.unqueue(idx, offset);
+        self.send_message(Message::Cancel {
+            index: idx,
+            begin: offset,
+            length: len,
+        });
+    }
+
+    /// Called when the peer rejects one of our requests.
+    pub fn request_rejected(&mut self, idx: u32, offset: u32) {
+        self.unqueue(idx, offset);
+    }
+
+    /// Removes a request from the queue, returning when it was sent if it
+    /// was still outstanding.
+    fn unqueue(&mut self, idx: u32, offset: u32) -> Option<time::Instant> {
+        let pos = self
+            .requested
+            .iter()
+            .position(|&(i, o, _)| i == idx && o == offset)?;
+        self.queued = self.queued.saturating_sub(1);
+        self.requested.remove(pos).map(|(_, _, at)| at)
+    }
+
+    /// Records that a block the peer requested is being read from disk.
+    pub fn reading(&mut self, idx: u32, offset: u32, len: u32) {
+        self.reading.insert((idx, offset), len);
+    }
+
+    /// Called once a block has been read for the peer, returning false if
+    /// the peer cancelled the request in the meantime.
+    pub fn read_done(&mut self, idx: u32, offset: u32) -> bool {
+        self.reading.remove(&(idx, offset)).is_some()
+    }
+
+    /// Whether the peer may be sent blocks of a piece. Choked peers may
+    /// only get the pieces we allowed them.
+    pub fn can_upload(&self, idx: u32) -> bool {
+        !self.local_status.choked || self.fast_set.contains(&idx)
+    }
+
+    /// Tells the peer we won't answer a request, if it understands.
+    pub fn reject_request(&mut self, idx: u32, offset: u32, len: u32) {
+        if self.caps.has(Bit::Fast) {
+            self.send_message(Message::RejectRequest {
+                index: idx,
+                begin: offset,
+                length: len,
+            });
+        }
+    }
+
+    /// Allows a peer which is just starting out to request pieces of ours
+    /// while choked.
+    pub fn allow_fast(&mut self, pieces: &Bitfield, hash: &[u8; 20]) {
+        if !self.caps.has(Bit::Fast)
+            || !self.fast_set.is_empty()
+            || self.piece_count >= fast::ALLOWED_FAST as usize
+        {
+            return;
+        }
+        self.fast_set = fast::allowed_set(
+            self.addr.ip(),
+            hash,
+            pieces.len() as u32,
+            fast::ALLOWED_FAST,
+        );
+        for idx in self.fast_set.clone() {
+            if pieces.has_bit(u64::from(idx)) {
+                self.send_message(Message::AllowedFast(idx));
+            }
+        }
+    }
+
+    /// Returns the bytes of uploads cancelled since the last call.
+    pub fn take_cancelled(&mut self) -> u64 {
+        mem::replace(&mut self.cancelled, 0)
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
             self.send_message(Message::Choke);
+            if self.caps.has(Bit::Fast) {
+                self.reject_pending();
+            }
+        }
+    }
+
+    /// Rejects requests which a choke leaves unanswered, as choking a
+    /// fast peer doesn't discard its requests.
+    fn reject_pending(&mut self) {
+        let fast_set = &self.fast_set;
+        let mut rejected: Vec<_> = self
+            .reading
+            .iter()
+            .filter(|((idx, _), _)| !fast_set.contains(idx))
+            .map(|(&(idx, begin), &len)| (idx, begin, len))
+            .collect();
+        for (idx, begin, _) in &rejected {
+            self.reading.remove(&(*idx, *begin));
+        }
+        self.cio.get_peer(self.id, |conn| {
+            conn.writer.write_queue.retain(|m| match *m {
+                Message::Piece {
+                    index,
+                    begin,
+                    length,
+                    ..
+                } if !fast_set.contains(&index) => {
+                    rejected.push((index, begin, length));
+                    false
+                }
+                _ => true,
+            });
+        });
+        for (idx, begin, len) in rejected {
+            self.reject_request(idx, begin, len);
         }
     }
 
@@ -496,10 +898,13 @@ impl<T: cio::CIO> Peer<T> {
                         id,
                         torrent_id: util::hash_to_id(&self.t_hash[..]),
                         client_id: util::hash_to_id(&cid[..]),
+                        client: self.client(),
                         ip: self.addr.to_string(),
                         rate_up: 0,
                         rate_down: 0,
                         availability: self.piece_count as f32 / self.pieces.len() as f32,
+                        source: self.source,
+                        capabilities: self.caps.names(),
                         ..Default::default()
                     },
                 )]));
@@ -519,6 +924,32 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    fn send_rpc_caps(&mut self) {
+        if self.cid.is_some() {
+            let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::PeerCapabilities {
+                    id,
+                    kind: resource::ResourceKind::Peer,
+                    capabilities: self.caps.names(),
+                },
+            ]));
+        }
+    }
+
+    fn send_rpc_client(&mut self) {
+        if let (true, Some(client)) = (self.cid.is_some(), self.client()) {
+            let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::PeerClient {
+                    id,
+                    kind: resource::ResourceKind::Peer,
+                    client,
+                },
+            ]));
+        }
+    }
+
     pub fn send_rpc_removal(&mut self) {
         if self.ready() {
             self.cio
@@ -546,21 +977,104 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
     }
 }
 
-impl ExtIDs {
-    fn new() -> ExtIDs {
-        ExtIDs {
-            ut_meta: None,
-            ut_pex: None,
-        }
+/// Picks a few random pieces to leave out of a seed's bitfield, so it
+/// doesn't plainly show a complete torrent. At least one piece is left in.
+fn lazy_pieces(len: u64) -> Vec<u64> {
+    let len = len as usize;
+    if len < 2 {
+        return Vec::new();
     }
+    let mut rng = rand::thread_rng();
+    let amount = rng.gen_range(1, cmp::min(MAX_LAZY_PIECES, len - 1) + 1);
+    rand::seq::sample_indices(&mut rng, len, amount)
+        .into_iter()
+        .map(|p| p as u64)
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Peer;
+    use super::{caps, lazy_pieces, Peer, MAX_LAZY_PIECES, SNUB_SECS};
     use crate::buffers::Buffer;
     use crate::control::cio::{test, CIO};
     use crate::torrent::Message;
+    use std::time::Duration;
+
+    #[test]
+    fn test_snubbed() {
+        let mut peer = Peer::test_from_stats(0, 0, 0);
+        peer.remote_status.choked = false;
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(0, 16_384, 16_384);
+        assert!(!peer.check_snubbed());
+        peer.requested[0].2 -= Duration::from_secs(SNUB_SECS);
+        assert!(peer.check_snubbed());
+        assert!(!peer.check_snubbed());
+        assert!(peer.snubbed());
+        // No more requests are queued while one is outstanding
+        assert_eq!(peer.queue_reqs(), None);
+
+        let mut p = Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut p).unwrap();
+        assert!(!peer.snubbed());
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let mut peer = Peer::test_from_stats(0, 0, 0);
+        peer.remote_status.choked = false;
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(0, 16_384, 16_384);
+        peer.cancel_request(0, 16_384, 16_384);
+        assert_eq!(peer.queued, 1);
+        assert_eq!(peer.requested.len(), 1);
+
+        // A cancelled request answered anyway doesn't free another slot
+        let mut p = Message::Piece {
+            index: 0,
+            begin: 16_384,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        };
+        peer.handle_msg(&mut p).unwrap();
+        assert_eq!(peer.queued, 1);
+        assert_eq!(peer.requested[0].1, 0);
+
+        peer.request_rejected(0, 0);
+        assert_eq!(peer.queued, 0);
+        assert!(peer.requested.is_empty());
+    }
+
+    #[test]
+    fn test_lazy_pieces() {
+        assert!(lazy_pieces(1).is_empty());
+        assert_eq!(lazy_pieces(2).len(), 1);
+        for _ in 0..20 {
+            let mut pieces = lazy_pieces(100);
+            assert!(!pieces.is_empty() && pieces.len() <= MAX_LAZY_PIECES);
+            assert!(pieces.iter().all(|&p| p < 100));
+            pieces.sort();
+            pieces.dedup();
+            assert!(!pieces.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut peer = Peer::test_from_stats(0, 0, 0);
+        assert!(peer.idle());
+        peer.remote_status.interested = true;
+        assert!(!peer.idle());
+        peer.remote_status.interested = false;
+        peer.stat.add_ul(16_384);
+        peer.stat.tick();
+        assert!(!peer.idle());
+    }
 
     #[test]
     fn test_cancel() {
@@ -601,5 +1115,86 @@ mod tests {
         assert_eq!(wq.len(), 2);
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
+        assert_eq!(peer.take_cancelled(), 16_384);
+
+        // Blocks still being read are dropped once the read completes
+        peer.reading(3, 0, 16_384);
+        peer.reading(3, 16_384, 16_384);
+        let mut c = Message::Cancel {
+            index: 3,
+            begin: 0,
+            length: 16_384,
+        };
+        peer.handle_msg(&mut c).unwrap();
+        assert!(!peer.read_done(3, 0));
+        assert!(peer.read_done(3, 16_384));
+        // Cancels for blocks already sent avoided nothing
+        peer.handle_msg(&mut c).unwrap();
+        assert_eq!(peer.take_cancelled(), 16_384);
+        assert_eq!(peer.take_cancelled(), 0);
+    }
+
+    #[test]
+    fn test_fast() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        // Fast messages need the extension to be negotiated first
+        assert!(peer.handle_msg(&mut Message::HaveAll).is_err());
+        peer.caps.set_rsv(caps::local_rsv());
+
+        peer.handle_msg(&mut Message::HaveAll).unwrap();
+        assert!(peer.pieces().complete());
+        assert_eq!(peer.pieces().len(), 4);
+        peer.handle_msg(&mut Message::AllowedFast(2)).unwrap();
+        peer.handle_msg(&mut Message::AllowedFast(7)).unwrap();
+        assert_eq!(peer.allowed_fast().iter().collect::<Vec<_>>(), vec![&2]);
+        peer.handle_msg(&mut Message::SuggestPiece(1)).unwrap();
+        assert_eq!(peer.piece_cache(), &vec![1]);
+
+        // Choking rejects every request outside of the allowed fast set
+        peer.fast_set = vec![1];
+        peer.unchoke();
+        peer.reading(0, 0, 16_384);
+        peer.reading(1, 0, 16_384);
+        peer.choke();
+        assert!(!peer.can_upload(0));
+        assert!(peer.can_upload(1));
+        let wq = tcio
+            .get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        assert_eq!(
+            wq.back(),
+            Some(&Message::RejectRequest {
+                index: 0,
+                begin: 0,
+                length: 16_384
+            })
+        );
+        assert!(!peer.read_done(0, 0));
+        assert!(peer.read_done(1, 0));
+    }
+
+    #[test]
+    fn test_dont_have() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.handle_msg(&mut Message::Have(1)).unwrap();
+        assert!(!peer.dont_have(2));
+        assert!(!peer.dont_have(40));
+        assert!(peer.dont_have(1));
+        assert!(!peer.dont_have(1));
+        assert_eq!(peer.piece_count, 0);
+        // The piece may be advertised again once recovered
+        peer.handle_msg(&mut Message::Have(1)).unwrap();
+        assert_eq!(peer.piece_count, 1);
+    }
+
+    #[test]
+    fn test_handshake_rsv() {
+        // All extensions are advertised by default
+        match Message::handshake(&[0; 20], &[0; 20]) {
+            Message::Handshake { rsv, .. } => assert_eq!(caps::local_rsv(), rsv),
+            _ => unreachable!(),
+        }
     }
 }
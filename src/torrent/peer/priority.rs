@@ -0,0 +1,124 @@
+//! Canonical peer priority (BEP 40).
+//!
+//! Both ends of a potential connection compute the same priority from
+//! their pair of addresses. Preferring high priority peers when choosing
+//! whom to connect to or drop makes the swarm settle on the same
+//! connections from both sides, instead of peers churning through each
+//! other at random.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::util::{crc32c, FHashMap};
+
+/// Most distinct addresses tallied before the votes are reset
+const MAX_VOTES: usize = 16;
+
+/// Computes the canonical priority of a connection between two
+/// addresses, or None if they're of different families.
+pub fn canonical(a: SocketAddr, b: SocketAddr) -> Option<u32> {
+    if a.ip() == b.ip() {
+        let (lo, hi) = sorted(a.port(), b.port());
+        return Some(crc32c(&[lo.to_be_bytes(), hi.to_be_bytes()].concat()));
+    }
+    let (a, b) = match (a.ip(), b.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let (a, b) = (a.octets(), b.octets());
+            let mask = if a[..3] == b[..3] {
+                [0xff, 0xff, 0xff, 0xff]
+            } else if a[..2] == b[..2] {
+                [0xff, 0xff, 0xff, 0x00]
+            } else {
+                [0xff, 0xff, 0x55, 0x00]
+            };
+            (masked(&a, &mask), masked(&b, &mask))
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            // The same masks, applied to the /64 network prefix
+            let (a, b) = (&a.octets()[..8], &b.octets()[..8]);
+            let mask = if a[..7] == b[..7] {
+                [0xff; 8]
+            } else if a[..6] == b[..6] {
+                [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]
+            } else {
+                [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x55, 0x00]
+            };
+            (masked(a, &mask), masked(b, &mask))
+        }
+        _ => return None,
+    };
+    let (lo, hi) = sorted(a, b);
+    Some(crc32c(&[lo, hi].concat()))
+}
+
+fn masked(ip: &[u8], mask: &[u8]) -> Vec<u8> {
+    ip.iter().zip(mask).map(|(b, m)| b & m).collect()
+}
+
+fn sorted<T: Ord>(a: T, b: T) -> (T, T) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Tallies the address peers report seeing us at, which is needed to
+/// compute priorities.
+#[derive(Debug, Default)]
+pub struct ExternalIp {
+    votes: FHashMap<IpAddr, u32>,
+}
+
+impl ExternalIp {
+    pub fn new() -> ExternalIp {
+        Default::default()
+    }
+
+    pub fn vote(&mut self, ip: IpAddr) {
+        if self.votes.len() >= MAX_VOTES && !self.votes.contains_key(&ip) {
+            self.votes.clear();
+        }
+        *self.votes.entry(ip).or_insert(0) += 1;
+    }
+
+    /// The address reported by the most peers, if any.
+    pub fn get(&self) -> Option<IpAddr> {
+        self.votes
+            .iter()
+            .max_by_key(|&(ip, &votes)| (votes, *ip))
+            .map(|(ip, _)| *ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_bep40_vectors() {
+        let ours = addr("123.213.32.10:0");
+        assert_eq!(canonical(ours, addr("98.76.54.32:0")), Some(0xec2d_7224));
+        assert_eq!(canonical(ours, addr("123.213.32.234:0")), Some(0x9956_8189));
+        // Both sides agree
+        assert_eq!(
+            canonical(addr("98.76.54.32:0"), ours),
+            canonical(ours, addr("98.76.54.32:0"))
+        );
+        assert_eq!(canonical(ours, addr("[::1]:0")), None);
+        assert!(canonical(addr("123.213.32.10:1"), addr("123.213.32.10:2")).is_some());
+    }
+
+    #[test]
+    fn test_external_ip() {
+        let mut ip = ExternalIp::new();
+        assert_eq!(ip.get(), None);
+        ip.vote("1.2.3.4".parse().unwrap());
+        ip.vote("5.6.7.8".parse().unwrap());
+        ip.vote("5.6.7.8".parse().unwrap());
+        assert_eq!(ip.get(), Some("5.6.7.8".parse().unwrap()));
+    }
+}
@@ -23,6 +23,9 @@ enum State {
     Request,
     Cancel,
     Port,
+    Suggest,
+    Reject,
+    AllowedFast,
     Handshake { data: [u8; 68] },
     PiecePrefix,
     Piece { data: Option<Buffer>, len: u32 },
@@ -143,6 +146,11 @@ impl Reader {
                             7 => self.state = State::PiecePrefix,
                             8 => self.state = State::Cancel,
                             9 => self.state = State::Port,
+                            0x0D => self.state = State::Suggest,
+                            0x0E => return RRes::Success(Message::HaveAll),
+                            0x0F => return RRes::Success(Message::HaveNone),
+                            0x10 => self.state = State::Reject,
+                            0x11 => self.state = State::AllowedFast,
                             20 => self.state = State::ExtensionID,
                             _ => return RRes::Err(io_err_val("Invalid ID used!")),
                         }
@@ -263,6 +271,42 @@ impl Reader {
                     IOR::EOF => return RRes::Err(io_err_val("EOF")),
                     IOR::Err(e) => return RRes::Err(e),
                 },
+                State::Suggest => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::SuggestPiece(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::Reject => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let index = BigEndian::read_u32(&self.prefix[5..9]);
+                        let begin = BigEndian::read_u32(&self.prefix[9..13]);
+                        let length = BigEndian::read_u32(&self.prefix[13..17]);
+                        return RRes::Success(Message::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        });
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::AllowedFast => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::AllowedFast(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
                 State::ExtensionID => match aread(&mut self.prefix[5..6], conn) {
                     IOR::Complete => {
                         let id = self.prefix[5];
@@ -302,8 +346,8 @@ impl State {
         match *self {
             State::Len => 4,
             State::ID => 5,
-            State::Have => 9,
-            State::Request | State::Cancel => 17,
+            State::Have | State::Suggest | State::AllowedFast => 9,
+            State::Request | State::Cancel | State::Reject => 17,
             State::PiecePrefix => 13,
             State::Port => 7,
             State::Handshake { .. } => 68,
@@ -513,6 +557,28 @@ mod tests {
         test_message(data, Message::Port(6881));
     }
 
+    #[test]
+    fn test_read_fast() {
+        test_message(
+            vec![0u8, 0, 0, 5, 0x0D, 0, 0, 0, 2],
+            Message::SuggestPiece(2),
+        );
+        test_message(vec![0u8, 0, 0, 1, 0x0E], Message::HaveAll);
+        test_message(vec![0u8, 0, 0, 1, 0x0F], Message::HaveNone);
+        test_message(
+            vec![0u8, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3],
+            Message::RejectRequest {
+                index: 1,
+                begin: 2,
+                length: 3,
+            },
+        );
+        test_message(
+            vec![0u8, 0, 0, 5, 0x11, 0, 0, 0, 4],
+            Message::AllowedFast(4),
+        );
+    }
+
     #[test]
     fn test_read_handshake() {
         use crate::PEER_ID;
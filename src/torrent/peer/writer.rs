@@ -315,6 +315,19 @@ mod tests {
         assert_eq!(buf, [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
     }
 
+    #[test]
+    fn test_write_reject() {
+        let mut w = Writer::new();
+        let mut buf = [0u8; 17];
+        let m = Message::RejectRequest {
+            index: 1,
+            begin: 1,
+            length: 1,
+        };
+        w.write_message(m, &mut &mut buf[..]).unwrap();
+        assert_eq!(buf, [0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
+    }
+
     #[test]
     fn test_write_handshake() {
         use crate::PEER_ID;
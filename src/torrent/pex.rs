@@ -0,0 +1,199 @@
+//! Peer exchange (BEP 11).
+//!
+//! Every so often each peer is sent the changes to our peer list since the
+//! last round. Peers we haven't sent anything to yet get the full list
+//! instead, so that everyone ends up with the same view of the swarm.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+
+use crate::bencode::{self, BEncode};
+use crate::util::{addr_to_bytes, bytes_to_addrs, UHashSet};
+
+/// The peer is a seed
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP
+pub const FLAG_UTP: u8 = 0x04;
+/// The peer accepts incoming connections
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// Most peers added or dropped in a single message
+pub const MAX_PEERS: usize = 50;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Msg {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+/// Peers advertised to the swarm so far.
+#[derive(Debug, Default)]
+pub struct Pex {
+    advertised: HashMap<SocketAddr, u8>,
+    /// Peers which have been sent the full list
+    synced: UHashSet,
+}
+
+impl Msg {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// Decodes a message, ignoring malformed addresses and any peers
+    /// past the limit of a single message.
+    pub fn decode(payload: &[u8]) -> Option<Msg> {
+        let mut d = bencode::decode_buf(payload).ok()?.into_dict()?;
+        let mut bytes = |key: &str| {
+            d.remove(key)
+                .and_then(BEncode::into_bytes)
+                .unwrap_or_default()
+        };
+        let mut added = Vec::new();
+        for &(key, v6) in &[("added", false), ("added6", true)] {
+            let flags = bytes(&format!("{}.f", key));
+            for (i, a) in bytes_to_addrs(&bytes(key), v6).into_iter().enumerate() {
+                added.push((a, flags.get(i).cloned().unwrap_or(0)));
+            }
+        }
+        let mut dropped = Vec::new();
+        for &(key, v6) in &[("dropped", false), ("dropped6", true)] {
+            dropped.extend(bytes_to_addrs(&bytes(key), v6));
+        }
+        added.truncate(MAX_PEERS);
+        dropped.truncate(MAX_PEERS);
+        Some(Msg { added, dropped })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        let mut insert = |key: &str, v: Vec<u8>| {
+            d.insert(key.to_owned(), BEncode::String(v));
+        };
+        let (a6, a4): (Vec<_>, Vec<_>) = self.added.iter().partition(|(a, _)| a.is_ipv6());
+        insert(
+            "added",
+            a4.iter().flat_map(|(a, _)| addr_to_bytes(a)).collect(),
+        );
+        insert("added.f", a4.iter().map(|(_, f)| *f).collect());
+        insert(
+            "added6",
+            a6.iter().flat_map(|(a, _)| addr_to_bytes(a)).collect(),
+        );
+        insert("added6.f", a6.iter().map(|(_, f)| *f).collect());
+        let (d6, d4): (Vec<_>, Vec<_>) = self.dropped.iter().partition(|a| a.is_ipv6());
+        insert("dropped", d4.into_iter().flat_map(addr_to_bytes).collect());
+        insert("dropped6", d6.into_iter().flat_map(addr_to_bytes).collect());
+        BEncode::Dict(d).encode_to_buf()
+    }
+}
+
+impl Pex {
+    pub fn new() -> Pex {
+        Default::default()
+    }
+
+    /// Moves the advertised peers towards `current`, returning the changes
+    /// for peers which have already been synced. Changes beyond the limit
+    /// of a message are left for the next round.
+    pub fn update(&mut self, current: &HashMap<SocketAddr, u8>) -> Msg {
+        let mut msg = Msg::default();
+        for (addr, flags) in current {
+            if msg.added.len() == MAX_PEERS {
+                break;
+            }
+            if self.advertised.get(addr) != Some(flags) {
+                msg.added.push((*addr, *flags));
+            }
+        }
+        for addr in self.advertised.keys() {
+            if msg.dropped.len() == MAX_PEERS {
+                break;
+            }
+            if !current.contains_key(addr) {
+                msg.dropped.push(*addr);
+            }
+        }
+        for addr in &msg.dropped {
+            self.advertised.remove(addr);
+        }
+        self.advertised.extend(msg.added.iter().cloned());
+        msg
+    }
+
+    /// Returns the full list for a peer if it hasn't been sent it yet.
+    pub fn sync(&mut self, pid: usize) -> Option<Msg> {
+        if !self.synced.insert(pid) {
+            return None;
+        }
+        let added = self
+            .advertised
+            .iter()
+            .take(MAX_PEERS)
+            .map(|(a, f)| (*a, *f))
+            .collect();
+        Some(Msg {
+            added,
+            dropped: Vec::new(),
+        })
+    }
+
+    pub fn remove_peer(&mut self, pid: usize) {
+        self.synced.remove(&pid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_msg() {
+        let msg = Msg {
+            added: vec![
+                (addr("1.2.3.4:5"), FLAG_SEED),
+                (addr("[::1]:6881"), FLAG_REACHABLE),
+            ],
+            dropped: vec![addr("4.3.2.1:65535")],
+        };
+        assert_eq!(Msg::decode(&msg.encode()), Some(msg));
+
+        // Flags are optional, and partial addresses are ignored
+        let msg = Msg::decode(b"d5:added8:\x01\x02\x03\x04\x00\x05\x01\x02e").unwrap();
+        assert_eq!(msg.added, vec![(addr("1.2.3.4:5"), 0)]);
+        assert!(msg.dropped.is_empty());
+        assert_eq!(Msg::decode(b"i5e"), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut pex = Pex::new();
+        let mut current = HashMap::new();
+        for i in 0..(MAX_PEERS + 10) {
+            current.insert(SocketAddr::new(addr("1.1.1.1:0").ip(), i as u16), 0);
+        }
+        assert_eq!(pex.update(&current).added.len(), MAX_PEERS);
+        // New peers get everything advertised so far, once
+        assert_eq!(pex.sync(0).unwrap().added.len(), MAX_PEERS);
+        assert_eq!(pex.sync(0), None);
+
+        let msg = pex.update(&current);
+        assert_eq!(msg.added.len(), 10);
+        assert!(msg.dropped.is_empty());
+        assert!(pex.update(&current).is_empty());
+
+        // Changed flags are sent as another addition
+        let first = addr("1.1.1.1:2");
+        current.insert(first, FLAG_SEED);
+        current.remove(&addr("1.1.1.1:1"));
+        let msg = pex.update(&current);
+        assert_eq!(msg.added, vec![(first, FLAG_SEED)]);
+        assert_eq!(msg.dropped, vec![addr("1.1.1.1:1")]);
+
+        pex.remove_peer(0);
+        assert!(pex.sync(0).is_some());
+    }
+}
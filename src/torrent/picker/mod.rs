@@ -1,3 +1,4 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time;
@@ -5,6 +6,7 @@ use std::time;
 use crate::control::cio;
 use crate::torrent::{Bitfield, Info, Peer};
 use crate::util::FHashSet;
+use crate::CONFIG;
 
 mod rarest;
 mod sequential;
@@ -18,8 +20,9 @@ pub struct Picker {
     scale: u32,
     last_piece_scale: u32,
     last_piece: u32,
-    /// Number of detected seeders
-    seeders: u16,
+    /// Peers which were seeders when added. Their pieces aren't counted
+    /// towards availability, as they'd raise every piece equally.
+    seeders: FHashSet<usize>,
     /// Currently active requests
     downloading: HashMap<Block, Request>,
     /// Blocks requested/completed per piece picked
@@ -31,8 +34,24 @@ pub struct Picker {
     unpicked: Bitfield,
     /// The current picker in use
     picker: PickerKind,
-    /// Piece priorities
+    /// Piece priorities, including any raise from aging
     priorities: Vec<u8>,
+    /// Piece priorities given by their files, which aging raises
+    base: Vec<u8>,
+    /// When each piece was last picked, or became wanted, from which
+    /// its aging is counted
+    waiting: Vec<time::Instant>,
+    /// Whether few enough wanted blocks are missing that they may be
+    /// requested from several peers at once
+    endgame: bool,
+    /// Pieces ahead of a streamed file's playback position, most urgent
+    /// first
+    stream: Vec<u32>,
+    /// Pieces with a deadline, soonest first
+    deadlines: Vec<u32>,
+    /// Whether the first and last pieces of wanted files get the highest
+    /// priority
+    first_last: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -68,10 +87,18 @@ struct Request {
     num_reqd: usize,
 }
 
+/// Upper bound on `picker.dup_requests`
 const MAX_DUP_REQS: usize = 3;
-const MAX_PC_SIZE: usize = 50;
 const MAX_DL_REREQ: usize = 150;
+/// Time the most urgent streamed piece's requests have before they're
+/// retried, with each piece after it getting a second more
+const STREAM_TIMEOUT: u64 = 2;
 const REQ_TIMEOUT: u64 = 10;
+/// Peers with the highest download rates, which pieces with a deadline
+/// are requested from
+const DEADLINE_PEERS: usize = 4;
+/// Highest piece priority
+const MAX_PRIORITY: u8 = 5;
 
 impl Picker {
     /// Creates a new picker, which will select over
@@ -104,13 +131,20 @@ impl Picker {
             last_piece,
             last_piece_scale,
             downloading,
-            seeders: 0,
+            seeders: FHashSet::default(),
             unpicked: pieces.clone(),
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
+            base: vec![3; info.pieces() as usize],
+            waiting: vec![time::Instant::now(); info.pieces() as usize],
+            endgame: false,
+            stream: Vec::new(),
+            deadlines: Vec::new(),
+            first_last: false,
             blocks,
         };
         picker.set_priorities(priorities, info);
+        picker.update_endgame();
         picker
     }
 
@@ -134,9 +168,15 @@ impl Picker {
             let reqd = self.blocks[block.index as usize].0;
             let _fully_reqd = reqd == self.scale as usize
                 || (block.index == self.last_piece && reqd == self.last_piece_scale as usize);
-            let deadline = (REQ_TIMEOUT as isize
+            let mut deadline = (REQ_TIMEOUT as isize
                 + (3 - self.priorities[block.index as usize] as isize))
                 as u64;
+            if let Some(i) = self.stream.iter().position(|&p| p == block.index) {
+                deadline = cmp::min(deadline, STREAM_TIMEOUT + i as u64);
+            }
+            if self.deadlines.contains(&block.index) {
+                deadline = cmp::min(deadline, STREAM_TIMEOUT);
+            }
             if req.requested_at.elapsed().as_secs() >= deadline && !self.stalled.contains(block) {
                 expired += 1;
                 self.stalled.insert(*block);
@@ -145,6 +185,11 @@ impl Picker {
         if expired != 0 {
             debug!("Expired {} chunks!", expired);
         }
+        self.update_endgame();
+        let aging = CONFIG.picker.aging_secs;
+        if aging != 0 {
+            self.age(aging, CONFIG.picker.aging_max);
+        }
         if !self.downloading.is_empty() {
             debug!(
                 "Unpicked: {}/{}, Downloading: {}",
@@ -155,11 +200,49 @@ impl Picker {
         }
     }
 
+    /// Enters endgame once no more than `picker.endgame_blocks` wanted
+    /// blocks are left to be downloaded, counting those already requested.
+    fn update_endgame(&mut self) {
+        if self.blocks.is_empty() {
+            self.endgame = false;
+            return;
+        }
+        let unpicked: usize = (0..self.priorities.len() as u32)
+            .filter(|&p| self.priorities[p as usize] != 0 && !self.unpicked.has_bit(u64::from(p)))
+            .map(|p| {
+                let scale = if p == self.last_piece {
+                    self.last_piece_scale
+                } else {
+                    self.scale
+                };
+                (scale as usize).saturating_sub(self.blocks[p as usize].0)
+            })
+            .sum();
+        let priorities = &self.priorities;
+        let requested = self
+            .downloading
+            .keys()
+            .filter(|b| priorities[b.index as usize] != 0)
+            .count();
+        let endgame = unpicked + requested <= CONFIG.picker.endgame_blocks;
+        if endgame && !self.endgame {
+            debug!("Entering endgame, {} blocks left", unpicked + requested);
+        }
+        self.endgame = endgame;
+    }
+
     /// Attempts to select a block for a peer.
     pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<Block> {
+        if peer.rank < DEADLINE_PEERS {
+            if let Some(b) = self.pick_deadline(peer) {
+                return Some(b);
+            }
+        }
+
         if !self.stalled.is_empty() {
             let block = self.stalled.iter().cloned().find(|b| {
                 peer.pieces().has_bit(u64::from(b.index))
+                    && self.priorities[b.index as usize] != 0
                     && !self.downloading[b].has_peer(peer.id())
             });
             if let Some(b) = block {
@@ -171,6 +254,10 @@ impl Picker {
             }
         }
 
+        if let Some(b) = self.pick_stream(peer) {
+            return Some(b);
+        }
+
         let piece = match self.picker {
             PickerKind::Sequential(ref mut p) => p.pick(peer),
             PickerKind::Rarest(ref mut p) => p.pick(peer),
@@ -180,10 +267,139 @@ impl Picker {
             .or_else(|| self.pick_dl(peer))
     }
 
+    /// Picks a block of the piece with the soonest deadline the peer has,
+    /// retrying stalled requests for such pieces first.
+    fn pick_deadline<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        if self.deadlines.is_empty() {
+            return None;
+        }
+        let stalled = {
+            let deadlines = &self.deadlines;
+            let downloading = &self.downloading;
+            let priorities = &self.priorities;
+            self.stalled
+                .iter()
+                .cloned()
+                .filter(|b| {
+                    peer.pieces().has_bit(u64::from(b.index))
+                        && priorities[b.index as usize] != 0
+                        && !downloading[b].has_peer(peer.id())
+                })
+                .filter_map(|b| deadlines.iter().position(|&p| p == b.index).map(|i| (i, b)))
+                .min_by_key(|&(i, b)| (i, b.offset))
+                .map(|(_, b)| b)
+        };
+        if let Some(b) = stalled {
+            self.stalled.remove(&b);
+            if let Some(req) = self.downloading.get_mut(&b) {
+                req.force_rereq(peer.id(), peer.rank);
+            }
+            return Some(b);
+        }
+        let piece = self.deadlines.iter().cloned().find(|&p| {
+            peer.pieces().has_bit(u64::from(p))
+                && !self.unpicked.has_bit(u64::from(p))
+                && self.priorities[p as usize] != 0
+        })?;
+        Some(self.pick_piece(piece, peer.id(), peer.rank))
+    }
+
+    /// Sets the pieces which have a deadline, soonest first. They're picked
+    /// before any other piece, but only for the fastest peers.
+    pub fn set_deadlines(&mut self, pieces: Vec<u32>) {
+        self.deadlines = pieces;
+    }
+
+    /// Picks a block from the most urgent streamed piece the peer has.
+    fn pick_stream<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let piece = self.stream.iter().cloned().find(|&p| {
+            peer.pieces().has_bit(u64::from(p))
+                && !self.unpicked.has_bit(u64::from(p))
+                && self.priorities[p as usize] != 0
+        })?;
+        Some(self.pick_piece(piece, peer.id(), peer.rank))
+    }
+
+    /// Sets the pieces ahead of a streamed file's playback position, in
+    /// the order they're needed. They're picked before any other piece.
+    pub fn set_stream(&mut self, pieces: Vec<u32>) {
+        self.stream = pieces;
+    }
+
+    /// Attempts to select a block for a peer which is choking us, from
+    /// the pieces it allows us to request anyway.
+    pub fn pick_allowed<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let piece = {
+            let unpicked = &self.unpicked;
+            let priorities = &self.priorities;
+            peer.allowed_fast().iter().cloned().find(|&p| {
+                peer.pieces().has_bit(u64::from(p))
+                    && !unpicked.has_bit(u64::from(p))
+                    && priorities[p as usize] != 0
+            })
+        }?;
+        Some(self.pick_piece(piece, peer.id(), peer.rank))
+    }
+
+    /// Picks an entire piece which nobody has started on yet, requesting all
+    /// of its blocks for `id`. This is used for sources such as web seeds, which
+    /// have every piece and are best used for whole pieces at a time.
+    pub fn pick_whole(&mut self, id: usize) -> Option<u32> {
+        let piece = {
+            let unpicked = &self.unpicked;
+            let blocks = &self.blocks;
+            let priorities = &self.priorities;
+            let eligible = |p: u32| {
+                !unpicked.has_bit(u64::from(p))
+                    && blocks[p as usize].0 == 0
+                    && priorities[p as usize] != 0
+            };
+            match self.picker {
+                PickerKind::Sequential(ref p) => p.pick_any(eligible),
+                PickerKind::Rarest(ref p) => p.pick_any(eligible),
+            }
+        }?;
+        let scale = if piece == self.last_piece {
+            self.last_piece_scale
+        } else {
+            self.scale
+        };
+        for _ in 0..scale {
+            self.pick_piece(piece, id, 0);
+        }
+        Some(piece)
+    }
+
+    /// Drops all outstanding requests `id` has for blocks of a piece,
+    /// leaving them to be picked up by other peers.
+    pub fn release_piece(&mut self, piece: u32, id: usize) {
+        for (_, req) in self
+            .downloading
+            .iter_mut()
+            .filter(|&(b, _)| b.index == piece)
+        {
+            req.remove_peer(id);
+        }
+    }
+
+    /// Drops every outstanding request `id` has, marking the blocks as
+    /// stalled so other peers pick them up first.
+    pub fn release_peer(&mut self, id: usize) {
+        for (b, req) in self.downloading.iter_mut() {
+            if req.has_peer(id) {
+                req.remove_peer(id);
+                self.stalled.insert(*b);
+            }
+        }
+    }
+
     /// Picks a block from a given piece for a peer
     fn pick_piece(&mut self, piece: u32, id: usize, rank: usize) -> Block {
         self.blocks[piece as usize].0 += 1;
         let amnt = self.blocks[piece as usize].0;
+        if amnt == 1 {
+            self.reset_age(piece);
+        }
         let offset = (amnt - 1) as u32 * 16_384;
         if amnt == self.scale as usize
             || (piece == self.last_piece && amnt == self.last_piece_scale as usize)
@@ -204,9 +420,19 @@ impl Picker {
 
     /// Attempts to pick the highest priority piece in the dl q
     fn pick_dl<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let priorities = &self.priorities;
+        let max_reqs = if self.endgame {
+            CONFIG.picker.dup_requests
+        } else {
+            1
+        };
         self.downloading
             .iter_mut()
-            .filter(|&(_, ref req)| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
+            .filter(|&(b, ref req)| {
+                req.num_reqd < max_reqs
+                    && !req.has_peer(peer.id())
+                    && priorities[b.index as usize] != 0
+            })
             .take(MAX_DL_REREQ)
             .fold(None, |c: Option<(&Block, &mut Request)>, this| match &c {
                 Some(min) => {
@@ -224,17 +450,23 @@ impl Picker {
             })
     }
 
-    /// Marks a block as completed. Returns a result indicating if the block
-    /// was actually requested, the success value containing a bool indicating
-    /// if the block is complete.
-    pub fn completed<F: FnMut(usize)>(&mut self, b: Block, mut cancel: F) -> Result<bool, ()> {
+    /// Marks a block received from `from` as completed, calling `cancel` for
+    /// every other peer it was requested from. Returns a result indicating if
+    /// the block was actually requested, the success value containing a bool
+    /// indicating if the block is complete.
+    pub fn completed<F: FnMut(usize)>(
+        &mut self,
+        b: Block,
+        from: usize,
+        mut cancel: F,
+    ) -> Result<bool, ()> {
         self.stalled.remove(&b);
         let dl = self.downloading.remove(&b);
         let dl = match dl {
             Some(dl) => dl,
             None => return Err(()),
         };
-        for peer in dl.reqd_from.iter() {
+        for peer in dl.peers().iter().filter(|&&p| p != from) {
             cancel(*peer);
         }
 
@@ -249,6 +481,18 @@ impl Picker {
         }
     }
 
+    /// Releases a block a peer rejected our request for, returning
+    /// whether it had been requested from the peer.
+    pub fn rejected(&mut self, b: Block, id: usize) -> bool {
+        match self.downloading.get_mut(&b) {
+            Some(req) if req.has_peer(id) => {
+                req.remove_peer(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
     pub fn have_block(&mut self, b: Block) -> bool {
         !self.downloading.contains_key(&b)
     }
@@ -272,52 +516,152 @@ impl Picker {
         }
     }
 
+    /// Accounts for a peer retracting a piece (BEP 54), before the peer
+    /// forgets it. Seeders aren't counted per piece, so one which loses a
+    /// piece is counted like any other peer from then on.
+    pub fn piece_unavailable<T: cio::CIO>(&mut self, peer: &Peer<T>, idx: u32) {
+        if self.seeders.remove(&peer.id()) {
+            if let PickerKind::Rarest(ref mut p) = self.picker {
+                p.add_peer(peer);
+            }
+        }
+        if let PickerKind::Rarest(ref mut p) = self.picker {
+            p.piece_unavailable(idx);
+        }
+    }
+
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
         if peer.pieces().complete() {
-            self.seeders += 1;
+            self.seeders.insert(peer.id());
         } else if let PickerKind::Rarest(ref mut p) = self.picker {
             p.add_peer(peer);
         }
     }
 
     pub fn remove_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
-        // Have to consider situation where a peer became a seeder but joined as leecher.
-        if peer.pieces().complete() && self.seeders > 0 {
-            self.seeders -= 1;
-        } else if let PickerKind::Rarest(ref mut p) = self.picker {
-            p.remove_peer(peer);
+        // Peers which became seeders after joining are still counted per piece
+        if !self.seeders.remove(&peer.id()) {
+            if let PickerKind::Rarest(ref mut p) = self.picker {
+                p.remove_peer(peer);
+            }
         }
 
         for (_, req) in self.downloading.iter_mut() {
-            if let Some((idx, _)) = req
-                .reqd_from
-                .iter()
-                .enumerate()
-                .find(|&(_, id)| *id == peer.id())
-            {
-                req.num_reqd -= 1;
-                req.reqd_from[idx] = req.reqd_from[req.num_reqd];
+            req.remove_peer(peer.id());
+        }
+    }
+
+    /// Drops outstanding requests for blocks of pieces which are no longer
+    /// wanted, calling `cancel` for each peer they were requested from. The
+    /// blocks are kept so they'll be requested again if the priority rises.
+    pub fn cancel_unwanted<F: FnMut(Block, usize)>(&mut self, mut cancel: F) {
+        let priorities = &self.priorities;
+        for (b, req) in self
+            .downloading
+            .iter_mut()
+            .filter(|(b, _)| priorities[b.index as usize] == 0)
+        {
+            for peer in req.peers() {
+                cancel(*b, *peer);
             }
+            req.num_reqd = 0;
         }
     }
 
-    /// Alters the picker to sequential/non sequential. If changing
-    /// from sequential to non sequential, peer state will need to be loaded
-    /// after this.
+    /// Alters the picker to sequential/non sequential. Peer state isn't
+    /// kept either way, so peers have to be added again after this.
     pub fn change_picker(&mut self, sequential: bool) {
+        self.seeders.clear();
         self.picker = if sequential {
             PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
         } else {
             PickerKind::Rarest(rarest::Picker::new(&self.unpicked))
         };
+        self.apply_priorities();
     }
 
+    /// Sets piece priorities from the given file priorities. Pieces which
+    /// were skipped before start waiting now, the rest keep their aging.
     pub fn set_priorities(&mut self, pri: &[u8], info: &Arc<Info>) {
         self.unapply_priorities();
-        self.priorities = generate_piece_pri(pri, info);
+        let base = generate_piece_pri(pri, info, self.first_last);
+        let now = time::Instant::now();
+        for (piece, &pri) in base.iter().enumerate() {
+            if self.base[piece] == 0 && pri != 0 {
+                self.waiting[piece] = now;
+            }
+        }
+        self.base = base;
+        self.priorities = self.base.clone();
         self.apply_priorities();
     }
 
+    /// Sets whether the first and last pieces of wanted files are picked
+    /// at the highest priority, applying the given file priorities.
+    pub fn set_first_last(&mut self, enabled: bool, pri: &[u8], info: &Arc<Info>) {
+        self.first_last = enabled;
+        self.set_priorities(pri, info);
+    }
+
+    /// Raises the priority of every waiting piece by a level for each
+    /// `secs` it has waited, up to max, so a low priority piece ends up
+    /// ahead of a steady stream of fresher, higher priority ones. Pieces
+    /// which are no longer waiting drop back to their base priority.
+    fn age(&mut self, secs: u64, max: u8) {
+        let unpicked = &self.unpicked;
+        let blocks = &self.blocks;
+        let waiting = &self.waiting;
+        let aged: Vec<_> = self
+            .base
+            .iter()
+            .enumerate()
+            .map(|(piece, &pri)| {
+                let picked = unpicked.has_bit(piece as u64)
+                    || !matches!(blocks.get(piece), Some(b) if b.0 == 0);
+                if pri == 0 || pri >= max || picked {
+                    return pri;
+                }
+                let levels = waiting[piece].elapsed().as_secs() / secs;
+                (u64::from(pri) + levels).min(u64::from(max)) as u8
+            })
+            .collect();
+        if aged == self.priorities {
+            return;
+        }
+        if let PickerKind::Rarest(ref mut p) = self.picker {
+            for (piece, (&new, &old)) in aged.iter().zip(&self.priorities).enumerate() {
+                for _ in old..new {
+                    p.piece_unavailable(piece as u32);
+                }
+                for _ in new..old {
+                    p.piece_available(piece as u32);
+                }
+            }
+            self.priorities = aged;
+        } else {
+            self.priorities = aged;
+            self.apply_priorities();
+        }
+    }
+
+    /// Drops a piece's aging bonus once it's been picked, counting any
+    /// further wait from now.
+    fn reset_age(&mut self, piece: u32) {
+        let idx = piece as usize;
+        self.waiting[idx] = time::Instant::now();
+        if self.priorities[idx] == self.base[idx] {
+            return;
+        }
+        if let PickerKind::Rarest(ref mut p) = self.picker {
+            for _ in self.base[idx]..self.priorities[idx] {
+                p.piece_available(piece);
+            }
+        }
+        // The sequential picker only reorders pieces when rebuilt, by
+        // which time this one will usually have been picked fully
+        self.priorities[idx] = self.base[idx];
+    }
+
     pub fn apply_priorities(&mut self) {
         if self.is_sequential() {
             self.picker = PickerKind::Sequential(sequential::Picker::with_pri(
@@ -362,7 +706,7 @@ impl Picker {
     }
 }
 
-fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
+fn generate_piece_pri(pri: &[u8], info: &Arc<Info>, first_last: bool) -> Vec<u8> {
     // Map piece -> priority
     let mut priorities = Vec::with_capacity(info.pieces() as usize);
     // If a piece is completely in a file, just assign that pri.
@@ -374,6 +718,18 @@ fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
             .expect("Piece must have locations!");
         priorities.push(max);
     }
+    if first_last {
+        // Media players read a file's header and index before playing it
+        let piece_len = u64::from(info.piece_len);
+        let mut start = 0;
+        for (file, f) in info.files.iter().enumerate() {
+            if pri[file] != 0 && !f.pad && f.length != 0 {
+                priorities[(start / piece_len) as usize] = MAX_PRIORITY;
+                priorities[((start + f.length - 1) / piece_len) as usize] = MAX_PRIORITY;
+            }
+            start += f.length;
+        }
+    }
     priorities
 }
 
@@ -433,7 +789,19 @@ impl Request {
         }
     }
 
+    /// Peers the block is currently requested from
+    fn peers(&self) -> &[usize] {
+        &self.reqd_from[..self.num_reqd]
+    }
+
+    fn remove_peer(&mut self, peer: usize) {
+        if let Some(idx) = self.peers().iter().position(|&id| id == peer) {
+            self.num_reqd -= 1;
+            self.reqd_from[idx] = self.reqd_from[self.num_reqd];
+        }
+    }
+
     fn has_peer(&self, peer: usize) -> bool {
-        self.reqd_from.contains(&peer)
+        self.peers().contains(&peer)
     }
 }
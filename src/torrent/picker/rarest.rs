@@ -1,9 +1,12 @@
 // Implementation based off of http://blog.libtorrent.org/2011/11/writing-a-fast-piece-picker/
+use std::collections::VecDeque;
 use std::ops::IndexMut;
 
-use super::MAX_PC_SIZE;
+use rand::{self, Rng};
+
 use crate::control::cio;
 use crate::torrent::{Bitfield, Peer};
+use crate::CONFIG;
 
 #[derive(Clone, Debug)]
 pub struct Picker {
@@ -13,6 +16,11 @@ pub struct Picker {
     priorities: Vec<usize>,
     /// Index mapping a piece to a position in the pieces field
     piece_idx: Vec<PieceInfo>,
+    /// Pieces most recently picked, and so being written, newest last
+    recent: VecDeque<u32>,
+    /// Distance in pieces from recently picked ones within which equally
+    /// rare pieces are preferred
+    locality: u32,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -29,6 +37,8 @@ struct PieceInfo {
 }
 
 const PIECE_COMPLETE_DEC: usize = 100;
+/// Recently picked pieces which others are picked near
+const RECENT_PIECES: usize = 8;
 
 impl Picker {
     pub fn new(pieces: &Bitfield) -> Picker {
@@ -44,6 +54,8 @@ impl Picker {
             pieces: (0..pieces.len() as u32).collect(),
             piece_idx,
             priorities: vec![pieces.len() as usize],
+            recent: VecDeque::with_capacity(RECENT_PIECES),
+            locality: CONFIG.picker.locality,
         };
 
         // Start every piece at an availability of 6.
@@ -121,26 +133,90 @@ impl Picker {
         }
 
         if peer.piece_cache().is_empty() {
-            for piece in &self.pieces {
-                if peer.pieces().has_bit(u64::from(*piece))
-                    && self.piece_idx[*piece as usize].status == PieceStatus::Incomplete
-                {
-                    peer.piece_cache().push(*piece);
+            let window = peer.prefetch();
+            let mut rng = rand::thread_rng();
+            let mut start = 0;
+            'buckets: for &end in &self.priorities {
+                if start == end {
+                    continue;
+                }
+                // Equally rare pieces near ones being written are taken
+                // first, sparing spinning disks from seeking around
+                for d in 1..=self.locality {
+                    for &r in self.recent.iter().rev() {
+                        for piece in [r.checked_add(d), r.checked_sub(d)].iter().flatten() {
+                            if self.candidate(*piece, peer, start, end) {
+                                peer.piece_cache().push(*piece);
+                                if peer.piece_cache().len() >= window {
+                                    break 'buckets;
+                                }
+                            }
+                        }
+                    }
                 }
-                if peer.piece_cache().len() >= MAX_PC_SIZE {
-                    break;
+                // Equally rare pieces are scanned from a random point, so
+                // that peers break ties differently rather than all going
+                // for the same pieces
+                let offset = rng.gen_range(start, end);
+                let bucket = self.pieces[offset..end]
+                    .iter()
+                    .chain(&self.pieces[start..offset]);
+                for piece in bucket {
+                    if peer.pieces().has_bit(u64::from(*piece))
+                        && self.piece_idx[*piece as usize].status == PieceStatus::Incomplete
+                        && !peer.piece_cache().contains(piece)
+                    {
+                        peer.piece_cache().push(*piece);
+                    }
+                    if peer.piece_cache().len() >= window {
+                        break 'buckets;
+                    }
                 }
+                start = end;
             }
             peer.piece_cache().reverse();
         }
 
-        let piece = peer.piece_cache().last();
-        if let Some(p) = piece {
+        let piece = peer.piece_cache().last().cloned();
+        if let Some(ref p) = piece {
             if (self.piece_idx[*p as usize].availability % 2) == 0 {
                 self.inc_pri(*p);
+                if self.recent.len() == RECENT_PIECES {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back(*p);
+            }
+        }
+        piece
+    }
+
+    /// Whether a piece is one of the given bucket's which the peer can be
+    /// asked for, and isn't yet lined up for it.
+    fn candidate<T: cio::CIO>(
+        &self,
+        piece: u32,
+        peer: &mut Peer<T>,
+        start: usize,
+        end: usize,
+    ) -> bool {
+        match self.piece_idx.get(piece as usize) {
+            Some(info) => {
+                info.idx >= start
+                    && info.idx < end
+                    && info.status == PieceStatus::Incomplete
+                    && peer.pieces().has_bit(u64::from(piece))
+                    && !peer.piece_cache().contains(&piece)
             }
+            None => false,
         }
-        piece.cloned()
+    }
+
+    /// Returns the rarest incomplete piece accepted by the filter.
+    pub fn pick_any<F: Fn(u32) -> bool>(&self, f: F) -> Option<u32> {
+        self.pieces
+            .iter()
+            .cloned()
+            .find(|p| self.piece_idx[*p as usize].status == PieceStatus::Incomplete && f(*p))
     }
 
     pub fn incomplete(&mut self, piece: u32) {
@@ -204,6 +280,69 @@ mod tests {
         picker.completed(1);
     }
 
+    #[test]
+    fn test_ties() {
+        let b = Bitfield::new(32);
+        let mut seeder = Peer::test_from_pieces(0, b.clone());
+        for i in 0..32 {
+            seeder.pieces_mut().set_bit(i);
+        }
+        // Equally rare pieces are picked in a different order each time
+        let mut first = Vec::new();
+        for _ in 0..10 {
+            let mut picker = Picker::new(&b);
+            picker.add_peer(&seeder);
+            seeder.piece_cache().clear();
+            first.push(picker.pick(&mut seeder).unwrap());
+        }
+        first.dedup();
+        assert!(first.len() > 1);
+
+        // Rarer pieces still come first
+        let mut picker = Picker::new(&b);
+        let mut other = Peer::test_from_pieces(0, b.clone());
+        other.pieces_mut().set_bit(7);
+        picker.add_peer(&seeder);
+        picker.add_peer(&other);
+        for _ in 0..31 {
+            seeder.piece_cache().clear();
+            let piece = picker.pick(&mut seeder).unwrap();
+            assert_ne!(piece, 7);
+            picker.completed(piece);
+        }
+        seeder.piece_cache().clear();
+        assert_eq!(picker.pick(&mut seeder), Some(7));
+    }
+
+    #[test]
+    fn test_locality() {
+        let b = Bitfield::new(64);
+        let mut seeder = Peer::test_from_pieces(0, b.clone());
+        for i in 0..64 {
+            seeder.pieces_mut().set_bit(i);
+        }
+        let mut other = Peer::test_from_pieces(0, b.clone());
+        for i in 0..64 {
+            if i % 8 == 3 {
+                other.pieces_mut().set_bit(i);
+            }
+        }
+        for _ in 0..10 {
+            let mut picker = Picker::new(&b);
+            picker.locality = 2;
+            picker.add_peer(&seeder);
+            picker.add_peer(&other);
+            seeder.piece_cache().clear();
+            let first = picker.pick(&mut seeder).unwrap();
+            assert_ne!(first % 8, 3);
+            // The next piece is an equally rare one close by
+            seeder.piece_cache().clear();
+            let next = picker.pick(&mut seeder).unwrap();
+            assert_ne!(next % 8, 3);
+            assert!((i64::from(next) - i64::from(first)).abs() <= 2);
+        }
+    }
+
     #[test]
     fn test_unavailable() {
         let b = Bitfield::new(3);
@@ -72,7 +72,17 @@ impl Picker {
     pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
         self.pieces[self.piece_idx..]
             .iter()
-            .find(|p| peer.pieces().has_bit(u64::from(p.pos)))
+            .find(|p| {
+                p.status == PieceStatus::Incomplete && peer.pieces().has_bit(u64::from(p.pos))
+            })
+            .map(|p| p.pos)
+    }
+
+    /// Returns the first incomplete piece accepted by the filter.
+    pub fn pick_any<F: Fn(u32) -> bool>(&self, f: F) -> Option<u32> {
+        self.pieces[self.piece_idx..]
+            .iter()
+            .find(|p| p.status == PieceStatus::Incomplete && f(p.pos))
             .map(|p| p.pos)
     }
 
@@ -101,10 +111,10 @@ impl Picker {
     }
 
     fn update_piece_idx(&mut self) {
-        for i in self.piece_idx..self.pieces.len() {
-            if self.pieces[i].status == PieceStatus::Complete {
-                self.piece_idx += 1;
-            }
+        while self.piece_idx < self.pieces.len()
+            && self.pieces[self.piece_idx].status == PieceStatus::Complete
+        {
+            self.piece_idx += 1;
         }
     }
 }
@@ -134,4 +144,19 @@ mod tests {
         picker.incomplete(1);
         assert_eq!(picker.pick(&peer), Some(1));
     }
+
+    #[test]
+    fn test_out_of_order_completion() {
+        let b = Bitfield::new(3);
+        let mut picker = Picker::new(&b);
+        let mut peer = Peer::test_from_pieces(0, b);
+        peer.pieces_mut().set_bit(1);
+        peer.pieces_mut().set_bit(2);
+        // A piece finishing before the ones ahead of it is neither handed
+        // out again nor skips them
+        picker.completed(1);
+        assert_eq!(picker.pick(&peer), Some(2));
+        peer.pieces_mut().set_bit(0);
+        assert_eq!(picker.pick(&peer), Some(0));
+    }
 }
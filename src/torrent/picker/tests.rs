@@ -1,12 +1,26 @@
 use super::{Block, Picker};
 use crate::control;
+use crate::torrent::fixtures::TorrentBuilder;
 use crate::torrent::{Bitfield, Info, Peer as TGPeer};
 use rand::distributions::{Distribution, Range};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
 type TPeer = TGPeer<control::cio::test::TCIO>;
 
+/// A torrent with a file of each of the given lengths.
+fn files_info(lengths: &[u64]) -> Info {
+    lengths
+        .iter()
+        .enumerate()
+        .fold(TorrentBuilder::multi("t"), |b, (n, &len)| {
+            b.file(&n.to_string(), len)
+        })
+        .build()
+}
+
 struct Simulation {
     cfg: TestCfg,
     ticks: usize,
@@ -96,7 +110,7 @@ impl Simulation {
                     let ref mut received = self.peers.borrow_mut()[req.peer];
                     received
                         .picker
-                        .completed(Block::new(req.piece, 0), |_| ())
+                        .completed(Block::new(req.piece, 0), peer.data.id(), |_| ())
                         .unwrap();
                     received.data.pieces_mut().set_bit(req.piece as u64);
                     if received.data.pieces().complete() {
@@ -264,7 +278,7 @@ fn test_seq_picker() {
     for i in 0..10 {
         let mut canceled = None;
         assert_eq!(
-            p.completed(Block::new(i, 0), |p| {
+            p.completed(Block::new(i, 0), usize::MAX, |p| {
                 canceled = Some(p);
             }),
             Ok(true)
@@ -276,3 +290,338 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_pick_whole() {
+    let i = TorrentBuilder::single("t", 131_072)
+        .piece_len(32_768)
+        .build();
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+
+    // A peer starting on a piece means it shouldn't be picked as a whole
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick_whole(usize::MAX), Some(1));
+    assert_eq!(p.pick_whole(usize::MAX), Some(2));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 16_384)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(3, 0)));
+    assert_eq!(p.pick_whole(usize::MAX), None);
+
+    // Once released, the blocks should be handed out to peers
+    p.release_piece(1, usize::MAX);
+    p.release_piece(2, usize::MAX);
+    let mut picked = vec![];
+    for _ in 0..5 {
+        picked.extend(p.pick(&mut peer));
+    }
+    picked.sort_by_key(|b| (b.index, b.offset));
+    assert_eq!(
+        picked,
+        vec![
+            Block::new(1, 0),
+            Block::new(1, 16_384),
+            Block::new(2, 0),
+            Block::new(2, 16_384),
+            Block::new(3, 16_384),
+        ]
+    );
+}
+
+/// Moves every piece's start of waiting `secs` into the past.
+fn wait(p: &mut Picker, secs: u64) {
+    for w in &mut p.waiting {
+        *w -= Duration::from_secs(secs);
+    }
+}
+
+#[test]
+fn test_priority_aging() {
+    let i = Arc::new(TorrentBuilder::many_files("t", 3, 16_384).build());
+    let b = Bitfield::new(3);
+    let mut p = Picker::new(&i, &b, &[2, 3, 3]);
+    p.change_picker(true);
+    let mut pb = Bitfield::new(3);
+    for i in 0..3 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+
+    // Nothing is raised before a full period has been waited
+    p.age(2, 5);
+    assert_eq!(p.priorities, vec![2, 3, 3]);
+    // Waiting pieces are raised a level per period, up to the cap
+    wait(&mut p, 2);
+    p.age(1, 5);
+    assert_eq!(p.priorities, vec![4, 5, 5]);
+    // Picking a piece drops it back to its base priority
+    assert_eq!(p.pick(&mut peer), Some(Block::new(1, 0)));
+    assert_eq!(p.priorities, vec![4, 3, 5]);
+    // Nothing is raised past a lower cap, and raises above it are undone
+    p.age(1, 3);
+    assert_eq!(p.priorities, vec![3, 3, 3]);
+    // Once caught up, the low priority piece is picked in order
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(2, 0)));
+}
+
+#[test]
+fn test_aging_starvation() {
+    // The last file is wanted at the lowest priority, while a new file
+    // at normal priority becomes wanted every period
+    let i = Arc::new(TorrentBuilder::many_files("t", 8, 16_384).build());
+    let mut pb = Bitfield::new(8);
+    for i in 0..8 {
+        pb.set_bit(i);
+    }
+    for &sequential in &[true, false] {
+        let mut pri = vec![0; 8];
+        pri[7] = 1;
+        let mut p = Picker::new(&i, &Bitfield::new(8), &pri);
+        p.change_picker(sequential);
+        let mut peer = TPeer::test_from_pieces(0, pb.clone());
+
+        let mut picked = Vec::new();
+        for file in 0..7 {
+            wait(&mut p, 1);
+            pri[file] = 3;
+            p.set_priorities(&pri, &i);
+            p.age(1, 5);
+            picked.push(p.pick(&mut peer).unwrap().index);
+            if picked.last() == Some(&7) {
+                break;
+            }
+        }
+        // Raised above the newest pieces once it's waited three periods
+        assert_eq!(picked.last(), Some(&7));
+        assert!(picked.len() <= 3);
+        if sequential {
+            assert_eq!(picked, vec![0, 1, 7]);
+        }
+    }
+}
+
+#[test]
+fn test_file_priorities() {
+    // Pieces 1 and 2 are shared by the skipped file and its neighbours
+    let i = files_info(&[24_576, 16_384, 24_576]);
+    let i = Arc::new(i);
+    let mut p = Picker::new(&i, &Bitfield::new(4), &[1, 0, 5]);
+    assert_eq!(p.priorities, vec![1, 1, 5, 5]);
+    p.change_picker(true);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    let mut picked: Vec<_> = (0..2).map(|_| p.pick(&mut peer).unwrap().index).collect();
+    picked.sort();
+    assert_eq!(picked, vec![2, 3]);
+
+    // Only pieces entirely within skipped files are never picked
+    p.set_priorities(&[0, 0, 5], &i);
+    assert_eq!(p.pick(&mut peer), None);
+    p.set_priorities(&[1, 0, 0], &i);
+    let mut picked: Vec<_> = (0..2).map(|_| p.pick(&mut peer).unwrap().index).collect();
+    picked.sort();
+    assert_eq!(picked, vec![0, 1]);
+}
+
+#[test]
+fn test_first_last() {
+    let i = files_info(&[49_152, 16_384, 65_536]);
+    let i = Arc::new(i);
+    let mut p = Picker::new(&i, &Bitfield::new(8), &[3, 0, 3]);
+    p.set_first_last(true, &[3, 0, 3], &i);
+    // Skipped files are left alone
+    assert_eq!(p.priorities, vec![5, 3, 5, 0, 5, 3, 3, 5]);
+    // The setting sticks as file priorities change
+    p.set_priorities(&[3, 3, 0], &i);
+    assert_eq!(p.priorities, vec![5, 3, 5, 5, 0, 0, 0, 0]);
+    p.set_first_last(false, &[3, 3, 0], &i);
+    assert_eq!(p.priorities, vec![3, 3, 3, 3, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_cancel_dups() {
+    let i = TorrentBuilder::many_files("t", 2, 16_384).build();
+    let i = Arc::new(i);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new(&i, &b, &[3, 3]);
+    p.change_picker(true);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+    let mut peers: Vec<_> = (0..4)
+        .map(|id| TPeer::test_from_pieces(id, pb.clone()))
+        .collect();
+
+    // Once every piece is picked, blocks are requested from up to three peers
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(1, 0)));
+    for peer in &mut peers[1..3] {
+        assert!(p.pick(peer).is_some());
+        assert!(p.pick(peer).is_some());
+    }
+    assert_eq!(p.pick(&mut peers[3]), None);
+
+    // The peer which delivered the block isn't sent a cancel
+    let mut cancelled = vec![];
+    assert_eq!(
+        p.completed(Block::new(0, 0), 1, |id| cancelled.push(id)),
+        Ok(true)
+    );
+    cancelled.sort();
+    assert_eq!(cancelled, vec![0, 2]);
+
+    // Requests for unwanted pieces are cancelled, and resumed if wanted again
+    p.set_priorities(&[3, 0], &i);
+    let mut cancelled = vec![];
+    p.cancel_unwanted(|b, id| cancelled.push((b.index, id)));
+    cancelled.sort();
+    assert_eq!(cancelled, vec![(1, 0), (1, 1), (1, 2)]);
+    assert_eq!(p.pick(&mut peers[3]), None);
+    p.set_priorities(&[3, 3], &i);
+    assert_eq!(p.pick(&mut peers[3]), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_pick_allowed() {
+    let i = TorrentBuilder::many_files("t", 4, 16_384).build();
+    let i = Arc::new(i);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new(&i, &b, &[3, 3, 0, 3]);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    pb.unset_bit(3);
+    let mut peer = TPeer::test_from_pieces(0, pb);
+
+    // Only allowed pieces which the peer has and we want are picked
+    assert_eq!(p.pick_allowed(&peer), None);
+    peer.allowed_fast_mut().extend(&[2, 3]);
+    assert_eq!(p.pick_allowed(&peer), None);
+    peer.allowed_fast_mut().insert(1);
+    assert_eq!(p.pick_allowed(&peer), Some(Block::new(1, 0)));
+
+    // Rejected blocks are released for other peers
+    assert!(p.rejected(Block::new(1, 0), 0));
+    assert!(!p.rejected(Block::new(1, 0), 0));
+    let mut ob = Bitfield::new(4);
+    ob.set_bit(1);
+    let mut other = TPeer::test_from_pieces(1, ob);
+    assert_eq!(p.pick(&mut other), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_release_peer() {
+    let i = TorrentBuilder::many_files("t", 2, 16_384).build();
+    let b = Bitfield::new(2);
+    let mut p = Picker::new(&Arc::new(i), &b, &[3, 3]);
+    p.change_picker(true);
+    let mut pb = Bitfield::new(2);
+    for i in 0..2 {
+        pb.set_bit(i);
+    }
+    let mut snubbing = TPeer::test_from_pieces(0, pb.clone());
+    let mut peer = TPeer::test_from_pieces(1, pb);
+
+    assert_eq!(p.pick(&mut snubbing), Some(Block::new(0, 0)));
+    // Blocks of a snubbing peer go to other peers ahead of new ones
+    p.release_peer(0);
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(1, 0)));
+}
+
+#[test]
+fn test_endgame() {
+    let i = TorrentBuilder::many_files("t", 200, 16_384).build();
+    let i = Arc::new(i);
+    let b = Bitfield::new(200);
+    let mut p = Picker::new(&i, &b, &[3; 200]);
+    let mut pb = Bitfield::new(200);
+    pb.set_bit(0);
+    let mut peers: Vec<_> = (0..2)
+        .map(|id| TPeer::test_from_pieces(id, pb.clone()))
+        .collect();
+
+    // Blocks aren't requested twice while plenty are missing
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut peers[1]), None);
+
+    // Unwanted blocks don't count as missing
+    let mut pri = vec![0; 200];
+    pri[0] = 3;
+    p.set_priorities(&pri, &i);
+    p.tick();
+    assert_eq!(p.pick(&mut peers[1]), Some(Block::new(0, 0)));
+}
+
+#[test]
+fn test_stream() {
+    let i = TorrentBuilder::many_files("t", 4, 16_384).build();
+    let b = Bitfield::new(4);
+    let mut p = Picker::new(&Arc::new(i), &b, &[3, 3, 0, 3]);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let mut peers: Vec<_> = (0..2)
+        .map(|id| TPeer::test_from_pieces(id, pb.clone()))
+        .collect();
+
+    // Streamed pieces come first and in order, unless they're unwanted
+    p.set_stream(vec![3, 2, 1]);
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(3, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(0, 0)));
+
+    // Their requests are retried well before others
+    for b in p.downloading.values_mut() {
+        b.requested_at -= Duration::from_secs(super::STREAM_TIMEOUT + 1);
+    }
+    p.tick();
+    assert_eq!(p.pick(&mut peers[1]), Some(Block::new(3, 0)));
+}
+
+#[test]
+fn test_deadlines() {
+    let i = TorrentBuilder::many_files("t", 4, 16_384).build();
+    let i = Arc::new(i);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new(&i, &b, &[3, 3, 0, 3]);
+    let mut pb = Bitfield::new(4);
+    for i in 0..4 {
+        pb.set_bit(i);
+    }
+    let mut peers: Vec<_> = (0..3)
+        .map(|id| TPeer::test_from_pieces(id, pb.clone()))
+        .collect();
+    peers[2].rank = super::DEADLINE_PEERS;
+
+    // Deadline pieces preempt streamed ones, soonest first, unless unwanted
+    p.set_stream(vec![0]);
+    p.set_deadlines(vec![3, 2, 1]);
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(3, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(0, 0)));
+
+    // Slow peers are left to the usual selection
+    let mut p = Picker::new(&i, &b, &[0, 0, 3, 5]);
+    p.set_deadlines(vec![2]);
+    assert_eq!(p.pick(&mut peers[2]), Some(Block::new(3, 0)));
+    assert_eq!(p.pick(&mut peers[0]), Some(Block::new(2, 0)));
+
+    // Their requests time out quickly and go to another fast peer
+    for b in p.downloading.values_mut() {
+        b.requested_at -= Duration::from_secs(super::STREAM_TIMEOUT + 1);
+    }
+    p.tick();
+    assert_eq!(p.pick(&mut peers[1]), Some(Block::new(2, 0)));
+}
@@ -0,0 +1,119 @@
+//! Super-seeding (BEP 16).
+//!
+//! While initially seeding, peers are told about a single piece at a time
+//! rather than everything we have. A peer is only offered another piece
+//! once the one it was given shows up at some other peer, so that uploads
+//! go towards pieces the swarm doesn't have yet instead of duplicates.
+
+use super::Bitfield;
+use crate::util::{FHashSet, UHashMap};
+
+#[derive(Debug, Default)]
+pub struct SuperSeed {
+    /// Piece each peer was last offered, if it hasn't been forwarded yet
+    offers: UHashMap<u32>,
+    /// Pieces revealed to each peer, which it may request
+    revealed: UHashMap<FHashSet<u32>>,
+}
+
+impl SuperSeed {
+    pub fn new() -> SuperSeed {
+        Default::default()
+    }
+
+    /// Whether pieces are being hidden from a peer. Peers connected
+    /// before super-seeding started already know what we have.
+    pub fn hiding(&self, pid: usize) -> bool {
+        self.revealed.contains_key(&pid)
+    }
+
+    pub fn add_peer(&mut self, pid: usize) {
+        self.revealed.insert(pid, FHashSet::default());
+    }
+
+    pub fn remove_peer(&mut self, pid: usize) {
+        self.offers.remove(&pid);
+        self.revealed.remove(&pid);
+    }
+
+    /// Whether a peer has been told we have a piece.
+    pub fn revealed(&self, pid: usize, piece: u32) -> bool {
+        self.revealed
+            .get(&pid)
+            .map(|r| r.contains(&piece))
+            .unwrap_or(true)
+    }
+
+    /// Whether a peer is waiting for its offer to be forwarded.
+    pub fn offered(&self, pid: usize) -> Option<u32> {
+        self.offers.get(&pid).cloned()
+    }
+
+    /// Picks the next piece to offer a peer: the rarest one it doesn't
+    /// have, preferring pieces which haven't been offered to anyone else.
+    pub fn offer(&mut self, pid: usize, pieces: &Bitfield, availability: &[u32]) -> Option<u32> {
+        let revealed = self.revealed.get_mut(&pid)?;
+        let offered: FHashSet<_> = self.offers.values().cloned().collect();
+        let piece = (0..availability.len() as u32)
+            .filter(|&p| !pieces.has_bit(u64::from(p)))
+            .min_by_key(|&p| (offered.contains(&p), availability[p as usize], p))?;
+        revealed.insert(piece);
+        self.offers.insert(pid, piece);
+        Some(piece)
+    }
+
+    /// Records that a peer has a piece, returning the peers which should
+    /// be offered another. Those are the peers which the piece was offered
+    /// to and has now spread from, or the peer itself if nobody else
+    /// needs the piece, as there is nobody to spread it to.
+    pub fn have(&mut self, pid: usize, piece: u32, needed: bool) -> Vec<usize> {
+        let mut done: Vec<_> = self
+            .offers
+            .iter()
+            .filter(|&(&p, &o)| o == piece && (p != pid || !needed))
+            .map(|(&p, _)| p)
+            .collect();
+        done.sort_unstable();
+        for p in &done {
+            self.offers.remove(p);
+        }
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offer() {
+        let mut s = SuperSeed::new();
+        let empty = Bitfield::new(4);
+        s.add_peer(1);
+        s.add_peer(2);
+        assert!(s.hiding(1) && !s.hiding(3));
+        assert!(s.revealed(3, 0));
+
+        // The rarest pieces go out first, and each to a different peer
+        let availability = [2, 0, 1, 0];
+        assert_eq!(s.offer(1, &empty, &availability), Some(1));
+        assert_eq!(s.offer(2, &empty, &availability), Some(3));
+        assert!(s.revealed(1, 1) && !s.revealed(1, 3));
+        assert_eq!(s.offer(3, &empty, &availability), None);
+
+        // Offers are only done once they spread to someone else
+        assert!(s.have(1, 1, true).is_empty());
+        assert_eq!(s.offered(1), Some(1));
+        assert_eq!(s.have(2, 1, true), vec![1]);
+        assert_eq!(s.offered(1), None);
+        assert_eq!(s.have(2, 3, false), vec![2]);
+
+        let mut pieces = Bitfield::new(4);
+        pieces.set_bit(1);
+        pieces.set_bit(2);
+        assert_eq!(s.offer(1, &pieces, &[0, 0, 0, 1]), Some(0));
+        assert_eq!(s.offer(2, &pieces, &[0, 0, 0, 1]), Some(3));
+        s.remove_peer(1);
+        assert!(!s.hiding(1));
+    }
+}
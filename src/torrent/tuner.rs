@@ -0,0 +1,254 @@
+//! Adaptive tuning of request parameters.
+//!
+//! Rather than fixed limits, the request queue depth of each peer, the number
+//! of unchoke slots and the prefetch window are adjusted based on how
+//! throughput and request latency respond to changes. Any of them can be
+//! pinned in the config, in which case the pinned value is always used.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+const INIT_QUEUE: u16 = 5;
+const MIN_QUEUE: u16 = 2;
+const MAX_QUEUE: u16 = 600;
+/// Seconds between adjustments of a peer's queue depth
+const QUEUE_ADJUST_SECS: u64 = 2;
+
+const INIT_SLOTS: usize = 5;
+const MIN_SLOTS: usize = 2;
+const MAX_SLOTS: usize = 20;
+
+const MIN_PREFETCH: usize = 4;
+const MAX_PREFETCH: usize = 50;
+
+/// Request queue depth of a single peer.
+///
+/// The depth grows while doing so improves throughput and keeps probing
+/// upwards while latency is stable. Once requests start queueing up at the
+/// peer, latency rises without a gain in throughput and the depth backs off.
+pub struct Queue {
+    depth: u16,
+    /// Whether the queue was filled since the last adjustment
+    saturated: bool,
+    /// Download rate at the last adjustment
+    rate: u64,
+    /// Moving average of request latency, in microseconds
+    latency: u64,
+    /// Average latency at the last adjustment
+    prev_latency: u64,
+    adjusted: Instant,
+}
+
+/// Per torrent tuning of unchoke slots and the prefetch window.
+pub struct Tuner {
+    /// Blocks per piece
+    scale: u32,
+    slots: usize,
+    /// Direction the slot count was last moved in
+    growing: bool,
+    /// Throughput with the current slot count
+    rate: Option<u64>,
+    /// Unchoke slots set for this torrent, overriding the config
+    pinned_slots: Option<usize>,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue {
+            depth: INIT_QUEUE,
+            saturated: false,
+            rate: 0,
+            latency: 0,
+            prev_latency: 0,
+            adjusted: Instant::now(),
+        }
+    }
+
+    pub fn depth(&self) -> u16 {
+        CONFIG.peer.tuning.queue_depth.unwrap_or(self.depth)
+    }
+
+    /// Records that every available request slot was used.
+    pub fn saturated(&mut self) {
+        self.saturated = true;
+    }
+
+    /// Records the time taken to fulfill a request.
+    pub fn sample(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.latency = if self.latency == 0 {
+            us
+        } else {
+            (self.latency * 7 + us) / 8
+        };
+    }
+
+    /// Adjusts the depth given the peer's current download rate.
+    pub fn update(&mut self, rate: u64) {
+        if self.adjusted.elapsed() < Duration::from_secs(QUEUE_ADJUST_SECS) {
+            return;
+        }
+        self.adjusted = Instant::now();
+        // A queue which never fills isn't what's holding the peer back
+        if self.saturated {
+            if rate > self.rate + self.rate / 20 {
+                self.depth += cmp::max(2, self.depth / 4);
+            } else if self.latency > self.prev_latency + self.prev_latency / 4 {
+                self.depth -= self.depth / 8;
+            } else {
+                self.depth += 1;
+            }
+            self.depth = self.depth.clamp(MIN_QUEUE, MAX_QUEUE);
+        }
+        self.saturated = false;
+        self.rate = rate;
+        self.prev_latency = self.latency;
+    }
+}
+
+impl Tuner {
+    pub fn new(scale: u32) -> Tuner {
+        Tuner {
+            scale: cmp::max(scale, 1),
+            slots: INIT_SLOTS,
+            growing: true,
+            rate: None,
+            pinned_slots: None,
+        }
+    }
+
+    pub fn set_scale(&mut self, scale: u32) {
+        self.scale = cmp::max(scale, 1);
+    }
+
+    pub fn pinned_slots(&self) -> Option<usize> {
+        self.pinned_slots
+    }
+
+    pub fn pin_slots(&mut self, slots: Option<usize>) {
+        self.pinned_slots = slots;
+    }
+
+    /// Slots pinned for this torrent or in the config, if any.
+    fn fixed_slots(&self) -> Option<usize> {
+        self.pinned_slots.or(CONFIG.peer.tuning.unchoke_slots)
+    }
+
+    pub fn unchoke_slots(&self) -> usize {
+        self.fixed_slots().unwrap_or(self.slots)
+    }
+
+    /// Number of slots which gives each roughly `slot_rate` of an upload
+    /// limit, if slots are scaled to the limit and aren't pinned.
+    pub fn limited_slots(&self, limit: Option<u64>) -> Option<usize> {
+        if self.fixed_slots().is_some() {
+            return None;
+        }
+        let per_slot = CONFIG.peer.tuning.slot_rate?;
+        Some(((limit? / per_slot) as usize).clamp(1, MAX_SLOTS))
+    }
+
+    /// Number of pieces to pick ahead for a peer, enough to keep
+    /// a few times its request queue busy.
+    pub fn prefetch(&self, depth: u16) -> usize {
+        if let Some(p) = CONFIG.peer.tuning.prefetch {
+            return p;
+        }
+        let pieces = u32::from(depth).div_ceil(self.scale);
+        (pieces as usize * 4).clamp(MIN_PREFETCH, MAX_PREFETCH)
+    }
+
+    /// Moves the slot count a step further in the same direction while
+    /// throughput improves, and reverses direction once it drops.
+    /// Slots are only tuned while peers are waiting to be unchoked.
+    pub fn update_slots(&mut self, rate: u64, waiting: bool) -> usize {
+        if !waiting {
+            self.rate = None;
+            return self.unchoke_slots();
+        }
+        if let Some(prev) = self.rate {
+            if rate < prev {
+                self.growing = !self.growing;
+            }
+            self.slots = if self.growing {
+                cmp::min(self.slots + 1, MAX_SLOTS)
+            } else {
+                cmp::max(self.slots - 1, MIN_SLOTS)
+            };
+        }
+        self.rate = Some(rate);
+        self.unchoke_slots()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adjust(q: &mut Queue, rate: u64, latency_ms: u64) {
+        q.sample(Duration::from_millis(latency_ms));
+        q.saturated();
+        q.adjusted -= Duration::from_secs(QUEUE_ADJUST_SECS);
+        q.update(rate);
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let mut q = Queue::new();
+        // Growing throughput grows the queue quickly
+        for i in 1..16 {
+            adjust(&mut q, i * 100_000, 50);
+        }
+        let grown = q.depth;
+        assert!(grown > 50, "depth {} should have grown", grown);
+
+        // Latency rising without a throughput gain shrinks it
+        for i in 1..5 {
+            adjust(&mut q, 1_500_000, 50 + i * 100);
+        }
+        assert!(q.depth < grown);
+
+        // Unsaturated queues are left alone
+        let depth = q.depth;
+        q.adjusted -= Duration::from_secs(QUEUE_ADJUST_SECS);
+        q.update(10_000_000);
+        assert_eq!(q.depth, depth);
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut t = Tuner::new(64);
+        assert_eq!(t.update_slots(100, true), INIT_SLOTS);
+        assert_eq!(t.update_slots(200, true), INIT_SLOTS + 1);
+        assert_eq!(t.update_slots(300, true), INIT_SLOTS + 2);
+        // Throughput dropped, so back off
+        assert_eq!(t.update_slots(250, true), INIT_SLOTS + 1);
+        assert_eq!(t.update_slots(260, true), INIT_SLOTS);
+        // Nothing to tune without waiting peers
+        assert_eq!(t.update_slots(0, false), INIT_SLOTS);
+    }
+
+    #[test]
+    fn test_pinned_slots() {
+        let mut t = Tuner::new(64);
+        t.pin_slots(Some(2));
+        assert_eq!(t.update_slots(100, true), 2);
+        assert_eq!(t.update_slots(200, true), 2);
+        // Pinned slots aren't scaled to the upload limit either
+        assert_eq!(t.limited_slots(Some(1_000_000)), None);
+        t.pin_slots(None);
+        assert_eq!(t.unchoke_slots(), INIT_SLOTS + 1);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut t = Tuner::new(64);
+        assert_eq!(t.prefetch(5), MIN_PREFETCH);
+        assert_eq!(t.prefetch(200), 16);
+        // Smaller pieces need more of them to cover the queue
+        t.set_scale(8);
+        assert_eq!(t.prefetch(600), MAX_PREFETCH);
+    }
+}
@@ -0,0 +1,401 @@
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::torrent::Info;
+use crate::tracker;
+use crate::util::http::encode_param;
+use crate::util::FHashMap;
+
+/// Maximum number of pieces fetched from a single source at once
+const MAX_INFLIGHT: usize = 2;
+/// Number of corrupt pieces a source may serve before it is blacklisted
+const MAX_CORRUPT: u32 = 2;
+const BACKOFF_SECS: u64 = 15;
+const MAX_BACKOFF_SECS: u64 = 1800;
+
+/// Tracks the BEP19 and BEP17 web seeds of a torrent, spreading whole
+/// piece downloads across them and keeping tabs on which ones misbehave.
+pub struct WebSeeds {
+    sources: Vec<Source>,
+    /// Pieces being fetched and the data received for them so far
+    pending: FHashMap<u32, Pending>,
+    /// Pieces awaiting validation and the source which served them
+    validating: FHashMap<u32, usize>,
+}
+
+struct Source {
+    url: Arc<Url>,
+    style: Style,
+    /// Pieces currently being fetched
+    active: usize,
+    /// Pieces served which passed validation
+    served: u64,
+    /// Consecutive failed requests
+    failures: u32,
+    /// Pieces served which failed validation
+    corrupt: u32,
+    retry: Option<Instant>,
+    banned: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Style {
+    /// BEP19 seeds serve the torrent's files, which are fetched by range
+    GetRight,
+    /// BEP17 seeds serve pieces, named in the query
+    Hoffman,
+}
+
+struct Pending {
+    source: usize,
+    data: Vec<u8>,
+    /// Piece offsets of the segments yet to arrive
+    remaining: Vec<u32>,
+}
+
+/// The id under which requests to a source are registered with the picker.
+pub fn peer_id(source: usize) -> usize {
+    usize::MAX - source
+}
+
+impl WebSeeds {
+    pub fn new(web_seeds: &[Arc<Url>], http_seeds: &[Arc<Url>]) -> WebSeeds {
+        let web_seeds = web_seeds.iter().map(|url| (url, Style::GetRight));
+        let http_seeds = http_seeds.iter().map(|url| (url, Style::Hoffman));
+        WebSeeds {
+            sources: web_seeds
+                .chain(http_seeds)
+                .map(|(url, style)| Source {
+                    url: url.clone(),
+                    style,
+                    active: 0,
+                    served: 0,
+                    failures: 0,
+                    corrupt: 0,
+                    retry: None,
+                    banned: false,
+                })
+                .collect(),
+            pending: FHashMap::default(),
+            validating: FHashMap::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Returns the least loaded healthy source which can take another piece.
+    pub fn next_source(&self) -> Option<usize> {
+        let now = Instant::now();
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|&(_, s)| {
+                !s.banned && s.active < MAX_INFLIGHT && s.retry.map(|r| r <= now).unwrap_or(true)
+            })
+            .min_by_key(|&(_, s)| (s.active, s.failures, cmp::Reverse(s.served)))
+            .map(|(i, _)| i)
+    }
+
+    /// Generates the requests needed to fetch a piece from a source,
+    /// one for each file the piece spans, or a single one for the whole
+    /// piece from BEP17 seeds.
+    pub fn fetch(
+        &mut self,
+        tid: usize,
+        info: &Arc<Info>,
+        source: usize,
+        piece: u32,
+    ) -> Vec<tracker::WebSeed> {
+        let url = self.sources[source].url.clone();
+        let reqs = match self.sources[source].style {
+            Style::GetRight => match file_requests(tid, info, &url, piece) {
+                Some(reqs) => reqs,
+                None => {
+                    info!("Web seed {} is not a valid base URL", url);
+                    self.sources[source].banned = true;
+                    return Vec::new();
+                }
+            },
+            Style::Hoffman => vec![tracker::WebSeed {
+                id: tid,
+                url: piece_url(&url, info, piece),
+                piece,
+                offset: 0,
+                start: 0,
+                len: info.piece_len(piece),
+                ranged: false,
+            }],
+        };
+        self.sources[source].active += 1;
+        self.pending.insert(
+            piece,
+            Pending {
+                source,
+                data: vec![0; info.piece_len(piece) as usize],
+                remaining: reqs.iter().map(|r| r.offset).collect(),
+            },
+        );
+        reqs
+    }
+
+    /// Stores a segment of a piece. Once every segment has arrived the
+    /// serving source and the complete piece are returned.
+    pub fn received(&mut self, piece: u32, offset: u32, data: &[u8]) -> Option<(usize, Vec<u8>)> {
+        {
+            let p = self.pending.get_mut(&piece)?;
+            let pos = p.remaining.iter().position(|&o| o == offset)?;
+            let start = offset as usize;
+            p.data
+                .get_mut(start..start + data.len())?
+                .copy_from_slice(data);
+            p.remaining.swap_remove(pos);
+            if !p.remaining.is_empty() {
+                return None;
+            }
+        }
+        let p = self.pending.remove(&piece)?;
+        let s = &mut self.sources[p.source];
+        s.active -= 1;
+        s.failures = 0;
+        s.retry = None;
+        Some((p.source, p.data))
+    }
+
+    /// Abandons a piece after a failed request, backing off the source
+    /// which was serving it. Sources which said when to retry are backed
+    /// off for that long instead.
+    pub fn failed(&mut self, piece: u32, retry_secs: Option<u64>) -> Option<usize> {
+        let p = self.pending.remove(&piece)?;
+        let s = &mut self.sources[p.source];
+        s.active -= 1;
+        s.failures += 1;
+        let backoff = cmp::min(
+            retry_secs.unwrap_or(BACKOFF_SECS << cmp::min(s.failures - 1, 10)),
+            MAX_BACKOFF_SECS,
+        );
+        debug!("Web seed {} failed, retrying in {}s", s.url, backoff);
+        s.retry = Some(Instant::now() + Duration::from_secs(backoff));
+        Some(p.source)
+    }
+
+    /// Notes that a piece served by source is awaiting validation.
+    pub fn validating(&mut self, piece: u32, source: usize) {
+        self.validating.insert(piece, source);
+    }
+
+    /// Records the result of validating a piece, blacklisting sources
+    /// which repeatedly serve corrupt data.
+    pub fn validated(&mut self, piece: u32, valid: bool) {
+        let s = match self.validating.remove(&piece) {
+            Some(source) => &mut self.sources[source],
+            None => return,
+        };
+        if valid {
+            s.served += 1;
+        } else {
+            s.corrupt += 1;
+            if s.corrupt >= MAX_CORRUPT && !s.banned {
+                info!(
+                    "Blacklisting web seed {} after {} corrupt pieces",
+                    s.url, s.corrupt
+                );
+                s.banned = true;
+            }
+        }
+    }
+}
+
+/// Builds the URL a file can be fetched from. Single file torrents use
+/// the URL as is unless it names a directory, otherwise the file path
+/// (which includes the torrent name) is appended.
+fn file_url(base: &Url, info: &Info, file: usize) -> Option<Url> {
+    if info.files.len() == 1 && !base.path().ends_with('/') {
+        return Some(base.clone());
+    }
+    let mut url = base.clone();
+    {
+        let mut segments = url.path_segments_mut().ok()?;
+        segments.pop_if_empty();
+        for c in info.files[file].path.iter() {
+            segments.push(c.to_str()?);
+        }
+    }
+    Some(url)
+}
+
+/// Builds the ranged requests for each file segment of a piece.
+fn file_requests(
+    tid: usize,
+    info: &Arc<Info>,
+    base: &Url,
+    piece: u32,
+) -> Option<Vec<tracker::WebSeed>> {
+    Info::piece_disk_locs(info, piece)
+        .filter(|l| l.end > l.start)
+        .map(|loc| {
+            Some(tracker::WebSeed {
+                id: tid,
+                url: file_url(base, info, loc.file)?,
+                piece,
+                offset: loc.start as u32,
+                start: loc.offset,
+                len: (loc.end - loc.start) as u32,
+                ranged: true,
+            })
+        })
+        .collect()
+}
+
+/// Builds the URL a piece is fetched from on a BEP17 seed.
+fn piece_url(base: &Url, info: &Info, piece: u32) -> Url {
+    let mut query = base.query().map(|q| format!("{}&", q)).unwrap_or_default();
+    let mut hash = Vec::new();
+    encode_param(&info.hash, &mut hash);
+    query.push_str("info_hash=");
+    query.push_str(&String::from_utf8_lossy(&hash));
+    query.push_str(&format!("&piece={}", piece));
+    let mut url = base.clone();
+    url.set_query(Some(&query));
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::fixtures::TorrentBuilder;
+
+    fn multi_file() -> Arc<Info> {
+        let info = TorrentBuilder::multi("name")
+            .file("a b", 20_000)
+            .file("dir/c", 16_384 * 3 - 20_000)
+            .build();
+        Arc::new(info)
+    }
+
+    fn seeds(urls: &[&str]) -> WebSeeds {
+        let urls: Vec<_> = urls
+            .iter()
+            .map(|u| Arc::new(Url::parse(u).unwrap()))
+            .collect();
+        WebSeeds::new(&urls, &[])
+    }
+
+    #[test]
+    fn test_file_url() {
+        let info = TorrentBuilder::single("file.iso", 16_384).build();
+        let base = Url::parse("http://example.com/pub/file.iso").unwrap();
+        assert_eq!(file_url(&base, &info, 0).unwrap(), base);
+        let base = Url::parse("http://example.com/pub/").unwrap();
+        assert_eq!(
+            file_url(&base, &info, 0).unwrap().as_str(),
+            "http://example.com/pub/file.iso"
+        );
+
+        let info = multi_file();
+        let base = Url::parse("http://example.com/pub").unwrap();
+        assert_eq!(
+            file_url(&base, &info, 0).unwrap().as_str(),
+            "http://example.com/pub/name/a%20b"
+        );
+        assert_eq!(
+            file_url(&base, &info, 1).unwrap().as_str(),
+            "http://example.com/pub/name/dir/c"
+        );
+    }
+
+    #[test]
+    fn test_fetch_segments() {
+        let info = multi_file();
+        let mut ws = seeds(&["http://example.com/"]);
+        let reqs = ws.fetch(0, &info, 0, 1);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(
+            (reqs[0].offset, reqs[0].start, reqs[0].len),
+            (0, 16_384, 3_616)
+        );
+        assert_eq!(
+            (reqs[1].offset, reqs[1].start, reqs[1].len),
+            (3_616, 0, 12_768)
+        );
+
+        assert_eq!(ws.received(1, 3_616, &[2; 12_768]), None);
+        let (source, data) = ws.received(1, 0, &[1; 3_616]).unwrap();
+        assert_eq!(source, 0);
+        assert_eq!(data.len(), 16_384);
+        assert!(data[..3_616].iter().all(|&b| b == 1));
+        assert!(data[3_616..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_fetch_pieces() {
+        let mut info = Info::with_pieces(2);
+        info.hash[0] = 0xab;
+        info.hash[19] = b'z';
+        let info = Arc::new(info);
+        let urls = [Arc::new(
+            Url::parse("http://example.com/seed.php?k=v").unwrap(),
+        )];
+        let mut ws = WebSeeds::new(&[], &urls);
+        let reqs = ws.fetch(0, &info, 0, 1);
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(
+            reqs[0].url.as_str(),
+            format!(
+                "http://example.com/seed.php?k=v&info_hash=%AB{}z&piece=1",
+                "%00".repeat(18)
+            )
+        );
+        assert_eq!((reqs[0].offset, reqs[0].len), (0, 16_384));
+        assert!(!reqs[0].ranged);
+        assert_eq!(ws.received(1, 0, &[1; 16_384]).unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_load_balancing() {
+        let info = multi_file();
+        let mut ws = seeds(&["http://a.com/", "http://b.com/"]);
+        for piece in 0..3 {
+            let s = ws.next_source().unwrap();
+            ws.fetch(0, &info, s, piece);
+        }
+        assert_eq!(ws.sources[0].active + ws.sources[1].active, 3);
+        assert!(ws.sources.iter().all(|s| s.active >= 1));
+
+        // A failing source is backed off
+        let s = ws.pending[&0].source;
+        assert_eq!(ws.failed(0, None), Some(s));
+        assert_eq!(ws.next_source(), Some(1 - s));
+        ws.fetch(0, &info, 1 - s, 0);
+        assert_eq!(ws.next_source(), None);
+
+        // Unless the source said when to come back
+        let s = ws.pending[&1].source;
+        ws.failed(1, Some(0));
+        assert_eq!(ws.next_source(), Some(s));
+        let s = ws.pending[&2].source;
+        ws.failed(2, Some(u64::MAX));
+        let retry = ws.sources[s].retry.unwrap() - Instant::now();
+        assert!(retry > Duration::from_secs(MAX_BACKOFF_SECS - 60));
+        assert!(retry <= Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn test_blacklist() {
+        let mut ws = seeds(&["http://a.com/", "http://b.com/"]);
+        ws.validating(0, 0);
+        ws.validated(0, false);
+        assert_eq!(ws.next_source(), Some(0));
+        ws.validating(1, 0);
+        ws.validated(1, false);
+        ws.validating(2, 1);
+        ws.validated(2, true);
+        assert_eq!(ws.next_source(), Some(1));
+        ws.sources[1].active = MAX_INFLIGHT;
+        assert_eq!(ws.next_source(), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time;
+
+use chrono::{DateTime, Duration, Utc};
+use url::Url;
+
+use crate::disk;
+use crate::tracker::TrackerResponse;
+use crate::CONFIG;
+
+const SESSION_FILE: &str = "tracker_cache";
+/// How long a tracker response remains usable after it was received
+const CACHE_SECS: i64 = 10 * 60;
+const FLUSH_SECS: u64 = 60;
+
+/// Remembers the last successful response of each tracker for a torrent,
+/// so that a torrent which is re-added (or the client restarted) can
+/// connect to peers straight away rather than waiting on a fresh announce.
+pub struct Cache {
+    entries: HashMap<(String, [u8; 20]), Entry>,
+    dirty: bool,
+    flushed: time::Instant,
+    db: amy::Sender<disk::Request>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    peers: Vec<SocketAddr>,
+    interval: u32,
+    seeders: u32,
+    leechers: u32,
+    downloaded: u32,
+    received: DateTime<Utc>,
+}
+
+impl Cache {
+    pub fn new(db: amy::Sender<disk::Request>) -> Cache {
+        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        let mut data = Vec::new();
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+            f.read_to_end(&mut data).ok();
+        }
+        let mut entries: HashMap<_, _> = bincode::deserialize(&data[..]).unwrap_or_default();
+        entries.retain(|_, e: &mut Entry| e.fresh());
+        Cache {
+            entries,
+            dirty: false,
+            flushed: time::Instant::now(),
+            db,
+        }
+    }
+
+    /// Returns the cached response for the tracker/torrent pair, if still fresh.
+    pub fn get(&self, url: &Url, hash: [u8; 20]) -> Option<TrackerResponse> {
+        self.entries
+            .get(&(url.as_str().to_owned(), hash))
+            .filter(|e| e.fresh())
+            .map(|e| TrackerResponse {
+                peers: e.peers.clone(),
+                interval: e.interval,
+                seeders: e.seeders,
+                leechers: e.leechers,
+                downloaded: e.downloaded,
+            })
+    }
+
+    pub fn insert(&mut self, url: &Url, hash: [u8; 20], resp: &TrackerResponse) {
+        // Responses without peers are of no use for a quick start
+        if resp.peers.is_empty() {
+            return;
+        }
+        let entry = Entry {
+            peers: resp.peers.clone(),
+            interval: resp.interval,
+            seeders: resp.seeders,
+            leechers: resp.leechers,
+            downloaded: resp.downloaded,
+            received: Utc::now(),
+        };
+        self.entries.insert((url.as_str().to_owned(), hash), entry);
+        self.dirty = true;
+    }
+
+    /// Periodically writes the cache out to the session directory.
+    pub fn tick(&mut self) {
+        if !self.dirty || self.flushed.elapsed() < time::Duration::from_secs(FLUSH_SECS) {
+            return;
+        }
+        self.entries.retain(|_, e| e.fresh());
+        let data = bincode::serialize(&self.entries).unwrap();
+        let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        self.db.send(disk::Request::WriteFile { data, path }).ok();
+        self.flushed = time::Instant::now();
+        self.dirty = false;
+    }
+}
+
+impl Entry {
+    fn fresh(&self) -> bool {
+        Utc::now().signed_duration_since(self.received) < Duration::seconds(CACHE_SECS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let poll = amy::Poller::new().unwrap();
+        let (db, _rx) = poll.get_registrar().channel().unwrap();
+        let mut cache = Cache {
+            entries: HashMap::new(),
+            dirty: false,
+            flushed: time::Instant::now(),
+            db,
+        };
+        let url = Url::parse("http://tracker.com/announce").unwrap();
+        let mut resp = TrackerResponse::empty();
+        cache.insert(&url, [0; 20], &resp);
+        assert!(cache.get(&url, [0; 20]).is_none());
+
+        resp.peers.push("127.0.0.1:6881".parse().unwrap());
+        cache.insert(&url, [0; 20], &resp);
+        assert_eq!(cache.get(&url, [0; 20]).unwrap().peers, resp.peers);
+        assert!(cache.get(&url, [1; 20]).is_none());
+
+        let key = (url.as_str().to_owned(), [0; 20]);
+        cache.entries.get_mut(&key).unwrap().received =
+            Utc::now() - Duration::seconds(CACHE_SECS + 1);
+        assert!(cache.get(&url, [0; 20]).is_none());
+    }
+}
@@ -1,7 +1,19 @@
-use std::io;
+use std::collections::HashMap;
 use std::net::{IpAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use std::{io, mem};
 
+use sstream::SStream;
+use url::Url;
+
+use crate::tracker::http::{self, HTTPRes, TrackerState};
 use crate::tracker::{ErrorKind, Result};
+use crate::util::{self, UHashMap};
+use crate::CONFIG;
+
+/// Query id used when resolving the DoH server itself
+const BOOTSTRAP_ID: usize = usize::MAX;
+const DOH_TIMEOUT_MS: u64 = 5_000;
 
 #[derive(Debug)]
 pub struct QueryResponse {
@@ -11,8 +23,31 @@ pub struct QueryResponse {
 
 pub struct Resolver {
     pub id: usize,
-    pub res: adns::Resolver,
-    pub sock: UdpSocket,
+    res: adns::Resolver,
+    sock: UdpSocket,
+    doh: Option<Doh>,
+}
+
+/// Resolves hosts over HTTPS, making a single request per connection.
+struct Doh {
+    url: Url,
+    reg: amy::Registrar,
+    /// Hosts waiting on the DoH server's address
+    waiting: Vec<String>,
+    prefer_v4: bool,
+    lookups: HashMap<String, Lookup>,
+    conns: UHashMap<Conn>,
+}
+
+struct Lookup {
+    ids: Vec<usize>,
+    v4: bool,
+}
+
+struct Conn {
+    host: String,
+    state: TrackerState,
+    last_updated: Instant,
 }
 
 impl Resolver {
@@ -20,16 +55,237 @@ impl Resolver {
         let sock = UdpSocket::bind("0.0.0.0:0")?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
+        let mut res = if CONFIG.dns.servers.is_empty() {
+            adns::Resolver::from_resolv()?
+        } else {
+            adns::Resolver::new(&CONFIG.dns.servers)
+        };
+        res.prefer_v6(CONFIG.dns.prefer_ipv6);
+        let doh = CONFIG.dns.doh.as_ref().map(|url| Doh {
+            url: url.clone(),
+            reg: reg.clone(),
+            waiting: Vec::new(),
+            prefer_v4: !CONFIG.dns.prefer_ipv6,
+            lookups: HashMap::new(),
+            conns: UHashMap::default(),
+        });
 
-        Ok(Resolver {
-            id,
-            sock,
-            res: adns::Resolver::from_resolv()?,
-        })
+        Ok(Resolver { id, sock, res, doh })
     }
 
     pub fn new_query(&mut self, id: usize, host: &str) -> io::Result<Option<IpAddr>> {
-        self.res.query(&mut self.sock, id, host)
+        let doh = match self.doh {
+            Some(ref mut doh) => doh,
+            None => return self.res.query(&mut self.sock, id, host),
+        };
+        if let Ok(ip) = host.parse() {
+            return Ok(Some(ip));
+        }
+        if let Some(ip) = self.res.cached(host) {
+            return Ok(Some(ip));
+        }
+        if let Some(lookup) = doh.lookups.get_mut(host) {
+            lookup.ids.push(id);
+            return Ok(None);
+        }
+        doh.lookups.insert(
+            host.to_owned(),
+            Lookup {
+                ids: vec![id],
+                v4: doh.prefer_v4,
+            },
+        );
+        if let Err(e) = self.send_doh(host.to_owned()) {
+            self.doh.as_mut().unwrap().lookups.remove(host);
+            return Err(e);
+        }
+        Ok(None)
+    }
+
+    pub fn purge(&mut self) {
+        self.res.purge();
+    }
+
+    /// Cancels all outstanding lookups and closes the resolver's sockets.
+    pub fn shutdown(&mut self, reg: &amy::Registrar) {
+        self.res.cancel();
+        reg.deregister(&self.sock).ok();
+        if let Some(ref mut doh) = self.doh {
+            for (_, conn) in doh.conns.drain() {
+                if let Some(sock) = conn.state.sock() {
+                    reg.deregister(sock).ok();
+                }
+            }
+            doh.lookups.clear();
+            doh.waiting.clear();
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.doh
+            .as_ref()
+            .map(|doh| doh.conns.contains_key(&id))
+            .unwrap_or(false)
+    }
+
+    /// Processes responses from the nameservers.
+    pub fn read(&mut self) -> (Vec<QueryResponse>, io::Result<()>) {
+        let mut resps = Vec::new();
+        let res = self
+            .res
+            .read(&mut self.sock, |resp| resps.push(resp.into()));
+        (self.bootstrapped(resps), res)
+    }
+
+    /// Times out and retries outstanding queries.
+    pub fn tick(&mut self) -> (Vec<QueryResponse>, io::Result<()>) {
+        let mut resps = Vec::new();
+        let res = self
+            .res
+            .tick(&mut self.sock, |resp| resps.push(resp.into()));
+        if let Some(ref mut doh) = self.doh {
+            let timeout = Duration::from_millis(DOH_TIMEOUT_MS);
+            let expired: Vec<_> = doh
+                .conns
+                .iter()
+                .filter(|(_, conn)| conn.last_updated.elapsed() > timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in expired {
+                debug!("DoH request {} timed out", id);
+                let conn = doh.conns.remove(&id).unwrap();
+                resps.extend(doh.failed(&conn.host, true));
+            }
+        }
+        (self.bootstrapped(resps), res)
+    }
+
+    /// Progresses a DoH request after an event on its connection.
+    pub fn handle_conn(&mut self, id: usize, event: http::Event) -> Vec<QueryResponse> {
+        let doh = match self.doh {
+            Some(ref mut doh) => doh,
+            None => return Vec::new(),
+        };
+        let res = match doh.conns.get_mut(&id) {
+            Some(conn) => {
+                conn.last_updated = Instant::now();
+                match conn.state.handle(event) {
+                    Ok(HTTPRes::None) => return Vec::new(),
+                    Ok(HTTPRes::Complete(200, data)) => adns::parse_answer(&data),
+                    Ok(_) | Err(_) => None,
+                }
+            }
+            None => return Vec::new(),
+        };
+        let host = doh.conns.remove(&id).unwrap().host;
+        if let Some((ip, ttl)) = res {
+            self.res.insert(&host, ip, ttl);
+            return doh.resolved(&host, ip);
+        }
+        match doh.lookups.get_mut(&host) {
+            Some(lookup) if lookup.v4 == doh.prefer_v4 => lookup.v4 = !lookup.v4,
+            _ => return doh.failed(&host, false),
+        }
+        match self.send_doh(host.clone()) {
+            Ok(()) => Vec::new(),
+            Err(_) => self.doh.as_mut().unwrap().failed(&host, false),
+        }
+    }
+
+    /// Sends the DoH request for a host, first resolving the DoH server if needed.
+    fn send_doh(&mut self, host: String) -> io::Result<()> {
+        let doh = self.doh.as_mut().unwrap();
+        let server = doh.url.host_str().unwrap_or("");
+        match self.res.query(&mut self.sock, BOOTSTRAP_ID, server)? {
+            Some(ip) => doh.connect(host, ip),
+            None => {
+                doh.waiting.push(host);
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts any DoH requests waiting on the DoH server's address.
+    fn bootstrapped(&mut self, mut resps: Vec<QueryResponse>) -> Vec<QueryResponse> {
+        let doh = match self.doh {
+            Some(ref mut doh) => doh,
+            None => return resps,
+        };
+        let pos = match resps.iter().position(|r| r.id == BOOTSTRAP_ID) {
+            Some(pos) => pos,
+            None => return resps,
+        };
+        let server = resps.swap_remove(pos).res;
+        for host in mem::take(&mut doh.waiting) {
+            let res = match server {
+                Ok(ip) => doh.connect(host.clone(), ip).is_ok(),
+                Err(_) => false,
+            };
+            if !res {
+                resps.extend(doh.failed(&host, false));
+            }
+        }
+        resps
+    }
+}
+
+impl Doh {
+    fn connect(&mut self, host: String, ip: IpAddr) -> io::Result<()> {
+        let v4 = self
+            .lookups
+            .get(&host)
+            .map(|l| l.v4)
+            .unwrap_or(self.prefer_v4);
+        let packet =
+            base64::encode_config(&adns::query_packet(0, &host, v4), base64::URL_SAFE_NO_PAD);
+        let server = self.url.host_str().unwrap_or("");
+        let mut req = Vec::with_capacity(256);
+        util::http::RequestBuilder::new(&mut req, "GET", self.url.path(), self.url.query())
+            .query("dns", packet.as_bytes())
+            .headers()
+            .header("User-agent", &CONFIG.trk.user_agent)
+            .header("Accept", "application/dns-message")
+            .header("Connection", "close")
+            .header("Host", server)
+            .finish();
+
+        let sock = SStream::new_dual(Some(server.to_owned()))?;
+        let id = self.reg.register(&sock, amy::Event::Both)?;
+        let mut state = TrackerState::new(sock, req, http::default_port(&self.url), None);
+        state
+            .handle(http::Event::DNSResolved(QueryResponse { id, res: Ok(ip) }))
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        self.conns.insert(
+            id,
+            Conn {
+                host,
+                state,
+                last_updated: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn resolved(&mut self, host: &str, ip: IpAddr) -> Vec<QueryResponse> {
+        let ids = self.lookups.remove(host).map(|l| l.ids).unwrap_or_default();
+        ids.into_iter()
+            .map(|id| QueryResponse { id, res: Ok(ip) })
+            .collect()
+    }
+
+    fn failed(&mut self, host: &str, timeout: bool) -> Vec<QueryResponse> {
+        debug!("DoH lookup of {} failed", host);
+        let ids = self.lookups.remove(host).map(|l| l.ids).unwrap_or_default();
+        ids.into_iter()
+            .map(|id| QueryResponse {
+                id,
+                res: Err(if timeout {
+                    ErrorKind::DNSTimeout.into()
+                } else {
+                    ErrorKind::DNSInvalid.into()
+                }),
+            })
+            .collect()
     }
 }
 
@@ -1,3 +1,5 @@
+use crate::rpc::resource::ErrorCode;
+
 error_chain! {
     errors {
         InvalidRequest(r: String) {
@@ -10,11 +12,16 @@ error_chain! {
             display("invalid tracker response: {}", r)
         }
 
-        TrackerError(e: String) {
+        TrackerError(e: String, retry: Option<super::Retry>) {
             description("tracker error response")
             display("tracker error: {}", e)
         }
 
+        Unavailable(retry_secs: Option<u64>) {
+            description("the server is temporarily unavailable")
+            display("server unavailable")
+        }
+
         EOF {
             description("the tracker closed the connection unexpectedly")
             display("tracker EOF")
@@ -41,3 +48,17 @@ error_chain! {
         }
     }
 }
+
+impl ErrorKind {
+    /// The code this error is reported to RPC clients with.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ErrorKind::InvalidRequest(_) => ErrorCode::TrackerInvalidRequest,
+            ErrorKind::InvalidResponse(_) => ErrorCode::TrackerInvalidResponse,
+            ErrorKind::TrackerError(..) => ErrorCode::TrackerRejected,
+            ErrorKind::Timeout => ErrorCode::TrackerTimeout,
+            ErrorKind::DNSTimeout | ErrorKind::DNSInvalid => ErrorCode::TrackerDns,
+            _ => ErrorCode::TrackerUnreachable,
+        }
+    }
+}
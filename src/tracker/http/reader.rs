@@ -1,5 +1,6 @@
 use std::io;
 use std::mem;
+use std::str;
 
 use crate::tracker::errors::{ErrorKind, Result};
 use crate::util::{aread, IOR};
@@ -7,12 +8,15 @@ use crate::util::{aread, IOR};
 pub struct Reader {
     data: Vec<u8>,
     idx: usize,
+    code: u16,
     state: ReadState,
+    /// Length of the body after which reading stops
+    limit: Option<usize>,
 }
 
 pub enum ReadRes {
     None,
-    Done(Vec<u8>),
+    Done(u16, Vec<u8>),
     Redirect(String),
 }
 
@@ -22,11 +26,13 @@ enum ReadState {
 }
 
 impl Reader {
-    pub fn new() -> Reader {
+    pub fn new(limit: Option<usize>) -> Reader {
         Reader {
             data: vec![0; 75],
             idx: 0,
+            code: 0,
             state: ReadState::Header,
+            limit,
         }
     }
 
@@ -52,7 +58,7 @@ impl Reader {
                     ReadState::Body => {
                         let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
                         data.truncate(self.idx);
-                        return Ok(ReadRes::Done(data));
+                        return Ok(ReadRes::Done(self.code, data));
                     }
                     _ => return Err(ErrorKind::EOF.into()),
                 },
@@ -87,6 +93,20 @@ impl Reader {
                             }
                             return Ok(Some(ReadRes::Redirect(loc.unwrap())));
                         }
+                        // Overloaded web seeds may say when to come back,
+                        // otherwise the body is left to be interpreted
+                        if resp.code == Some(503) {
+                            let retry = resp
+                                .headers
+                                .iter()
+                                .find(|h| h.name.eq_ignore_ascii_case("Retry-After"))
+                                .and_then(|h| str::from_utf8(h.value).ok())
+                                .and_then(|v| v.trim().parse().ok());
+                            if let Some(retry) = retry {
+                                return Err(ErrorKind::Unavailable(Some(retry)).into());
+                            }
+                        }
+                        self.code = resp.code.unwrap_or(0);
                         header_done = Some(i);
                     }
                     Ok(httparse::Status::Partial) => {}
@@ -103,6 +123,53 @@ impl Reader {
             self.data = body;
             self.state = ReadState::Body;
         }
+        if let (ReadState::Body, Some(limit)) = (&self.state, self.limit) {
+            if self.idx >= limit {
+                let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
+                data.truncate(limit);
+                return Ok(Some(ReadRes::Done(self.code, data)));
+            }
+        }
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn read(resp: &[u8]) -> Result<ReadRes> {
+        Reader::new(None).readable(&mut Cursor::new(resp))
+    }
+
+    #[test]
+    fn test_limit() {
+        // The body never ends, so only stopping at the limit returns
+        let resp = Cursor::new(b"HTTP/1.1 200 OK\r\n\r\n".to_vec()).chain(io::repeat(7));
+        match Reader::new(Some(100_000)).readable(&mut resp.take(1 << 20)) {
+            Ok(ReadRes::Done(200, data)) => assert_eq!(data, vec![7; 100_000]),
+            _ => panic!("expected a complete response"),
+        }
+    }
+
+    #[test]
+    fn test_unavailable() {
+        match read(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi") {
+            Ok(ReadRes::Done(200, data)) => assert_eq!(data, b"hi"),
+            _ => panic!("expected a complete response"),
+        }
+        match read(b"HTTP/1.1 503 Service Unavailable\r\nretry-after: 120\r\n\r\n") {
+            Err(e) => match e.kind() {
+                ErrorKind::Unavailable(retry) => assert_eq!(*retry, Some(120)),
+                _ => panic!("expected the server to be unavailable"),
+            },
+            _ => panic!("expected the server to be unavailable"),
+        }
+        // Dates aren't understood, and are left to whoever reads the body
+        match read(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n60") {
+            Ok(ReadRes::Done(503, data)) => assert_eq!(data, b"60"),
+            _ => panic!("expected a complete response"),
+        }
+    }
+}
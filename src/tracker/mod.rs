@@ -1,15 +1,16 @@
-mod dht;
+mod cache;
 mod dns;
 mod errors;
 mod http;
 mod udp;
 
-use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{io, result, thread};
+use std::time::{Duration, Instant};
+use std::{io, mem, result, str, thread};
 
-use byteorder::{BigEndian, ByteOrder};
+use rand::{self, Rng};
 use url::Url;
 
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
@@ -18,6 +19,7 @@ use crate::control::cio;
 use crate::disk;
 use crate::handle;
 use crate::torrent::Torrent;
+use crate::util::{self, FHashSet, UHashMap};
 use crate::CONFIG;
 
 pub struct Tracker {
@@ -26,8 +28,14 @@ pub struct Tracker {
     http: http::Handler,
     queue: VecDeque<Announce>,
     udp: udp::Handler,
-    dht: dht::Manager,
     dns: dns::Resolver,
+    cache: cache::Cache,
+    /// Info hash of the torrents we've announced for, until they stop
+    hashes: UHashMap<[u8; 20]>,
+    /// Stopped announces which are still in flight
+    stopping: FHashSet<(usize, Arc<Url>)>,
+    /// Startup announces waiting to be sent, and when to send them
+    delayed: Vec<(Instant, Announce)>,
     timer: usize,
     shutting_down: bool,
 }
@@ -35,9 +43,10 @@ pub struct Tracker {
 #[derive(Debug)]
 pub enum Request {
     Announce(Announce),
-    GetPeers(GetPeers),
-    AddNode(SocketAddr),
-    DHTAnnounce([u8; 20]),
+    /// A started announce of a torrent loaded at startup, which is sent
+    /// after a random delay so the session doesn't announce all at once
+    Startup(Announce),
+    WebSeed(WebSeed),
     PurgeDNS,
     Ping,
     Shutdown,
@@ -56,10 +65,20 @@ pub struct Announce {
     event: Option<Event>,
 }
 
+/// A GET of a single file segment of a piece from a web seed.
 #[derive(Debug)]
-pub struct GetPeers {
+pub struct WebSeed {
     pub id: usize,
-    pub hash: [u8; 20],
+    pub url: Url,
+    pub piece: u32,
+    /// Offset of the segment into the piece
+    pub offset: u32,
+    /// Offset of the segment into the file
+    pub start: u64,
+    pub len: u32,
+    /// Whether the segment is requested as a byte range. BEP17 seeds
+    /// are given the piece in the URL instead.
+    pub ranged: bool,
 }
 
 #[derive(Debug)]
@@ -67,6 +86,8 @@ pub enum Event {
     Started,
     Stopped,
     Completed,
+    /// Sent instead of regular announces by partial seeds (BEP 21)
+    Paused,
 }
 
 #[derive(Debug)]
@@ -76,13 +97,20 @@ pub enum Response {
         url: Arc<Url>,
         resp: Result<TrackerResponse>,
     },
-    DHT {
+    PEX {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
-    PEX {
+    /// A relay asked us to connect to a peer over uTP (BEP 55)
+    Holepunch {
         tid: usize,
-        peers: Vec<SocketAddr>,
+        peer: SocketAddr,
+    },
+    WebSeed {
+        tid: usize,
+        piece: u32,
+        offset: u32,
+        resp: Result<Vec<u8>>,
     },
 }
 
@@ -92,9 +120,33 @@ pub struct TrackerResponse {
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
+    pub downloaded: u32,
+}
+
+/// Retry hint a tracker may attach to a failure, see BEP 31.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retry {
+    /// Minutes to wait before announcing again
+    In(u32),
+    Never,
 }
 
 const POLL_INT_MS: usize = 1000;
+/// Interval used when a tracker omits it, if parsing leniently
+const LENIENT_INTERVAL: u32 = 1800;
+/// Response keys which are valid but unused
+const KNOWN_KEYS: &[&str] = &[
+    "min interval",
+    "tracker id",
+    "warning message",
+    "external ip",
+];
+/// Fraction of an announce interval which reannounces are randomly delayed by
+const JITTER: f64 = 0.1;
+/// Longest delay of startup announces
+const STARTUP_SPREAD_MS: u64 = 30_000;
+/// How long shutdown waits on outstanding stopped announces
+const SHUTDOWN_MS: u64 = 3_000;
 
 impl Tracker {
     pub fn start(
@@ -104,9 +156,9 @@ impl Tracker {
         let poll = amy::Poller::new()?;
         let mut reg = poll.get_registrar();
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
-        let timer = reg.set_interval(150)?;
+        let timer = reg.set_interval(CONFIG.trk.tick_ms as usize)?;
         let udp = udp::Handler::new(&reg)?;
-        let dht = dht::Manager::new(&reg, db)?;
+        let cache = cache::Cache::new(db);
         let http = http::Handler::new(&reg)?;
         let dns = dns::Resolver::new(&reg)?;
         let th = dh.run("trk", move |h| {
@@ -114,9 +166,12 @@ impl Tracker {
                 poll,
                 ch: h,
                 udp,
-                dht,
                 http,
                 dns,
+                cache,
+                hashes: UHashMap::default(),
+                stopping: FHashSet::default(),
+                delayed: Vec::new(),
                 timer,
                 queue: VecDeque::new(),
                 shutting_down: false,
@@ -127,8 +182,6 @@ impl Tracker {
     }
 
     pub fn run(&mut self) {
-        self.dht.init();
-
         debug!("Initialized!");
         'outer: loop {
             match self.poll.wait(POLL_INT_MS) {
@@ -146,16 +199,53 @@ impl Tracker {
         }
 
         self.shutting_down = true;
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_MS);
+
+        // Only stopped announces are worth sending now, and they shouldn't
+        // have to wait on the open announce limit.
+        for req in mem::take(&mut self.queue) {
+            if let Some(Event::Stopped) = req.event {
+                self.start_announce(req);
+            }
+        }
 
-        // Shutdown loop - wait for all requests to complete
-        loop {
-            for event in self.poll.wait(POLL_INT_MS).unwrap() {
-                if self.handle_event(event).is_err() {}
-                if self.http.complete() && self.udp.complete() {
-                    return;
+        // Drain loop - give stopped announces a chance to reach their trackers
+        while !self.stopping.is_empty() {
+            if Instant::now() > deadline {
+                info!(
+                    "Gave up on {} stopped announces during shutdown",
+                    self.stopping.len()
+                );
+                break;
+            }
+            match self.poll.wait(POLL_INT_MS) {
+                Ok(events) => {
+                    for event in events {
+                        self.handle_event(event).ok();
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to poll for events: {}", e);
                 }
             }
         }
+        self.shutdown();
+    }
+
+    /// Cancels all in flight requests and releases their registrations.
+    fn shutdown(&mut self) {
+        let reg = self.poll.get_registrar();
+        let http = self.http.shutdown();
+        let udp = self.udp.shutdown(&reg);
+        self.dns.shutdown(&reg);
+        reg.cancel_timeout(self.timer).ok();
+        self.queue.clear();
+        self.stopping.clear();
+        self.delayed.clear();
+        debug!(
+            "Cancelled {} HTTP and {} UDP tracker requests on shutdown",
+            http, udp
+        );
     }
 
     fn handle_event(&mut self, event: amy::Notification) -> result::Result<(), ()> {
@@ -175,21 +265,26 @@ impl Tracker {
         while let Ok(r) = self.ch.recv() {
             match r {
                 Request::Announce(req) => self.handle_announce(req),
-                Request::GetPeers(gp) => {
-                    trace!("Handling dht peer find req!");
-                    self.dht.get_peers(gp.id, gp.hash);
+                Request::Startup(req) => {
+                    let delay = rand::thread_rng().gen_range(0, STARTUP_SPREAD_MS);
+                    let at = Instant::now() + Duration::from_millis(delay);
+                    self.delayed.push((at, req));
                 }
-                Request::AddNode(addr) => {
-                    trace!("Handling dht node addition req!");
-                    self.dht.add_addr(addr);
-                }
-                Request::DHTAnnounce(hash) => {
-                    trace!("Handling dht announce req!");
-                    self.dht.announce(hash);
+                Request::WebSeed(req) => {
+                    trace!("Handling web seed req!");
+                    let (tid, piece, offset) = (req.id, req.piece, req.offset);
+                    if let Err(e) = self.http.new_web_seed(req, &mut self.dns) {
+                        self.send_response(Response::WebSeed {
+                            tid,
+                            piece,
+                            offset,
+                            resp: Err(e),
+                        });
+                    }
                 }
                 Request::Ping => {}
                 Request::PurgeDNS => {
-                    self.dns.res.purge();
+                    self.dns.purge();
                 }
                 Request::Shutdown => {
                     return Err(());
@@ -201,12 +296,46 @@ impl Tracker {
 
     fn handle_announce(&mut self, req: Announce) {
         debug!("Handling announce request!");
-        if self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
+        match req.event {
+            // Torrents are stopped before being removed
+            Some(Event::Stopped) => {
+                self.hashes.remove(&req.id);
+                self.delayed
+                    .retain(|(_, a)| a.id != req.id || a.url != req.url);
+            }
+            _ => {
+                self.hashes.insert(req.id, req.hash);
+            }
+        }
+        if let Some(Event::Started) = req.event {
+            if let Some(resp) = self.cache.get(&req.url, req.hash) {
+                debug!("Using cached response for {}", req.url);
+                // Not a fresh response, so it isn't cached again
+                self.ch
+                    .send(Response::Tracker {
+                        tid: req.id,
+                        url: req.url.clone(),
+                        resp: Ok(resp),
+                    })
+                    .ok();
+            }
+        }
+        self.start_announce(req);
+    }
+
+    /// Sends an announce, or queues it if too many are open already.
+    fn start_announce(&mut self, req: Announce) {
+        if !self.shutting_down
+            && self.udp.active_requests() + self.http.active_requests()
+                > CONFIG.net.max_open_announces
         {
             self.queue.push_back(req);
         } else {
             let id = req.id;
             let url = req.url.clone();
+            if let Some(Event::Stopped) = req.event {
+                self.stopping.insert((id, url.clone()));
+            }
             let response = match url.scheme() {
                 "http" | "https" => self.http.new_announce(req, &mut self.dns),
                 "udp" => self.udp.new_announce(req, &mut self.dns),
@@ -227,20 +356,17 @@ impl Tracker {
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
         if let Some(a) = self.queue.pop_front() {
-            self.handle_announce(a);
+            self.start_announce(a);
         }
     }
 
     fn handle_dns(&mut self) {
-        let mut dresps = vec![];
-        let res = self.dns.res.read(&mut self.dns.sock, |resp| {
-            dresps.push(resp);
-        });
+        let (dresps, res) = self.dns.read();
         if let Err(e) = res {
             error!("DNS resolution failed: {}", e);
         }
         for r in dresps {
-            self.handle_dns_resp(r.into());
+            self.handle_dns_resp(r);
         }
     }
 
@@ -258,6 +384,15 @@ impl Tracker {
     }
 
     fn handle_timer(&mut self) {
+        let now = Instant::now();
+        let (due, delayed) = mem::take(&mut self.delayed)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.delayed = delayed;
+        for (_, req) in due {
+            self.handle_announce(req);
+        }
+
         for r in self
             .http
             .tick()
@@ -267,16 +402,13 @@ impl Tracker {
             self.send_response(r);
         }
 
-        self.dht.tick();
-        let mut dresps = vec![];
-        let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
-            dresps.push(resp);
-        });
+        self.cache.tick();
+        let (dresps, res) = self.dns.tick();
         if let Err(e) = res {
             info!("Failed to query dns: {}", e);
         }
         for r in dresps {
-            self.handle_dns_resp(r.into());
+            self.handle_dns_resp(r);
         }
     }
 
@@ -290,27 +422,42 @@ impl Tracker {
             if let Some(r) = resp {
                 self.send_response(r);
             }
+        } else if self.dns.contains(event.id) {
+            let dev = if event.event.readable() {
+                http::Event::Readable
+            } else {
+                http::Event::Writable
+            };
+            for r in self.dns.handle_conn(event.id, dev) {
+                self.handle_dns_resp(r);
+            }
         } else if self.udp.id() == event.id {
             for resp in self.udp.readable() {
                 self.send_response(resp);
             }
-        } else if self.dht.id() == event.id {
-            for resp in self.dht.readable() {
-                self.send_response(resp);
-            }
         } else {
             error!("Unknown event occured for tracker: {:?}", event);
         };
     }
 
     fn send_response(&mut self, r: Response) {
+        if let Response::Tracker { tid, ref url, .. } = r {
+            self.stopping.remove(&(tid, url.clone()));
+        }
+        if let Response::Tracker {
+            tid,
+            ref url,
+            resp: Ok(ref resp),
+        } = r
+        {
+            if let Some(hash) = self.hashes.get(&tid) {
+                self.cache.insert(url, *hash, resp);
+            }
+        }
         if !self.shutting_down {
             trace!("Sending trk response to control!");
             self.ch.send(r).ok();
         }
-        // TODO: The active announce queue could grow with DHT usage,
-        // since DHT stuff doesn't go into the announce queue, but still triggers send_response.
-        // Not a big deal, but worth thinking about for later.
         self.dequeue_req();
     }
 }
@@ -331,13 +478,8 @@ impl Request {
             hash: torrent.info().hash,
             port: CONFIG.port,
             uploaded: torrent.uploaded(),
-            downloaded: torrent.downloaded(),
-            // This should be fine because the true len is usually slightly less than
-            // piece_len * pieces_dld (due to shorter last piece), so we always get
-            // either the correct amount left or 0.
-            left: torrent.info().total_len.saturating_sub(
-                torrent.pieces().iter().count() as u64 * u64::from(torrent.info().piece_len),
-            ),
+            downloaded: torrent.announce_downloaded(),
+            left: torrent.left(),
             // TODO: Develop better heuristics here.
             // For now, only request peers if we're leeching,
             // let existing peers connect otherwise
@@ -350,26 +492,69 @@ impl Request {
         Request::new_announce(torrent, Some(Event::Started))
     }
 
+    /// Started announce of a torrent loaded at startup.
+    pub fn startup<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
+        Request::started(torrent).map(|r| match r {
+            Request::Announce(a) => Request::Startup(a),
+            r => r,
+        })
+    }
+
     pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
         Request::new_announce(torrent, Some(Event::Stopped))
     }
 
+    /// Announces that the wanted files are done. Partial seeds don't have
+    /// the whole torrent, so they announce that they're paused instead.
     pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Completed))
+        if torrent.partial_seed() {
+            Request::new_announce(torrent, Some(Event::Paused))
+        } else {
+            Request::new_announce(torrent, Some(Event::Completed))
+        }
     }
 
     pub fn interval<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, None)
+        Request::new_announce(torrent, Request::regular_event(torrent))
     }
 
     pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
+        Request::new_announce(torrent, Request::regular_event(torrent)).map(|mut r| {
             if let Request::Announce(ref mut a) = r {
                 a.url = url
             }
             r
         })
     }
+
+    fn regular_event<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Event> {
+        if torrent.partial_seed() {
+            Some(Event::Paused)
+        } else {
+            None
+        }
+    }
+}
+
+/// Randomly lengthens an interval of secs by up to `JITTER`, so torrents
+/// started together don't keep announcing together. Announces are never
+/// brought forward, which trackers may refuse.
+pub fn jitter(secs: u32) -> Duration {
+    let scale = 1. + rand::thread_rng().gen_range(0., JITTER);
+    Duration::from_millis((f64::from(secs) * scale * 1000.) as u64)
+}
+
+/// Reads an integer from a response, also accepting decimal strings if lenient.
+fn int_field(d: &mut BTreeMap<String, BEncode>, key: &str, lenient: bool) -> Option<i64> {
+    match d.remove(key)? {
+        BEncode::Int(i) => Some(i),
+        BEncode::String(ref s) if lenient => {
+            let i = str::from_utf8(s).ok()?.trim().parse().ok()?;
+            debug!("Tracker response has string encoded {}", key);
+            Some(i)
+        }
+        _ => None,
+    }
 }
 
 impl TrackerResponse {
@@ -379,38 +564,156 @@ impl TrackerResponse {
             interval: 900,
             leechers: 0,
             seeders: 0,
+            downloaded: 0,
         }
     }
 
     pub fn from_bencode(data: BEncode) -> Result<TrackerResponse> {
+        TrackerResponse::parse(data, CONFIG.trk.lenient)
+    }
+
+    /// Parses a tracker response. Lenient parsing accepts some common spec
+    /// violations, logging them rather than failing the announce.
+    pub fn parse(data: BEncode, lenient: bool) -> Result<TrackerResponse> {
         let mut d = data.into_dict().ok_or(ErrorKind::InvalidResponse(
             "Tracker response must be a dictionary type!",
         ))?;
         if let Some(BEncode::String(data)) = d.remove("failure reason") {
             let reason = String::from_utf8(data)
                 .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
-            return Err(ErrorKind::TrackerError(reason).into());
+            let retry = match d.get("retry in") {
+                Some(BEncode::String(ref s)) if &s[..] == b"never" => Some(Retry::Never),
+                // Waiting at least a minute keeps a failing tracker from
+                // being announced to every tick
+                _ => int_field(&mut d, "retry in", lenient)
+                    .map(|i| Retry::In(i.clamp(1, i64::from(u32::MAX)) as u32)),
+            };
+            return Err(ErrorKind::TrackerError(reason, retry).into());
         }
         let mut resp = TrackerResponse::empty();
-        if let Some(BEncode::String(ref data)) = d.remove("peers") {
-            for p in data.chunks(6) {
-                if p.len() != 6 {
+        for &(key, v6) in &[("peers", false), ("peers6", true)] {
+            if let Some(BEncode::String(ref data)) = d.remove(key) {
+                let len = if v6 {
+                    util::COMPACT_V6_LEN
+                } else {
+                    util::COMPACT_V4_LEN
+                };
+                if data.len() % len != 0 {
                     debug!("Unusual trailing bytes received for tracker!");
-                    continue;
                 }
-                let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                resp.peers.push(SocketAddr::V4(socket));
+                resp.peers.extend(util::bytes_to_addrs(data, v6));
             }
         }
-        match d.remove("interval") {
-            Some(BEncode::Int(ref i)) => {
-                resp.interval = *i as u32;
+        match int_field(&mut d, "interval", lenient) {
+            Some(i) => {
+                resp.interval = i as u32;
             }
-            _ => {
+            None if lenient => {
+                debug!("Tracker response has no valid interval, using the default");
+                resp.interval = LENIENT_INTERVAL;
+            }
+            None => {
                 return Err(ErrorKind::InvalidResponse("Response must have interval!").into());
             }
         };
+        // Swarm statistics are optional, trackers which omit them just leave these at 0
+        if let Some(i) = int_field(&mut d, "complete", lenient) {
+            resp.seeders = i.max(0) as u32;
+        }
+        if let Some(i) = int_field(&mut d, "incomplete", lenient) {
+            resp.leechers = i.max(0) as u32;
+        }
+        if let Some(i) = int_field(&mut d, "downloaded", lenient) {
+            resp.downloaded = i.max(0) as u32;
+        }
+        if lenient {
+            for key in d.keys().filter(|k| !KNOWN_KEYS.contains(&k.as_str())) {
+                debug!("Ignoring unknown tracker response key {:?}", key);
+            }
+        }
         Ok(resp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{jitter, ErrorKind, Retry, TrackerResponse};
+    use crate::bencode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_swarm_stats() {
+        let data = b"d8:completei12e10:downloadedi340e10:incompletei7e8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(&data[..]).unwrap()).unwrap();
+        assert_eq!(resp.seeders, 12);
+        assert_eq!(resp.leechers, 7);
+        assert_eq!(resp.downloaded, 340);
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        let data = b"d8:intervali900e5:peers0:6:peers618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e";
+        let resp = TrackerResponse::from_bencode(bencode::decode_buf(&data[..]).unwrap()).unwrap();
+        assert_eq!(resp.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+        assert_eq!(resp.seeders, 0);
+        assert_eq!(resp.leechers, 0);
+        assert_eq!(resp.downloaded, 0);
+    }
+
+    #[test]
+    fn test_retry_in() {
+        let retry =
+            |data: &[u8]| match TrackerResponse::from_bencode(bencode::decode_buf(data).unwrap()) {
+                Err(e) => match *e.kind() {
+                    ErrorKind::TrackerError(_, retry) => retry,
+                    _ => panic!("unexpected error kind"),
+                },
+                Ok(_) => panic!("failure response parsed as success"),
+            };
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini30ee"),
+            Some(Retry::In(30))
+        );
+        assert_eq!(
+            retry(b"d14:failure reason4:gone8:retry in5:nevere"),
+            Some(Retry::Never)
+        );
+        assert_eq!(retry(b"d14:failure reason4:busye"), None);
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini0ee"),
+            Some(Retry::In(1))
+        );
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini-5ee"),
+            Some(Retry::In(1))
+        );
+        assert_eq!(
+            retry(b"d14:failure reason4:busy8:retry ini4294967296ee"),
+            Some(Retry::In(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        for _ in 0..100 {
+            let d = jitter(1800);
+            assert!(d >= Duration::from_secs(1800) && d <= Duration::from_secs(1980));
+        }
+        assert_eq!(jitter(0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_lenient() {
+        let parse = |data: &[u8], lenient| {
+            TrackerResponse::parse(bencode::decode_buf(data).unwrap(), lenient)
+        };
+        let data = b"d8:completei3e5:peers0:e";
+        assert!(parse(data, false).is_err());
+        assert_eq!(parse(data, true).unwrap().interval, 1800);
+
+        let data = b"d8:complete2:123:fooi1e8:interval3:9005:peers0:e";
+        assert!(parse(data, false).is_err());
+        let resp = parse(data, true).unwrap();
+        assert_eq!(resp.interval, 900);
+        assert_eq!(resp.seeders, 12);
+    }
+}
@@ -8,12 +8,11 @@ use rand::random;
 use crate::tracker::{
     dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse,
 };
-use crate::util::{bytes_to_addr, FHashMap, UHashMap};
+use crate::util::{self, FHashMap, UHashMap};
 use crate::{CONFIG, PEER_ID};
 
 // We're not going to bother with backoff, if the tracker/network aren't working now
 // the torrent can just resend a request later.
-const TIMEOUT_MS: u64 = 15_000;
 const RETRANS_MS: u64 = 5_000;
 const MAGIC_NUM: u64 = 0x417_2710_1980;
 
@@ -60,14 +59,19 @@ impl Handler {
         self.id
     }
 
-    pub fn complete(&self) -> bool {
-        self.connections.is_empty()
-    }
-
     pub fn active_requests(&self) -> usize {
         self.connections.len()
     }
 
+    /// Abandons all in flight announces, returning how many there were.
+    pub fn shutdown(&mut self, reg: &amy::Registrar) -> usize {
+        let count = self.connections.len();
+        self.connections.clear();
+        self.transactions.clear();
+        reg.deregister(&self.sock).ok();
+        count
+    }
+
     pub fn contains(&self, id: usize) -> bool {
         self.connections.contains_key(&id)
     }
@@ -75,7 +79,7 @@ impl Handler {
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
         let url = req.url.clone();
         debug!("Received a new announce req for {:?}", url);
-        let host = url.host_str().ok_or_else(|| {
+        let host = util::ascii_host(&url).ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
             ))
@@ -98,7 +102,7 @@ impl Handler {
             },
         );
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
-        if let Some(ip) = dns.new_query(id, host).chain_err(|| ErrorKind::IO)? {
+        if let Some(ip) = dns.new_query(id, &host).chain_err(|| ErrorKind::IO)? {
             debug!("Using cached DNS response");
             let res = self.dns_resolved(dns::QueryResponse { id, res: Ok(ip) });
             if res.is_some() {
@@ -189,7 +193,14 @@ impl Handler {
         let mut retrans = Vec::new();
         {
             self.connections.retain(|id, conn| {
-                if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
+                let timeout = match conn.state {
+                    State::Announcing { .. } => CONFIG.trk.udp_timeout_ms,
+                    _ => CONFIG
+                        .trk
+                        .connect_timeout_ms
+                        .unwrap_or(CONFIG.trk.udp_timeout_ms),
+                };
+                if conn.last_updated.elapsed() > time::Duration::from_millis(timeout) {
                     resps.push(Response::Tracker {
                         tid: conn.torrent,
                         url: conn.announce.url.clone(),
@@ -273,6 +284,9 @@ impl Handler {
                     Some(Event::Completed) => {
                         announce_req.write_u32::<BigEndian>(1).unwrap();
                     }
+                    Some(Event::Paused) => {
+                        announce_req.write_u32::<BigEndian>(4).unwrap();
+                    }
                     None => {
                         announce_req.write_u32::<BigEndian>(0).unwrap();
                     }
@@ -315,10 +329,13 @@ impl Handler {
         resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.seeders = announce_resp.read_u32::<BigEndian>().unwrap();
         if len > 20 {
+            // Trackers reached over IPv6 respond with IPv6 peers
+            let v6 = match conn.state {
+                State::Announcing { addr, .. } => addr.is_ipv6(),
+                _ => false,
+            };
             let pos = announce_resp.position() as usize;
-            for p in announce_resp.get_ref()[pos..].chunks(6) {
-                resp.peers.push(bytes_to_addr(p));
-            }
+            resp.peers = util::bytes_to_addrs(&announce_resp.get_ref()[pos..], v6);
         }
         Some(Response::Tracker {
             tid: conn.torrent,
@@ -354,7 +371,7 @@ impl Handler {
             Some(Response::Tracker {
                 tid: conn.torrent,
                 url: conn.announce.url,
-                resp: Err(ErrorKind::TrackerError(s).into()),
+                resp: Err(ErrorKind::TrackerError(s, None).into()),
             })
         }
     }
@@ -1,117 +1,130 @@
-use url::percent_encoding::percent_encode_byte;
+use std::io::Write;
 
-#[derive(Debug)]
-pub struct RequestBuilder<'a> {
-    method: &'a str,
-    path: &'a str,
-    base_query: Option<&'a str>,
-    query_pairs: Vec<QueryPair<'a>>,
-    headers: Vec<HttpHeader<'a>>,
-}
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
 
+/// Writes an HTTP request directly into a buffer, percent-encoding query
+/// values in place so that building a request doesn't allocate.
+///
+/// The builder writes the request line's query parameters, and is turned
+/// into a `HeaderBuilder` by `headers`, so they can't be added after.
 #[derive(Debug)]
-pub struct HttpHeader<'a> {
-    name: &'a str,
-    value: &'a str,
+pub struct RequestBuilder<'a> {
+    buf: &'a mut Vec<u8>,
 }
 
+/// Writes the headers of a request started by a `RequestBuilder`.
 #[derive(Debug)]
-pub struct QueryPair<'a> {
-    name: &'a str,
-    value: &'a [u8],
-}
-
-pub trait EncodeToBuf {
-    fn encode(&self, buf: &mut Vec<u8>);
+pub struct HeaderBuilder<'a> {
+    buf: &'a mut Vec<u8>,
 }
 
 impl<'a> RequestBuilder<'a> {
-    pub fn new(method: &'a str, path: &'a str, query: Option<&'a str>) -> RequestBuilder<'a> {
-        RequestBuilder {
-            method,
-            path,
-            base_query: query,
-            query_pairs: Vec::with_capacity(10),
-            headers: Vec::with_capacity(10),
+    pub fn new(
+        buf: &'a mut Vec<u8>,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+    ) -> RequestBuilder<'a> {
+        buf.extend_from_slice(method.as_bytes());
+        buf.extend_from_slice(b" ");
+        buf.extend_from_slice(path.as_bytes());
+
+        buf.extend_from_slice(b"?");
+        if let Some(q) = query {
+            buf.extend_from_slice(q.as_bytes());
+            buf.extend_from_slice(b"&");
         }
+        RequestBuilder { buf }
     }
-}
 
-impl<'a> RequestBuilder<'a> {
-    pub fn query(&mut self, name: &'a str, value: &'a [u8]) -> &mut RequestBuilder<'a> {
-        self.query_pairs.push(QueryPair { name, value });
+    pub fn query(mut self, name: &str, value: &[u8]) -> RequestBuilder<'a> {
+        self.query_name(name);
+        encode_param(value, self.buf);
+        self.buf.extend_from_slice(b"&");
         self
     }
 
-    pub fn query_opt(&mut self, name: &'a str, value: Option<&'a [u8]>) -> &mut RequestBuilder<'a> {
+    pub fn query_opt(mut self, name: &str, value: Option<&[u8]>) -> RequestBuilder<'a> {
         if let Some(v) = value {
-            self.query_pairs.push(QueryPair { name, value: v });
+            self = self.query(name, v);
         }
         self
     }
 
-    pub fn header(&mut self, name: &'a str, value: &'a str) -> &mut RequestBuilder<'a> {
-        self.headers.push(HttpHeader { name, value });
+    pub fn query_int(mut self, name: &str, value: u64) -> RequestBuilder<'a> {
+        self.query_name(name);
+        write!(self.buf, "{}", value).unwrap();
+        self.buf.extend_from_slice(b"&");
         self
     }
+
+    /// Ends the request line, moving on to the headers.
+    pub fn headers(self) -> HeaderBuilder<'a> {
+        // The query either encodes an extra ? or an extra &, pop either off
+        self.buf.pop();
+        self.buf.extend_from_slice(b" HTTP/1.1");
+        self.buf.extend_from_slice(b"\r\n");
+        HeaderBuilder { buf: self.buf }
+    }
+
+    fn query_name(&mut self, name: &str) {
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(b"=");
+    }
 }
 
-impl<'a> RequestBuilder<'a> {
-    pub fn encode(&self, buf: &mut Vec<u8>) {
-        buf.extend_from_slice(self.method.as_bytes());
-        buf.extend_from_slice(b" ");
-        buf.extend_from_slice(self.path.as_bytes());
+impl<'a> HeaderBuilder<'a> {
+    pub fn header(&mut self, name: &str, value: &str) -> &mut HeaderBuilder<'a> {
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(b": ");
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.extend_from_slice(b"\r\n");
+        self
+    }
 
-        buf.extend_from_slice(b"?");
-        if let Some(q) = self.base_query {
-            buf.extend_from_slice(q.as_bytes());
-            buf.extend_from_slice(b"&");
-        }
-        for pair in &self.query_pairs {
-            buf.extend_from_slice(pair.name.as_bytes());
-            buf.extend_from_slice(b"=");
-            encode_param(pair.value, buf);
-            buf.extend_from_slice(b"&");
-        }
-        // The query either encodes an extra ? or an extra &, pop either off
-        buf.pop();
-        buf.extend_from_slice(b" HTTP/1.1");
-        buf.extend_from_slice(b"\r\n");
-        for header in &self.headers {
-            buf.extend_from_slice(header.name.as_bytes());
-            buf.extend_from_slice(b": ");
-            buf.extend_from_slice(header.value.as_bytes());
-            buf.extend_from_slice(b"\r\n");
+    pub fn header_opt(&mut self, name: &str, value: Option<&str>) -> &mut HeaderBuilder<'a> {
+        if let Some(v) = value {
+            self.header(name, v);
         }
-        buf.extend_from_slice(b"\r\n");
+        self
+    }
+
+    /// Terminates the request, which must be done before it's sent.
+    pub fn finish(&mut self) {
+        self.buf.extend_from_slice(b"\r\n");
     }
 }
 
-fn encode_param(param: &[u8], buf: &mut Vec<u8>) {
-    for byte in param {
-        let c = char::from(*byte);
-        let mut char_buf = [0u8; 4];
-        if (*byte > 0x20 && *byte < 0x7E) && (c.is_numeric() || c.is_alphabetic() || c == '-') {
-            c.encode_utf8(&mut char_buf);
-            buf.extend_from_slice(&char_buf[0..c.len_utf8()]);
+/// Percent encodes a query parameter value.
+pub fn encode_param(param: &[u8], buf: &mut Vec<u8>) {
+    buf.reserve(param.len() * 3);
+    for &byte in param {
+        if byte.is_ascii_alphanumeric() || byte == b'-' {
+            buf.push(byte);
         } else {
-            buf.extend_from_slice(percent_encode_byte(*byte).as_bytes());
+            buf.extend_from_slice(&[
+                b'%',
+                HEX[usize::from(byte >> 4)],
+                HEX[usize::from(byte & 0xf)],
+            ]);
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    // Referred to through super, since benches include this file without
+    // compiling the tests, where a glob import would go unused
 
     #[test]
     fn test_encode_http_request() {
         let mut encoded = Vec::new();
-        RequestBuilder::new("GET", "/foobar/baz", Some("a=b"))
+        super::RequestBuilder::new(&mut encoded, "GET", "/foobar/baz", Some("a=b"))
             .query("b", "c".as_bytes())
+            .headers()
             .header("header1", "value1")
             .header("header2", "value2")
-            .encode(&mut encoded);
+            .finish();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
             vec![
@@ -127,10 +140,11 @@ mod test {
     #[test]
     fn test_encode_opt_param() {
         let mut encoded = Vec::new();
-        RequestBuilder::new("GET", "/foobar/baz", Some("a=b"))
+        super::RequestBuilder::new(&mut encoded, "GET", "/foobar/baz", Some("a=b"))
             .query_opt("a", None)
             .query_opt("b", Some(b"c"))
-            .encode(&mut encoded);
+            .headers()
+            .finish();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
             vec!["GET /foobar/baz?a=b&b=c HTTP/1.1", "\r\n",].join("\r\n")
@@ -140,12 +154,27 @@ mod test {
     #[test]
     fn test_percent_encode_query() {
         let mut encoded = Vec::new();
-        let req = RequestBuilder::new("GET", "/foobar/baz", None)
+        super::RequestBuilder::new(&mut encoded, "GET", "/foobar/baz", None)
             .query("a", "&".as_bytes())
-            .encode(&mut encoded);
+            .headers()
+            .finish();
         assert_eq!(
             String::from_utf8(encoded).unwrap(),
             vec!["GET /foobar/baz?a=%26 HTTP/1.1", "\r\n",].join("\r\n")
         );
     }
+
+    #[test]
+    fn test_encode_query_int() {
+        let mut encoded = Vec::new();
+        super::RequestBuilder::new(&mut encoded, "GET", "/announce", None)
+            .query("info_hash", &[0x12, 0xab, b'z', b'-', b'.'])
+            .query_int("left", 1_234_567)
+            .headers()
+            .finish();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "GET /announce?info_hash=%12%ABz-%2E&left=1234567 HTTP/1.1\r\n\r\n"
+        );
+    }
 }
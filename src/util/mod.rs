@@ -2,16 +2,19 @@ pub mod http;
 mod io;
 pub mod native;
 
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use byteorder::{BigEndian, ByteOrder};
 use metrohash::MetroHash;
 use rand::distributions::Alphanumeric;
 use rand::{self, Rng};
 use sha1::{Digest, Sha1};
+use url::percent_encoding::percent_decode;
+use url::Url;
 
 pub type FHashMap<K, V> = fnv::FnvHashMap<K, V>;
 pub type FHashSet<T> = fnv::FnvHashSet<T>;
@@ -131,31 +134,65 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Length of a compact IPv4 address and port
+pub const COMPACT_V4_LEN: usize = 6;
+/// Length of a compact IPv6 address and port
+pub const COMPACT_V6_LEN: usize = 18;
+
+/// Decodes a compact address, which is 18 bytes for IPv6 and 6 for IPv4.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
-    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-    SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
-}
-
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
-    match *addr {
-        SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            BigEndian::write_u16(&mut data[4..], s.port());
-        }
-        _ => panic!("IPv6 DHT not supported"),
-    }
+    let ip = if p.len() == COMPACT_V6_LEN {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&p[..16]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    } else {
+        IpAddr::V4(Ipv4Addr::new(p[0], p[1], p[2], p[3]))
+    };
+    SocketAddr::new(ip, BigEndian::read_u16(&p[p.len() - 2..]))
+}
+
+/// Encodes an address in the compact format.
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    data.extend_from_slice(&addr.port().to_be_bytes());
     data
 }
 
-pub fn find_subseq(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+/// Decodes a list of compact addresses, ignoring any trailing bytes.
+pub fn bytes_to_addrs(data: &[u8], v6: bool) -> Vec<SocketAddr> {
+    let len = if v6 { COMPACT_V6_LEN } else { COMPACT_V4_LEN };
+    data.chunks_exact(len).map(bytes_to_addr).collect()
+}
+
+/// Returns the host of a URL in a form suitable for DNS and the `Host` header.
+/// Internationalized names are only converted to punycode when parsing URLs
+/// with special schemes such as http, so udp hosts are left percent encoded.
+pub fn ascii_host(url: &Url) -> Option<Cow<'_, str>> {
+    let host = url.host_str()?;
+    if !host.contains('%') && host.is_ascii() {
+        return Some(Cow::Borrowed(host));
+    }
+    let host = percent_decode(host.as_bytes()).decode_utf8().ok()?;
+    url::idna::domain_to_ascii(&host).ok().map(Cow::Owned)
+}
+
+/// CRC32-C (Castagnoli), as used by BEP 40 and BEP 42.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
 }
 
 #[macro_export]
@@ -175,4 +212,46 @@ mod test {
         let s = hash_to_id(&hash);
         assert_eq!(id_to_hash(&s).unwrap(), hash);
     }
+
+    #[test]
+    fn test_compact_addr() {
+        let v4: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+        assert_eq!(addr_to_bytes(&v4), vec![1, 2, 3, 4, 0x1a, 0xe1]);
+        assert_eq!(addr_to_bytes(&v6).len(), COMPACT_V6_LEN);
+        assert_eq!(bytes_to_addr(&addr_to_bytes(&v4)), v4);
+        assert_eq!(bytes_to_addr(&addr_to_bytes(&v6)), v6);
+
+        let mut data = addr_to_bytes(&v4);
+        data.extend(addr_to_bytes(&v4));
+        data.push(0);
+        assert_eq!(bytes_to_addrs(&data, false), vec![v4, v4]);
+        assert!(bytes_to_addrs(&data, true).is_empty());
+        let data = [addr_to_bytes(&v6), addr_to_bytes(&v4)].concat();
+        assert_eq!(bytes_to_addrs(&data, true), vec![v6]);
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_ascii_host() {
+        let host = |u: &str| ascii_host(&Url::parse(u).unwrap()).map(|h| h.into_owned());
+        assert_eq!(host("http://tracker.com/announce").unwrap(), "tracker.com");
+        assert_eq!(
+            host("http://bücher.example:80/announce").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            host("udp://bücher.example:80/announce").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            host("udp://ТРЕКЕР.рф:6969").unwrap(),
+            "xn--e1aaowdh.xn--p1ai"
+        );
+        assert_eq!(host("udp://[::1]:6969").unwrap(), "[::1]");
+    }
 }
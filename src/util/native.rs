@@ -2,8 +2,10 @@ use std::fs::File;
 use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
 
 use nix::errno::Errno;
+use nix::libc::{self, c_void};
 
 use crate::util::io::io_err;
 
@@ -13,6 +15,7 @@ mod sys {
     #[link(name = "fallocate")]
     extern "C" {
         pub fn native_fallocate(fd: c_int, len: u64) -> c_int;
+        pub fn native_preallocate(fd: c_int, offset: u64, len: u64) -> c_int;
     }
 }
 
@@ -45,3 +48,60 @@ pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
         }
     }
 }
+
+/// Allocates a range of a file, growing the file to cover it. Unlike
+/// `fallocate`, filesystems which can't reserve space directly have it
+/// written out with zeroes instead.
+pub fn preallocate(f: &File, offset: u64, len: u64) -> io::Result<()> {
+    loop {
+        match unsafe { sys::native_preallocate(f.as_raw_fd(), offset, len) } {
+            0 => return Ok(()),
+            _ => match Errno::last() {
+                Errno::EINTR => continue,
+                e => return Err(io::Error::from_raw_os_error(e as i32)),
+            },
+        }
+    }
+}
+
+/// A read only shared mapping of part of a file.
+pub struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is only ever read from, and is unmapped when dropped
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    /// Maps `len` bytes of a file starting at `offset`, which must be a
+    /// multiple of the page size.
+    pub fn new(f: &File, offset: u64, len: usize) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                f.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
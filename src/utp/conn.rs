@@ -0,0 +1,731 @@
+//! State of a single uTP connection.
+//!
+//! Connections don't do any IO themselves. Packets received for the
+//! connection are passed to `on_packet`, and `flush` returns the packets
+//! which should be sent in turn. The send rate is governed by LEDBAT, which
+//! backs off as soon as queuing delay builds up along the path, so that
+//! uTP yields to other traffic rather than filling router buffers.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::packet::{Header, Type, HEADER_LEN};
+use crate::util::FHashMap;
+
+/// Queuing delay LEDBAT aims for, in microseconds
+const TARGET_DELAY: i64 = 100_000;
+/// Most the window grows by per RTT, in bytes
+const MAX_CWND_INCREASE: i64 = 3000;
+/// Bytes buffered for reading before the window closes
+pub const RECV_BUF: usize = 1024 * 1024;
+/// Bytes buffered for sending, on top of those in flight
+const SEND_BUF: usize = 64 * 1024;
+/// Duplicate acks after which a packet is assumed lost
+const DUP_ACKS: u32 = 3;
+const INITIAL_RTO: Duration = Duration::from_millis(1000);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// Consecutive timeouts before the connection is given up on
+const MAX_TIMEOUTS: u32 = 5;
+/// Seconds each base delay sample is kept for
+const BASE_DELAY_SECS: u64 = 60;
+/// Packet sizes, including IP and UDP headers, searched for the path MTU
+const MTU_FLOOR: usize = 576;
+const MTU_CEILING: usize = 1500;
+/// The search stops once the bounds are this close
+const MTU_SEARCH_DONE: usize = 16;
+/// Furthest ahead of the last in order packet we accept data
+const MAX_REORDER: u16 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    /// The peer reset the connection
+    Reset,
+    /// Too many consecutive timeouts
+    TimedOut,
+}
+
+pub struct Conn {
+    state: State,
+    /// ID of packets we receive
+    recv_id: u16,
+    /// ID of packets we send
+    send_id: u16,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Sequence number of the last packet received in order
+    ack_nr: u16,
+    /// Latest sequence number the peer has acked
+    last_ack: u16,
+    /// Sent packets awaiting an ack, oldest first
+    in_flight: VecDeque<Sent>,
+    /// Bytes written but not sent yet
+    send_buf: VecDeque<u8>,
+    /// Bytes received in order, waiting to be read
+    recv_buf: VecDeque<u8>,
+    /// Packets received ahead of a missing one
+    reordered: FHashMap<u16, Vec<u8>>,
+    /// Sequence number of the peer's FIN, if received
+    fin: Option<u16>,
+    /// Whether every packet up to the peer's FIN has been received
+    eof: bool,
+    /// Whether the connection should be closed once everything written
+    /// has been sent
+    closing: bool,
+    fin_sent: bool,
+    /// Timestamp difference to report back to the peer
+    reply_micro: u32,
+    peer_wnd: usize,
+    need_ack: bool,
+    dup_acks: u32,
+    ledbat: Ledbat,
+    mtu: Mtu,
+    /// Smoothed RTT and its variance, in microseconds
+    rtt: i64,
+    rtt_var: i64,
+    rto: Duration,
+    /// When the oldest packet in flight times out
+    timeout: Option<Instant>,
+    timeouts: u32,
+}
+
+struct Sent {
+    seq: u16,
+    kind: Type,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    resend: bool,
+}
+
+/// LEDBAT congestion control, as described in BEP 29.
+struct Ledbat {
+    cwnd: usize,
+    /// Windows grow exponentially until delay or loss is seen
+    slow_start: bool,
+    /// Lowest delay seen in each recent period, which estimates the delay
+    /// of the path without any queuing, latest last
+    base_delays: VecDeque<u32>,
+    base_updated: Instant,
+}
+
+/// Path MTU discovery, by binary search between bounds which are known to
+/// work and to fail. Probes are packets sized halfway in between, and
+/// their loss isn't treated as congestion.
+struct Mtu {
+    floor: usize,
+    ceiling: usize,
+    /// IP and UDP header size
+    overhead: usize,
+    /// Sequence number and size of the probe in flight
+    probe: Option<(u16, usize)>,
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap around.
+fn seq_lt(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+impl Conn {
+    /// Starts connecting, sending a SYN on the next flush.
+    pub fn connect(recv_id: u16, overhead: usize, now: Instant) -> Conn {
+        let mut c = Conn::new(
+            State::SynSent,
+            recv_id,
+            recv_id.wrapping_add(1),
+            1,
+            overhead,
+        );
+        c.queue(Type::Syn, Vec::new(), now);
+        c
+    }
+
+    /// Accepts a connection from the peer which sent `syn`.
+    pub fn accept(syn: &Header, seq_nr: u16, overhead: usize) -> Conn {
+        let mut c = Conn::new(
+            State::Connected,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            seq_nr,
+            overhead,
+        );
+        c.ack_nr = syn.seq_nr;
+        c.peer_wnd = syn.wnd_size as usize;
+        c.need_ack = true;
+        c
+    }
+
+    fn new(state: State, recv_id: u16, send_id: u16, seq_nr: u16, overhead: usize) -> Conn {
+        let mtu = Mtu {
+            floor: MTU_FLOOR,
+            ceiling: MTU_CEILING,
+            overhead,
+            probe: None,
+        };
+        Conn {
+            state,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            last_ack: seq_nr.wrapping_sub(1),
+            in_flight: VecDeque::new(),
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            reordered: FHashMap::default(),
+            fin: None,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            reply_micro: 0,
+            peer_wnd: RECV_BUF,
+            need_ack: false,
+            dup_acks: 0,
+            ledbat: Ledbat::new(mtu.payload()),
+            mtu,
+            rtt: 0,
+            rtt_var: 0,
+            rto: INITIAL_RTO,
+            timeout: None,
+            timeouts: 0,
+        }
+    }
+
+    /// Whether the connection is finished with, either because it failed
+    /// or because it was closed and everything sent was acked.
+    pub fn done(&self) -> bool {
+        match self.state {
+            State::Reset | State::TimedOut => true,
+            _ => self.fin_sent && self.in_flight.is_empty(),
+        }
+    }
+
+    /// Handles a packet from the peer.
+    pub fn on_packet(&mut self, h: &Header, payload: &[u8], now: Instant, now_us: u32) {
+        self.reply_micro = now_us.wrapping_sub(h.timestamp);
+        self.peer_wnd = h.wnd_size as usize;
+        match h.kind {
+            Type::Reset => {
+                self.state = State::Reset;
+                return;
+            }
+            // Our ack of the SYN was lost
+            Type::Syn => {
+                self.need_ack = true;
+                return;
+            }
+            Type::State if self.state == State::SynSent => {
+                self.state = State::Connected;
+                // State packets don't use up a sequence number
+                self.ack_nr = h.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            return;
+        }
+        self.handle_ack(h, payload.is_empty(), now);
+
+        match h.kind {
+            Type::Data => self.receive(h.seq_nr, payload),
+            Type::Fin => {
+                self.fin = Some(h.seq_nr);
+                self.receive(h.seq_nr, &[]);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_ack(&mut self, h: &Header, empty: bool, now: Instant) {
+        let mut acked = 0;
+        while let Some(p) = self.in_flight.front() {
+            if seq_lt(h.ack_nr, p.seq) {
+                break;
+            }
+            let p = self.in_flight.pop_front().unwrap();
+            acked += p.payload.len();
+            if p.transmissions == 1 {
+                self.update_rtt(now.duration_since(p.sent_at));
+            }
+            if let Some((seq, size)) = self.mtu.probe {
+                if seq == p.seq {
+                    self.mtu.acked(size);
+                }
+            }
+        }
+        if seq_lt(self.last_ack, h.ack_nr) {
+            self.last_ack = h.ack_nr;
+            self.dup_acks = 0;
+            self.timeouts = 0;
+            self.timeout = None;
+            self.ledbat
+                .on_ack(acked, h.timestamp_diff, self.mtu.payload(), now);
+        } else if h.kind == Type::State && empty && !self.in_flight.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == DUP_ACKS {
+                self.lost(false);
+            }
+        }
+    }
+
+    /// Stores data from the peer, delivering it once everything before it
+    /// has arrived.
+    fn receive(&mut self, seq: u16, payload: &[u8]) {
+        self.need_ack = true;
+        let next = self.ack_nr.wrapping_add(1);
+        if seq != next {
+            if seq_lt(next, seq) && seq.wrapping_sub(next) < MAX_REORDER {
+                self.reordered.insert(seq, payload.to_vec());
+            }
+            return;
+        }
+        // The peer ignored our window, it'll have to send this again
+        if self.recv_buf.len() + payload.len() > RECV_BUF {
+            return;
+        }
+        self.recv_buf.extend(payload);
+        self.ack_nr = seq;
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if self.fin == Some(self.ack_nr) {
+                self.eof = true;
+                self.reordered.clear();
+                break;
+            }
+            match self.reordered.remove(&next) {
+                Some(data) => {
+                    self.recv_buf.extend(data);
+                    self.ack_nr = next;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as i64;
+        if self.rtt == 0 {
+            self.rtt = sample;
+            self.rtt_var = sample / 2;
+        } else {
+            self.rtt_var += ((self.rtt - sample).abs() - self.rtt_var) / 4;
+            self.rtt += (sample - self.rtt) / 8;
+        }
+        let rto = Duration::from_micros((self.rtt + 4 * self.rtt_var) as u64);
+        self.rto = rto.max(MIN_RTO).min(MAX_RTO);
+    }
+
+    /// Resends the oldest packet in flight. Lost probes only mean the
+    /// probe was too big, anything else means congestion.
+    fn lost(&mut self, timeout: bool) {
+        let p = match self.in_flight.front_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        p.resend = true;
+        match self.mtu.probe {
+            Some((seq, size)) if seq == p.seq => self.mtu.lost(size),
+            _ if timeout => self.ledbat.on_timeout(self.mtu.payload()),
+            _ => self.ledbat.on_loss(self.mtu.payload()),
+        }
+    }
+
+    /// Checks for a timeout of the oldest packet in flight.
+    pub fn tick(&mut self, now: Instant) {
+        match self.timeout {
+            Some(t) if now >= t => {}
+            _ => return,
+        }
+        self.timeouts += 1;
+        if self.timeouts > MAX_TIMEOUTS {
+            self.state = State::TimedOut;
+            return;
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.timeout = None;
+        self.lost(true);
+    }
+
+    /// Queues data to be sent, returning how much fit in the buffer.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check()?;
+        let amnt = buf.len().min(SEND_BUF - self.send_buf.len());
+        if amnt == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.send_buf.extend(&buf[..amnt]);
+        Ok(amnt)
+    }
+
+    /// Reads received data, returning 0 once the peer has closed the
+    /// connection.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.recv_buf.is_empty() {
+            self.check()?;
+            if self.eof {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let was_full = self.recv_buf.len() > RECV_BUF / 2;
+        let amnt = buf.len().min(self.recv_buf.len());
+        for (b, d) in buf.iter_mut().zip(self.recv_buf.drain(..amnt)) {
+            *b = d;
+        }
+        // Let the peer know the window has opened up again
+        if was_full && self.recv_buf.len() <= RECV_BUF / 2 {
+            self.need_ack = true;
+        }
+        Ok(amnt)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match self.state {
+            State::Reset => Err(io::ErrorKind::ConnectionReset.into()),
+            State::TimedOut => Err(io::ErrorKind::TimedOut.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a FIN once everything written has been sent.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    fn queue(&mut self, kind: Type, payload: Vec<u8>, now: Instant) {
+        self.in_flight.push_back(Sent {
+            seq: self.seq_nr,
+            kind,
+            payload,
+            sent_at: now,
+            transmissions: 0,
+            resend: true,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|p| p.transmissions > 0 && !p.resend)
+            .map(|p| p.payload.len())
+            .sum()
+    }
+
+    /// Returns the packets which should be sent now.
+    pub fn flush(&mut self, now: Instant, now_us: u32) -> Vec<Vec<u8>> {
+        if self.done() && !self.closing {
+            return Vec::new();
+        }
+        if self.state == State::Connected {
+            self.packetize(now);
+        }
+
+        let mut packets = Vec::new();
+        let wnd_size = (RECV_BUF - self.recv_buf.len()) as u32;
+        for p in self.in_flight.iter_mut().filter(|p| p.resend) {
+            let h = Header {
+                kind: p.kind,
+                // The SYN carries the ID we want to receive with
+                conn_id: if p.kind == Type::Syn {
+                    self.recv_id
+                } else {
+                    self.send_id
+                },
+                timestamp: now_us,
+                timestamp_diff: self.reply_micro,
+                wnd_size,
+                seq_nr: p.seq,
+                ack_nr: self.ack_nr,
+            };
+            packets.push(h.encode(&p.payload));
+            p.resend = false;
+            p.transmissions += 1;
+            p.sent_at = now;
+        }
+        if self.need_ack && packets.is_empty() && self.state == State::Connected {
+            let h = Header {
+                kind: Type::State,
+                conn_id: self.send_id,
+                timestamp: now_us,
+                timestamp_diff: self.reply_micro,
+                wnd_size,
+                seq_nr: self.seq_nr,
+                ack_nr: self.ack_nr,
+            };
+            packets.push(h.encode(&[]));
+        }
+        if !packets.is_empty() {
+            self.need_ack = false;
+        }
+        if self.timeout.is_none() && !self.in_flight.is_empty() {
+            self.timeout = Some(now + self.rto);
+        }
+        packets
+    }
+
+    /// Splits buffered data into packets as the windows allow.
+    fn packetize(&mut self, now: Instant) {
+        let mut in_flight = self.bytes_in_flight();
+        let wnd = self.ledbat.cwnd.min(self.peer_wnd);
+        while !self.send_buf.is_empty() {
+            let size = match self.mtu.probe_size() {
+                Some(size) if self.send_buf.len() >= size => size,
+                _ => self.mtu.payload(),
+            };
+            let size = size.min(self.send_buf.len());
+            // One packet is always allowed, so a closed window gets probed
+            if in_flight > 0 && in_flight + size > wnd {
+                break;
+            }
+            if size > self.mtu.payload() {
+                self.mtu.probe = Some((self.seq_nr, size + HEADER_LEN + self.mtu.overhead));
+            }
+            let payload: Vec<u8> = self.send_buf.drain(..size).collect();
+            self.queue(Type::Data, payload, now);
+            in_flight += size;
+        }
+        if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+            self.queue(Type::Fin, Vec::new(), now);
+            self.fin_sent = true;
+        }
+    }
+
+    #[cfg(test)]
+    fn cwnd(&self) -> usize {
+        self.ledbat.cwnd
+    }
+}
+
+impl Ledbat {
+    fn new(mss: usize) -> Ledbat {
+        Ledbat {
+            cwnd: 2 * mss,
+            slow_start: true,
+            base_delays: VecDeque::new(),
+            base_updated: Instant::now(),
+        }
+    }
+
+    /// Grows or shrinks the window depending on how far the queuing delay
+    /// is from the target.
+    fn on_ack(&mut self, bytes: usize, delay: u32, mss: usize, now: Instant) {
+        if now.duration_since(self.base_updated) >= Duration::from_secs(BASE_DELAY_SECS)
+            || self.base_delays.is_empty()
+        {
+            if self.base_delays.len() == 2 {
+                self.base_delays.pop_front();
+            }
+            self.base_delays.push_back(delay);
+            self.base_updated = now;
+        }
+        let last = self.base_delays.back_mut().unwrap();
+        *last = (*last).min(delay);
+        let base = *self.base_delays.iter().min().unwrap();
+        let queuing = i64::from(delay - base);
+
+        if self.slow_start && queuing < TARGET_DELAY / 2 {
+            self.cwnd += bytes;
+            return;
+        }
+        self.slow_start = false;
+        let off_target = TARGET_DELAY - queuing;
+        let gain = MAX_CWND_INCREASE * off_target * bytes as i64 / TARGET_DELAY / self.cwnd as i64;
+        self.cwnd = (self.cwnd as i64 + gain).max(mss as i64) as usize;
+        self.cwnd = self.cwnd.min(RECV_BUF);
+    }
+
+    fn on_loss(&mut self, mss: usize) {
+        self.slow_start = false;
+        self.cwnd = (self.cwnd / 2).max(mss);
+    }
+
+    fn on_timeout(&mut self, mss: usize) {
+        self.slow_start = false;
+        self.cwnd = mss;
+    }
+}
+
+impl Mtu {
+    /// Payload size of regular packets.
+    fn payload(&self) -> usize {
+        self.floor - self.overhead - HEADER_LEN
+    }
+
+    /// Payload size of the next probe, if one should be sent.
+    fn probe_size(&self) -> Option<usize> {
+        if self.probe.is_some() || self.ceiling - self.floor < MTU_SEARCH_DONE {
+            return None;
+        }
+        Some((self.floor + self.ceiling) / 2 - self.overhead - HEADER_LEN)
+    }
+
+    fn acked(&mut self, size: usize) {
+        self.floor = self.floor.max(size);
+        self.probe = None;
+    }
+
+    fn lost(&mut self, size: usize) {
+        self.ceiling = self.ceiling.min(size - 1);
+        self.probe = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERHEAD: usize = 28;
+
+    /// Delivers packets to `to`, returning how many there were.
+    fn deliver(packets: Vec<Vec<u8>>, to: &mut Conn, now: Instant) -> usize {
+        let n = packets.len();
+        for p in packets {
+            let (h, payload) = Header::decode(&p).unwrap();
+            to.on_packet(&h, payload, now, 0);
+        }
+        n
+    }
+
+    fn pair(now: Instant) -> (Conn, Conn) {
+        let mut a = Conn::connect(10, OVERHEAD, now);
+        let syn = a.flush(now, 0);
+        assert_eq!(syn.len(), 1);
+        let (h, _) = Header::decode(&syn[0]).unwrap();
+        assert_eq!((h.kind, h.conn_id), (Type::Syn, 10));
+        let mut b = Conn::accept(&h, 500, OVERHEAD);
+        assert_eq!(b.recv_id, 11);
+        // Data written before the connection is up waits for it
+        a.write(b"hi").unwrap();
+        assert!(a.flush(now, 0).is_empty());
+        deliver(b.flush(now, 0), &mut a, now);
+        (a, b)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        a.write(&data).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        for _ in 0..20 {
+            deliver(a.flush(now, 0), &mut b, now);
+            deliver(b.flush(now, 0), &mut a, now);
+            while let Ok(n) = b.read(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+        }
+        assert_eq!(&received[..2], b"hi");
+        assert_eq!(&received[2..], &data[..]);
+
+        // Closing sends a FIN once everything is acked
+        a.close();
+        deliver(a.flush(now, 0), &mut b, now);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        deliver(b.flush(now, 0), &mut a, now);
+        assert!(a.done());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        a.ledbat.cwnd = RECV_BUF;
+        a.write(&[7u8; 3000]).unwrap();
+        let mut packets = a.flush(now, 0);
+        assert!(packets.len() > 2);
+        packets.reverse();
+        deliver(packets, &mut b, now);
+        let mut buf = [0u8; 4096];
+        assert_eq!(b.read(&mut buf).unwrap(), 3002);
+    }
+
+    #[test]
+    fn test_loss() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        a.ledbat.cwnd = 10_000;
+        a.mtu.ceiling = a.mtu.floor;
+        a.write(&[1u8; 4000]).unwrap();
+        let mut packets = a.flush(now, 0);
+        // The first packet is lost, and the rest are acked as duplicates
+        packets.remove(0);
+        for p in packets {
+            deliver(vec![p], &mut b, now);
+            deliver(b.flush(now, 0), &mut a, now);
+        }
+        let resent = a.flush(now, 0);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(a.cwnd(), 5000);
+        deliver(resent, &mut b, now);
+        let mut buf = [0u8; 8192];
+        assert_eq!(b.read(&mut buf).unwrap(), 4002);
+
+        // Timeouts resend too, and enough of them kill the connection
+        a.write(&[1u8; 100]).unwrap();
+        assert_eq!(a.flush(now, 0).len(), 1);
+        let mut later = now;
+        for _ in 0..MAX_TIMEOUTS {
+            later += MAX_RTO;
+            a.tick(later);
+            assert_eq!(a.flush(later, 0).len(), 1);
+        }
+        assert_eq!(a.cwnd(), a.mtu.payload());
+        a.tick(later + MAX_RTO);
+        assert!(a.done());
+        assert_eq!(
+            a.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_ledbat() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(1000);
+        // Windows double until delay builds up
+        l.on_ack(2000, 1000, 1000, now);
+        assert_eq!(l.cwnd, 4000);
+        l.on_ack(4000, 1000 + TARGET_DELAY as u32, 1000, now);
+        assert!(!l.slow_start);
+        assert_eq!(l.cwnd, 4000);
+        // Then grow below the target and shrink above it
+        l.on_ack(4000, 1000, 1000, now);
+        assert_eq!(l.cwnd, 7000);
+        l.on_ack(4000, 1000 + 2 * TARGET_DELAY as u32, 1000, now);
+        assert!(l.cwnd < 7000);
+        l.on_loss(1000);
+        l.on_timeout(1000);
+        assert_eq!(l.cwnd, 1000);
+    }
+
+    #[test]
+    fn test_mtu() {
+        let mut m = Mtu {
+            floor: MTU_FLOOR,
+            ceiling: MTU_CEILING,
+            overhead: OVERHEAD,
+            probe: None,
+        };
+        let mut probes = 0;
+        while let Some(size) = m.probe_size() {
+            let total = size + HEADER_LEN + OVERHEAD;
+            m.probe = Some((0, total));
+            // The path allows packets up to 1400 bytes
+            if total <= 1400 {
+                m.acked(total);
+            } else {
+                m.lost(total);
+            }
+            probes += 1;
+        }
+        assert!(probes < 10);
+        assert!(m.floor <= 1400 && m.floor > 1400 - MTU_SEARCH_DONE);
+        assert_eq!(m.payload(), m.floor - OVERHEAD - HEADER_LEN);
+    }
+}
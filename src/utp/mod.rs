@@ -0,0 +1,371 @@
+//! uTP transport (BEP 29).
+//!
+//! All uTP connections share a single UDP socket, bound to the same port
+//! as the TCP listener. `Utp` owns the socket and multiplexes packets onto
+//! connections, each of which is exposed to the rest of the client as a
+//! nonblocking `Stream`. Since streams have no file descriptor of their
+//! own, readiness is reported through tokens instead of the poller.
+
+mod conn;
+mod packet;
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Instant;
+
+use rand::random;
+
+use self::conn::Conn;
+use self::packet::{Header, Type};
+use crate::util::FHashMap;
+
+/// IP and UDP header size of IPv4 packets
+const V4_OVERHEAD: usize = 28;
+/// IP and UDP header size of IPv6 packets
+const V6_OVERHEAD: usize = 48;
+/// Largest datagram we expect to receive
+const MAX_PACKET: usize = 1 << 16;
+
+/// Handle to the uTP socket, shared by every stream.
+#[derive(Clone)]
+pub struct Utp {
+    inner: Rc<RefCell<Inner>>,
+}
+
+/// A uTP connection.
+pub struct Stream {
+    utp: Utp,
+    key: Key,
+}
+
+/// Connections are identified by the peer's address and the ID of the
+/// packets we receive from them.
+type Key = (SocketAddr, u16);
+
+struct Inner {
+    sock: UdpSocket,
+    conns: FHashMap<Key, Entry>,
+    /// Tokens of streams which may be read from or written to
+    ready: Vec<usize>,
+    start: Instant,
+}
+
+struct Entry {
+    conn: Conn,
+    token: Option<usize>,
+    /// The stream has been dropped, and the connection stays around only
+    /// until what was sent is acked
+    dropped: bool,
+}
+
+impl Utp {
+    pub fn bind(port: u16) -> io::Result<Utp> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))?;
+        sock.set_nonblocking(true)?;
+        Ok(Utp {
+            inner: Rc::new(RefCell::new(Inner {
+                sock,
+                conns: FHashMap::default(),
+                ready: Vec::new(),
+                start: Instant::now(),
+            })),
+        })
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.borrow().sock.local_addr().unwrap()
+    }
+
+    /// Opens a connection to `addr`, whose readiness will be reported
+    /// with `token`.
+    pub fn connect(&self, addr: SocketAddr, token: usize) -> Stream {
+        let mut inner = self.inner.borrow_mut();
+        let now = Instant::now();
+        let mut id: u16 = random();
+        while inner.conns.contains_key(&(addr, id)) {
+            id = id.wrapping_add(2);
+        }
+        let conn = Conn::connect(id, overhead(&addr), now);
+        let key = (addr, id);
+        inner.conns.insert(
+            key,
+            Entry {
+                conn,
+                token: Some(token),
+                dropped: false,
+            },
+        );
+        inner.flush(&key, now);
+        Stream {
+            utp: self.clone(),
+            key,
+        }
+    }
+
+    /// Processes every datagram received, returning any new incoming
+    /// connections.
+    pub fn readable(&self) -> Vec<Stream> {
+        let mut inner = self.inner.borrow_mut();
+        let mut incoming = Vec::new();
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let (amnt, addr) = match inner.sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("uTP receive failed: {}", e);
+                    continue;
+                }
+            };
+            let (header, payload) = match Header::decode(&buf[..amnt]) {
+                Some(p) => p,
+                None => continue,
+            };
+            let now = Instant::now();
+            let now_us = inner.timestamp(now);
+            let id = header.conn_id;
+            let key = match header.kind {
+                Type::Syn => (addr, id.wrapping_add(1)),
+                // Resets may carry either ID of the connection
+                Type::Reset => [id, id.wrapping_sub(1), id.wrapping_add(1)]
+                    .iter()
+                    .map(|&id| (addr, id))
+                    .find(|key| inner.conns.contains_key(key))
+                    .unwrap_or((addr, id)),
+                _ => (addr, id),
+            };
+            if let Some(e) = inner.conns.get_mut(&key) {
+                e.conn.on_packet(&header, payload, now, now_us);
+                if let Some(token) = e.token {
+                    inner.ready.push(token);
+                }
+            } else if header.kind == Type::Syn {
+                debug!("Accepted uTP connection from {}", addr);
+                let conn = Conn::accept(&header, random(), overhead(&addr));
+                inner.conns.insert(
+                    key,
+                    Entry {
+                        conn,
+                        token: None,
+                        dropped: false,
+                    },
+                );
+                incoming.push(Stream {
+                    utp: self.clone(),
+                    key,
+                });
+            } else if header.kind != Type::Reset {
+                inner.reset(&header, addr, now_us);
+                continue;
+            }
+            inner.flush(&key, now);
+        }
+        incoming
+    }
+
+    /// Handles retransmission timeouts, and drops connections which are
+    /// finished with.
+    pub fn tick(&self) {
+        let mut inner = self.inner.borrow_mut();
+        let now = Instant::now();
+        let keys: Vec<_> = inner.conns.keys().cloned().collect();
+        for key in keys {
+            let e = inner.conns.get_mut(&key).unwrap();
+            e.conn.tick(now);
+            if e.conn.done() {
+                if e.dropped {
+                    inner.conns.remove(&key);
+                    continue;
+                }
+                // Let the owner see the error
+                if let Some(token) = e.token {
+                    inner.ready.push(token);
+                }
+            }
+            inner.flush(&key, now);
+        }
+    }
+
+    /// Returns the tokens of streams which became ready since the last
+    /// call.
+    pub fn take_ready(&self) -> Vec<usize> {
+        let mut ready = std::mem::take(&mut self.inner.borrow_mut().ready);
+        ready.sort_unstable();
+        ready.dedup();
+        ready
+    }
+}
+
+impl AsRawFd for Utp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.borrow().sock.as_raw_fd()
+    }
+}
+
+impl Inner {
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.start).as_micros() as u32
+    }
+
+    /// Sends whatever packets a connection has ready.
+    fn flush(&mut self, key: &Key, now: Instant) {
+        let now_us = self.timestamp(now);
+        let packets = match self.conns.get_mut(key) {
+            Some(e) => e.conn.flush(now, now_us),
+            None => return,
+        };
+        for p in packets {
+            if let Err(e) = self.sock.send_to(&p, key.0) {
+                // Lost packets get resent, so there's nothing to do
+                debug!("uTP send to {} failed: {}", key.0, e);
+            }
+        }
+    }
+
+    /// Tells the sender of a packet for an unknown connection to go away.
+    fn reset(&self, h: &Header, addr: SocketAddr, now_us: u32) {
+        let reset = Header {
+            kind: Type::Reset,
+            conn_id: h.conn_id,
+            timestamp: now_us,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: h.seq_nr,
+        };
+        self.sock.send_to(&reset.encode(&[]), addr).ok();
+    }
+}
+
+impl Stream {
+    pub fn addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    /// Sets the token readiness is reported with. Streams which already
+    /// have something to read are reported straight away.
+    pub fn set_token(&self, token: usize) {
+        let mut inner = self.utp.inner.borrow_mut();
+        inner.conns.get_mut(&self.key).unwrap().token = Some(token);
+        inner.ready.push(token);
+    }
+
+    fn with_conn<T, F: FnOnce(&mut Conn) -> T>(&self, f: F) -> T {
+        let mut inner = self.utp.inner.borrow_mut();
+        f(&mut inner.conns.get_mut(&self.key).unwrap().conn)
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.with_conn(|c| c.read(buf));
+        // Reading may have opened the receive window
+        self.utp.inner.borrow_mut().flush(&self.key, Instant::now());
+        res
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.with_conn(|c| c.write(buf));
+        self.utp.inner.borrow_mut().flush(&self.key, Instant::now());
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut inner = self.utp.inner.borrow_mut();
+        if let Some(e) = inner.conns.get_mut(&self.key) {
+            e.conn.close();
+            e.token = None;
+            e.dropped = true;
+        }
+        inner.flush(&self.key, Instant::now());
+    }
+}
+
+fn overhead(addr: &SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(..) => V4_OVERHEAD,
+        SocketAddr::V6(..) => V6_OVERHEAD,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::{thread, time};
+
+    /// Runs both ends until `f` returns true, collecting incoming streams.
+    fn run<F: FnMut(&mut Vec<Stream>) -> bool>(a: &Utp, b: &Utp, mut f: F) -> Vec<Stream> {
+        let mut incoming = Vec::new();
+        for _ in 0..200 {
+            incoming.extend(a.readable());
+            incoming.extend(b.readable());
+            if f(&mut incoming) {
+                return incoming;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        panic!("uTP streams made no progress");
+    }
+
+    #[test]
+    fn test_loopback() {
+        let a = Utp::bind(0).unwrap();
+        let b = Utp::bind(0).unwrap();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), b.local_addr().port());
+        let mut s = a.connect(addr, 1);
+        s.write_all(b"hello").unwrap();
+
+        let mut r = run(&a, &b, |inc| !inc.is_empty()).pop().unwrap();
+        assert_eq!(r.addr().port(), a.local_addr().port());
+        r.set_token(2);
+        assert_eq!(b.take_ready(), vec![2]);
+        run(&a, &b, |_| a.take_ready().contains(&1));
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut received = Vec::new();
+        let mut sent = 0;
+        let mut buf = [0u8; 16_384];
+        run(&a, &b, |_| {
+            if let Ok(n) = s.write(&data[sent..]) {
+                sent += n;
+            }
+            while let Ok(n) = r.read(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received.len() == data.len() + 5
+        });
+        assert_eq!(&received[..5], b"hello");
+        assert_eq!(&received[5..], &data[..]);
+
+        // Dropping a stream closes it once everything is acked
+        drop(s);
+        run(&a, &b, |_| r.read(&mut buf).ok() == Some(0));
+        run(&a, &b, |_| {
+            a.tick();
+            a.inner.borrow().conns.is_empty()
+        });
+
+        // Packets for connections which are gone get reset
+        let mut s = a.connect(addr, 3);
+        run(&a, &b, |inc| !inc.is_empty());
+        b.inner.borrow_mut().conns.clear();
+        s.write_all(b"hello").unwrap();
+        run(&a, &b, |_| match s.read(&mut buf) {
+            Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+            Ok(_) => false,
+        });
+    }
+}
@@ -0,0 +1,125 @@
+//! uTP packet framing.
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub kind: Type,
+    pub conn_id: u16,
+    /// Send time in microseconds, by the sender's clock
+    pub timestamp: u32,
+    /// Difference between the sender's clock when the last packet was
+    /// received and the timestamp of that packet
+    pub timestamp_diff: u32,
+    /// Bytes the sender is willing to receive
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+}
+
+impl Type {
+    fn from_u8(b: u8) -> Option<Type> {
+        match b {
+            0 => Some(Type::Data),
+            1 => Some(Type::Fin),
+            2 => Some(Type::State),
+            3 => Some(Type::Reset),
+            4 => Some(Type::Syn),
+            _ => None,
+        }
+    }
+}
+
+impl Header {
+    /// Decodes a packet, returning its header and payload. Extensions
+    /// are skipped, as none are used.
+    pub fn decode(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0F != VERSION {
+            return None;
+        }
+        let header = Header {
+            kind: Type::from_u8(buf[0] >> 4)?,
+            conn_id: BigEndian::read_u16(&buf[2..4]),
+            timestamp: BigEndian::read_u32(&buf[4..8]),
+            timestamp_diff: BigEndian::read_u32(&buf[8..12]),
+            wnd_size: BigEndian::read_u32(&buf[12..16]),
+            seq_nr: BigEndian::read_u16(&buf[16..18]),
+            ack_nr: BigEndian::read_u16(&buf[18..20]),
+        };
+        let mut ext = buf[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            if buf.len() < pos + 2 {
+                return None;
+            }
+            ext = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+        }
+        if buf.len() < pos {
+            return None;
+        }
+        Some((header, &buf[pos..]))
+    }
+
+    /// Encodes the header followed by the payload into a packet.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN + payload.len()];
+        buf[0] = (self.kind as u8) << 4 | VERSION;
+        BigEndian::write_u16(&mut buf[2..4], self.conn_id);
+        BigEndian::write_u32(&mut buf[4..8], self.timestamp);
+        BigEndian::write_u32(&mut buf[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut buf[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut buf[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut buf[18..20], self.ack_nr);
+        buf[HEADER_LEN..].copy_from_slice(payload);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let h = Header {
+            kind: Type::Syn,
+            conn_id: 0xBEEF,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+        };
+        let buf = h.encode(b"abc");
+        assert_eq!(buf[0], 0x41);
+        assert_eq!(Header::decode(&buf), Some((h, &b"abc"[..])));
+
+        // Extensions are skipped over
+        let mut ext = buf[..HEADER_LEN].to_vec();
+        ext[1] = 1;
+        ext.extend_from_slice(&[0, 4, 0xFF, 0xFF, 0xFF, 0xFF]);
+        ext.extend_from_slice(b"abc");
+        assert_eq!(Header::decode(&ext), Some((h, &b"abc"[..])));
+        assert_eq!(Header::decode(&ext[..HEADER_LEN + 3]), None);
+
+        assert_eq!(Header::decode(&buf[..HEADER_LEN - 1]), None);
+        let mut bad = buf.clone();
+        bad[0] = 0x42;
+        assert_eq!(Header::decode(&bad), None);
+        bad[0] = 0x51;
+        assert_eq!(Header::decode(&bad), None);
+    }
+}
@@ -1,5 +1,5 @@
 use std::io::{self, Read};
-use std::net::{SocketAddr, TcpStream};
+use std::net::{SocketAddr, SocketAddrV6, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 
@@ -14,6 +14,9 @@ const EINPROGRESS: i32 = 115;
 pub struct SStream {
     conn: SConn,
     fd: i32,
+    /// Whether this is a dual-stack IPv6 socket, which reaches
+    /// IPv4 hosts through mapped addresses.
+    dual: bool,
 }
 
 enum SConn {
@@ -39,6 +42,21 @@ impl SStream {
         SStream::new(conn, host)
     }
 
+    /// Creates a socket able to connect to both IPv4 and IPv6 addresses,
+    /// falling back to IPv4 only if IPv6 is unavailable.
+    pub fn new_dual(host: Option<String>) -> io::Result<SStream> {
+        let conn =
+            TcpBuilder::new_v6().and_then(|b| b.only_v6(false).and_then(|b| b.to_tcp_stream()));
+        match conn {
+            Ok(conn) => {
+                let mut s = SStream::new(conn, host)?;
+                s.dual = true;
+                Ok(s)
+            }
+            Err(_) => SStream::new_v4(host),
+        }
+    }
+
     fn new(conn: TcpStream, host: Option<String>) -> io::Result<SStream> {
         conn.set_nonblocking(true)?;
         let fd = conn.as_raw_fd();
@@ -61,17 +79,25 @@ impl SStream {
                 SStream {
                     conn: SConn::SSLC { conn, session },
                     fd,
+                    dual: false,
                 }
             }
             None => SStream {
                 conn: SConn::Plain(conn),
                 fd,
+                dual: false,
             },
         };
         Ok(sock)
     }
 
     pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let addr = match addr {
+            SocketAddr::V4(a) if self.dual => {
+                SocketAddr::V6(SocketAddrV6::new(a.ip().to_ipv6_mapped(), a.port(), 0, 0))
+            }
+            a => a,
+        };
         match self.conn {
             SConn::Plain(ref mut c)
             | SConn::SSLC {
@@ -94,6 +120,7 @@ impl SStream {
         Ok(SStream {
             conn: SConn::Plain(stream),
             fd,
+            dual: false,
         })
     }
 
@@ -104,6 +131,7 @@ impl SStream {
         Ok(SStream {
             conn: SConn::SSLS { conn, session },
             fd,
+            dual: false,
         })
     }
 
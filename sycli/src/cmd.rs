@@ -24,13 +24,15 @@ pub fn add(
     dir: Option<&str>,
     start: bool,
     import: bool,
+    link: Option<message::LinkMode>,
+    memory: bool,
     output: &str,
 ) -> Result<()> {
     for file in files {
         if let Ok(magnet) = Url::parse(file) {
-            add_magnet(&mut c, magnet, dir, start, output)?;
+            add_magnet(&mut c, magnet, dir, start, memory, output)?;
         } else {
-            add_file(&mut c, url, file, dir, start, import, output)?;
+            add_file(&mut c, url, file, dir, start, import, link, memory, output)?;
         }
     }
     Ok(())
@@ -43,6 +45,8 @@ fn add_file(
     dir: Option<&str>,
     start: bool,
     import: bool,
+    link: Option<message::LinkMode>,
+    memory: bool,
     output: &str,
 ) -> Result<()> {
     let mut torrent = Vec::new();
@@ -56,6 +60,8 @@ fn add_file(
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
         import,
+        link,
+        memory,
     };
     let token = if let SMessage::TransferOffer { token, .. } = c.rr(msg)? {
         token
@@ -91,6 +97,7 @@ fn add_magnet(
     magnet: Url,
     dir: Option<&str>,
     start: bool,
+    memory: bool,
     output: &str,
 ) -> Result<()> {
     let msg = CMessage::UploadMagnet {
@@ -98,6 +105,7 @@ fn add_magnet(
         uri: magnet.as_str().to_owned(),
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
+        memory,
     };
     match c.rr(msg)? {
         SMessage::ResourcesExtant { ids, .. } => {
@@ -481,6 +489,23 @@ pub fn move_torrent(mut c: Client, id: &str, dir: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn rename_torrent(mut c: Client, id: &str, name: &str) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: torrent[0].id().to_owned(),
+            name: Some(name.to_owned()),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
 pub fn verify_torrent(mut c: Client, id: &str) -> Result<()> {
     let torrent = search_torrent_name(&mut c, id)?;
     if torrent.len() != 1 {
@@ -494,6 +519,19 @@ pub fn verify_torrent(mut c: Client, id: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn repair_torrent(mut c: Client, id: &str) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let msg = CMessage::RepairResources {
+        serial: c.next_serial(),
+        ids: vec![torrent[0].id().to_owned()],
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
 pub fn add_trackers(mut c: Client, id: &str, trackers: Vec<&str>) -> Result<()> {
     let torrent = search_torrent_name(&mut c, id)?;
     if torrent.len() != 1 {
@@ -702,6 +740,19 @@ pub fn set_file_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn rename_file(mut c: Client, id: &str, path: &str) -> Result<()> {
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: id.to_owned(),
+            path: Some(path.to_owned()),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
 pub fn get_files(mut c: Client, id: &str, output: &str) -> Result<()> {
     print_torrent_res(&mut c, id, ResourceKind::File, output)
 }
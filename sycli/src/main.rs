@@ -75,6 +75,20 @@ fn main() {
                         .short("i")
                         .long("import"),
                 )
+                .arg(
+                    Arg::with_name("link")
+                        .help("Reflink or hardlink files existing torrents already downloaded.")
+                        .short("l")
+                        .long("link")
+                        .possible_values(&["reflink", "hardlink"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("memory")
+                        .help("Keep the torrent's data in memory rather than on disk.")
+                        .short("m")
+                        .long("memory"),
+                )
                 .arg(
                     Arg::with_name("files")
                         .help("Torrent files or magnets to add")
@@ -127,14 +141,24 @@ fn main() {
                         .index(1)
                         .required(true),
                 )
-                .subcommands(vec![SubCommand::with_name("priority")
-                    .about("Adjust a file's priority.")
-                    .arg(
-                        Arg::with_name("file pri")
-                            .help("priority to set file to (0-5)")
-                            .index(1)
-                            .required(true),
-                    )])
+                .subcommands(vec![
+                    SubCommand::with_name("priority")
+                        .about("Adjust a file's priority.")
+                        .arg(
+                            Arg::with_name("file pri")
+                                .help("priority to set file to (0-5)")
+                                .index(1)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("rename")
+                        .about("Rename a file, keeping it in its torrent's folder.")
+                        .arg(
+                            Arg::with_name("path")
+                                .help("New path of the file, relative to the torrent's directory.")
+                                .index(1)
+                                .required(true),
+                        ),
+                ])
                 .setting(AppSettings::SubcommandRequiredElseHelp),
             SubCommand::with_name("get")
                 .about("Gets the specified resource.")
@@ -238,6 +262,14 @@ fn main() {
                                 .index(1)
                                 .required(true),
                         ),
+                    SubCommand::with_name("rename")
+                        .about("Rename a torrent along with its folder or file")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("New name of the torrent.")
+                                .index(1)
+                                .required(true),
+                        ),
                     SubCommand::with_name("tracker")
                         .about("Manipulate trackers for a torrent")
                         .subcommands(vec![
@@ -329,6 +361,8 @@ fn main() {
                     SubCommand::with_name("tags").about("Prints a torrent's tags"),
                     SubCommand::with_name("files").about("Prints a torrent's files"),
                     SubCommand::with_name("verify").about("Verify integrity of downloaded files"),
+                    SubCommand::with_name("repair")
+                        .about("Redownload files which were deleted or damaged"),
                 ])
                 .arg(
                     Arg::with_name("output")
@@ -416,6 +450,12 @@ fn main() {
                 args.value_of("directory"),
                 !args.is_present("pause"),
                 args.is_present("import"),
+                match args.value_of("link") {
+                    Some("reflink") => Some(rpc::message::LinkMode::Reflink),
+                    Some("hardlink") => Some(rpc::message::LinkMode::Hardlink),
+                    _ => None,
+                },
+                args.is_present("memory"),
                 output,
             );
             if let Err(e) = res {
@@ -456,6 +496,14 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                "rename" => {
+                    let rscmd = subcmd.subcommand_matches("rename").unwrap();
+                    let path = rscmd.value_of("path").unwrap();
+                    if let Err(e) = cmd::rename_file(client, id, path) {
+                        eprintln!("Failed to rename file: {}", e.display_chain());
+                        process::exit(1);
+                    }
+                }
                 _ => unreachable!(),
             }
         }
@@ -524,12 +572,29 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                "rename" => {
+                    let name = subcmd
+                        .subcommand_matches("rename")
+                        .unwrap()
+                        .value_of("name")
+                        .unwrap();
+                    if let Err(e) = cmd::rename_torrent(client, id, name) {
+                        eprintln!("Failed to rename torrent: {}", e.display_chain());
+                        process::exit(1);
+                    }
+                }
                 "verify" => {
                     if let Err(e) = cmd::verify_torrent(client, id) {
                         eprintln!("Failed to verify integrity: {}", e.display_chain());
                         process::exit(1);
                     }
                 }
+                "repair" => {
+                    if let Err(e) = cmd::repair_torrent(client, id) {
+                        eprintln!("Failed to repair torrent: {}", e.display_chain());
+                        process::exit(1);
+                    }
+                }
                 "tracker" => {
                     let sscmd = subcmd.subcommand_matches("tracker").unwrap();
                     match sscmd.subcommand_name().unwrap() {